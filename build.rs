@@ -1,3 +1,9 @@
 fn main() {
-    println!("cargo:rustc-link-arg=-Tlinkall.x");
-}
\ No newline at end of file
+    // Only the firmware binary is linked with the ESP32-C6 linker script;
+    // host builds of the library and its tests use the default linker.
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("none") {
+        println!("cargo:rustc-link-arg-bins=-Tlinkall.x");
+        // ROM functions used by the esp-wifi blobs
+        println!("cargo:rustc-link-arg-bins=-Trom_functions.x");
+    }
+}
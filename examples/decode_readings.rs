@@ -0,0 +1,87 @@
+//! Decode encoded sensor readings on the host
+//!
+//! Reads hex-encoded readings from stdin, one per line, or with `--raw`
+//! a binary stream of readings stored back to back, and prints each one:
+//!
+//! ```text
+//! echo 01010301ac02010000b040 | cargo run --example decode_readings
+//! cargo run --example decode_readings -- --raw < readings.bin
+//! ```
+
+use std::io::{self, BufRead, Read};
+use std::process::ExitCode;
+
+use altruist_rs::sensors::codec::{self, CodecError};
+use altruist_rs::sensors::SensorReading;
+
+fn print(reading: &SensorReading) {
+    let utc = reading.utc_timestamp.map_or("-".to_string(), |utc| utc.to_string());
+    println!(
+        "{} {} t={}ms utc={} {:?}",
+        reading.sensor_type.name(),
+        reading.quality.name(),
+        reading.timestamp,
+        utc,
+        reading.data
+    );
+}
+
+fn parse_hex(line: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = line.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn decode_raw() -> io::Result<bool> {
+    let mut bytes = Vec::new();
+    io::stdin().read_to_end(&mut bytes)?;
+    let mut rest = &bytes[..];
+    while !rest.is_empty() {
+        match codec::decode_prefix(rest) {
+            Ok((reading, used)) => {
+                print(&reading);
+                rest = &rest[used..];
+            }
+            Err(e) => {
+                eprintln!("offset {}: {}", bytes.len() - rest.len(), e);
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+fn decode_lines() -> io::Result<bool> {
+    let mut ok = true;
+    for (number, line) in io::stdin().lock().lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_hex(&line).ok_or(CodecError::InvalidValue).and_then(|bytes| codec::decode(&bytes)) {
+            Ok(reading) => print(&reading),
+            Err(e) => {
+                eprintln!("line {}: {}", number + 1, e);
+                ok = false;
+            }
+        }
+    }
+    Ok(ok)
+}
+
+fn main() -> ExitCode {
+    let raw = std::env::args().any(|arg| arg == "--raw");
+    match if raw { decode_raw() } else { decode_lines() } {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,1044 @@
+//! Persistent device configuration
+//!
+//! Wi-Fi credentials, upload targets, per-sensor enable/interval and
+//! calibration, and the station altitude are kept in a versioned,
+//! CRC-protected record in `storage::CONFIG_PARTITION`. Build-time settings
+//! only serve as defaults until a record has been saved.
+//!
+//! The partition's two erase sectors form a double buffer. Each save appends
+//! a record to the active sector:
+//!
+//! | offset | size | content                                        |
+//! |--------|------|------------------------------------------------|
+//! | 0      | 4    | magic `ALTC`                                   |
+//! | 4      | 2    | format version, little-endian                  |
+//! | 6      | 2    | payload length                                 |
+//! | 8      | 4    | sequence number, one higher for every save     |
+//! | 12     | 4    | CRC-32 of the payload followed by bytes 4..12  |
+//! | 16     | n    | payload, padded to 4 bytes with 0xFF           |
+//!
+//! Once the active sector is full, the other one is erased and takes the
+//! next record. Each erase is spread over several saves, both sectors wear
+//! evenly, and the sector holding the latest good record is never erased,
+//! so a power cut during a save falls back to the previous settings.
+//! Saves that wouldn't change anything don't touch the flash at all.
+
+use core::fmt::Write as _;
+
+use embassy_time::Duration;
+use embedded_storage::nor_flash::NorFlash;
+use heapless::{String, Vec};
+
+use crate::net::home_assistant::MqttConfig;
+use crate::net::influxdb::{self, InfluxDbConfig};
+use crate::net::mqtt;
+use crate::net::opensensemap::{OpenSenseMapConfig, OpenSenseMapError, SensorId, UploadFormat};
+use crate::net::sensor_community::{self, SensorCommunityConfig};
+use crate::sensors::calibration::{self, Calibration};
+use crate::sensors::{Field, SensorReading, SensorType};
+use crate::storage::{crc32, crc32_update, Partition};
+
+/// Current payload format version
+pub const CONFIG_VERSION: u16 = 4;
+
+/// Largest payload a record can carry
+pub const MAX_PAYLOAD: usize = 1536;
+
+/// Sensors with non-default settings
+pub const MAX_SENSOR_SETTINGS: usize = 8;
+
+/// Calibrated fields per sensor
+pub const MAX_CALIBRATIONS: usize = 4;
+
+const MAGIC: [u8; 4] = *b"ALTC";
+const HEADER_LEN: usize = 16;
+/// Records start and end on this boundary, enough for the ESP32 flash
+const ALIGN: usize = 4;
+
+/// Configuration errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigError {
+    /// Reading, writing or erasing the flash failed
+    Flash,
+    /// No valid record in the partition
+    NotFound,
+    /// Record passed its CRC but the payload doesn't decode
+    Corrupt,
+    /// Record was written by newer firmware
+    UnsupportedVersion(u16),
+    /// Value doesn't fit its field, or the payload exceeds `MAX_PAYLOAD`
+    TooLarge,
+    /// Value doesn't parse
+    Invalid,
+}
+
+impl core::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ConfigError::Flash => write!(f, "Flash access failed"),
+            ConfigError::NotFound => write!(f, "No stored configuration"),
+            ConfigError::Corrupt => write!(f, "Corrupt configuration"),
+            ConfigError::UnsupportedVersion(v) => write!(f, "Unsupported configuration version {}", v),
+            ConfigError::TooLarge => write!(f, "Value too large"),
+            ConfigError::Invalid => write!(f, "Invalid value"),
+        }
+    }
+}
+
+/// Copy a string into a fixed-capacity one
+pub fn copy_str<const N: usize>(s: &str) -> Result<String<N>, ConfigError> {
+    String::try_from(s).map_err(|_| ConfigError::TooLarge)
+}
+
+/// Station credentials
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct WifiSettings {
+    pub ssid: String<32>,
+    pub password: String<64>,
+}
+
+impl WifiSettings {
+    pub fn new(ssid: &str, password: &str) -> Result<Self, ConfigError> {
+        Ok(Self { ssid: copy_str(ssid)?, password: copy_str(password)? })
+    }
+
+    /// Whether there is a network to join
+    pub fn is_configured(&self) -> bool {
+        !self.ssid.is_empty()
+    }
+}
+
+/// sensor.community upload settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorCommunitySettings {
+    pub enabled: bool,
+    /// Station ID; empty to derive it from the MAC address
+    pub sensor_id: String<32>,
+}
+
+impl Default for SensorCommunitySettings {
+    fn default() -> Self {
+        Self { enabled: true, sensor_id: String::new() }
+    }
+}
+
+impl SensorCommunitySettings {
+    /// Uploader settings, or `None` if uploads are disabled
+    pub fn to_config(&self, mac: [u8; 6]) -> Option<SensorCommunityConfig> {
+        if !self.enabled {
+            return None;
+        }
+        Some(match self.sensor_id.is_empty() {
+            true => SensorCommunityConfig::new(&sensor_community::sensor_id_from_mac(mac)),
+            false => SensorCommunityConfig::new(&self.sensor_id),
+        })
+    }
+}
+
+/// openSenseMap upload settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenSenseMapSettings {
+    pub box_id: SensorId,
+    pub access_token: String<64>,
+    /// `field=id` list, as taken by `OpenSenseMapConfig::parse_sensor_ids`
+    pub sensor_ids: String<256>,
+    pub format: UploadFormat,
+}
+
+impl Default for OpenSenseMapSettings {
+    fn default() -> Self {
+        Self {
+            box_id: String::new(),
+            access_token: String::new(),
+            sensor_ids: String::new(),
+            format: UploadFormat::Json,
+        }
+    }
+}
+
+impl OpenSenseMapSettings {
+    pub fn new(box_id: &str, access_token: &str, sensor_ids: &str) -> Result<Self, ConfigError> {
+        Ok(Self {
+            box_id: copy_str(box_id)?,
+            access_token: copy_str(access_token)?,
+            sensor_ids: copy_str(sensor_ids)?,
+            format: UploadFormat::Json,
+        })
+    }
+
+    /// Whether a box has been set up
+    pub fn is_configured(&self) -> bool {
+        !self.box_id.is_empty()
+    }
+
+    /// Uploader settings; fails if the sensor list doesn't parse
+    pub fn to_config(&self) -> Result<OpenSenseMapConfig, OpenSenseMapError> {
+        let mut config = OpenSenseMapConfig::new(&self.box_id, &self.access_token)?;
+        config.parse_sensor_ids(&self.sensor_ids)?;
+        config.format = self.format;
+        Ok(config)
+    }
+}
+
+/// MQTT broker settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqttSettings {
+    pub host: String<64>,
+    pub port: u16,
+    /// Empty for anonymous access
+    pub username: String<32>,
+    pub password: String<64>,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            port: mqtt::DEFAULT_PORT,
+            username: String::new(),
+            password: String::new(),
+        }
+    }
+}
+
+impl MqttSettings {
+    /// Broker address as `host` or `host:port`, with optional credentials
+    pub fn new(address: &str, username: &str, password: &str) -> Result<Self, ConfigError> {
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| ConfigError::Invalid)?),
+            None => (address, mqtt::DEFAULT_PORT),
+        };
+        Ok(Self {
+            host: copy_str(host)?,
+            port,
+            username: copy_str(username)?,
+            password: copy_str(password)?,
+        })
+    }
+
+    /// Whether a broker has been set up
+    pub fn is_configured(&self) -> bool {
+        !self.host.is_empty()
+    }
+
+    /// Publisher settings; `None` if a value doesn't fit
+    pub fn to_config(&self, device_id: &str) -> Option<MqttConfig> {
+        let mut config = MqttConfig::new(&self.host, device_id)?;
+        config.port = self.port;
+        match self.username.is_empty() {
+            true => Some(config),
+            false => config.with_credentials(&self.username, &self.password),
+        }
+    }
+}
+
+/// InfluxDB v2 upload settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct InfluxDbSettings {
+    pub host: String<64>,
+    pub port: u16,
+    pub org: String<64>,
+    pub bucket: String<64>,
+    pub token: String<128>,
+}
+
+impl Default for InfluxDbSettings {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            port: influxdb::DEFAULT_PORT,
+            org: String::new(),
+            bucket: String::new(),
+            token: String::new(),
+        }
+    }
+}
+
+impl InfluxDbSettings {
+    /// Server address as `host` or `host:port`, with the target bucket
+    pub fn new(address: &str, org: &str, bucket: &str, token: &str) -> Result<Self, ConfigError> {
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| ConfigError::Invalid)?),
+            None => (address, influxdb::DEFAULT_PORT),
+        };
+        Ok(Self {
+            host: copy_str(host)?,
+            port,
+            org: copy_str(org)?,
+            bucket: copy_str(bucket)?,
+            token: copy_str(token)?,
+        })
+    }
+
+    /// Whether a server has been set up
+    pub fn is_configured(&self) -> bool {
+        !self.host.is_empty()
+    }
+
+    /// Uploader settings; `None` if the device ID doesn't fit
+    pub fn to_config(&self, device_id: &str) -> Option<InfluxDbConfig> {
+        let mut config = InfluxDbConfig::new(&self.host, &self.org, &self.bucket, &self.token, device_id)?;
+        config.port = self.port;
+        Some(config)
+    }
+}
+
+/// Per-sensor settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorSettings {
+    pub sensor_type: SensorType,
+    pub enabled: bool,
+    /// Seconds between readings; 0 keeps the driver's recommendation
+    pub interval_s: u32,
+    /// Corrections of the measured values; fields without one are left as
+    /// measured
+    pub calibration: Vec<Calibration, MAX_CALIBRATIONS>,
+}
+
+impl SensorSettings {
+    /// Enabled, default interval, not calibrated
+    pub fn new(sensor_type: SensorType) -> Self {
+        Self { sensor_type, enabled: true, interval_s: 0, calibration: Vec::new() }
+    }
+
+    /// Reading interval, falling back to the driver's default
+    pub fn interval(&self, default: Duration) -> Duration {
+        match self.interval_s {
+            0 => default,
+            s => Duration::from_secs(u64::from(s)),
+        }
+    }
+
+    /// Calibration of a field, the identity if none is set
+    pub fn calibration(&self, field: Field) -> Calibration {
+        self.calibration.iter().find(|c| c.field == field).copied().unwrap_or(Calibration::identity(field))
+    }
+
+    /// Set a field's calibration; the identity removes it
+    pub fn set_calibration(&mut self, calibration: Calibration) -> Result<(), ConfigError> {
+        if !calibration.gain.is_finite() || !calibration.offset.is_finite() {
+            return Err(ConfigError::Invalid);
+        }
+        self.calibration.retain(|c| c.field != calibration.field);
+        if !calibration.is_identity() {
+            self.calibration.push(calibration).map_err(|_| ConfigError::TooLarge)?;
+        }
+        Ok(())
+    }
+
+    /// Calibrate a reading, flagging it if any value changed
+    pub fn calibrate(&self, reading: &mut SensorReading) -> bool {
+        calibration::apply(&self.calibration, reading)
+    }
+}
+
+/// Everything the device persists
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Config {
+    pub wifi: WifiSettings,
+    pub sensor_community: SensorCommunitySettings,
+    pub opensensemap: OpenSenseMapSettings,
+    pub mqtt: MqttSettings,
+    /// Only sensors that differ from `SensorSettings::new`
+    pub sensors: Vec<SensorSettings, MAX_SENSOR_SETTINGS>,
+    pub influxdb: InfluxDbSettings,
+    /// Station altitude above sea level in metres, for sea-level pressure
+    pub altitude_m: Option<f32>,
+}
+
+impl Config {
+    /// Settings of a sensor, defaults if none are stored
+    pub fn sensor(&self, sensor_type: SensorType) -> SensorSettings {
+        self.sensors
+            .iter()
+            .find(|s| s.sensor_type == sensor_type)
+            .cloned()
+            .unwrap_or_else(|| SensorSettings::new(sensor_type))
+    }
+
+    /// Mutable settings of a sensor, adding defaults if none are stored
+    pub fn sensor_mut(&mut self, sensor_type: SensorType) -> Result<&mut SensorSettings, ConfigError> {
+        let index = match self.sensors.iter().position(|s| s.sensor_type == sensor_type) {
+            Some(index) => index,
+            None => {
+                self.sensors.push(SensorSettings::new(sensor_type)).map_err(|_| ConfigError::TooLarge)?;
+                self.sensors.len() - 1
+            }
+        };
+        Ok(&mut self.sensors[index])
+    }
+
+    /// Serialize into `out`, returning the payload length
+    ///
+    /// Version 1 layout, little-endian, strings as a u16 length and UTF-8:
+    /// Wi-Fi SSID and password; sensor.community enabled flag and ID;
+    /// openSenseMap box, token, sensor list and format; MQTT host, port,
+    /// username and password; then a u8 sensor count, each with its type
+    /// index, enabled flag, u32 interval and a u8 count of
+    /// (field index, f32 offset) pairs.
+    ///
+    /// Version 2 appends the InfluxDB host, port, organization, bucket and
+    /// token; version 1 records decode with InfluxDB uploads off.
+    ///
+    /// Version 3 appends a flag and, if set, the f32 station altitude;
+    /// older records decode without an altitude.
+    ///
+    /// Version 4 follows each calibration offset with an f32 gain; older
+    /// records decode with a gain of 1.
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, ConfigError> {
+        let mut w = Writer { out, len: 0 };
+        w.str(&self.wifi.ssid)?;
+        w.str(&self.wifi.password)?;
+        w.bool(self.sensor_community.enabled)?;
+        w.str(&self.sensor_community.sensor_id)?;
+        w.str(&self.opensensemap.box_id)?;
+        w.str(&self.opensensemap.access_token)?;
+        w.str(&self.opensensemap.sensor_ids)?;
+        w.u8(match self.opensensemap.format {
+            UploadFormat::Json => 0,
+            UploadFormat::Csv => 1,
+        })?;
+        w.str(&self.mqtt.host)?;
+        w.bytes(&self.mqtt.port.to_le_bytes())?;
+        w.str(&self.mqtt.username)?;
+        w.str(&self.mqtt.password)?;
+        w.u8(self.sensors.len() as u8)?;
+        for sensor in &self.sensors {
+            w.u8(sensor_index(sensor.sensor_type))?;
+            w.bool(sensor.enabled)?;
+            w.bytes(&sensor.interval_s.to_le_bytes())?;
+            w.u8(sensor.calibration.len() as u8)?;
+            for calibration in &sensor.calibration {
+                w.u8(field_index(calibration.field))?;
+                w.bytes(&calibration.offset.to_le_bytes())?;
+                w.bytes(&calibration.gain.to_le_bytes())?;
+            }
+        }
+        w.str(&self.influxdb.host)?;
+        w.bytes(&self.influxdb.port.to_le_bytes())?;
+        w.str(&self.influxdb.org)?;
+        w.str(&self.influxdb.bucket)?;
+        w.str(&self.influxdb.token)?;
+        w.bool(self.altitude_m.is_some())?;
+        if let Some(altitude) = self.altitude_m {
+            w.bytes(&altitude.to_le_bytes())?;
+        }
+        Ok(w.len)
+    }
+
+    /// Deserialize a payload written with format `version`
+    pub fn decode(version: u16, payload: &[u8]) -> Result<Self, ConfigError> {
+        if version == 0 || version > CONFIG_VERSION {
+            return Err(ConfigError::UnsupportedVersion(version));
+        }
+        let mut r = Reader { data: payload };
+        let mut config = Config::default();
+        config.wifi.ssid = r.str()?;
+        config.wifi.password = r.str()?;
+        config.sensor_community.enabled = r.bool()?;
+        config.sensor_community.sensor_id = r.str()?;
+        config.opensensemap.box_id = r.str()?;
+        config.opensensemap.access_token = r.str()?;
+        config.opensensemap.sensor_ids = r.str()?;
+        config.opensensemap.format = match r.u8()? {
+            0 => UploadFormat::Json,
+            1 => UploadFormat::Csv,
+            _ => return Err(ConfigError::Corrupt),
+        };
+        config.mqtt.host = r.str()?;
+        config.mqtt.port = u16::from_le_bytes(r.array()?);
+        config.mqtt.username = r.str()?;
+        config.mqtt.password = r.str()?;
+        for _ in 0..r.u8()? {
+            let sensor_type = SensorType::from_index(r.u8()?).ok_or(ConfigError::Corrupt)?;
+            let mut sensor = SensorSettings::new(sensor_type);
+            sensor.enabled = r.bool()?;
+            sensor.interval_s = u32::from_le_bytes(r.array()?);
+            for _ in 0..r.u8()? {
+                let field = Field::from_index(r.u8()?).ok_or(ConfigError::Corrupt)?;
+                let offset = f32::from_le_bytes(r.array()?);
+                let gain = if version >= 4 { f32::from_le_bytes(r.array()?) } else { 1.0 };
+                let calibration = Calibration::new(field, gain, offset);
+                sensor.calibration.push(calibration).map_err(|_| ConfigError::Corrupt)?;
+            }
+            config.sensors.push(sensor).map_err(|_| ConfigError::Corrupt)?;
+        }
+        if version >= 2 {
+            config.influxdb.host = r.str()?;
+            config.influxdb.port = u16::from_le_bytes(r.array()?);
+            config.influxdb.org = r.str()?;
+            config.influxdb.bucket = r.str()?;
+            config.influxdb.token = r.str()?;
+        }
+        if version >= 3 && r.bool()? {
+            config.altitude_m = Some(f32::from_le_bytes(r.array()?));
+        }
+        Ok(config)
+    }
+}
+
+fn sensor_index(sensor_type: SensorType) -> u8 {
+    SensorType::ALL.iter().position(|t| *t == sensor_type).unwrap_or(0) as u8
+}
+
+fn field_index(field: Field) -> u8 {
+    Field::ALL.iter().position(|f| *f == field).unwrap_or(0) as u8
+}
+
+struct Writer<'a> {
+    out: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), ConfigError> {
+        let end = self.len + bytes.len();
+        self.out.get_mut(self.len..end).ok_or(ConfigError::TooLarge)?.copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), ConfigError> {
+        self.bytes(&[value])
+    }
+
+    fn bool(&mut self, value: bool) -> Result<(), ConfigError> {
+        self.u8(value as u8)
+    }
+
+    fn str(&mut self, value: &str) -> Result<(), ConfigError> {
+        let len = u16::try_from(value.len()).map_err(|_| ConfigError::TooLarge)?;
+        self.bytes(&len.to_le_bytes())?;
+        self.bytes(value.as_bytes())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ConfigError> {
+        if self.data.len() < len {
+            return Err(ConfigError::Corrupt);
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ConfigError> {
+        let mut out = [0; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> Result<u8, ConfigError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, ConfigError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(ConfigError::Corrupt),
+        }
+    }
+
+    fn str<const N: usize>(&mut self) -> Result<String<N>, ConfigError> {
+        let len = u16::from_le_bytes(self.array()?);
+        let bytes = self.take(usize::from(len))?;
+        let s = core::str::from_utf8(bytes).map_err(|_| ConfigError::Corrupt)?;
+        String::try_from(s).map_err(|_| ConfigError::Corrupt)
+    }
+}
+
+const fn padded(len: usize) -> usize {
+    len.div_ceil(ALIGN) * ALIGN
+}
+
+/// A valid record found in flash
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    sector: u32,
+    offset: u32,
+    version: u16,
+    len: u16,
+    sequence: u32,
+    payload_crc: u32,
+}
+
+/// Result of scanning one sector
+#[derive(Debug, Clone, Copy)]
+struct SectorScan {
+    latest: Option<Record>,
+    /// Newest record this firmware can decode
+    supported: Option<Record>,
+    /// Where the next record can be appended; the sector size if it can't
+    free: u32,
+}
+
+/// Where the next save goes
+#[derive(Debug, Clone, Copy)]
+struct Active {
+    sector: u32,
+    free: u32,
+    sequence: u32,
+    version: u16,
+    len: u16,
+    payload_crc: u32,
+}
+
+/// Configuration record store on a NOR flash partition
+///
+/// The partition must span at least two erase sectors; only the first two
+/// are used.
+pub struct ConfigStore<F> {
+    flash: F,
+    partition: Partition,
+    /// Newest record, once the partition has been scanned
+    active: Option<Option<Active>>,
+}
+
+impl<F: NorFlash> ConfigStore<F> {
+    pub fn new(flash: F, partition: Partition) -> Self {
+        debug_assert!(ALIGN % F::WRITE_SIZE == 0 && ALIGN % F::READ_SIZE == 0);
+        debug_assert!(partition.sectors(F::ERASE_SIZE as u32) >= 2);
+        Self { flash, partition, active: None }
+    }
+
+    /// Release the flash driver
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    fn sector_size() -> u32 {
+        F::ERASE_SIZE as u32
+    }
+
+    fn address(&self, sector: u32, offset: u32) -> u32 {
+        self.partition.offset + sector * Self::sector_size() + offset
+    }
+
+    /// Read a record's payload into `buf`, returning its CRC-32
+    fn read_payload(&mut self, address: u32, len: usize, buf: &mut [u8; MAX_PAYLOAD]) -> Result<u32, ConfigError> {
+        let padded = &mut buf[..padded(len)];
+        self.flash.read(address, padded).map_err(|_| ConfigError::Flash)?;
+        Ok(crc32(&padded[..len]))
+    }
+
+    /// Walk the records of a sector up to the first free or damaged slot
+    fn scan_sector(&mut self, sector: u32, buf: &mut [u8; MAX_PAYLOAD]) -> Result<SectorScan, ConfigError> {
+        let size = Self::sector_size();
+        let mut scan = SectorScan { latest: None, supported: None, free: size };
+        let mut offset = 0;
+
+        while offset + HEADER_LEN as u32 <= size {
+            let mut header = [0; HEADER_LEN];
+            self.flash.read(self.address(sector, offset), &mut header).map_err(|_| ConfigError::Flash)?;
+            if header.iter().all(|b| *b == 0xFF) {
+                scan.free = offset;
+                break;
+            }
+
+            let version = u16::from_le_bytes([header[4], header[5]]);
+            let len = u16::from_le_bytes([header[6], header[7]]);
+            let end = offset + (HEADER_LEN + padded(usize::from(len))) as u32;
+            if header[..4] != MAGIC || usize::from(len) > MAX_PAYLOAD || end > size {
+                break;
+            }
+
+            let payload_crc = self.read_payload(self.address(sector, offset + HEADER_LEN as u32), usize::from(len), buf)?;
+            let crc = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+            if crc32_update(payload_crc, &header[4..12]) != crc {
+                // Torn write; nothing after it can be trusted
+                break;
+            }
+
+            let record = Record {
+                sector,
+                offset,
+                version,
+                len,
+                sequence: u32::from_le_bytes([header[8], header[9], header[10], header[11]]),
+                payload_crc,
+            };
+            scan.latest = Some(record);
+            if version <= CONFIG_VERSION {
+                scan.supported = Some(record);
+            }
+            offset = end;
+        }
+        Ok(scan)
+    }
+
+    /// Scan both sectors, returning the records this firmware can decode,
+    /// newest first
+    fn scan(&mut self, buf: &mut [u8; MAX_PAYLOAD]) -> Result<[Option<Record>; 2], ConfigError> {
+        let scans = [self.scan_sector(0, buf)?, self.scan_sector(1, buf)?];
+        let newest = |a: Option<Record>, b: Option<Record>| match (a, b) {
+            (Some(a), Some(b)) if b.sequence > a.sequence => [Some(b), Some(a)],
+            (None, b) => [b, None],
+            (a, b) => [a, b],
+        };
+
+        self.active = Some(newest(scans[0].latest, scans[1].latest)[0].map(|r| Active {
+            sector: r.sector,
+            free: scans[r.sector as usize].free,
+            sequence: r.sequence,
+            version: r.version,
+            len: r.len,
+            payload_crc: r.payload_crc,
+        }));
+        Ok(newest(scans[0].supported, scans[1].supported))
+    }
+
+    /// Load the newest valid configuration
+    /// Falls back to the previous record if the newest doesn't decode
+    pub fn load(&mut self) -> Result<Config, ConfigError> {
+        let mut buf = [0; MAX_PAYLOAD];
+        let mut result = Err(ConfigError::NotFound);
+        for record in self.scan(&mut buf)?.into_iter().flatten() {
+            let address = self.address(record.sector, record.offset + HEADER_LEN as u32);
+            let len = usize::from(record.len);
+            if self.read_payload(address, len, &mut buf)? != record.payload_crc {
+                result = Err(ConfigError::Corrupt);
+                continue;
+            }
+            result = Config::decode(record.version, &buf[..len]);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Persist a configuration
+    /// Returns false if it matches the stored one and nothing was written
+    pub fn save(&mut self, config: &Config) -> Result<bool, ConfigError> {
+        let mut buf = [0xFF; HEADER_LEN + MAX_PAYLOAD];
+        let len = config.encode(&mut buf[HEADER_LEN..])?;
+        let payload_crc = crc32(&buf[HEADER_LEN..HEADER_LEN + len]);
+
+        let active = match self.active {
+            Some(active) => active,
+            None => {
+                let mut scratch = [0; MAX_PAYLOAD];
+                self.scan(&mut scratch)?;
+                self.active.flatten()
+            }
+        };
+        if let Some(a) = active {
+            if a.version == CONFIG_VERSION && usize::from(a.len) == len && a.payload_crc == payload_crc {
+                return Ok(false);
+            }
+        }
+
+        let record_len = (HEADER_LEN + padded(len)) as u32;
+        let sequence = active.map_or(1, |a| a.sequence.wrapping_add(1));
+        let (sector, offset) = match active {
+            Some(a) if a.free + record_len <= Self::sector_size() => (a.sector, a.free),
+            Some(a) => (1 - a.sector, 0),
+            None => (0, 0),
+        };
+
+        buf[0..4].copy_from_slice(&MAGIC);
+        buf[4..6].copy_from_slice(&CONFIG_VERSION.to_le_bytes());
+        buf[6..8].copy_from_slice(&(len as u16).to_le_bytes());
+        buf[8..12].copy_from_slice(&sequence.to_le_bytes());
+        let crc = crc32_update(payload_crc, &buf[4..12]);
+        buf[12..16].copy_from_slice(&crc.to_le_bytes());
+
+        // Forget the scan until the write is known to have succeeded
+        self.active = None;
+        if offset == 0 {
+            let start = self.address(sector, 0);
+            self.flash.erase(start, start + Self::sector_size()).map_err(|_| ConfigError::Flash)?;
+        }
+        self.flash
+            .write(self.address(sector, offset), &buf[..record_len as usize])
+            .map_err(|_| ConfigError::Flash)?;
+
+        self.active = Some(Some(Active {
+            sector,
+            free: offset + record_len,
+            sequence,
+            version: CONFIG_VERSION,
+            len: len as u16,
+            payload_crc,
+        }));
+        log::info!("[CONFIG] Saved record {} ({} bytes) to sector {}", sequence, len, sector);
+        Ok(true)
+    }
+}
+
+/// One-line summary of a configuration for the boot log, without secrets
+pub fn summary(config: &Config) -> String<256> {
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "wifi={} sensor.community={} opensensemap={} mqtt={} influxdb={} sensors={}",
+        if config.wifi.is_configured() { config.wifi.ssid.as_str() } else { "-" },
+        if config.sensor_community.enabled { "on" } else { "off" },
+        if config.opensensemap.is_configured() { "on" } else { "off" },
+        if config.mqtt.is_configured() { config.mqtt.host.as_str() } else { "-" },
+        if config.influxdb.is_configured() { config.influxdb.host.as_str() } else { "-" },
+        config.sensors.len(),
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+    use crate::sensors::{Flags, Quality, SensorData};
+    use crate::storage::CONFIG_PARTITION;
+
+    const SECTOR: u32 = 4096;
+
+    fn sample() -> Config {
+        let mut config = Config {
+            wifi: WifiSettings::new("home", "secret password").unwrap(),
+            sensor_community: SensorCommunitySettings { enabled: false, sensor_id: copy_str("esp32-123456").unwrap() },
+            opensensemap: OpenSenseMapSettings::new("5f2b0c0f8a3b4c001b9d0a11", "token", "pm25=aa,pm10=bb").unwrap(),
+            mqtt: MqttSettings::new("broker.lan:1884", "ha", "pw").unwrap(),
+            sensors: Vec::new(),
+            influxdb: InfluxDbSettings::new("influx.lan", "home", "sensors", "t0ken").unwrap(),
+            altitude_m: Some(245.5),
+        };
+        config.opensensemap.format = UploadFormat::Csv;
+        let sds = config.sensor_mut(SensorType::SDS011).unwrap();
+        sds.interval_s = 300;
+        sds.set_calibration(Calibration::new(Field::Pm25, 0.8, -1.5)).unwrap();
+        config.sensor_mut(SensorType::ME2CO).unwrap().enabled = false;
+        config
+    }
+
+    fn store() -> ConfigStore<RamFlash> {
+        ConfigStore::new(RamFlash::new(CONFIG_PARTITION.end() as usize), CONFIG_PARTITION)
+    }
+
+    fn reopen(store: ConfigStore<RamFlash>) -> ConfigStore<RamFlash> {
+        ConfigStore::new(store.into_inner(), CONFIG_PARTITION)
+    }
+
+    /// A config whose encoding differs for every `n`
+    fn numbered(n: u32) -> Config {
+        let mut config = sample();
+        config.sensor_mut(SensorType::BME280).unwrap().interval_s = n;
+        config
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let config = sample();
+        let mut buf = [0; MAX_PAYLOAD];
+        let len = config.encode(&mut buf).unwrap();
+        assert_eq!(Config::decode(CONFIG_VERSION, &buf[..len]), Ok(config.clone()));
+
+        // Defaults round-trip too
+        let len = Config::default().encode(&mut buf).unwrap();
+        assert_eq!(Config::decode(CONFIG_VERSION, &buf[..len]), Ok(Config::default()));
+
+        // Truncated, unknown or too small
+        assert_eq!(Config::decode(CONFIG_VERSION, &buf[..len - 1]), Err(ConfigError::Corrupt));
+        assert_eq!(Config::decode(CONFIG_VERSION + 1, &buf[..len]), Err(ConfigError::UnsupportedVersion(CONFIG_VERSION + 1)));
+        assert_eq!(config.encode(&mut [0; 32]), Err(ConfigError::TooLarge));
+    }
+
+    #[test]
+    fn test_decode_older_versions() {
+        // Version 3 calibrations have no gain
+        let mut config = sample();
+        let sds = config.sensor_mut(SensorType::SDS011).unwrap();
+        sds.set_calibration(Calibration::new(Field::Pm25, 1.0, -1.5)).unwrap();
+        let mut buf = [0; MAX_PAYLOAD];
+        let len = config.encode(&mut buf).unwrap();
+        let gain_at = buf[..len].windows(4).position(|w| w == (-1.5f32).to_le_bytes()).unwrap() + 4;
+        buf.copy_within(gain_at + 4..len, gain_at);
+        let v3_len = len - 4;
+        assert_eq!(Config::decode(3, &buf[..v3_len]), Ok(config.clone()));
+
+        // Version 2 payloads end after the InfluxDB settings
+        let v2_len = v3_len - (1 + 4);
+        config.altitude_m = None;
+        assert_eq!(Config::decode(2, &buf[..v2_len]), Ok(config.clone()));
+        assert_eq!(Config::decode(3, &buf[..v2_len]), Err(ConfigError::Corrupt));
+
+        // Version 1 payloads end after the sensor list
+        let v1_len = v2_len - (2 + 10 + 2 + 2 + 4 + 2 + 7 + 2 + 5);
+        config.influxdb = InfluxDbSettings::default();
+        assert_eq!(Config::decode(1, &buf[..v1_len]), Ok(config));
+        assert_eq!(Config::decode(2, &buf[..v1_len]), Err(ConfigError::Corrupt));
+    }
+
+    #[test]
+    fn test_settings() {
+        let config = sample();
+        assert_eq!(config.mqtt.host.as_str(), "broker.lan");
+        assert_eq!(config.mqtt.port, 1884);
+        assert_eq!(MqttSettings::new("broker", "", "").unwrap().port, mqtt::DEFAULT_PORT);
+        assert_eq!(MqttSettings::new("broker:x", "", ""), Err(ConfigError::Invalid));
+
+        let mqtt = config.mqtt.to_config("altruist-abcdef").unwrap();
+        assert_eq!(mqtt.username.as_deref(), Some("ha"));
+        let osm = config.opensensemap.to_config().unwrap();
+        assert_eq!(osm.sensor_id(Field::Pm10), Some("bb"));
+        assert_eq!(osm.format, UploadFormat::Csv);
+        assert!(config.sensor_community.to_config([0; 6]).is_none());
+        let influx = config.influxdb.to_config("altruist-abcdef").unwrap();
+        assert_eq!((influx.host.as_str(), influx.port), ("influx.lan", influxdb::DEFAULT_PORT));
+        assert_eq!(InfluxDbSettings::new("10.0.0.2:9999", "o", "b", "t").unwrap().port, 9999);
+        assert_eq!(InfluxDbSettings::new("10.0.0.2:", "o", "b", "t"), Err(ConfigError::Invalid));
+        let sc = SensorCommunitySettings::default().to_config([0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56]).unwrap();
+        assert_eq!(sc.sensor_id.as_str(), "esp32-240AC4123456");
+
+        // Sensors without stored settings get defaults
+        assert_eq!(config.sensor(SensorType::BME280), SensorSettings::new(SensorType::BME280));
+        assert!(!config.sensor(SensorType::ME2CO).enabled);
+        let mut sds = config.sensor(SensorType::SDS011);
+        assert_eq!(sds.interval(Duration::from_secs(30)), Duration::from_secs(300));
+        assert_eq!(SensorSettings::new(SensorType::SDS011).interval(Duration::from_secs(30)), Duration::from_secs(30));
+
+        assert_eq!(sds.calibration(Field::Pm25), Calibration::new(Field::Pm25, 0.8, -1.5));
+        assert!(sds.calibration(Field::Pm10).is_identity());
+        let data = SensorData::AirQuality { pm25: Some(10.0), pm10: None, pm25_corrected: None, pm10_corrected: None };
+        let mut reading = SensorReading::new(SensorType::SDS011, data, Quality::Good);
+        assert!(sds.calibrate(&mut reading));
+        assert_eq!(reading.data.value(Field::Pm25), Some(6.5));
+        assert_eq!(reading.data.value(Field::Pm10), None);
+        assert!(reading.flags.contains(Flags::CALIBRATED));
+
+        // The identity removes a calibration, non-finite ones are refused
+        sds.set_calibration(Calibration::identity(Field::Pm25)).unwrap();
+        assert!(sds.calibration.is_empty());
+        assert_eq!(sds.set_calibration(Calibration::new(Field::Pm10, f32::NAN, 0.0)), Err(ConfigError::Invalid));
+    }
+
+    #[test]
+    fn test_store_round_trip() {
+        let mut store = store();
+        assert_eq!(store.load(), Err(ConfigError::NotFound));
+
+        let config = sample();
+        assert_eq!(store.save(&config), Ok(true));
+        assert_eq!(store.load(), Ok(config.clone()));
+
+        // Survives a reboot
+        let mut store = reopen(store);
+        assert_eq!(store.load(), Ok(config.clone()));
+
+        // Unchanged settings aren't rewritten, even without a prior load
+        let mut store = reopen(store);
+        let writes = store.flash.writes();
+        assert_eq!(store.save(&config), Ok(false));
+        assert_eq!(store.flash.writes(), writes);
+    }
+
+    #[test]
+    fn test_store_wear_leveling() {
+        let mut store = store();
+        for n in 1..=40 {
+            assert_eq!(store.save(&numbered(n)), Ok(true));
+        }
+        assert_eq!(store.load(), Ok(numbered(40)));
+
+        // Several records share each erase, and the two sectors take turns
+        let first = store.flash.erase_count(CONFIG_PARTITION.offset);
+        let second = store.flash.erase_count(CONFIG_PARTITION.offset + SECTOR);
+        assert!(first + second <= 40 / 2, "{} + {} erases", first, second);
+        assert!(first.abs_diff(second) <= 1);
+
+        let mut store = reopen(store);
+        assert_eq!(store.load(), Ok(numbered(40)));
+        assert_eq!(store.save(&numbered(41)), Ok(true));
+        assert_eq!(reopen(store).load(), Ok(numbered(41)));
+    }
+
+    #[test]
+    fn test_store_torn_write() {
+        let mut buf = [0; MAX_PAYLOAD];
+        let len = numbered(100).encode(&mut buf).unwrap();
+        let per_sector = SECTOR / (HEADER_LEN + padded(len)) as u32;
+        // Cutting the padding wouldn't lose anything, so stop before it
+        let last_byte = HEADER_LEN + len - 1;
+
+        // Tear appends and sector switches at various points in the record
+        for saved in [1, 2, per_sector, per_sector + 1] {
+            for torn_at in [0, 3, 8, 15, 16, 100, last_byte] {
+                let mut store = store();
+                for n in 1..=saved {
+                    store.save(&numbered(n)).unwrap();
+                }
+
+                store.flash.tear_next_write(torn_at);
+                assert_eq!(store.save(&numbered(100)), Err(ConfigError::Flash));
+
+                // Power comes back: the previous settings are intact
+                let mut store = reopen(store);
+                assert_eq!(store.load(), Ok(numbered(saved)), "saved {} torn at {}", saved, torn_at);
+
+                // And saving works again
+                assert_eq!(store.save(&numbered(101)), Ok(true));
+                assert_eq!(reopen(store).load(), Ok(numbered(101)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_store_corruption() {
+        let mut store = store();
+        store.save(&numbered(1)).unwrap();
+        store.save(&numbered(2)).unwrap();
+
+        // Flip a bit in the newest record's payload: the older one wins
+        let mut flash = store.into_inner();
+        let mut buf = [0; MAX_PAYLOAD];
+        let len = numbered(1).encode(&mut buf).unwrap();
+        let second = CONFIG_PARTITION.offset as usize + HEADER_LEN * 2 + padded(len) + 10;
+        flash.data_mut()[second] ^= 0x01;
+        let mut store = ConfigStore::new(flash, CONFIG_PARTITION);
+        assert_eq!(store.load(), Ok(numbered(1)));
+
+        // Writing again moves past the damage
+        store.save(&numbered(3)).unwrap();
+        assert_eq!(reopen(store).load(), Ok(numbered(3)));
+
+        // Garbage everywhere
+        let mut flash = RamFlash::new(CONFIG_PARTITION.end() as usize);
+        flash.data_mut().fill(0x5A);
+        let mut store = ConfigStore::new(flash, CONFIG_PARTITION);
+        assert_eq!(store.load(), Err(ConfigError::NotFound));
+        store.save(&numbered(4)).unwrap();
+        assert_eq!(reopen(store).load(), Ok(numbered(4)));
+    }
+
+    #[test]
+    fn test_store_newer_version() {
+        let mut store = store();
+        store.save(&numbered(1)).unwrap();
+
+        // Hand-craft a record from future firmware after it
+        let mut flash = store.into_inner();
+        let payload = [0xAB; 8];
+        let mut record = [0xFF; HEADER_LEN + 8];
+        record[0..4].copy_from_slice(&MAGIC);
+        record[4..6].copy_from_slice(&(CONFIG_VERSION + 1).to_le_bytes());
+        record[6..8].copy_from_slice(&8u16.to_le_bytes());
+        record[8..12].copy_from_slice(&2u32.to_le_bytes());
+        let crc = crc32_update(crc32(&payload), &record[4..12]);
+        record[12..16].copy_from_slice(&crc.to_le_bytes());
+        record[HEADER_LEN..].copy_from_slice(&payload);
+        let mut buf = [0; MAX_PAYLOAD];
+        let end = HEADER_LEN + padded(numbered(1).encode(&mut buf).unwrap());
+        let start = CONFIG_PARTITION.offset as usize + end;
+        flash.data_mut()[start..start + record.len()].copy_from_slice(&record);
+
+        // Older settings are still usable, and new saves supersede both
+        let mut store = ConfigStore::new(flash, CONFIG_PARTITION);
+        assert_eq!(store.load(), Ok(numbered(1)));
+        store.save(&numbered(5)).unwrap();
+        assert_eq!(reopen(store).load(), Ok(numbered(5)));
+    }
+}
@@ -0,0 +1,18 @@
+//! Altruist - sensor framework
+//!
+//! Hardware-agnostic sensor drivers and data flow shared by the ESP32-C6
+//! firmware (`src/main.rs`) and host-side tests. Drivers are generic over
+//! the `embedded-hal-async` I2C and `embedded-io-async` UART traits so they
+//! can be exercised with mock buses on a Linux host.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod config;
+pub mod net;
+pub mod ring_log;
+pub mod sensors;
+pub mod storage;
+pub mod time;
+
+#[cfg(test)]
+pub(crate) mod mock;
@@ -11,46 +11,461 @@ use esp_hal::prelude::*;
 use esp_hal::timer::timg::TimerGroup;
 use esp_hal::uart::Uart;
 use esp_hal::i2c::I2c;
-use esp_hal::gpio::Io;
+use esp_hal::gpio::{Input, Io, Pull};
+use esp_hal::peripherals::{I2C0, UART0, UART1};
+use esp_hal::rng::Rng;
 use esp_println::println;
+use esp_storage::FlashStorage;
+use esp_wifi::wifi::{
+    AccessPointConfiguration, ClientConfiguration, Configuration, WifiApDevice, WifiController, WifiDevice, WifiEvent,
+    WifiStaDevice,
+};
+use embassy_futures::select::{select, Either};
+use embassy_net::{Ipv4Address, Ipv4Cidr, Stack, StackResources, StaticConfigV4};
+use embassy_time::{Duration, Timer};
 use static_cell::StaticCell;
 
 // Import our sensor abstraction
-mod sensors;
-use sensors::{
+use altruist_rs::sensors::{
     bme280::Bme280Sensor,
-    manager::{
-        bme280_sensor_task, me2co_sensor_task, sds011_sensor_task, sensor_aggregator_task,
-        SensorManager,
-    },
+    filter::FilterConfig,
+    derived::DerivedMetrics,
+    humidity::HumidityCorrection,
+    manager::{latest_values_impl, reading_logger_impl, sensor_aggregator_impl, sensor_task_impl, with_sensor_manager},
     me2co::Me2CoSensorWrapper,
     sds011::Sds011Sensor,
+    stats,
+    SensorType,
+};
+use altruist_rs::config::{
+    self, Config, ConfigError, ConfigStore, InfluxDbSettings, MqttSettings, OpenSenseMapSettings, SensorSettings, WifiSettings,
+};
+use altruist_rs::net::{
+    api::api_task_impl,
+    dhcp_server::{dhcp_server_task_impl, DhcpServer},
+    dns::dns_task_impl,
+    home_assistant::{self, home_assistant_task_impl, MqttConfig},
+    http::TcpClient,
+    influxdb::{self, influxdb_task_impl, InfluxDbConfig},
+    opensensemap::{self, opensensemap_task_impl, OpenSenseMapConfig},
+    portal::{self, portal_task_impl, DHCP_POOL_START, PORTAL_IP},
+    sensor_community::{sensor_community_task_impl, SensorCommunityConfig},
+    sntp::{self, sntp_task_impl},
+    store_forward::{store_forward_task_impl, Sink, MAX_SINKS},
+    wifi::{publish_link_state, LinkState, WifiSupervisor},
 };
+use altruist_rs::ring_log::RingLog;
+use altruist_rs::storage::{CONFIG_PARTITION, RING_LOG_PARTITION};
+
+// Build-time settings are only defaults for a device without a stored
+// configuration, e.g. `WIFI_SSID=home WIFI_PASSWORD=secret cargo flash`
+
+/// Wi-Fi credentials
+const WIFI_SSID: Option<&str> = option_env!("WIFI_SSID");
+const WIFI_PASSWORD: Option<&str> = option_env!("WIFI_PASSWORD");
+
+/// openSenseMap box, access token and `field=sensor-id` list, baked in at build time
+const OPENSENSEMAP_BOX_ID: Option<&str> = option_env!("OPENSENSEMAP_BOX_ID");
+const OPENSENSEMAP_TOKEN: Option<&str> = option_env!("OPENSENSEMAP_TOKEN");
+const OPENSENSEMAP_SENSORS: Option<&str> = option_env!("OPENSENSEMAP_SENSORS");
+
+/// MQTT broker for Home Assistant, baked in at build time
+/// `MQTT_HOST` may carry a port, e.g. `192.168.1.10:1883`
+const MQTT_HOST: Option<&str> = option_env!("MQTT_HOST");
+const MQTT_USERNAME: Option<&str> = option_env!("MQTT_USERNAME");
+const MQTT_PASSWORD: Option<&str> = option_env!("MQTT_PASSWORD");
+
+/// InfluxDB v2 server (`host` or `host:port`), organization, bucket and API token
+const INFLUXDB_HOST: Option<&str> = option_env!("INFLUXDB_HOST");
+const INFLUXDB_ORG: Option<&str> = option_env!("INFLUXDB_ORG");
+const INFLUXDB_BUCKET: Option<&str> = option_env!("INFLUXDB_BUCKET");
+const INFLUXDB_TOKEN: Option<&str> = option_env!("INFLUXDB_TOKEN");
+
+/// Station altitude above sea level in metres, for sea-level pressure
+const STATION_ALTITUDE_M: Option<&str> = option_env!("STATION_ALTITUDE_M");
+
+/// Length of the averaging windows for uploads in seconds, 0 to upload every reading
+const AGGREGATION_WINDOW_SECS: Option<&str> = option_env!("AGGREGATION_WINDOW_SECS");
+
+/// Network stack shared by all network tasks
+type WifiStack = Stack<WifiDevice<'static, WifiStaDevice>>;
+
+/// Network stack of the setup access point
+type ApStack = Stack<WifiDevice<'static, WifiApDevice>>;
+
+/// Sensors that can be switched on and off from the setup page
+static PORTAL_SENSORS: [SensorType; 3] = [SensorType::SDS011, SensorType::BME280, SensorType::ME2CO];
+
+/// Concrete bus types the sensors are wired to
+type Bme280I2c = I2c<'static, I2C0, esp_hal::Async>;
+type Sds011Uart = Uart<'static, UART0, esp_hal::Async>;
+type Me2CoUart = Uart<'static, UART1, esp_hal::Async>;
+
+// Create sensor tasks for the concrete sensor types
+// This works around Embassy's limitation of no generic tasks
+
+/// ME2-CO sensor task
+#[embassy_executor::task]
+async fn me2co_sensor_task(mut sensor: Me2CoSensorWrapper<Me2CoUart>, settings: SensorSettings) {
+    sensor_task_impl(&mut sensor, &settings, FilterConfig::default(), HumidityCorrection::default(), DerivedMetrics::default()).await;
+}
+
+/// SDS011 sensor task
+#[embassy_executor::task]
+async fn sds011_sensor_task(mut sensor: Sds011Sensor<Sds011Uart>, settings: SensorSettings) {
+    sensor_task_impl(&mut sensor, &settings, FilterConfig::default(), HumidityCorrection::default(), DerivedMetrics::default()).await;
+}
+
+/// BME280 sensor task
+#[embassy_executor::task]
+async fn bme280_sensor_task(mut sensor: Bme280Sensor<Bme280I2c>, settings: SensorSettings, derived: DerivedMetrics) {
+    sensor_task_impl(&mut sensor, &settings, FilterConfig::default(), HumidityCorrection::default(), derived).await;
+}
+
+/// Sensor aggregator task that receives all sensor readings
+#[embassy_executor::task]
+async fn sensor_aggregator_task(window: Duration) {
+    sensor_aggregator_impl(window).await;
+}
+
+/// Keeps the latest values for the local API
+#[embassy_executor::task]
+async fn latest_values_task() {
+    latest_values_impl().await;
+}
+
+/// Prints readings as they come in
+#[embassy_executor::task]
+async fn reading_logger_task() {
+    reading_logger_impl().await;
+}
+
+/// Wi-Fi supervisor task
+/// Keeps the station connected, backing off between failed attempts,
+/// and publishes link state changes
+#[embassy_executor::task]
+async fn wifi_supervisor_task(mut controller: WifiController<'static>, stack: &'static WifiStack, wifi: &'static WifiSettings) {
+    let mut supervisor = WifiSupervisor::default();
+    let ssid = wifi.ssid.as_str();
+
+    let mut client_config = ClientConfiguration::default();
+    if client_config.ssid.push_str(ssid).is_err() || client_config.password.push_str(&wifi.password).is_err() {
+        log::error!("[WIFI] SSID or password too long");
+        publish_link_state(LinkState::Down);
+        return;
+    }
+    if let Err(e) = controller.set_configuration(&Configuration::Client(client_config)) {
+        log::error!("[WIFI] Failed to configure station: {:?}", e);
+        publish_link_state(LinkState::Down);
+        return;
+    }
+
+    loop {
+        if !matches!(controller.is_started(), Ok(true)) {
+            log::info!("[WIFI] Starting station");
+            if let Err(e) = controller.start().await {
+                log::warn!("[WIFI] Start failed: {:?}", e);
+            }
+        }
+
+        publish_link_state(supervisor.on_connect_start());
+        log::info!("[WIFI] Connecting to {}", ssid);
+
+        match controller.connect().await {
+            Ok(()) => {
+                publish_link_state(supervisor.on_connected());
+                log::info!("[WIFI] Associated with {}", ssid);
+
+                // Wait for DHCP, unless the link drops first
+                let disconnected = controller.wait_for_event(WifiEvent::StaDisconnected);
+                if let Either::Second(()) = select(disconnected, stack.wait_config_up()).await {
+                    publish_link_state(supervisor.on_config_up());
+                    if let Some(config) = stack.config_v4() {
+                        log::info!("[WIFI] Link up, address {}", config.address);
+                    }
+                    controller.wait_for_event(WifiEvent::StaDisconnected).await;
+                }
+
+                publish_link_state(supervisor.on_disconnected());
+                log::warn!("[WIFI] Disconnected, reconnecting");
+            }
+            Err(e) => {
+                let delay = supervisor.on_connect_failed();
+                publish_link_state(supervisor.state());
+                log::warn!("[WIFI] Connect failed: {:?}, retrying in {}s", e, delay.as_secs());
+                Timer::after(delay).await;
+            }
+        }
+    }
+}
+
+/// Network stack task
+#[embassy_executor::task]
+async fn net_task(stack: &'static WifiStack) {
+    stack.run().await
+}
+
+/// SNTP time synchronization task
+#[embassy_executor::task]
+async fn sntp_task(stack: &'static WifiStack) {
+    sntp_task_impl(stack, sntp::DEFAULT_SERVER).await
+}
+
+/// sensor.community upload task
+#[embassy_executor::task]
+async fn sensor_community_task(stack: &'static WifiStack, config: SensorCommunityConfig) {
+    let mut client = TcpClient::new(stack);
+    sensor_community_task_impl(&mut client, config).await
+}
+
+/// openSenseMap upload task
+#[embassy_executor::task]
+async fn opensensemap_task(stack: &'static WifiStack, config: OpenSenseMapConfig) {
+    let mut client = TcpClient::new(stack);
+    opensensemap_task_impl(&mut client, config).await
+}
+
+/// InfluxDB upload task
+#[embassy_executor::task]
+async fn influxdb_task(stack: &'static WifiStack, config: InfluxDbConfig) {
+    let mut client = TcpClient::new(stack);
+    influxdb_task_impl(&mut client, config).await
+}
+
+/// Store-and-forward task
+/// Keeps readings for the uploaders in flash while offline
+#[embassy_executor::task]
+async fn store_forward_task(sinks: heapless::Vec<Sink, MAX_SINKS>) {
+    match RingLog::mount(FlashStorage::new(), RING_LOG_PARTITION) {
+        Ok(log) => store_forward_task_impl(log, sinks).await,
+        Err(e) => log::error!("[STORE] Failed to mount the flash log: {}", e),
+    }
+}
+
+/// MQTT / Home Assistant publishing task
+#[embassy_executor::task]
+async fn home_assistant_task(stack: &'static WifiStack, config: MqttConfig) {
+    home_assistant_task_impl(stack, config).await
+}
+
+/// Local JSON API task
+#[embassy_executor::task]
+async fn api_task(stack: &'static WifiStack) {
+    api_task_impl(stack).await
+}
+
+/// Setup access point task
+/// Starts the open soft-AP and keeps the controller alive
+#[embassy_executor::task]
+async fn ap_task(mut controller: WifiController<'static>, ssid: heapless::String<32>) {
+    let ap_config = AccessPointConfiguration { ssid, ..Default::default() };
+    if let Err(e) = controller.set_configuration(&Configuration::AccessPoint(ap_config.clone())) {
+        log::error!("[WIFI] Failed to configure access point: {:?}", e);
+        return;
+    }
+    if let Err(e) = controller.start().await {
+        log::error!("[WIFI] Failed to start access point: {:?}", e);
+        return;
+    }
+    log::info!("[WIFI] Setup network {} up", ap_config.ssid);
+
+    loop {
+        controller.wait_for_event(WifiEvent::ApStaconnected).await;
+        log::info!("[WIFI] Client joined the setup network");
+    }
+}
+
+/// Setup access point network stack task
+#[embassy_executor::task]
+async fn ap_net_task(stack: &'static ApStack) {
+    stack.run().await
+}
+
+/// DHCP server for setup clients
+#[embassy_executor::task]
+async fn dhcp_server_task(stack: &'static ApStack) {
+    dhcp_server_task_impl(stack, DhcpServer::new(PORTAL_IP, DHCP_POOL_START)).await
+}
+
+/// Catch-all DNS for setup clients
+#[embassy_executor::task]
+async fn dns_task(stack: &'static ApStack) {
+    dns_task_impl(stack, PORTAL_IP).await
+}
+
+/// Setup page, reboots once the configuration is saved
+#[embassy_executor::task]
+async fn portal_task(stack: &'static ApStack, config: Config) {
+    let store = ConfigStore::new(FlashStorage::new(), CONFIG_PARTITION);
+    portal_task_impl(stack, store, config, &PORTAL_SENSORS, esp_hal::reset::software_reset).await
+}
+
+/// Network side of the firmware, chosen at boot
+enum Network {
+    /// Normal operation as a Wi-Fi station
+    Station(&'static WifiStack, WifiController<'static>),
+    /// Setup portal on an access point of our own
+    Setup(&'static ApStack, WifiController<'static>),
+}
+
+/// Add a sensor to the registry shown by the local API
+/// Disabled sensors are listed too, without a running task
+fn register_sensor(sensor_type: SensorType, enabled: bool) {
+    with_sensor_manager(|manager| {
+        if manager.register_sensor(sensor_type).is_ok() && enabled {
+            manager.mark_task_spawned(sensor_type);
+        }
+    });
+}
+
+/// Defaults from the build environment
+/// Settings that are malformed or too long are left out
+fn build_time_config() -> Config {
+    let mut config = Config::default();
+    if let Some(ssid) = WIFI_SSID {
+        match WifiSettings::new(ssid, WIFI_PASSWORD.unwrap_or("")) {
+            Ok(wifi) => config.wifi = wifi,
+            Err(e) => println!("Ignoring WIFI_SSID: {}", e),
+        }
+    }
+    if let (Some(box_id), Some(token)) = (OPENSENSEMAP_BOX_ID, OPENSENSEMAP_TOKEN) {
+        match OpenSenseMapSettings::new(box_id, token, OPENSENSEMAP_SENSORS.unwrap_or("")) {
+            Ok(opensensemap) => config.opensensemap = opensensemap,
+            Err(e) => println!("Ignoring openSenseMap settings: {}", e),
+        }
+    }
+    if let Some(host) = MQTT_HOST {
+        match MqttSettings::new(host, MQTT_USERNAME.unwrap_or(""), MQTT_PASSWORD.unwrap_or("")) {
+            Ok(mqtt) => config.mqtt = mqtt,
+            Err(e) => println!("Ignoring MQTT settings: {}", e),
+        }
+    }
+    if let Some(host) = INFLUXDB_HOST {
+        let settings = InfluxDbSettings::new(
+            host,
+            INFLUXDB_ORG.unwrap_or(""),
+            INFLUXDB_BUCKET.unwrap_or(""),
+            INFLUXDB_TOKEN.unwrap_or(""),
+        );
+        match settings {
+            Ok(influxdb) => config.influxdb = influxdb,
+            Err(e) => println!("Ignoring InfluxDB settings: {}", e),
+        }
+    }
+    if let Some(altitude) = STATION_ALTITUDE_M {
+        match altitude.parse() {
+            Ok(altitude) => config.altitude_m = Some(altitude),
+            Err(_) => println!("Ignoring STATION_ALTITUDE_M: {}", altitude),
+        }
+    }
+    config
+}
+
+/// Stored configuration, or the build-time defaults if there is none
+fn load_config() -> Config {
+    let mut store = ConfigStore::new(FlashStorage::new(), CONFIG_PARTITION);
+    match store.load() {
+        Ok(config) => {
+            println!("Loaded stored configuration");
+            config
+        }
+        Err(ConfigError::NotFound) => {
+            println!("No stored configuration, using build-time defaults");
+            build_time_config()
+        }
+        Err(e) => {
+            println!("Failed to load configuration ({}), using build-time defaults", e);
+            build_time_config()
+        }
+    }
+}
 
 #[esp_hal::entry]
 fn main() -> ! {
+    esp_println::logger::init_logger_from_env();
+
     println!("Altruist");
     println!("==============================================");
 
     // Initialize system and take peripherals
-    let peripherals = esp_hal::init(esp_hal::Config::default());
+    // Wi-Fi needs at least 80MHz, run at full speed
+    let mut config = esp_hal::Config::default();
+    config.cpu_clock = esp_hal::clock::CpuClock::Clock160MHz;
+    let peripherals = esp_hal::init(config);
+
+    // Heap for the Wi-Fi driver
+    esp_alloc::heap_allocator!(72 * 1024);
+
+    // Persistent settings
+    static CONFIG: StaticCell<Config> = StaticCell::new();
+    let config = &*CONFIG.init(load_config());
+    println!("Config: {}", config::summary(config));
 
     // Initialize Embassy timer
     let timg0 = TimerGroup::new(peripherals.TIMG0);
     esp_hal_embassy::init(timg0.timer0);
 
-    // Static storage for the executor and sensor manager
-    static EXECUTOR: StaticCell<esp_hal_embassy::Executor> = StaticCell::new();
-    static SENSOR_MANAGER: StaticCell<SensorManager> = StaticCell::new();
+    let io = Io::new(peripherals.GPIO, peripherals.IO_MUX);
+
+    // Setup mode without Wi-Fi settings, or while BOOT is held at power-up
+    let setup_mode = {
+        let boot_button = Input::new(io.pins.gpio9, Pull::Up);
+        !config.wifi.is_configured() || boot_button.is_low()
+    };
+
+    // Initialize Wi-Fi
+    let timg1 = TimerGroup::new(peripherals.TIMG1);
+    let mut rng = Rng::new(peripherals.RNG);
+    static WIFI_INIT: StaticCell<esp_wifi::EspWifiInitialization> = StaticCell::new();
+    let wifi_init = WIFI_INIT.init(
+        esp_wifi::init(esp_wifi::EspWifiInitFor::Wifi, timg1.timer0, rng, peripherals.RADIO_CLK)
+            .expect("Failed to initialize Wi-Fi"),
+    );
+    let seed = ((rng.random() as u64) << 32) | rng.random() as u64;
+
+    let network = if setup_mode {
+        // Access point with a fixed address; clients get theirs from our DHCP server
+        let (ap_device, ap_controller) =
+            esp_wifi::wifi::new_with_mode(wifi_init, peripherals.WIFI, WifiApDevice)
+                .expect("Failed to create Wi-Fi access point");
+        let [a, b, c, d] = PORTAL_IP;
+        static AP_STACK: StaticCell<ApStack> = StaticCell::new();
+        static AP_STACK_RESOURCES: StaticCell<StackResources<6>> = StaticCell::new();
+        let stack = &*AP_STACK.init(Stack::new(
+            ap_device,
+            embassy_net::Config::ipv4_static(StaticConfigV4 {
+                address: Ipv4Cidr::new(Ipv4Address::new(a, b, c, d), 24),
+                gateway: None,
+                dns_servers: heapless::Vec::new(),
+            }),
+            AP_STACK_RESOURCES.init(StackResources::new()),
+            seed,
+        ));
+        Network::Setup(stack, ap_controller)
+    } else {
+        let (wifi_device, wifi_controller) =
+            esp_wifi::wifi::new_with_mode(wifi_init, peripherals.WIFI, WifiStaDevice)
+                .expect("Failed to create Wi-Fi station");
 
+        // Network stack with DHCP
+        static STACK: StaticCell<WifiStack> = StaticCell::new();
+        static STACK_RESOURCES: StaticCell<StackResources<6>> = StaticCell::new();
+        let stack = &*STACK.init(Stack::new(
+            wifi_device,
+            embassy_net::Config::dhcpv4(Default::default()),
+            STACK_RESOURCES.init(StackResources::new()),
+            seed,
+        ));
+        Network::Station(stack, wifi_controller)
+    };
+
+    // Static storage for the executor
+    static EXECUTOR: StaticCell<esp_hal_embassy::Executor> = StaticCell::new();
     let executor = EXECUTOR.init(esp_hal_embassy::Executor::new());
-    let sensor_manager = SENSOR_MANAGER.init(SensorManager::new());
 
     println!("Initializing sensor framework...");
     
     // Configure async UARTs for sensors
-    let io = Io::new(peripherals.GPIO, peripherals.IO_MUX);
-    
     // UART1 for ME2-CO sensor (pins RX=19, TX=18, 9600 baud)
     let uart1 = Uart::new_async_with_config(
         peripherals.UART1,
@@ -80,21 +495,102 @@ fn main() -> ! {
     // Run the executor with our sensor tasks
     executor.run(|spawner| {
         println!("Spawning sensor aggregator task...");
-        spawner.must_spawn(sensor_aggregator_task());
+        let window = AGGREGATION_WINDOW_SECS.and_then(|secs| secs.parse().ok()).map_or(
+            Duration::from_millis(stats::DEFAULT_WINDOW_MS),
+            Duration::from_secs,
+        );
+        spawner.must_spawn(sensor_aggregator_task(window));
+        spawner.must_spawn(latest_values_task());
+        spawner.must_spawn(reading_logger_task());
 
         println!("Spawning sensor tasks...");
 
         // Spawn ME2-CO sensor task with async UART  
-        let me2co_sensor = Me2CoSensorWrapper::new(uart1);
-        spawner.must_spawn(me2co_sensor_task(me2co_sensor));
+        let settings = config.sensor(SensorType::ME2CO);
+        register_sensor(SensorType::ME2CO, settings.enabled);
+        if settings.enabled {
+            let me2co_sensor = Me2CoSensorWrapper::new(uart1);
+            spawner.must_spawn(me2co_sensor_task(me2co_sensor, settings));
+        }
 
         // Spawn SDS011 sensor task with async UART
-        let sds_sensor = Sds011Sensor::new(uart0);
-        spawner.must_spawn(sds011_sensor_task(sds_sensor));
+        let settings = config.sensor(SensorType::SDS011);
+        register_sensor(SensorType::SDS011, settings.enabled);
+        if settings.enabled {
+            let sds_sensor = Sds011Sensor::new(uart0);
+            spawner.must_spawn(sds011_sensor_task(sds_sensor, settings));
+        }
 
         // Spawn BME280 sensor task with I2C
-        let bme_sensor = Bme280Sensor::new(i2c0);
-        spawner.must_spawn(bme280_sensor_task(bme_sensor));
+        let settings = config.sensor(SensorType::BME280);
+        register_sensor(SensorType::BME280, settings.enabled);
+        if settings.enabled {
+            let bme_sensor = Bme280Sensor::new(i2c0);
+            spawner.must_spawn(bme280_sensor_task(bme_sensor, settings, DerivedMetrics::new(config.altitude_m)));
+        }
+
+        // Networking
+        let mac = esp_hal::efuse::Efuse::read_base_mac_address();
+        match network {
+            Network::Setup(stack, controller) => {
+                let ssid = portal::ap_ssid(mac);
+                println!("Setup mode: join {} and open http://192.168.4.1/", ssid);
+                spawner.must_spawn(ap_net_task(stack));
+                spawner.must_spawn(ap_task(controller, ssid));
+                spawner.must_spawn(dhcp_server_task(stack));
+                spawner.must_spawn(dns_task(stack));
+                spawner.must_spawn(portal_task(stack, config.clone()));
+            }
+            Network::Station(stack, controller) => {
+                println!("Spawning network tasks...");
+                spawner.must_spawn(net_task(stack));
+                spawner.must_spawn(wifi_supervisor_task(controller, stack, &config.wifi));
+                spawner.must_spawn(sntp_task(stack));
+                spawner.must_spawn(api_task(stack));
+
+                if let Some(sensor_community) = config.sensor_community.to_config(mac) {
+                    spawner.must_spawn(sensor_community_task(stack, sensor_community));
+                }
+
+                if config.mqtt.is_configured() {
+                    match config.mqtt.to_config(&home_assistant::device_id_from_mac(mac)) {
+                        Some(mqtt) => spawner.must_spawn(home_assistant_task(stack, mqtt)),
+                        None => println!("Invalid MQTT settings, not publishing"),
+                    }
+                }
+
+                // Uploaders fed through the flash log
+                let mut sinks = heapless::Vec::new();
+
+                if config.influxdb.is_configured() {
+                    match config.influxdb.to_config(&home_assistant::device_id_from_mac(mac)) {
+                        Some(upload) => {
+                            spawner.must_spawn(influxdb_task(stack, upload));
+                            let _ = sinks.push(Sink { name: "InfluxDB", queue: &influxdb::UPLOAD_QUEUE, backlog: &influxdb::BACKLOG });
+                        }
+                        None => println!("Invalid InfluxDB settings, not uploading"),
+                    }
+                }
+
+                if config.opensensemap.is_configured() {
+                    match config.opensensemap.to_config() {
+                        Ok(upload) => {
+                            spawner.must_spawn(opensensemap_task(stack, upload));
+                            let _ = sinks.push(Sink {
+                                name: "openSenseMap",
+                                queue: &opensensemap::UPLOAD_QUEUE,
+                                backlog: &opensensemap::BACKLOG,
+                            });
+                        }
+                        Err(e) => println!("Invalid openSenseMap settings ({}), not uploading", e),
+                    }
+                }
+
+                if !sinks.is_empty() {
+                    spawner.must_spawn(store_forward_task(sinks));
+                }
+            }
+        }
 
         println!("All sensor tasks started!");
         println!("Monitor sensor readings below:");
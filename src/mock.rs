@@ -0,0 +1,617 @@
+//! Scripted mock buses for host tests
+//!
+//! `MockI2c` plays back an exact list of expected I2C transactions and
+//! `MockUart` replays a received byte stream (split into arbitrary chunks,
+//! with stalls and errors) while recording everything written to it.
+//! `StdStream`, `StdHttpClient` and `http_stand_in` connect protocol code
+//! to real sockets on the loopback interface. `RamFlash` is a NOR flash
+//! image in memory that can simulate a power cut halfway through a write.
+//! Futures are driven by `block_on`, which advances embassy's mock time
+//! driver whenever the future is pending, so timeouts and delays run
+//! instantly and deterministically.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{Read as _, Write as _};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::pin::pin;
+use std::string::String;
+use std::sync::Mutex;
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use std::thread::{self, JoinHandle};
+use std::vec::Vec;
+
+use embassy_time::{Duration, Instant, MockDriver};
+use embedded_hal_async::i2c::{self, Operation, SevenBitAddress};
+use embedded_storage::nor_flash::{self, NorFlash, NorFlashErrorKind, ReadNorFlash};
+
+use crate::net::http::{self, Header, HttpClient, HttpError};
+
+/// Serializes tests that depend on the global mock time driver
+static TIME_LOCK: Mutex<()> = Mutex::new(());
+
+/// Virtual time step used while a future is pending
+const TIME_STEP: Duration = Duration::from_millis(1);
+
+/// Give up if a future hasn't completed after this much virtual time
+const TIME_LIMIT: Duration = Duration::from_secs(600);
+
+/// Waker that does nothing; `block_on` polls continuously anyway
+fn noop_waker() -> Waker {
+    const VTABLE: RawWakerVTable = RawWakerVTable::new(|_| RAW, |_| {}, |_| {}, |_| {});
+    const RAW: RawWaker = RawWaker::new(core::ptr::null(), &VTABLE);
+    // SAFETY: the vtable functions ignore the data pointer
+    unsafe { Waker::from_raw(RAW) }
+}
+
+/// Run a future to completion, advancing virtual time while it is pending
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let _guard = TIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let driver = MockDriver::get();
+    let start = Instant::now();
+    let mut future = pin!(future);
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        assert!(start.elapsed() < TIME_LIMIT, "future did not complete within {}s of virtual time", TIME_LIMIT.as_secs());
+        driver.advance(TIME_STEP);
+    }
+}
+
+/// Future that never completes, used to model a stalled bus
+async fn stall() -> ! {
+    core::future::pending().await
+}
+
+/// One expected I2C transaction
+#[derive(Debug, Clone)]
+pub struct I2cTransaction {
+    address: u8,
+    write: Vec<u8>,
+    read: Option<Vec<u8>>,
+    outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Ok,
+    Error(i2c::ErrorKind),
+    Stall,
+}
+
+impl I2cTransaction {
+    /// Expect a plain write of `bytes` to `address`
+    pub fn write(address: u8, bytes: &[u8]) -> Self {
+        Self { address, write: bytes.to_vec(), read: None, outcome: Outcome::Ok }
+    }
+
+    /// Expect a write of `bytes` followed by a read answered with `response`
+    pub fn write_read(address: u8, bytes: &[u8], response: &[u8]) -> Self {
+        Self { address, write: bytes.to_vec(), read: Some(response.to_vec()), outcome: Outcome::Ok }
+    }
+
+    /// Fail this transaction with a bus error (e.g. address NACK)
+    pub fn with_error(mut self, kind: i2c::ErrorKind) -> Self {
+        self.outcome = Outcome::Error(kind);
+        self
+    }
+
+    /// Never complete this transaction
+    pub fn with_stall(mut self) -> Self {
+        self.outcome = Outcome::Stall;
+        self
+    }
+}
+
+/// Mock I2C bus that checks each transaction against a script
+pub struct MockI2c {
+    expected: VecDeque<I2cTransaction>,
+}
+
+impl MockI2c {
+    pub fn new(expected: impl IntoIterator<Item = I2cTransaction>) -> Self {
+        Self { expected: expected.into_iter().collect() }
+    }
+
+    /// Append more expected transactions
+    pub fn expect(&mut self, more: impl IntoIterator<Item = I2cTransaction>) {
+        self.expected.extend(more);
+    }
+
+    /// Assert that every scripted transaction was performed
+    pub fn done(&self) {
+        assert!(self.expected.is_empty(), "unconsumed I2C transactions: {:x?}", self.expected);
+    }
+}
+
+impl i2c::ErrorType for MockI2c {
+    type Error = i2c::ErrorKind;
+}
+
+impl i2c::I2c for MockI2c {
+    async fn transaction(&mut self, address: SevenBitAddress, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+        let expected = self.expected.pop_front()
+            .unwrap_or_else(|| panic!("unexpected I2C transaction to {:#04x}: {:x?}", address, operations));
+        assert_eq!(address, expected.address, "I2C address mismatch");
+
+        let (write, read) = match operations {
+            [Operation::Write(w)] => (&**w, None),
+            [Operation::Write(w), Operation::Read(r)] => (&**w, Some(r)),
+            _ => panic!("unsupported I2C operation sequence: {:x?}", operations),
+        };
+        assert_eq!(write, &expected.write[..], "I2C write mismatch at {:#04x}", address);
+        assert_eq!(read.is_some(), expected.read.is_some(), "I2C read expectation mismatch at {:#04x}", address);
+
+        match expected.outcome {
+            Outcome::Ok => {}
+            Outcome::Error(kind) => return Err(kind),
+            Outcome::Stall => stall().await,
+        }
+
+        if let (Some(buf), Some(response)) = (read, expected.read) {
+            assert_eq!(buf.len(), response.len(), "I2C read length mismatch at {:#04x}", address);
+            buf.copy_from_slice(&response);
+        }
+        Ok(())
+    }
+}
+
+/// One event on the UART receive line
+#[derive(Debug, Clone)]
+pub enum UartEvent {
+    /// Bytes that become available together; a single read returns at most one chunk
+    Data(Vec<u8>),
+    /// The next read blocks until the caller gives up (times out)
+    Stall,
+    /// The next read fails with a UART error
+    Error,
+}
+
+/// Mock UART that replays scripted receive events and records transmitted bytes
+///
+/// Once the script is exhausted the line is idle and reads never complete.
+pub struct MockUart {
+    rx: VecDeque<UartEvent>,
+    tx: Vec<u8>,
+    stall_writes: bool,
+}
+
+impl MockUart {
+    pub fn new() -> Self {
+        Self { rx: VecDeque::new(), tx: Vec::new(), stall_writes: false }
+    }
+
+    /// Queue bytes delivered as a single chunk
+    pub fn data(mut self, bytes: &[u8]) -> Self {
+        self.rx.push_back(UartEvent::Data(bytes.to_vec()));
+        self
+    }
+
+    /// Queue bytes fragmented into chunks of at most `size` bytes
+    pub fn fragmented(mut self, bytes: &[u8], size: usize) -> Self {
+        for chunk in bytes.chunks(size) {
+            self.rx.push_back(UartEvent::Data(chunk.to_vec()));
+        }
+        self
+    }
+
+    /// Queue a stall: the next read never completes
+    pub fn stall(mut self) -> Self {
+        self.rx.push_back(UartEvent::Stall);
+        self
+    }
+
+    /// Queue a receive error
+    pub fn error(mut self) -> Self {
+        self.rx.push_back(UartEvent::Error);
+        self
+    }
+
+    /// Make every write block forever
+    pub fn stall_writes(mut self) -> Self {
+        self.stall_writes = true;
+        self
+    }
+
+    /// Everything written to the UART so far
+    pub fn written(&self) -> &[u8] {
+        &self.tx
+    }
+
+    /// Whether every scripted receive event was consumed
+    pub fn rx_drained(&self) -> bool {
+        self.rx.is_empty()
+    }
+}
+
+impl Default for MockUart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl embedded_io_async::ErrorType for MockUart {
+    type Error = embedded_io_async::ErrorKind;
+}
+
+impl embedded_io_async::Read for MockUart {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.rx.pop_front() {
+            Some(UartEvent::Data(mut chunk)) => {
+                let n = chunk.len().min(buf.len());
+                buf[..n].copy_from_slice(&chunk[..n]);
+                if n < chunk.len() {
+                    self.rx.push_front(UartEvent::Data(chunk.split_off(n)));
+                }
+                Ok(n)
+            }
+            Some(UartEvent::Error) => Err(embedded_io_async::ErrorKind::Other),
+            Some(UartEvent::Stall) | None => stall().await,
+        }
+    }
+}
+
+impl embedded_io_async::Write for MockUart {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if self.stall_writes {
+            stall().await;
+        }
+        self.tx.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+}
+
+/// Blocking std TCP stream standing in for an embassy-net socket
+pub struct StdStream(pub TcpStream);
+
+impl StdStream {
+    /// Connect with a short read timeout so a silent peer fails the test
+    pub fn connect(addr: SocketAddr) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(2)))?;
+        Ok(Self(stream))
+    }
+}
+
+fn io_error(e: std::io::Error) -> embedded_io_async::ErrorKind {
+    match e.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => embedded_io_async::ErrorKind::TimedOut,
+        _ => embedded_io_async::ErrorKind::Other,
+    }
+}
+
+impl embedded_io_async::ErrorType for StdStream {
+    type Error = embedded_io_async::ErrorKind;
+}
+
+impl embedded_io_async::Read for StdStream {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.0.read(buf).map_err(io_error)
+    }
+}
+
+impl embedded_io_async::Write for StdStream {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0.write(buf).map_err(io_error)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0.flush().map_err(io_error)
+    }
+}
+
+/// HTTP client that sends every request to one local address
+/// The host and port arguments only end up in the `Host` header
+pub struct StdHttpClient {
+    addr: SocketAddr,
+}
+
+impl StdHttpClient {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self { addr }
+    }
+}
+
+impl HttpClient for StdHttpClient {
+    async fn post(
+        &mut self,
+        host: &str,
+        _port: u16,
+        path: &str,
+        headers: &[Header<'_>],
+        body: &[u8],
+    ) -> Result<u16, HttpError> {
+        let mut stream = StdStream::connect(self.addr).map_err(|_| HttpError::Connect)?;
+        http::request(&mut stream, "POST", host, path, headers, body).await
+    }
+}
+
+/// Request received by the HTTP stand-in server
+#[derive(Debug, Clone)]
+pub struct CapturedRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl CapturedRequest {
+    /// Value of a header, matched case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    pub fn body_str(&self) -> &str {
+        std::str::from_utf8(&self.body).expect("request body is not UTF-8")
+    }
+}
+
+/// Local HTTP server answering one connection per entry of `statuses`
+/// Joining the handle returns the requests in the order they arrived
+pub fn http_stand_in(statuses: Vec<u16>) -> (SocketAddr, JoinHandle<Vec<CapturedRequest>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let handle = thread::spawn(move || {
+        let mut requests = Vec::new();
+        for status in statuses {
+            let (mut stream, _) = listener.accept().unwrap();
+            requests.push(read_http_request(&mut stream));
+            let response = std::format!("HTTP/1.1 {} Stand-in\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+        requests
+    });
+
+    (addr, handle)
+}
+
+fn read_http_request(stream: &mut TcpStream) -> CapturedRequest {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 1024];
+    let head_end = loop {
+        if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0, "connection closed before end of headers");
+        data.extend_from_slice(&buf[..n]);
+    };
+
+    let head = std::str::from_utf8(&data[..head_end]).unwrap().to_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap().split(' ');
+    let method = request_line.next().unwrap().to_owned();
+    let path = request_line.next().unwrap().to_owned();
+    let headers: Vec<(String, String)> = lines
+        .map(|line| {
+            let (name, value) = line.split_once(':').expect("malformed header");
+            (name.trim().to_owned(), value.trim().to_owned())
+        })
+        .collect();
+
+    let length: usize = headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case("content-length"))
+        .map(|(_, v)| v.parse().unwrap())
+        .unwrap_or(0);
+    let mut body = data.split_off(head_end + 4);
+    while body.len() < length {
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0, "connection closed before end of body");
+        body.extend_from_slice(&buf[..n]);
+    }
+
+    CapturedRequest { method, path, headers, body }
+}
+
+/// Message received by the MQTT stand-in broker
+#[derive(Debug, Clone)]
+pub struct ReceivedPublish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+/// What a client did during one session with the MQTT stand-in broker
+#[derive(Debug, Clone, Default)]
+pub struct BrokerSession {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub will: Option<(String, Vec<u8>)>,
+    pub publishes: Vec<ReceivedPublish>,
+    pub pings: usize,
+    pub clean_disconnect: bool,
+}
+
+impl BrokerSession {
+    /// Payload of the last message published to `topic`
+    pub fn last_payload(&self, topic: &str) -> Option<&[u8]> {
+        self.publishes.iter().rev().find(|p| p.topic == topic).map(|p| p.payload.as_slice())
+    }
+}
+
+/// Local MQTT broker accepting one connection
+/// Answers CONNECT with `connack_code` and PINGREQ with PINGRESP; joining
+/// the handle returns the session once the client disconnects
+pub fn mqtt_stand_in(connack_code: u8) -> (SocketAddr, JoinHandle<BrokerSession>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        let mut session = BrokerSession::default();
+
+        while let Some((first, body)) = read_mqtt_packet(&mut stream) {
+            match first >> 4 {
+                1 => {
+                    parse_connect(&body, &mut session);
+                    stream.write_all(&[0x20, 2, 0, connack_code]).unwrap();
+                    if connack_code != 0 {
+                        break;
+                    }
+                }
+                3 => {
+                    let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                    session.publishes.push(ReceivedPublish {
+                        topic: String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap(),
+                        payload: body[2 + topic_len..].to_vec(),
+                        retain: first & 0x01 != 0,
+                    });
+                }
+                12 => {
+                    session.pings += 1;
+                    stream.write_all(&[0xd0, 0]).unwrap();
+                }
+                14 => {
+                    session.clean_disconnect = true;
+                    break;
+                }
+                other => panic!("unexpected MQTT packet type {}", other),
+            }
+        }
+        session
+    });
+
+    (addr, handle)
+}
+
+fn read_mqtt_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+    let mut byte = [0u8];
+    stream.read_exact(&mut byte).ok()?;
+    let first = byte[0];
+    let mut len = 0usize;
+    for shift in (0..4).map(|i| 7 * i) {
+        stream.read_exact(&mut byte).ok()?;
+        len |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = std::vec![0u8; len];
+    stream.read_exact(&mut body).ok()?;
+    Some((first, body))
+}
+
+fn parse_connect(body: &[u8], session: &mut BrokerSession) {
+    assert_eq!(&body[..7], b"\0\x04MQTT\x04", "not an MQTT 3.1.1 CONNECT");
+    let flags = body[7];
+    let mut pos = 10;
+    let mut field = || {
+        let len = u16::from_be_bytes([body[pos], body[pos + 1]]) as usize;
+        let value = body[pos + 2..pos + 2 + len].to_vec();
+        pos += 2 + len;
+        value
+    };
+    session.client_id = String::from_utf8(field()).unwrap();
+    if flags & 0x04 != 0 {
+        let topic = String::from_utf8(field()).unwrap();
+        session.will = Some((topic, field()));
+    }
+    if flags & 0x80 != 0 {
+        session.username = Some(String::from_utf8(field()).unwrap());
+    }
+}
+
+/// NOR flash image in RAM with 4 KiB sectors and 4-byte words
+///
+/// Erasing sets bytes to 0xFF and writing can only clear bits, like real
+/// NOR flash. Erases are counted per sector to check wear.
+pub struct RamFlash {
+    data: Vec<u8>,
+    erases: Vec<u32>,
+    writes: usize,
+    tear_after: Option<usize>,
+}
+
+impl RamFlash {
+    pub const SECTOR_SIZE: usize = 4096;
+
+    /// Erased image of `size` bytes, a multiple of the sector size
+    pub fn new(size: usize) -> Self {
+        assert_eq!(size % Self::SECTOR_SIZE, 0);
+        Self {
+            data: std::vec![0xFF; size],
+            erases: std::vec![0; size / Self::SECTOR_SIZE],
+            writes: 0,
+            tear_after: None,
+        }
+    }
+
+    /// Raw image, e.g. to inject corruption
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    /// Number of times the sector containing `offset` was erased
+    pub fn erase_count(&self, offset: u32) -> u32 {
+        self.erases[offset as usize / Self::SECTOR_SIZE]
+    }
+
+    /// Number of write calls so far
+    pub fn writes(&self) -> usize {
+        self.writes
+    }
+
+    /// Lose power during the next write: only its first `bytes` bytes
+    /// reach the flash and the write fails
+    pub fn tear_next_write(&mut self, bytes: usize) {
+        self.tear_after = Some(bytes);
+    }
+}
+
+impl nor_flash::ErrorType for RamFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for RamFlash {
+    const READ_SIZE: usize = 4;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        nor_flash::check_read(self, offset, bytes.len())?;
+        let start = offset as usize;
+        bytes.copy_from_slice(&self.data[start..start + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for RamFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = Self::SECTOR_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        nor_flash::check_erase(self, from, to)?;
+        self.data[from as usize..to as usize].fill(0xFF);
+        for sector in from as usize / Self::SECTOR_SIZE..to as usize / Self::SECTOR_SIZE {
+            self.erases[sector] += 1;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        nor_flash::check_write(self, offset, bytes.len())?;
+        self.writes += 1;
+        let torn = self.tear_after.take();
+        let len = torn.map_or(bytes.len(), |n| n.min(bytes.len()));
+        let start = offset as usize;
+        for (cell, byte) in self.data[start..start + len].iter_mut().zip(bytes) {
+            *cell &= byte;
+        }
+        match torn {
+            Some(_) => Err(NorFlashErrorKind::Other),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,753 @@
+//! Local JSON API
+//!
+//! Lets nodes on the LAN be polled without a cloud service:
+//!
+//! - `GET /api/readings`: the latest reading of every sensor type, with its
+//!   quality, filter flags and age, and the current air quality indices
+//! - `GET /api/sensors`: the sensor registry (task state, error counts,
+//!   last reading time)
+//! - `GET /api/health`: uptime, Wi-Fi link state and clock sync
+//! - `GET /metrics`: the same data for Prometheus to scrape
+//!
+//! Bodies are rendered by plain functions, so the output can be checked on
+//! the host, and streamed to the connection through a fixed-size buffer:
+//! each piece of the body is one rendering pass that keeps only the bytes
+//! falling into it, so no response is ever too large.
+
+use core::fmt;
+
+use embassy_net::driver::Driver;
+use embassy_net::Stack;
+use embedded_io_async::Write;
+use heapless::Vec;
+
+use super::http::Header;
+use super::http_server::{self, write_head, write_response, Handler, Method, Request, ServerError, HTTP_PORT};
+use super::prometheus::{self, Exposition, MetricType};
+use super::{influxdb, opensensemap, sensor_community, store_forward};
+use super::wifi::{self, LinkState};
+use crate::ring_log::LogStats;
+use crate::sensors::aqi::{self, Indices};
+use crate::sensors::bus::{QueueStats, SubscriberStats, READINGS};
+use crate::sensors::latest::{latest_readings, LATEST};
+use crate::sensors::manager::{with_sensor_manager, SensorRegistry, MAX_SENSORS};
+use crate::sensors::{Field, SensorReading};
+use crate::time;
+
+/// Bytes of a response body written to the connection at a time
+pub const BODY_CAPACITY: usize = 1024;
+
+/// Room for one request; the API only takes bodiless GETs
+pub const REQUEST_CAPACITY: usize = 1024;
+
+const JSON: [Header<'static>; 1] = [("Content-Type", "application/json")];
+
+/// Device status for `/api/health`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Health {
+    pub uptime_ms: u64,
+    pub link: LinkState,
+    /// Wall-clock time, once synced
+    pub utc_ms: Option<u64>,
+    /// Sensors with a running task
+    pub sensors: usize,
+    /// Sensor types with at least one reading
+    pub readings: usize,
+}
+
+impl Health {
+    /// Snapshot of the running device
+    pub fn current() -> Self {
+        Self {
+            uptime_ms: time::now_ms(),
+            link: wifi::link_state(),
+            utc_ms: time::utc_now_ms(),
+            sensors: with_sensor_manager(|m| m.get_registered_sensors().iter().filter(|s| s.task_spawned).count()),
+            readings: LATEST.lock(|latest| latest.borrow().readings().count()),
+        }
+    }
+}
+
+/// JSON number, or `null` for NaN and infinities
+fn write_number(out: &mut impl fmt::Write, value: f32) -> fmt::Result {
+    if value.is_finite() {
+        write!(out, "{}", value)
+    } else {
+        out.write_str("null")
+    }
+}
+
+/// JSON integer or `null`
+fn write_optional(out: &mut impl fmt::Write, value: Option<u64>) -> fmt::Result {
+    match value {
+        Some(value) => write!(out, "{}", value),
+        None => out.write_str("null"),
+    }
+}
+
+/// `{"readings":[…],"indices":{…}}` with one object per reading and one
+/// per air quality scale, `null` where a scale is unknown
+pub fn render_readings(readings: &[SensorReading], indices: &Indices, now_ms: u64, out: &mut impl fmt::Write) -> fmt::Result {
+    out.write_str("{\"readings\":[")?;
+    for (i, reading) in readings.iter().enumerate() {
+        if i > 0 {
+            out.write_char(',')?;
+        }
+        write!(
+            out,
+            "{{\"sensor_type\":\"{}\",\"quality\":\"{}\",\"timestamp\":{},\"utc_timestamp\":",
+            reading.sensor_type.name(),
+            reading.quality.name(),
+            reading.timestamp
+        )?;
+        write_optional(out, reading.utc_timestamp)?;
+        out.write_str(",\"flags\":[")?;
+        for (j, name) in reading.flags.names().enumerate() {
+            if j > 0 {
+                out.write_char(',')?;
+            }
+            write!(out, "\"{}\"", name)?;
+        }
+        write!(out, "],\"age_ms\":{},\"values\":{{", now_ms.saturating_sub(reading.timestamp))?;
+        for (j, (field, value)) in reading.data.values().iter().enumerate() {
+            if j > 0 {
+                out.write_char(',')?;
+            }
+            write!(out, "\"{}\":", field.name())?;
+            write_number(out, *value)?;
+        }
+        out.write_str("}}")?;
+    }
+    out.write_str("],\"indices\":{")?;
+    for (i, (name, scale)) in Indices::SCALES.iter().zip(indices.scales()).enumerate() {
+        if i > 0 {
+            out.write_char(',')?;
+        }
+        write!(out, "\"{}\":", name)?;
+        match scale {
+            Some(scale) => write!(
+                out,
+                "{{\"value\":{},\"category\":\"{}\",\"pollutant\":\"{}\"}}",
+                scale.value,
+                scale.category,
+                scale.pollutant.name()
+            )?,
+            None => out.write_str("null")?,
+        }
+    }
+    out.write_str("}}")
+}
+
+/// `{"sensors":[…]}` with one object per registry entry
+/// `age_ms` is `null` for sensors that haven't reported yet
+pub fn render_sensors(registry: &[SensorRegistry], now_ms: u64, out: &mut impl fmt::Write) -> fmt::Result {
+    out.write_str("{\"sensors\":[")?;
+    for (i, entry) in registry.iter().enumerate() {
+        if i > 0 {
+            out.write_char(',')?;
+        }
+        write!(
+            out,
+            "{{\"sensor_type\":\"{}\",\"task_spawned\":{},\"error_count\":{},\"last_reading_time\":{},\"age_ms\":",
+            entry.sensor_type.name(),
+            entry.task_spawned,
+            entry.error_count,
+            entry.last_reading_time
+        )?;
+        let reported = entry.last_reading_time > 0;
+        write_optional(out, reported.then(|| now_ms.saturating_sub(entry.last_reading_time)))?;
+        out.write_char('}')?;
+    }
+    out.write_str("]}")
+}
+
+/// `/api/health` body; `time` is RFC 3339 once the clock is synced
+pub fn render_health(health: &Health, out: &mut impl fmt::Write) -> fmt::Result {
+    write!(out, "{{\"status\":\"ok\",\"uptime_ms\":{},\"wifi\":\"{}\",\"time\":", health.uptime_ms, health.link.name())?;
+    match health.utc_ms {
+        Some(utc_ms) => write!(out, "\"{}\"", time::format_rfc3339(utc_ms))?,
+        None => out.write_str("null")?,
+    }
+    write!(out, ",\"sensors\":{},\"readings\":{}}}", health.sensors, health.readings)
+}
+
+/// Prometheus metric name and help text for a field
+pub fn metric(field: Field) -> (&'static str, &'static str) {
+    match field {
+        Field::Temperature => ("altruist_temperature_celsius", "Air temperature in degrees Celsius"),
+        Field::Humidity => ("altruist_humidity_percent", "Relative humidity in percent"),
+        Field::Pressure => ("altruist_pressure_hpa", "Air pressure in hectopascals"),
+        Field::GasResistance => ("altruist_gas_resistance_ohms", "Gas sensor resistance in ohms"),
+        Field::Pm25 => ("altruist_pm25_ug_per_m3", "PM2.5 concentration in micrograms per cubic metre"),
+        Field::Pm10 => ("altruist_pm10_ug_per_m3", "PM10 concentration in micrograms per cubic metre"),
+        Field::CoPpm => ("altruist_co_ppm", "Carbon monoxide in parts per million"),
+        Field::Co2Ppm => ("altruist_co2_ppm", "Carbon dioxide in parts per million"),
+        Field::VocIndex => ("altruist_voc_index", "Volatile organic compounds index"),
+        Field::DoseRate => ("altruist_dose_rate_usv_per_hour", "Radiation dose rate in microsieverts per hour"),
+        Field::NoiseDbA => ("altruist_noise_dba", "A-weighted sound level in decibels"),
+        Field::Pm25Corrected => ("altruist_pm25_corrected_ug_per_m3", "Humidity-corrected PM2.5 concentration in micrograms per cubic metre"),
+        Field::Pm10Corrected => ("altruist_pm10_corrected_ug_per_m3", "Humidity-corrected PM10 concentration in micrograms per cubic metre"),
+        Field::DewPoint => ("altruist_dew_point_celsius", "Dew point in degrees Celsius"),
+        Field::AbsoluteHumidity => ("altruist_absolute_humidity_g_per_m3", "Absolute humidity in grams per cubic metre"),
+        Field::HeatIndex => ("altruist_heat_index_celsius", "Heat index in degrees Celsius"),
+        Field::SeaLevelPressure => ("altruist_sea_level_pressure_hpa", "Air pressure reduced to sea level in hectopascals"),
+    }
+}
+
+/// Per-sensor counter: metric name, help text and registry value
+type Counter = (&'static str, &'static str, fn(&SensorRegistry) -> u32);
+
+const COUNTERS: [Counter; 3] = [
+    ("altruist_sensor_read_errors_total", "Failed sensor reads", |e| e.error_count),
+    ("altruist_sensor_dropped_readings_total", "Readings that pushed an unread one off the full reading bus", |e| e.dropped_count),
+    ("altruist_sensor_backoffs_total", "Back-offs after repeated read errors", |e| e.backoff_count),
+];
+
+/// How readings make it to their consumers, for `/metrics`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Delivery<'a> {
+    /// Readings missed per bus subscriber
+    pub bus: &'a [SubscriberStats],
+    /// Readings dropped per queue
+    pub queues: &'a [QueueStats],
+    /// The store-and-forward log, if mounted
+    pub log: Option<&'a LogStats>,
+}
+
+/// Prometheus exposition of the latest readings and the sensor registry
+///
+/// Every field of every reading becomes a gauge labeled with the sensor
+/// type and the reading's quality, every known air quality index one
+/// labeled with its scale, category and pollutant; the registry adds
+/// per-sensor counters,
+/// the reading bus missed readings per subscriber, the upload queues
+/// dropped readings per queue and the store-and-forward log, if mounted,
+/// its fill level.
+pub fn render_metrics(
+    readings: &[SensorReading],
+    indices: &Indices,
+    registry: &[SensorRegistry],
+    delivery: Delivery<'_>,
+    now_ms: u64,
+    out: &mut impl fmt::Write,
+) -> fmt::Result {
+    let mut metrics = Exposition::new(out);
+
+    for field in Field::ALL {
+        let (name, help) = metric(field);
+        let mut samples = readings.iter().filter_map(|r| Some((r, r.data.value(field)?))).peekable();
+        if samples.peek().is_none() {
+            continue;
+        }
+        metrics.family(name, MetricType::Gauge, help)?;
+        for (reading, value) in samples {
+            let labels = [("sensor_type", reading.sensor_type.name()), ("quality", reading.quality.name())];
+            metrics.sample(name, &labels, value)?;
+        }
+    }
+
+    if !indices.is_empty() {
+        let name = "altruist_air_quality_index";
+        metrics.family(name, MetricType::Gauge, "Air quality index of the worst pollutant")?;
+        for scale in indices.scales().into_iter().flatten() {
+            let labels = [("scale", scale.scale), ("category", scale.category), ("pollutant", scale.pollutant.name())];
+            metrics.sample(name, &labels, u32::from(scale.value))?;
+        }
+    }
+
+    if !readings.is_empty() {
+        metrics.family("altruist_reading_age_seconds", MetricType::Gauge, "Seconds since the latest reading")?;
+        for reading in readings {
+            let age = now_ms.saturating_sub(reading.timestamp) as f32 / 1000.0;
+            metrics.sample("altruist_reading_age_seconds", &[("sensor_type", reading.sensor_type.name())], age)?;
+        }
+    }
+
+    if !registry.is_empty() {
+        for (name, help, count) in COUNTERS {
+            metrics.family(name, MetricType::Counter, help)?;
+            for entry in registry {
+                metrics.sample(name, &[("sensor_type", entry.sensor_type.name())], count(entry))?;
+            }
+        }
+    }
+
+    if !delivery.bus.is_empty() {
+        let name = "altruist_bus_missed_readings_total";
+        metrics.family(name, MetricType::Counter, "Readings a bus subscriber fell too far behind to see")?;
+        for subscriber in delivery.bus {
+            metrics.sample(name, &[("subscriber", subscriber.name)], subscriber.missed)?;
+        }
+    }
+
+    if !delivery.queues.is_empty() {
+        let name = "altruist_queue_dropped_readings_total";
+        metrics.family(name, MetricType::Counter, "Readings dropped because a consumer's queue was full")?;
+        for queue in delivery.queues {
+            metrics.sample(name, &[("queue", queue.name)], queue.dropped)?;
+        }
+    }
+
+    if let Some(log) = delivery.log {
+        metrics.family("altruist_log_pending_readings", MetricType::Gauge, "Readings in the flash log waiting for upload")?;
+        metrics.sample("altruist_log_pending_readings", &[], log.pending)?;
+        metrics.family("altruist_log_used_bytes", MetricType::Gauge, "Flash log bytes in use")?;
+        metrics.sample("altruist_log_used_bytes", &[], log.used_bytes)?;
+        metrics.family("altruist_log_capacity_bytes", MetricType::Gauge, "Flash log size in bytes")?;
+        metrics.sample("altruist_log_capacity_bytes", &[], log.capacity_bytes)?;
+        metrics.family("altruist_log_dropped_readings_total", MetricType::Counter, "Readings overwritten in the full flash log")?;
+        metrics.sample("altruist_log_dropped_readings_total", &[], log.dropped)?;
+    }
+
+    metrics.family("altruist_uptime_seconds", MetricType::Gauge, "Seconds since boot")?;
+    metrics.sample("altruist_uptime_seconds", &[], now_ms / 1000)
+}
+
+/// Keeps the bytes of a rendering that fall into one piece of the body
+/// and counts all of them
+pub struct Piece<'a> {
+    buf: &'a mut [u8],
+    /// Body offset of `buf[0]`
+    start: usize,
+    /// Bytes rendered so far
+    len: usize,
+}
+
+impl<'a> Piece<'a> {
+    pub fn new(buf: &'a mut [u8], start: usize) -> Self {
+        Self { buf, start, len: 0 }
+    }
+
+    /// Length of the whole body, once rendered
+    pub fn body_len(&self) -> usize {
+        self.len
+    }
+
+    /// The rendered bytes of this piece
+    pub fn bytes(&self) -> &[u8] {
+        let end = self.len.clamp(self.start, self.start + self.buf.len());
+        &self.buf[..end - self.start]
+    }
+}
+
+impl fmt::Write for Piece<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        let from = self.len.max(self.start);
+        let to = end.min(self.start + self.buf.len());
+        if from < to {
+            self.buf[from - self.start..to - self.start].copy_from_slice(&s.as_bytes()[from - self.len..to - self.len]);
+        }
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Routes API requests to the renderers
+pub struct ApiHandler {
+    body: [u8; BODY_CAPACITY],
+}
+
+impl Default for ApiHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ApiHandler {
+    pub const fn new() -> Self {
+        Self { body: [0; BODY_CAPACITY] }
+    }
+
+    /// Render a body piece by piece straight to the connection
+    ///
+    /// `render` runs once per `BODY_CAPACITY` bytes and must produce the
+    /// same output every time.
+    async fn stream<C: Write>(
+        &mut self,
+        conn: &mut C,
+        content_type: &str,
+        render: impl Fn(&mut Piece<'_>) -> fmt::Result,
+    ) -> Result<(), ServerError> {
+        let mut start = 0;
+        loop {
+            let mut piece = Piece::new(&mut self.body, start);
+            if render(&mut piece).is_err() {
+                // Only the first pass can get here, before the head is sent
+                log::warn!("[API] Rendering a response failed");
+                return write_response(conn, 500, &JSON, b"{\"error\":\"rendering failed\"}").await;
+            }
+            if start == 0 {
+                write_head(conn, 200, &[("Content-Type", content_type)], piece.body_len()).await?;
+            }
+            conn.write_all(piece.bytes()).await.map_err(|_| ServerError::Network)?;
+            start += BODY_CAPACITY;
+            if start >= piece.body_len() {
+                return conn.flush().await.map_err(|_| ServerError::Network);
+            }
+        }
+    }
+}
+
+impl Handler for ApiHandler {
+    async fn handle<C: Write>(&mut self, request: &Request<'_>, conn: &mut C) -> Result<(), ServerError> {
+        if request.method != Method::Get {
+            return write_response(conn, 405, &[("Allow", "GET")], b"").await;
+        }
+
+        // Every pass must render the same snapshot
+        let now_ms = time::now_ms();
+        let registry = || -> Vec<SensorRegistry, MAX_SENSORS> {
+            with_sensor_manager(|m| m.get_registered_sensors().iter().cloned().collect())
+        };
+        let json = "application/json";
+        match request.path {
+            "/api/readings" => {
+                let readings = latest_readings();
+                let indices = aqi::current_indices();
+                self.stream(conn, json, |out| render_readings(&readings, &indices, now_ms, out)).await
+            }
+            "/api/sensors" => {
+                let registry = registry();
+                self.stream(conn, json, |out| render_sensors(&registry, now_ms, out)).await
+            }
+            "/api/health" => {
+                let health = Health::current();
+                self.stream(conn, json, |out| render_health(&health, out)).await
+            }
+            "/metrics" => {
+                let readings = latest_readings();
+                let indices = aqi::current_indices();
+                let registry = registry();
+                let bus = READINGS.subscriber_stats();
+                let queues = [
+                    sensor_community::UPLOAD_QUEUE.stats(),
+                    store_forward::LOG_QUEUE.stats(),
+                    influxdb::UPLOAD_QUEUE.stats(),
+                    opensensemap::UPLOAD_QUEUE.stats(),
+                ];
+                let log = store_forward::log_stats();
+                let delivery = Delivery { bus: &bus, queues: &queues, log: log.as_ref() };
+                self.stream(conn, prometheus::CONTENT_TYPE, |out| {
+                    render_metrics(&readings, &indices, &registry, delivery, now_ms, out)
+                })
+                .await
+            }
+            _ => write_response(conn, 404, &JSON, b"{\"error\":\"not found\"}").await,
+        }
+    }
+}
+
+/// Serve the API on port 80 forever
+pub async fn api_task_impl<D: Driver>(stack: &Stack<D>) -> ! {
+    let mut handler = ApiHandler::new();
+    let mut buf = [0u8; REQUEST_CAPACITY];
+
+    log::info!("[API] Serving JSON API on port {}", HTTP_PORT);
+    http_server::serve(stack, HTTP_PORT, &mut handler, &mut buf).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write as _;
+    use heapless::String;
+    use crate::mock::{block_on, MockUart};
+    use crate::net::http_server::serve_connection;
+    use crate::sensors::aqi::{AqiCategory, AqiTracker, Index};
+    use crate::sensors::latest;
+    use crate::sensors::{Flags, Quality, SensorData, SensorType};
+
+    fn reading(sensor_type: SensorType, data: SensorData, timestamp: u64) -> SensorReading {
+        SensorReading { sensor_type, data, timestamp, utc_timestamp: None, quality: Quality::Good, flags: Flags::NONE }
+    }
+
+    fn environmental(temperature: f32) -> SensorData {
+        SensorData::Environmental { temperature: Some(temperature), humidity: Some(40.0), pressure: None, gas_resistance: None, dew_point: None, absolute_humidity: None, heat_index: None, sea_level_pressure: None }
+    }
+
+    #[test]
+    fn test_render_readings() {
+        let mut pm = reading(SensorType::SDS011, SensorData::AirQuality { pm25: Some(12.5), pm10: Some(f32::NAN), pm25_corrected: None, pm10_corrected: None }, 4_000);
+        pm.quality = Quality::Degraded;
+        pm.flags = Flags::MEDIAN_OUTLIER | Flags::HAMPEL_OUTLIER;
+        pm.utc_timestamp = Some(1_700_000_000_000);
+        let readings = [reading(SensorType::BME280, environmental(21.25), 9_000), pm];
+
+        let indices = Indices { us_aqi: Some((Field::Pm25, Index { value: 62, category: AqiCategory::Moderate })), ..Default::default() };
+
+        let mut out: String<640> = String::new();
+        render_readings(&readings, &indices, 10_000, &mut out).unwrap();
+        assert_eq!(
+            out.as_str(),
+            "{\"readings\":[\
+             {\"sensor_type\":\"BME280\",\"quality\":\"good\",\"timestamp\":9000,\"utc_timestamp\":null,\"flags\":[],\
+             \"age_ms\":1000,\"values\":{\"temperature\":21.25,\"humidity\":40}},\
+             {\"sensor_type\":\"SDS011\",\"quality\":\"degraded\",\"timestamp\":4000,\"utc_timestamp\":1700000000000,\
+             \"flags\":[\"median_outlier\",\"hampel_outlier\"],\"age_ms\":6000,\"values\":{\"pm25\":12.5,\"pm10\":null}}],\
+             \"indices\":{\"us_aqi\":{\"value\":62,\"category\":\"moderate\",\"pollutant\":\"pm25\"},\"caqi\":null,\"daqi\":null}}"
+        );
+
+        out.clear();
+        render_readings(&[], &Indices::default(), 0, &mut out).unwrap();
+        assert_eq!(out.as_str(), "{\"readings\":[],\"indices\":{\"us_aqi\":null,\"caqi\":null,\"daqi\":null}}");
+
+        // Doesn't fit
+        let mut small: String<32> = String::new();
+        assert!(render_readings(&readings, &indices, 10_000, &mut small).is_err());
+    }
+
+    #[test]
+    fn test_render_sensors() {
+        let registry = [
+            SensorRegistry { sensor_type: SensorType::SDS011, task_spawned: true, last_reading_time: 2_500, error_count: 3, dropped_count: 0, backoff_count: 0 },
+            SensorRegistry { sensor_type: SensorType::ME2CO, task_spawned: false, last_reading_time: 0, error_count: 0, dropped_count: 0, backoff_count: 0 },
+        ];
+        let mut out: String<512> = String::new();
+        render_sensors(&registry, 3_000, &mut out).unwrap();
+        assert_eq!(
+            out.as_str(),
+            "{\"sensors\":[\
+             {\"sensor_type\":\"SDS011\",\"task_spawned\":true,\"error_count\":3,\"last_reading_time\":2500,\"age_ms\":500},\
+             {\"sensor_type\":\"ME2-CO\",\"task_spawned\":false,\"error_count\":0,\"last_reading_time\":0,\"age_ms\":null}]}"
+        );
+    }
+
+    #[test]
+    fn test_render_health() {
+        let mut health = Health { uptime_ms: 61_000, link: LinkState::Up, utc_ms: None, sensors: 3, readings: 2 };
+        let mut out: String<256> = String::new();
+        render_health(&health, &mut out).unwrap();
+        assert_eq!(
+            out.as_str(),
+            "{\"status\":\"ok\",\"uptime_ms\":61000,\"wifi\":\"up\",\"time\":null,\"sensors\":3,\"readings\":2}"
+        );
+
+        health.link = LinkState::Backoff { attempt: 2, delay_ms: 4_000 };
+        health.utc_ms = Some(1_700_000_000_000);
+        out.clear();
+        render_health(&health, &mut out).unwrap();
+        assert!(out.contains("\"wifi\":\"backoff\",\"time\":\"2023-11-14T22:13:20.000Z\""), "{}", out);
+    }
+
+    #[test]
+    fn test_render_metrics() {
+        let mut pm = reading(SensorType::SDS011, SensorData::AirQuality { pm25: Some(12.5), pm10: Some(20.0), pm25_corrected: None, pm10_corrected: None }, 1_500);
+        pm.quality = Quality::Degraded;
+        let readings = [reading(SensorType::BME280, environmental(21.25), 9_000), pm];
+        let registry = [
+            SensorRegistry { sensor_type: SensorType::BME280, task_spawned: true, last_reading_time: 9_000, error_count: 0, dropped_count: 0, backoff_count: 0 },
+            SensorRegistry { sensor_type: SensorType::SDS011, task_spawned: true, last_reading_time: 1_500, error_count: 5, dropped_count: 2, backoff_count: 1 },
+        ];
+
+        let mut out: String<4096> = String::new();
+        render_metrics(&readings, &Indices::default(), &registry, Delivery::default(), 10_000, &mut out).unwrap();
+        assert_eq!(
+            out.as_str(),
+            "# HELP altruist_temperature_celsius Air temperature in degrees Celsius\n\
+             # TYPE altruist_temperature_celsius gauge\n\
+             altruist_temperature_celsius{sensor_type=\"BME280\",quality=\"good\"} 21.25\n\
+             # HELP altruist_humidity_percent Relative humidity in percent\n\
+             # TYPE altruist_humidity_percent gauge\n\
+             altruist_humidity_percent{sensor_type=\"BME280\",quality=\"good\"} 40\n\
+             # HELP altruist_pm25_ug_per_m3 PM2.5 concentration in micrograms per cubic metre\n\
+             # TYPE altruist_pm25_ug_per_m3 gauge\n\
+             altruist_pm25_ug_per_m3{sensor_type=\"SDS011\",quality=\"degraded\"} 12.5\n\
+             # HELP altruist_pm10_ug_per_m3 PM10 concentration in micrograms per cubic metre\n\
+             # TYPE altruist_pm10_ug_per_m3 gauge\n\
+             altruist_pm10_ug_per_m3{sensor_type=\"SDS011\",quality=\"degraded\"} 20\n\
+             # HELP altruist_reading_age_seconds Seconds since the latest reading\n\
+             # TYPE altruist_reading_age_seconds gauge\n\
+             altruist_reading_age_seconds{sensor_type=\"BME280\"} 1\n\
+             altruist_reading_age_seconds{sensor_type=\"SDS011\"} 8.5\n\
+             # HELP altruist_sensor_read_errors_total Failed sensor reads\n\
+             # TYPE altruist_sensor_read_errors_total counter\n\
+             altruist_sensor_read_errors_total{sensor_type=\"BME280\"} 0\n\
+             altruist_sensor_read_errors_total{sensor_type=\"SDS011\"} 5\n\
+             # HELP altruist_sensor_dropped_readings_total Readings that pushed an unread one off the full reading bus\n\
+             # TYPE altruist_sensor_dropped_readings_total counter\n\
+             altruist_sensor_dropped_readings_total{sensor_type=\"BME280\"} 0\n\
+             altruist_sensor_dropped_readings_total{sensor_type=\"SDS011\"} 2\n\
+             # HELP altruist_sensor_backoffs_total Back-offs after repeated read errors\n\
+             # TYPE altruist_sensor_backoffs_total counter\n\
+             altruist_sensor_backoffs_total{sensor_type=\"BME280\"} 0\n\
+             altruist_sensor_backoffs_total{sensor_type=\"SDS011\"} 1\n\
+             # HELP altruist_uptime_seconds Seconds since boot\n\
+             # TYPE altruist_uptime_seconds gauge\n\
+             altruist_uptime_seconds 10\n"
+        );
+
+        // Nothing known yet: just the uptime
+        out.clear();
+        render_metrics(&[], &Indices::default(), &[], Delivery::default(), 999, &mut out).unwrap();
+        assert_eq!(
+            out.as_str(),
+            "# HELP altruist_uptime_seconds Seconds since boot\n# TYPE altruist_uptime_seconds gauge\naltruist_uptime_seconds 0\n"
+        );
+
+        let log = LogStats { pending: 12, dropped: 3, used_bytes: 480, capacity_bytes: 16_384 };
+        out.clear();
+        let bus = [SubscriberStats { name: "aggregator", missed: 0 }, SubscriberStats { name: "logger", missed: 7 }];
+        let queues = [QueueStats { name: "sensor_community", dropped: 0 }, QueueStats { name: "store_forward", dropped: 4 }];
+        let delivery = Delivery { bus: &bus, queues: &queues, log: Some(&log) };
+        render_metrics(&[], &Indices::default(), &[], delivery, 999, &mut out).unwrap();
+        assert!(out.starts_with(
+            "# HELP altruist_bus_missed_readings_total Readings a bus subscriber fell too far behind to see\n\
+             # TYPE altruist_bus_missed_readings_total counter\n\
+             altruist_bus_missed_readings_total{subscriber=\"aggregator\"} 0\n\
+             altruist_bus_missed_readings_total{subscriber=\"logger\"} 7\n\
+             # HELP altruist_queue_dropped_readings_total Readings dropped because a consumer's queue was full\n\
+             # TYPE altruist_queue_dropped_readings_total counter\n\
+             altruist_queue_dropped_readings_total{queue=\"sensor_community\"} 0\n\
+             altruist_queue_dropped_readings_total{queue=\"store_forward\"} 4\n"
+        ));
+        assert!(out.contains(
+            "# HELP altruist_log_pending_readings Readings in the flash log waiting for upload\n\
+             # TYPE altruist_log_pending_readings gauge\n\
+             altruist_log_pending_readings 12\n"
+        ));
+        assert!(out.contains("\naltruist_log_used_bytes 480\n"));
+        assert!(out.contains("\naltruist_log_capacity_bytes 16384\n"));
+        assert!(out.contains("# TYPE altruist_log_dropped_readings_total counter\naltruist_log_dropped_readings_total 3\n"));
+    }
+
+    /// The stock station: BME280 with derived values, SDS011 with
+    /// humidity-corrected PM and ME2CO, all with every counter, the air
+    /// quality indices, every bus subscriber and queue, and the flash log
+    /// mounted
+    fn full_station() -> std::string::String {
+        let bme = SensorData::Environmental {
+            temperature: Some(21.37),
+            humidity: Some(48.62),
+            pressure: Some(1003.41),
+            gas_resistance: None,
+            dew_point: Some(9.98),
+            absolute_humidity: Some(8.97),
+            heat_index: Some(20.91),
+            sea_level_pressure: Some(1031.72),
+        };
+        let sds = SensorData::AirQuality { pm25: Some(12.34), pm10: Some(23.45), pm25_corrected: Some(9.87), pm10_corrected: Some(18.76) };
+        let mut readings = [
+            reading(SensorType::BME280, bme, 1_234_567),
+            reading(SensorType::SDS011, sds, 1_230_001),
+            reading(SensorType::ME2CO, SensorData::Gas { co_ppm: Some(1.23), co2_ppm: None, voc_index: None }, 1_229_999),
+        ];
+        readings[1].quality = Quality::Degraded;
+        let registry = readings.each_ref().map(|r| SensorRegistry {
+            sensor_type: r.sensor_type,
+            task_spawned: true,
+            last_reading_time: r.timestamp,
+            error_count: 1_234,
+            dropped_count: 56,
+            backoff_count: 78,
+        });
+        let bus = ["aggregator", "latest", "logger", "home_assistant"].map(|name| SubscriberStats { name, missed: 12_345 });
+        let queues = ["sensor_community", "store_forward", "influxdb", "opensensemap"].map(|name| QueueStats { name, dropped: 678 });
+        let log = LogStats { pending: 12_345, dropped: 678, used_bytes: 987_654, capacity_bytes: 1_048_576 };
+
+        let mut tracker = AqiTracker::new();
+        readings.iter().for_each(|r| tracker.record(r));
+        let indices = tracker.indices(1_240_000);
+
+        let mut out = std::string::String::new();
+        let delivery = Delivery { bus: &bus, queues: &queues, log: Some(&log) };
+        render_metrics(&readings, &indices, &registry, delivery, 1_240_000, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_metrics_stream_in_pieces() {
+        // More than one piece, and more than the old fixed 4096-byte body
+        let body = full_station();
+        assert!(body.len() > 4096, "{}", body.len());
+        assert!(body.contains("\naltruist_sea_level_pressure_hpa{sensor_type=\"BME280\",quality=\"good\"} 1031.72\n"));
+        assert!(body.contains(
+            "# TYPE altruist_air_quality_index gauge\n\
+             altruist_air_quality_index{scale=\"us_aqi\",category=\"good\",pollutant=\"co_ppm\"} 14\n\
+             altruist_air_quality_index{scale=\"caqi\",category=\"very_low\",pollutant=\"pm10\"} 19\n\
+             altruist_air_quality_index{scale=\"daqi\",category=\"low\",pollutant=\"pm10\"} 2\n"
+        ), "{}", body);
+        assert!(body.ends_with("altruist_uptime_seconds 1240\n"));
+
+        // Every pass keeps its own piece, together they make up the body
+        let mut buf = [0u8; BODY_CAPACITY];
+        let mut joined = std::vec::Vec::new();
+        for start in (0..body.len()).step_by(BODY_CAPACITY) {
+            let mut piece = Piece::new(&mut buf, start);
+            piece.write_str(&body).unwrap();
+            assert_eq!(piece.body_len(), body.len());
+            joined.extend_from_slice(piece.bytes());
+        }
+        assert_eq!(joined, body.as_bytes());
+
+        // Writes straddling piece boundaries
+        let mut small = [0u8; 4];
+        let mut piece = Piece::new(&mut small, 3);
+        let value = 1.5;
+        write!(piece, "ab{}cdefgh", value).unwrap();
+        assert_eq!(piece.bytes(), b".5cd");
+        let mut piece = Piece::new(&mut small, 6);
+        piece.write_str("abcdefgh").unwrap();
+        assert_eq!(piece.bytes(), b"gh");
+        let mut piece = Piece::new(&mut small, 8);
+        piece.write_str("abc").unwrap();
+        assert_eq!(piece.bytes(), b"");
+    }
+
+    #[test]
+    fn test_stream_response() {
+        let body = full_station();
+        let mut handler = ApiHandler::new();
+        let mut conn = MockUart::new();
+        block_on(handler.stream(&mut conn, prometheus::CONTENT_TYPE, |out| out.write_str(&body))).unwrap();
+
+        let head = std::format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            prometheus::CONTENT_TYPE,
+            body.len()
+        );
+        assert_eq!(conn.written(), [head.as_bytes(), body.as_bytes()].concat());
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    /// Response to a raw request, as written to the connection
+    async fn respond(raw: &[u8]) -> std::vec::Vec<u8> {
+        let mut conn = MockUart::new().data(raw);
+        let mut buf = [0u8; REQUEST_CAPACITY];
+        serve_connection(&mut conn, &mut ApiHandler::new(), &mut buf).await.unwrap();
+        conn.written().to_vec()
+    }
+
+    #[test]
+    fn test_routes() {
+        block_on(async {
+            let response = respond(b"GET /api/health HTTP/1.1\r\nHost: altruist\r\n\r\n").await;
+            assert!(response.starts_with(b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n"));
+            assert!(contains(&response, b"{\"status\":\"ok\","));
+
+            // Later readings replace earlier ones of the same type
+            latest::record(&reading(SensorType::BME280, environmental(20.0), 1_000));
+            latest::record(&reading(SensorType::BME280, environmental(22.5), 2_000));
+            assert_eq!(latest_readings().len(), 1);
+            let response = respond(b"GET /api/readings?pretty HTTP/1.1\r\n\r\n").await;
+            assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+            assert!(contains(&response, b"\"temperature\":22.5,"));
+
+            let response = respond(b"GET /api/sensors HTTP/1.1\r\n\r\n").await;
+            assert!(response.ends_with(b"{\"sensors\":[]}"));
+
+            let response = respond(b"GET /metrics HTTP/1.1\r\n\r\n").await;
+            assert!(response.starts_with(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\n"));
+            assert!(contains(&response, b"\naltruist_temperature_celsius{sensor_type=\"BME280\",quality=\"good\"} 22.5\n"));
+
+            let response = respond(b"GET /api/nope HTTP/1.1\r\n\r\n").await;
+            assert!(response.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+
+            let response = respond(b"POST /api/readings HTTP/1.1\r\nContent-Length: 0\r\n\r\n").await;
+            assert!(response.starts_with(b"HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\n"));
+        });
+    }
+}
@@ -0,0 +1,403 @@
+//! Minimal DHCP server (RFC 2131) for the setup access point
+//!
+//! Hands out addresses from a small pool on the soft-AP's /24 and names
+//! the device itself as router and DNS server, which is what lets the
+//! catch-all DNS responder redirect clients to the setup page. Leases are
+//! remembered per MAC address; when the pool is full the oldest is reused.
+
+use embassy_net::driver::Driver;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Ipv4Address, Stack};
+use heapless::Vec;
+
+/// Port the server listens on
+pub const SERVER_PORT: u16 = 67;
+
+/// Port replies are sent to
+pub const CLIENT_PORT: u16 = 68;
+
+/// Clients served at the same time
+pub const MAX_LEASES: usize = 8;
+
+/// Buffer for one message; requests with long option lists are truncated
+/// by the socket and then rejected
+pub const MAX_MESSAGE: usize = 576;
+
+/// Fixed BOOTP fields plus the magic cookie
+const OPTIONS_OFFSET: usize = 240;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+
+/// DHCP message types (option 53)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+    Inform = 8,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => MessageType::Discover,
+            2 => MessageType::Offer,
+            3 => MessageType::Request,
+            4 => MessageType::Decline,
+            5 => MessageType::Ack,
+            6 => MessageType::Nak,
+            7 => MessageType::Release,
+            8 => MessageType::Inform,
+            _ => return None,
+        })
+    }
+}
+
+/// The parts of a client message the server cares about
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ClientMessage {
+    message_type: MessageType,
+    mac: [u8; 6],
+    ciaddr: [u8; 4],
+    requested_ip: Option<[u8; 4]>,
+    server_id: Option<[u8; 4]>,
+}
+
+fn parse(message: &[u8]) -> Option<ClientMessage> {
+    if message.len() < OPTIONS_OFFSET
+        || message[0] != OP_REQUEST
+        || message[1] != HTYPE_ETHERNET
+        || message[2] != 6
+        || message[236..240] != MAGIC_COOKIE
+    {
+        return None;
+    }
+
+    let mut parsed = ClientMessage {
+        message_type: MessageType::Discover,
+        mac: message[28..34].try_into().ok()?,
+        ciaddr: message[12..16].try_into().ok()?,
+        requested_ip: None,
+        server_id: None,
+    };
+    let mut message_type = None;
+
+    let mut options = &message[OPTIONS_OFFSET..];
+    loop {
+        match *options {
+            [] | [OPTION_END, ..] => break,
+            [OPTION_PAD, ref rest @ ..] => options = rest,
+            [code, len, ref rest @ ..] => {
+                let value = rest.get(..usize::from(len))?;
+                match (code, value) {
+                    (OPTION_MESSAGE_TYPE, [t]) => message_type = MessageType::from_u8(*t),
+                    (OPTION_REQUESTED_IP, &[a, b, c, d]) => parsed.requested_ip = Some([a, b, c, d]),
+                    (OPTION_SERVER_ID, &[a, b, c, d]) => parsed.server_id = Some([a, b, c, d]),
+                    _ => {}
+                }
+                options = &rest[usize::from(len)..];
+            }
+            [_] => return None,
+        }
+    }
+
+    parsed.message_type = message_type?;
+    Some(parsed)
+}
+
+/// Address pool and lease table
+pub struct DhcpServer {
+    ip: [u8; 4],
+    pool_start: u8,
+    lease_time: u32,
+    /// Client MACs, oldest first; a client's address is its pool slot
+    leases: Vec<([u8; 6], u8), MAX_LEASES>,
+}
+
+impl DhcpServer {
+    /// Serve `MAX_LEASES` addresses from `pool_start` on the server's /24
+    pub fn new(ip: [u8; 4], pool_start: u8) -> Self {
+        Self { ip, pool_start, lease_time: 3600, leases: Vec::new() }
+    }
+
+    /// Address leased to a client, allocating one if needed
+    fn lease(&mut self, mac: [u8; 6]) -> [u8; 4] {
+        if let Some(ip) = self.leased(mac) {
+            return ip;
+        }
+        let slot = match self.leases.is_full() {
+            // Reuse the oldest lease
+            true => self.leases.remove(0).1,
+            false => (0..MAX_LEASES as u8).find(|s| self.leases.iter().all(|(_, used)| used != s)).unwrap_or(0),
+        };
+        let _ = self.leases.push((mac, slot));
+        self.address(slot)
+    }
+
+    /// Address already leased to a client
+    fn leased(&self, mac: [u8; 6]) -> Option<[u8; 4]> {
+        self.leases.iter().find(|(m, _)| *m == mac).map(|(_, slot)| self.address(*slot))
+    }
+
+    fn address(&self, slot: u8) -> [u8; 4] {
+        [self.ip[0], self.ip[1], self.ip[2], self.pool_start.wrapping_add(slot)]
+    }
+
+    fn release(&mut self, mac: [u8; 6]) {
+        self.leases.retain(|(m, _)| *m != mac);
+    }
+
+    /// Handle one client message, writing the reply into `out`
+    /// Returns the reply length, or `None` if there is nothing to send
+    pub fn handle(&mut self, request: &[u8], out: &mut [u8]) -> Option<usize> {
+        let message = parse(request)?;
+        if message.server_id.is_some_and(|id| id != self.ip) {
+            // The client picked another server
+            return None;
+        }
+
+        let (reply_type, yiaddr) = match message.message_type {
+            MessageType::Discover => (MessageType::Offer, self.lease(message.mac)),
+            MessageType::Request => {
+                // Only clients we made an offer to get an address, so an
+                // unknown one can't push out somebody else's lease
+                let requested = message.requested_ip.unwrap_or(message.ciaddr);
+                match self.leased(message.mac) {
+                    Some(leased) if leased == requested => (MessageType::Ack, leased),
+                    _ => (MessageType::Nak, [0; 4]),
+                }
+            }
+            MessageType::Release | MessageType::Decline => {
+                self.release(message.mac);
+                return None;
+            }
+            _ => return None,
+        };
+
+        let out = out.get_mut(..MAX_MESSAGE)?;
+        out.fill(0);
+        out[0] = OP_REPLY;
+        out[1] = HTYPE_ETHERNET;
+        out[2] = 6;
+        // xid, then flags (keeps the client's broadcast bit)
+        out[4..8].copy_from_slice(&request[4..8]);
+        out[10..12].copy_from_slice(&request[10..12]);
+        out[16..20].copy_from_slice(&yiaddr);
+        out[20..24].copy_from_slice(&self.ip);
+        // giaddr and chaddr
+        out[24..44].copy_from_slice(&request[24..44]);
+        out[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        let mut len = OPTIONS_OFFSET;
+        let mut option = |code: u8, value: &[u8]| {
+            out[len] = code;
+            out[len + 1] = value.len() as u8;
+            out[len + 2..len + 2 + value.len()].copy_from_slice(value);
+            len += 2 + value.len();
+        };
+        option(OPTION_MESSAGE_TYPE, &[reply_type as u8]);
+        option(OPTION_SERVER_ID, &self.ip);
+        if reply_type != MessageType::Nak {
+            option(OPTION_LEASE_TIME, &self.lease_time.to_be_bytes());
+            option(OPTION_SUBNET_MASK, &[255, 255, 255, 0]);
+            option(OPTION_ROUTER, &self.ip);
+            option(OPTION_DNS, &self.ip);
+        }
+        out[len] = OPTION_END;
+        Some(len + 1)
+    }
+}
+
+/// Serve DHCP on port 67 forever, broadcasting every reply
+pub async fn dhcp_server_task_impl<D: Driver>(stack: &Stack<D>, mut server: DhcpServer) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 2 * MAX_MESSAGE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0u8; 2 * MAX_MESSAGE];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    if let Err(e) = socket.bind(SERVER_PORT) {
+        log::error!("[DHCP] Failed to bind port {}: {:?}", SERVER_PORT, e);
+        loop {
+            core::future::pending::<()>().await;
+        }
+    }
+    log::info!("[DHCP] Serving addresses from .{}", server.pool_start);
+
+    let broadcast = IpEndpoint::new(Ipv4Address::BROADCAST.into(), CLIENT_PORT);
+    let mut request = [0u8; MAX_MESSAGE];
+    let mut reply = [0u8; MAX_MESSAGE];
+    loop {
+        let len = match socket.recv_from(&mut request).await {
+            Ok((len, _)) => len,
+            Err(e) => {
+                log::warn!("[DHCP] Receive failed: {:?}", e);
+                continue;
+            }
+        };
+        if let Some(len) = server.handle(&request[..len], &mut reply) {
+            if let Err(e) = socket.send_to(&reply[..len], broadcast).await {
+                log::warn!("[DHCP] Send failed: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER_IP: [u8; 4] = [192, 168, 4, 1];
+    const MAC: [u8; 6] = [0x02, 0x11, 0x22, 0x33, 0x44, 0x55];
+
+    /// Client message with the given options, xid 0xDEADBEEF, broadcast flag set
+    fn request(mac: [u8; 6], options: &[&[u8]]) -> std::vec::Vec<u8> {
+        let mut m = std::vec![0u8; OPTIONS_OFFSET];
+        m[0..4].copy_from_slice(&[OP_REQUEST, HTYPE_ETHERNET, 6, 0]);
+        m[4..8].copy_from_slice(&0xDEAD_BEEFu32.to_be_bytes());
+        m[10] = 0x80;
+        m[28..34].copy_from_slice(&mac);
+        m[236..240].copy_from_slice(&MAGIC_COOKIE);
+        for option in options {
+            m.extend_from_slice(option);
+        }
+        m.push(OPTION_END);
+        m
+    }
+
+    fn discover(mac: [u8; 6]) -> std::vec::Vec<u8> {
+        request(mac, &[&[OPTION_MESSAGE_TYPE, 1, 1], &[OPTION_PAD]])
+    }
+
+    fn select(mac: [u8; 6], ip: [u8; 4], server: [u8; 4]) -> std::vec::Vec<u8> {
+        let requested = [OPTION_REQUESTED_IP, 4, ip[0], ip[1], ip[2], ip[3]];
+        let server = [OPTION_SERVER_ID, 4, server[0], server[1], server[2], server[3]];
+        request(mac, &[&[OPTION_MESSAGE_TYPE, 1, 3], &requested, &server])
+    }
+
+    /// Reply type and offered address
+    fn reply(server: &mut DhcpServer, request: &[u8]) -> Option<(MessageType, [u8; 4])> {
+        let mut out = [0u8; MAX_MESSAGE];
+        let len = server.handle(request, &mut out)?;
+        assert_eq!(out[0], OP_REPLY);
+        assert_eq!(&out[4..8], &request[4..8]);
+        assert_eq!(out[10], 0x80);
+        assert_eq!(&out[28..34], &request[28..34]);
+        assert_eq!(out[len - 1], OPTION_END);
+        assert_eq!(&out[OPTIONS_OFFSET..OPTIONS_OFFSET + 2], &[OPTION_MESSAGE_TYPE, 1]);
+        let message_type = MessageType::from_u8(out[OPTIONS_OFFSET + 2]).unwrap();
+        Some((message_type, out[16..20].try_into().unwrap()))
+    }
+
+    #[test]
+    fn test_discover_request() {
+        let mut server = DhcpServer::new(SERVER_IP, 100);
+        let mut out = [0u8; MAX_MESSAGE];
+        let len = server.handle(&discover(MAC), &mut out).unwrap();
+        assert_eq!(&out[16..20], &[192, 168, 4, 100]);
+        assert_eq!(&out[20..24], &SERVER_IP);
+        assert_eq!(
+            &out[OPTIONS_OFFSET..len],
+            &[
+                53, 1, 2,
+                54, 4, 192, 168, 4, 1,
+                51, 4, 0, 0, 0x0E, 0x10,
+                1, 4, 255, 255, 255, 0,
+                3, 4, 192, 168, 4, 1,
+                6, 4, 192, 168, 4, 1,
+                255,
+            ]
+        );
+
+        let offered = [192, 168, 4, 100];
+        assert_eq!(reply(&mut server, &select(MAC, offered, SERVER_IP)), Some((MessageType::Ack, offered)));
+
+        // Renewal without options 50/54, from the leased address
+        let mut renew = request(MAC, &[&[OPTION_MESSAGE_TYPE, 1, 3]]);
+        renew[12..16].copy_from_slice(&offered);
+        assert_eq!(reply(&mut server, &renew), Some((MessageType::Ack, offered)));
+    }
+
+    #[test]
+    fn test_nak_and_other_servers() {
+        let mut server = DhcpServer::new(SERVER_IP, 100);
+
+        // Address from a previous network
+        let stale = select(MAC, [10, 0, 0, 7], SERVER_IP);
+        assert_eq!(reply(&mut server, &stale), Some((MessageType::Nak, [0; 4])));
+        let init_reboot = request(MAC, &[&[OPTION_MESSAGE_TYPE, 1, 3], &[OPTION_REQUESTED_IP, 4, 10, 0, 0, 7]]);
+        assert_eq!(reply(&mut server, &init_reboot), Some((MessageType::Nak, [0; 4])));
+
+        // Request meant for another server is ignored
+        let mut out = [0u8; MAX_MESSAGE];
+        assert_eq!(server.handle(&select(MAC, [192, 168, 4, 100], [192, 168, 4, 2]), &mut out), None);
+    }
+
+    #[test]
+    fn test_leases() {
+        let mut server = DhcpServer::new(SERVER_IP, 100);
+        let mac = |n: u8| [0x02, 0, 0, 0, 0, n];
+
+        // Stable per client, distinct across clients
+        assert_eq!(reply(&mut server, &discover(mac(1))).unwrap().1, [192, 168, 4, 100]);
+        assert_eq!(reply(&mut server, &discover(mac(2))).unwrap().1, [192, 168, 4, 101]);
+        assert_eq!(reply(&mut server, &discover(mac(1))).unwrap().1, [192, 168, 4, 100]);
+
+        // Released addresses are handed out again
+        let mut out = [0u8; MAX_MESSAGE];
+        assert_eq!(server.handle(&request(mac(1), &[&[OPTION_MESSAGE_TYPE, 1, 7]]), &mut out), None);
+        assert_eq!(reply(&mut server, &discover(mac(3))).unwrap().1, [192, 168, 4, 100]);
+
+        // A full pool reuses the oldest lease
+        for n in 4..=9 {
+            reply(&mut server, &discover(mac(n))).unwrap();
+        }
+        assert_eq!(reply(&mut server, &discover(mac(10))).unwrap().1, [192, 168, 4, 101]);
+
+        // An unknown client asking for an address on a full pool takes nobody's lease
+        let oldest = [192, 168, 4, 100];
+        assert_eq!(reply(&mut server, &select(mac(11), oldest, SERVER_IP)), Some((MessageType::Nak, [0; 4])));
+        let init_reboot = request(mac(12), &[&[OPTION_MESSAGE_TYPE, 1, 3], &[OPTION_REQUESTED_IP, 4, 10, 0, 0, 7]]);
+        assert_eq!(reply(&mut server, &init_reboot), Some((MessageType::Nak, [0; 4])));
+        assert_eq!(reply(&mut server, &discover(mac(3))).unwrap().1, oldest);
+        assert_eq!(reply(&mut server, &discover(mac(4))).unwrap().1, [192, 168, 4, 102]);
+    }
+
+    #[test]
+    fn test_ignore_malformed() {
+        let mut server = DhcpServer::new(SERVER_IP, 100);
+        let mut out = [0u8; MAX_MESSAGE];
+        let valid = discover(MAC);
+
+        assert_eq!(server.handle(&valid[..200], &mut out), None);
+
+        let mut reply_op = valid.clone();
+        reply_op[0] = OP_REPLY;
+        assert_eq!(server.handle(&reply_op, &mut out), None);
+
+        let mut bad_cookie = valid.clone();
+        bad_cookie[236] = 0;
+        assert_eq!(server.handle(&bad_cookie, &mut out), None);
+
+        // No message type, truncated option, unknown type
+        assert_eq!(server.handle(&request(MAC, &[]), &mut out), None);
+        assert_eq!(server.handle(&valid[..OPTIONS_OFFSET + 2], &mut out), None);
+        assert_eq!(server.handle(&request(MAC, &[&[OPTION_MESSAGE_TYPE, 1, 42]]), &mut out), None);
+    }
+}
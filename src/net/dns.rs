@@ -0,0 +1,191 @@
+//! Catch-all DNS responder for the setup portal
+//!
+//! Answers every A query with the device's own address, so any URL a phone
+//! or laptop tries after joining the setup network lands on the
+//! configuration page. Other query types get an empty answer, which makes
+//! clients fall back to IPv4.
+
+use embassy_net::driver::Driver;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::Stack;
+
+/// Well-known DNS server port
+pub const DNS_PORT: u16 = 53;
+
+/// Largest query we handle; plain UDP DNS messages are capped at 512 bytes
+pub const MAX_MESSAGE: usize = 512;
+
+/// Answer TTL; short so clients re-resolve once the device leaves setup
+const TTL_SECONDS: u32 = 60;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+/// Flag bits of the header's second 16-bit word
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const OPCODE_MASK: u16 = 0x7800;
+
+/// Build the response to `query` into `out`, returning its length
+///
+/// Returns `None` for messages that aren't a single standard query, which
+/// are dropped without a reply.
+pub fn answer(query: &[u8], ip: [u8; 4], out: &mut [u8]) -> Option<usize> {
+    if query.len() < HEADER_LEN {
+        return None;
+    }
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    let questions = u16::from_be_bytes([query[4], query[5]]);
+    if flags & (FLAG_RESPONSE | OPCODE_MASK) != 0 || questions != 1 {
+        return None;
+    }
+
+    // QNAME: uncompressed labels up to the root label
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = usize::from(*query.get(pos)?);
+        if len == 0 {
+            pos += 1;
+            break;
+        }
+        if len > 63 {
+            return None;
+        }
+        pos += 1 + len;
+    }
+    let question_end = pos + 4;
+    let question = query.get(HEADER_LEN..question_end)?;
+    let qtype = u16::from_be_bytes([query[pos], query[pos + 1]]);
+    let qclass = u16::from_be_bytes([query[pos + 2], query[pos + 3]]);
+    let answers = (qclass == CLASS_IN && (qtype == TYPE_A || qtype == TYPE_ANY)) as u16;
+
+    let len = HEADER_LEN + question.len() + usize::from(answers) * 16;
+    let out = out.get_mut(..len)?;
+    out[0..2].copy_from_slice(&query[0..2]);
+    let flags = FLAG_RESPONSE | FLAG_AUTHORITATIVE | (flags & FLAG_RECURSION_DESIRED);
+    out[2..4].copy_from_slice(&flags.to_be_bytes());
+    out[4..6].copy_from_slice(&1u16.to_be_bytes());
+    out[6..8].copy_from_slice(&answers.to_be_bytes());
+    out[8..12].fill(0);
+    out[HEADER_LEN..HEADER_LEN + question.len()].copy_from_slice(question);
+
+    if answers == 1 {
+        let record = &mut out[HEADER_LEN + question.len()..];
+        // Name as a pointer to the question's QNAME
+        record[0..2].copy_from_slice(&(0xC000 | HEADER_LEN as u16).to_be_bytes());
+        record[2..4].copy_from_slice(&TYPE_A.to_be_bytes());
+        record[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+        record[6..10].copy_from_slice(&TTL_SECONDS.to_be_bytes());
+        record[10..12].copy_from_slice(&4u16.to_be_bytes());
+        record[12..16].copy_from_slice(&ip);
+    }
+    Some(len)
+}
+
+/// Answer DNS queries on port 53 forever
+pub async fn dns_task_impl<D: Driver>(stack: &Stack<D>, ip: [u8; 4]) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; MAX_MESSAGE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0u8; MAX_MESSAGE];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    if let Err(e) = socket.bind(DNS_PORT) {
+        log::error!("[DNS] Failed to bind port {}: {:?}", DNS_PORT, e);
+        loop {
+            core::future::pending::<()>().await;
+        }
+    }
+    log::info!("[DNS] Answering all queries with {}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3]);
+
+    let mut query = [0u8; MAX_MESSAGE];
+    let mut response = [0u8; MAX_MESSAGE];
+    loop {
+        let (len, remote) = match socket.recv_from(&mut query).await {
+            Ok(received) => received,
+            Err(e) => {
+                log::warn!("[DNS] Receive failed: {:?}", e);
+                continue;
+            }
+        };
+        if let Some(len) = answer(&query[..len], ip, &mut response) {
+            if let Err(e) = socket.send_to(&response[..len], remote).await {
+                log::warn!("[DNS] Send to {} failed: {:?}", remote, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: [u8; 4] = [192, 168, 4, 1];
+
+    /// Query for `example.com` with the given type, ID 0x1234, RD set
+    fn query(qtype: u16) -> std::vec::Vec<u8> {
+        let mut q = std::vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        q.extend_from_slice(b"\x07example\x03com\x00");
+        q.extend_from_slice(&qtype.to_be_bytes());
+        q.extend_from_slice(&CLASS_IN.to_be_bytes());
+        q
+    }
+
+    #[test]
+    fn test_answer_a() {
+        let q = query(TYPE_A);
+        let mut out = [0u8; MAX_MESSAGE];
+        let len = answer(&q, IP, &mut out).unwrap();
+
+        let mut expected = std::vec![0x12, 0x34, 0x85, 0x00, 0, 1, 0, 1, 0, 0, 0, 0];
+        expected.extend_from_slice(&q[HEADER_LEN..]);
+        expected.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 168, 4, 1]);
+        assert_eq!(&out[..len], expected.as_slice());
+    }
+
+    #[test]
+    fn test_answer_other_types() {
+        // AAAA: no records, but still a valid authoritative reply
+        let q = query(28);
+        let mut out = [0u8; MAX_MESSAGE];
+        let len = answer(&q, IP, &mut out).unwrap();
+        assert_eq!(len, q.len());
+        assert_eq!(&out[2..8], &[0x85, 0x00, 0, 1, 0, 0]);
+
+        // ANY is answered like A
+        let len = answer(&query(TYPE_ANY), IP, &mut out).unwrap();
+        assert_eq!(&out[len - 4..len], &IP);
+    }
+
+    #[test]
+    fn test_ignore_malformed() {
+        let mut out = [0u8; MAX_MESSAGE];
+        let q = query(TYPE_A);
+
+        // Truncated in the header, the name and the question type
+        assert_eq!(answer(&q[..10], IP, &mut out), None);
+        assert_eq!(answer(&q[..16], IP, &mut out), None);
+        assert_eq!(answer(&q[..q.len() - 1], IP, &mut out), None);
+
+        // Responses, other opcodes and multiple questions
+        let mut response = q.clone();
+        response[2] |= 0x80;
+        assert_eq!(answer(&response, IP, &mut out), None);
+        let mut status = q.clone();
+        status[2] |= 0x10;
+        assert_eq!(answer(&status, IP, &mut out), None);
+        let mut two = q.clone();
+        two[5] = 2;
+        assert_eq!(answer(&two, IP, &mut out), None);
+
+        // Compression pointers don't belong in a query name
+        let mut pointer = q.clone();
+        pointer[HEADER_LEN] = 0xC0;
+        assert_eq!(answer(&pointer, IP, &mut out), None);
+
+        // Reply doesn't fit
+        assert_eq!(answer(&q, IP, &mut out[..20]), None);
+    }
+}
@@ -0,0 +1,121 @@
+//! HTML form decoding (`application/x-www-form-urlencoded`)
+//!
+//! Splits `name=value&…` bodies and percent-decodes values into
+//! fixed-capacity strings. Checkboxes follow browser semantics: a checked
+//! box is sent, an unchecked one is simply missing.
+
+use heapless::{String, Vec};
+
+/// Form decoding errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FormError {
+    /// Bad `%` escape or the decoded bytes aren't UTF-8
+    BadEncoding,
+    /// Decoded value doesn't fit
+    TooLong,
+}
+
+impl core::fmt::Display for FormError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FormError::BadEncoding => write!(f, "Bad form encoding"),
+            FormError::TooLong => write!(f, "Form value too long"),
+        }
+    }
+}
+
+/// Decode a `+`/`%XX`-encoded value
+pub fn decode<const N: usize>(raw: &str) -> Result<String<N>, FormError> {
+    let mut bytes: Vec<u8, N> = Vec::new();
+    let mut input = raw.bytes();
+    while let Some(byte) = input.next() {
+        let decoded = match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hi = input.next().and_then(hex_digit).ok_or(FormError::BadEncoding)?;
+                let lo = input.next().and_then(hex_digit).ok_or(FormError::BadEncoding)?;
+                (hi << 4) | lo
+            }
+            other => other,
+        };
+        bytes.push(decoded).map_err(|_| FormError::TooLong)?;
+    }
+    String::from_utf8(bytes).map_err(|_| FormError::BadEncoding)
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+/// A submitted form body
+#[derive(Debug, Clone, Copy)]
+pub struct Form<'a> {
+    body: &'a str,
+}
+
+impl<'a> Form<'a> {
+    /// Wrap a request body; the encoded form itself is always ASCII
+    pub fn new(body: &'a [u8]) -> Result<Self, FormError> {
+        let body = core::str::from_utf8(body).map_err(|_| FormError::BadEncoding)?;
+        Ok(Self { body })
+    }
+
+    /// Still-encoded `(name, value)` pairs in order
+    pub fn pairs(&self) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.body
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+    }
+
+    /// Decoded value of the first field called `name`
+    /// Field names are matched as sent, so keep them to plain ASCII
+    pub fn get<const N: usize>(&self, name: &str) -> Result<Option<String<N>>, FormError> {
+        match self.pairs().find(|(n, _)| *n == name) {
+            Some((_, value)) => decode(value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether a checkbox was ticked
+    pub fn checked(&self, name: &str) -> bool {
+        self.pairs().any(|(n, _)| n == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode::<32>("my+home%21").unwrap().as_str(), "my home!");
+        assert_eq!(decode::<32>("a%2Bb%3Dc%26d").unwrap().as_str(), "a+b=c&d");
+        assert_eq!(decode::<32>("caf%C3%A9").unwrap().as_str(), "café");
+        assert_eq!(decode::<32>("").unwrap().as_str(), "");
+
+        assert_eq!(decode::<32>("100%"), Err(FormError::BadEncoding));
+        assert_eq!(decode::<32>("%zz"), Err(FormError::BadEncoding));
+        assert_eq!(decode::<32>("%C3"), Err(FormError::BadEncoding));
+        assert_eq!(decode::<4>("12345"), Err(FormError::TooLong));
+        // Capacity counts decoded bytes
+        assert_eq!(decode::<4>("%41%42%43%44").unwrap().as_str(), "ABCD");
+    }
+
+    #[test]
+    fn test_form() {
+        let form = Form::new(b"ssid=My+Net&password=p%40ss&&upload=on&empty=&flag").unwrap();
+        assert_eq!(form.get::<32>("ssid").unwrap().unwrap().as_str(), "My Net");
+        assert_eq!(form.get::<32>("password").unwrap().unwrap().as_str(), "p@ss");
+        assert_eq!(form.get::<32>("empty").unwrap().unwrap().as_str(), "");
+        assert_eq!(form.get::<32>("missing").unwrap(), None);
+        assert_eq!(form.get::<2>("ssid"), Err(FormError::TooLong));
+
+        assert!(form.checked("upload"));
+        assert!(form.checked("flag"));
+        assert!(!form.checked("ssi"));
+        assert_eq!(form.pairs().count(), 5);
+
+        assert!(Form::new(b"bad=\xff").is_err());
+    }
+}
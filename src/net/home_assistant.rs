@@ -0,0 +1,662 @@
+//! Home Assistant integration over MQTT
+//!
+//! Every field a sensor reports becomes one Home Assistant sensor entity.
+//! The first time a field is published in a session, a retained discovery
+//! config is sent to `<prefix>/sensor/<device>/<field>/config`; values then
+//! go to `<base>/<device>/<field>`. The air quality indices follow PM and
+//! CO readings as two entities per scale, `<scale>` with the value and
+//! `<scale>_category` with its category. Availability is a retained
+//! `online`/`offline` status topic, with `offline` registered as the last
+//! will so the broker flags the device when the connection drops.
+//!
+//! The publisher subscribes to the reading bus itself, so readings it falls
+//! behind on are counted there. Readings that arrive while there is no
+//! broker connection are skipped: Home Assistant only shows current values.
+
+use crate::net::mqtt::{self, ConnectOptions, LastWill, MqttClient, MqttError};
+use crate::net::wifi::Backoff;
+use crate::sensors::aqi::{self, Indices};
+use crate::sensors::bus::{Event, ReadingSubscriber, READINGS};
+use crate::sensors::{Field, SensorData, SensorReading};
+use core::fmt::Write as _;
+use embassy_futures::select::{select, Either};
+use embassy_net::driver::Driver;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::Stack;
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_io_async::{Read, Write};
+use heapless::String;
+
+/// Topic prefix Home Assistant listens on for discovery
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// Prefix for state and availability topics
+pub const DEFAULT_BASE_TOPIC: &str = "altruist";
+
+/// Availability payloads
+pub const PAYLOAD_ONLINE: &str = "online";
+pub const PAYLOAD_OFFLINE: &str = "offline";
+
+/// Room for a topic name
+pub const TOPIC_CAPACITY: usize = 128;
+
+/// Room for one discovery config
+pub const DISCOVERY_CAPACITY: usize = 768;
+
+/// How long to wait for CONNACK or PINGRESP
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Broker and topic settings
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String<64>,
+    pub port: u16,
+    pub username: Option<String<32>>,
+    pub password: Option<String<64>>,
+    /// Node ID in topics and the Home Assistant device identifier
+    pub device_id: String<32>,
+    pub discovery_prefix: &'static str,
+    pub base_topic: &'static str,
+    pub keep_alive: Duration,
+}
+
+impl MqttConfig {
+    /// Default port, topics and keep-alive; `None` if a value is too long
+    pub fn new(host: &str, device_id: &str) -> Option<Self> {
+        Some(Self {
+            host: String::try_from(host).ok()?,
+            port: mqtt::DEFAULT_PORT,
+            username: None,
+            password: None,
+            device_id: String::try_from(device_id).ok()?,
+            discovery_prefix: DEFAULT_DISCOVERY_PREFIX,
+            base_topic: DEFAULT_BASE_TOPIC,
+            keep_alive: Duration::from_secs(60),
+        })
+    }
+
+    /// Set broker credentials; `None` if a value is too long
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Option<Self> {
+        self.username = Some(String::try_from(username).ok()?);
+        self.password = Some(String::try_from(password).ok()?);
+        Some(self)
+    }
+}
+
+/// Device ID derived from the station's MAC address, e.g. `altruist-4e5f60`
+pub fn device_id_from_mac(mac: [u8; 6]) -> String<32> {
+    let mut id = String::new();
+    let _ = write!(id, "altruist-{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5]);
+    id
+}
+
+/// Home Assistant `device_class` of a field, if there is a matching one
+pub fn device_class(field: Field) -> Option<&'static str> {
+    match field {
+        Field::Temperature | Field::DewPoint | Field::HeatIndex => Some("temperature"),
+        Field::Humidity => Some("humidity"),
+        Field::AbsoluteHumidity => Some("absolute_humidity"),
+        Field::Pressure | Field::SeaLevelPressure => Some("atmospheric_pressure"),
+        Field::Pm25 | Field::Pm25Corrected => Some("pm25"),
+        Field::Pm10 | Field::Pm10Corrected => Some("pm10"),
+        Field::CoPpm => Some("carbon_monoxide"),
+        Field::Co2Ppm => Some("carbon_dioxide"),
+        Field::NoiseDbA => Some("sound_pressure"),
+        Field::GasResistance | Field::VocIndex | Field::DoseRate => None,
+    }
+}
+
+/// Unit in the spelling Home Assistant expects
+pub fn unit_of_measurement(field: Field) -> Option<&'static str> {
+    match field {
+        Field::NoiseDbA => Some("dBA"),
+        _ => Some(field.unit()).filter(|unit| !unit.is_empty()),
+    }
+}
+
+/// Entity name shown in Home Assistant
+pub fn display_name(field: Field) -> &'static str {
+    match field {
+        Field::Temperature => "Temperature",
+        Field::Humidity => "Humidity",
+        Field::Pressure => "Pressure",
+        Field::GasResistance => "Gas resistance",
+        Field::Pm25 => "PM2.5",
+        Field::Pm10 => "PM10",
+        Field::CoPpm => "Carbon monoxide",
+        Field::Co2Ppm => "Carbon dioxide",
+        Field::VocIndex => "VOC index",
+        Field::DoseRate => "Radiation dose rate",
+        Field::NoiseDbA => "Noise",
+        Field::Pm25Corrected => "PM2.5 (humidity corrected)",
+        Field::Pm10Corrected => "PM10 (humidity corrected)",
+        Field::DewPoint => "Dew point",
+        Field::AbsoluteHumidity => "Absolute humidity",
+        Field::HeatIndex => "Heat index",
+        Field::SeaLevelPressure => "Sea-level pressure",
+    }
+}
+
+/// `<prefix>/sensor/<device>/<field>/config`
+pub fn discovery_topic(config: &MqttConfig, field: Field) -> String<TOPIC_CAPACITY> {
+    entity_discovery_topic(config, field.name())
+}
+
+/// `<base>/<device>/<field>`
+pub fn state_topic(config: &MqttConfig, field: Field) -> String<TOPIC_CAPACITY> {
+    entity_state_topic(config, field.name())
+}
+
+/// `<prefix>/sensor/<device>/<key>/config`
+pub fn entity_discovery_topic(config: &MqttConfig, key: &str) -> String<TOPIC_CAPACITY> {
+    let mut topic = String::new();
+    let _ = write!(topic, "{}/sensor/{}/{}/config", config.discovery_prefix, config.device_id, key);
+    topic
+}
+
+/// `<base>/<device>/<key>`
+pub fn entity_state_topic(config: &MqttConfig, key: &str) -> String<TOPIC_CAPACITY> {
+    let mut topic = String::new();
+    let _ = write!(topic, "{}/{}/{}", config.base_topic, config.device_id, key);
+    topic
+}
+
+/// `<base>/<device>/status`
+pub fn availability_topic(config: &MqttConfig) -> String<TOPIC_CAPACITY> {
+    let mut topic = String::new();
+    let _ = write!(topic, "{}/{}/status", config.base_topic, config.device_id);
+    topic
+}
+
+/// What Home Assistant is told about one entity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Entity<'a> {
+    /// Topic segment and `unique_id` suffix
+    pub key: &'a str,
+    pub name: &'a str,
+    pub device_class: Option<&'a str>,
+    pub unit: Option<&'a str>,
+    /// Numeric values Home Assistant keeps statistics of
+    pub measurement: bool,
+}
+
+impl Entity<'static> {
+    /// Entity of a field
+    pub fn of_field(field: Field) -> Self {
+        Self {
+            key: field.name(),
+            name: display_name(field),
+            device_class: device_class(field),
+            unit: unit_of_measurement(field),
+            measurement: true,
+        }
+    }
+}
+
+/// Discovery config for one field
+pub fn discovery_payload(config: &MqttConfig, field: Field) -> Result<String<DISCOVERY_CAPACITY>, MqttError> {
+    entity_discovery_payload(config, &Entity::of_field(field))
+}
+
+/// Discovery config for one entity
+pub fn entity_discovery_payload(config: &MqttConfig, entity: &Entity<'_>) -> Result<String<DISCOVERY_CAPACITY>, MqttError> {
+    let mut json = String::new();
+    let device = config.device_id.as_str();
+    (|| {
+        write!(json, "{{\"name\":\"{}\",\"unique_id\":\"{}_{}\"", entity.name, device, entity.key)?;
+        write!(json, ",\"state_topic\":\"{}\"", entity_state_topic(config, entity.key))?;
+        write!(json, ",\"availability_topic\":\"{}\"", availability_topic(config))?;
+        if let Some(class) = entity.device_class {
+            write!(json, ",\"device_class\":\"{}\"", class)?;
+        }
+        if let Some(unit) = entity.unit {
+            write!(json, ",\"unit_of_measurement\":\"{}\"", unit)?;
+        }
+        if entity.measurement {
+            write!(json, ",\"state_class\":\"measurement\"")?;
+        }
+        write!(
+            json,
+            ",\"device\":{{\"identifiers\":[\"{}\"],\"name\":\"{}\",\"model\":\"Altruist\",\"sw_version\":\"{}\"}}}}",
+            device, device, env!("CARGO_PKG_VERSION")
+        )
+    })()
+    .map_err(|_| MqttError::BufferTooSmall)?;
+    Ok(json)
+}
+
+/// State payload: the plain value
+pub fn state_payload(value: f32) -> String<16> {
+    let mut payload = String::new();
+    let _ = write!(payload, "{:.2}", value);
+    payload
+}
+
+/// Publishes readings and their discovery configs over one MQTT session
+pub struct HomeAssistantPublisher {
+    config: MqttConfig,
+    /// Fields announced in the current session, one bit per `Field`
+    announced: u32,
+    /// Index entities announced in the current session: one bit per scale
+    /// for the values, then one per scale for the categories
+    announced_indices: u8,
+}
+
+impl HomeAssistantPublisher {
+    pub fn new(config: MqttConfig) -> Self {
+        Self { config, announced: 0, announced_indices: 0 }
+    }
+
+    pub fn config(&self) -> &MqttConfig {
+        &self.config
+    }
+
+    /// Connect with `offline` as last will and announce the device as online
+    pub async fn start_session<C: Read + Write>(&mut self, client: &mut MqttClient<C>) -> Result<(), MqttError> {
+        let availability = availability_topic(&self.config);
+        let options = ConnectOptions {
+            client_id: &self.config.device_id,
+            keep_alive_secs: self.config.keep_alive.as_secs().min(u16::MAX as u64) as u16,
+            clean_session: true,
+            username: self.config.username.as_deref(),
+            password: self.config.password.as_deref(),
+            will: Some(LastWill {
+                topic: &availability,
+                message: PAYLOAD_OFFLINE.as_bytes(),
+                retain: true,
+            }),
+        };
+        with_timeout(RESPONSE_TIMEOUT, client.connect(&options))
+            .await
+            .map_err(|_| MqttError::Timeout)??;
+        self.announced = 0;
+        self.announced_indices = 0;
+        client.publish(&availability, PAYLOAD_ONLINE.as_bytes(), true).await
+    }
+
+    /// Publish every field of a reading, announcing fields seen for the first time
+    /// Returns the number of values published
+    pub async fn publish_reading<C: Read + Write>(
+        &mut self,
+        client: &mut MqttClient<C>,
+        reading: &SensorReading,
+    ) -> Result<usize, MqttError> {
+        if !reading.is_valid() {
+            return Ok(0);
+        }
+        let mut published = 0;
+        for (field, value) in reading.data.values() {
+            if !value.is_finite() {
+                continue;
+            }
+            let bit = 1u32 << field as u32;
+            if self.announced & bit == 0 {
+                let payload = discovery_payload(&self.config, field)?;
+                client.publish(&discovery_topic(&self.config, field), payload.as_bytes(), true).await?;
+                self.announced |= bit;
+            }
+            client
+                .publish(&state_topic(&self.config, field), state_payload(value).as_bytes(), false)
+                .await?;
+            published += 1;
+        }
+        Ok(published)
+    }
+
+    /// Publish the value and category of every known index, announcing
+    /// entities seen for the first time
+    /// Returns the number of values published
+    pub async fn publish_indices<C: Read + Write>(
+        &mut self,
+        client: &mut MqttClient<C>,
+        indices: &Indices,
+    ) -> Result<usize, MqttError> {
+        let mut published = 0;
+        for (i, scale) in indices.scales().into_iter().enumerate() {
+            let Some(scale) = scale else {
+                continue;
+            };
+            let (mut category_key, mut category_name) = (String::<32>::new(), String::<32>::new());
+            let _ = write!(category_key, "{}_category", scale.scale);
+            let _ = write!(category_name, "{} category", scale.label);
+            let mut value = String::<8>::new();
+            let _ = write!(value, "{}", scale.value);
+
+            let entities = [
+                (i, Entity { key: scale.scale, name: scale.label, device_class: Some("aqi"), unit: None, measurement: true }, value.as_str()),
+                (i + Indices::SCALES.len(), Entity { key: &category_key, name: &category_name, device_class: None, unit: None, measurement: false }, scale.category),
+            ];
+            for (bit, entity, state) in entities {
+                let bit = 1u8 << bit;
+                if self.announced_indices & bit == 0 {
+                    let payload = entity_discovery_payload(&self.config, &entity)?;
+                    client.publish(&entity_discovery_topic(&self.config, entity.key), payload.as_bytes(), true).await?;
+                    self.announced_indices |= bit;
+                }
+                client.publish(&entity_state_topic(&self.config, entity.key), state.as_bytes(), false).await?;
+                published += 1;
+            }
+        }
+        Ok(published)
+    }
+
+    /// Mark the device offline and disconnect cleanly
+    pub async fn end_session<C: Read + Write>(&mut self, client: &mut MqttClient<C>) -> Result<(), MqttError> {
+        client.publish(&availability_topic(&self.config), PAYLOAD_OFFLINE.as_bytes(), true).await?;
+        client.disconnect().await
+    }
+}
+
+/// Publish readings from the bus until the connection fails
+async fn run_session<C: Read + Write>(
+    publisher: &mut HomeAssistantPublisher,
+    client: &mut MqttClient<C>,
+    subscriber: &mut ReadingSubscriber<'_>,
+) -> Result<(), MqttError> {
+    let ping_interval = publisher.config().keep_alive / 2;
+    loop {
+        match select(subscriber.next_reading(), Timer::after(ping_interval)).await {
+            Either::First(reading) if reading.is_valid() => {
+                publisher.publish_reading(client, &reading).await?;
+                // The indices as they are now, which may not include this
+                // reading yet
+                if matches!(reading.data, SensorData::AirQuality { .. } | SensorData::Gas { .. }) {
+                    publisher.publish_indices(client, &aqi::current_indices()).await?;
+                }
+            }
+            Either::First(_) => {}
+            Either::Second(()) => {
+                with_timeout(RESPONSE_TIMEOUT, client.ping())
+                    .await
+                    .map_err(|_| MqttError::Timeout)??;
+            }
+        }
+    }
+}
+
+/// Wait for `until` while taking readings off the bus unpublished, so a
+/// missing connection doesn't hold the bus full
+async fn skip_readings<T>(subscriber: &mut ReadingSubscriber<'_>, until: impl core::future::Future<Output = T>) -> T {
+    let skip = async {
+        loop {
+            if let Event::Missed(missed) = subscriber.next().await {
+                log::warn!("[MQTT] Missed {} readings", missed);
+            }
+        }
+    };
+    match select(until, skip).await {
+        Either::First(output) => output,
+        Either::Second(never) => never,
+    }
+}
+
+/// MQTT publishing loop over an embassy-net stack
+/// Reconnects with exponential backoff whenever the broker goes away
+pub async fn home_assistant_task_impl<D: Driver>(stack: &Stack<D>, config: MqttConfig) -> ! {
+    let mut subscriber = match READINGS.subscribe("home_assistant") {
+        Ok(subscriber) => subscriber,
+        Err(e) => {
+            log::error!("[MQTT] Can't subscribe to readings: {}", e);
+            loop {
+                core::future::pending::<()>().await;
+            }
+        }
+    };
+    let mut publisher = HomeAssistantPublisher::new(config);
+    let mut backoff = Backoff::new(Duration::from_secs(2), Duration::from_secs(120));
+    let mut rx_buffer = [0u8; 256];
+    let mut tx_buffer = [0u8; 1024];
+
+    log::info!(
+        "[MQTT] Publishing as {} to {}:{}",
+        publisher.config().device_id, publisher.config().host, publisher.config().port
+    );
+
+    loop {
+        skip_readings(&mut subscriber, stack.wait_config_up()).await;
+
+        let host = publisher.config().host.clone();
+        let result = match skip_readings(&mut subscriber, stack.dns_query(&host, embassy_net::dns::DnsQueryType::A)).await {
+            Ok(addrs) if !addrs.is_empty() => {
+                let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+                socket.set_timeout(Some(publisher.config().keep_alive * 2));
+                match skip_readings(&mut subscriber, socket.connect((addrs[0], publisher.config().port))).await {
+                    Ok(()) => {
+                        let mut client = MqttClient::new(socket);
+                        let result = match skip_readings(&mut subscriber, publisher.start_session(&mut client)).await {
+                            Ok(()) => {
+                                log::info!("[MQTT] Connected");
+                                backoff.reset();
+                                run_session(&mut publisher, &mut client, &mut subscriber).await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        client.into_inner().close();
+                        result
+                    }
+                    Err(_) => Err(MqttError::Network),
+                }
+            }
+            _ => Err(MqttError::Network),
+        };
+
+        let delay = backoff.next_delay();
+        if let Err(e) = result {
+            log::warn!("[MQTT] Connection lost: {}, reconnecting in {}s", e, delay.as_secs());
+        }
+        skip_readings(&mut subscriber, Timer::after(delay)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{block_on, mqtt_stand_in, StdStream};
+    use crate::sensors::bus::{ReadingBus, SubscriberStats, BUS_CAPACITY};
+    use crate::sensors::{Quality, SensorData, SensorType};
+
+    fn test_config() -> MqttConfig {
+        MqttConfig::new("broker.local", "altruist-4e5f60").unwrap()
+    }
+
+    fn env_reading() -> SensorReading {
+        SensorReading::new(
+            SensorType::BME280,
+            SensorData::Environmental {
+                temperature: Some(21.456),
+                humidity: Some(48.0),
+                pressure: None,
+                gas_resistance: None,
+                dew_point: None,
+                absolute_humidity: None,
+                heat_index: None,
+                sea_level_pressure: None,
+            },
+            Quality::Good,
+        )
+    }
+
+    #[test]
+    fn test_topics() {
+        let config = test_config();
+        assert_eq!(discovery_topic(&config, Field::Pm25).as_str(), "homeassistant/sensor/altruist-4e5f60/pm25/config");
+        assert_eq!(state_topic(&config, Field::CoPpm).as_str(), "altruist/altruist-4e5f60/co_ppm");
+        assert_eq!(availability_topic(&config).as_str(), "altruist/altruist-4e5f60/status");
+        assert_eq!(device_id_from_mac([0x0a, 0x1b, 0x2c, 0x4e, 0x5f, 0x60]).as_str(), "altruist-4e5f60");
+    }
+
+    #[test]
+    fn test_discovery_payload() {
+        let payload = discovery_payload(&test_config(), Field::Pm25).unwrap();
+        assert_eq!(
+            payload.as_str(),
+            concat!(
+                "{\"name\":\"PM2.5\",\"unique_id\":\"altruist-4e5f60_pm25\"",
+                ",\"state_topic\":\"altruist/altruist-4e5f60/pm25\"",
+                ",\"availability_topic\":\"altruist/altruist-4e5f60/status\"",
+                ",\"device_class\":\"pm25\",\"unit_of_measurement\":\"µg/m³\",\"state_class\":\"measurement\"",
+                ",\"device\":{\"identifiers\":[\"altruist-4e5f60\"],\"name\":\"altruist-4e5f60\",\"model\":\"Altruist\",\"sw_version\":\"",
+                env!("CARGO_PKG_VERSION"),
+                "\"}}"
+            )
+        );
+
+        // No device class or unit for a dimensionless index
+        let voc = discovery_payload(&test_config(), Field::VocIndex).unwrap();
+        assert!(!voc.contains("device_class"));
+        assert!(!voc.contains("unit_of_measurement"));
+        assert!(voc.contains("\"state_class\":\"measurement\""));
+    }
+
+    #[test]
+    fn test_field_metadata() {
+        for field in Field::ALL {
+            let payload = discovery_payload(&test_config(), field).unwrap();
+            assert!(payload.ends_with("}}"), "{:?}", field);
+        }
+        assert_eq!(device_class(Field::Temperature), Some("temperature"));
+        assert_eq!(unit_of_measurement(Field::Temperature), Some("°C"));
+        assert_eq!(device_class(Field::CoPpm), Some("carbon_monoxide"));
+        assert_eq!(unit_of_measurement(Field::NoiseDbA), Some("dBA"));
+        assert_eq!(state_payload(21.456).as_str(), "21.46");
+    }
+
+    #[test]
+    fn test_session_against_stand_in_broker() {
+        let (addr, broker) = mqtt_stand_in(0);
+        let mut client = MqttClient::new(StdStream::connect(addr).unwrap());
+        let config = test_config().with_credentials("user", "secret").unwrap();
+        let mut publisher = HomeAssistantPublisher::new(config.clone());
+
+        block_on(async {
+            publisher.start_session(&mut client).await.unwrap();
+            assert_eq!(publisher.publish_reading(&mut client, &env_reading()).await, Ok(2));
+            assert_eq!(publisher.publish_reading(&mut client, &env_reading()).await, Ok(2));
+            publisher.end_session(&mut client).await.unwrap();
+        });
+
+        let session = broker.join().unwrap();
+        assert_eq!(session.client_id, "altruist-4e5f60");
+        assert_eq!(session.username.as_deref(), Some("user"));
+        let (will_topic, will_message) = session.will.clone().unwrap();
+        assert_eq!(will_topic, "altruist/altruist-4e5f60/status");
+        assert_eq!(will_message, b"offline");
+
+        let topics: std::vec::Vec<&str> = session.publishes.iter().map(|p| p.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "altruist/altruist-4e5f60/status",
+                "homeassistant/sensor/altruist-4e5f60/temperature/config",
+                "altruist/altruist-4e5f60/temperature",
+                "homeassistant/sensor/altruist-4e5f60/humidity/config",
+                "altruist/altruist-4e5f60/humidity",
+                // Already announced in this session
+                "altruist/altruist-4e5f60/temperature",
+                "altruist/altruist-4e5f60/humidity",
+                "altruist/altruist-4e5f60/status",
+            ]
+        );
+        assert!(session.publishes[0].retain && session.publishes[1].retain);
+        assert!(!session.publishes[2].retain);
+        assert_eq!(session.publishes[0].payload, b"online");
+        assert_eq!(session.last_payload("altruist/altruist-4e5f60/temperature"), Some(&b"21.46"[..]));
+        assert_eq!(session.last_payload("altruist/altruist-4e5f60/status"), Some(&b"offline"[..]));
+        assert!(session.clean_disconnect);
+    }
+
+    #[test]
+    fn test_announces_every_field() {
+        let (addr, broker) = mqtt_stand_in(0);
+        let mut client = MqttClient::new(StdStream::connect(addr).unwrap());
+        let mut publisher = HomeAssistantPublisher::new(test_config());
+        let mut reading = env_reading();
+        reading.data.set_value(Field::SeaLevelPressure, 1013.2);
+
+        block_on(async {
+            publisher.start_session(&mut client).await.unwrap();
+            assert_eq!(publisher.publish_reading(&mut client, &reading).await, Ok(3));
+            assert_eq!(publisher.publish_reading(&mut client, &reading).await, Ok(3));
+        });
+        drop(client);
+
+        // The last field has a bit of its own
+        let session = broker.join().unwrap();
+        let configs = session.publishes.iter().filter(|p| p.topic.ends_with("/config"));
+        assert_eq!(configs.count(), 3);
+    }
+
+    #[test]
+    fn test_publish_indices() {
+        use crate::sensors::aqi::{AqiCategory, DaqiBand, Index};
+
+        let (addr, broker) = mqtt_stand_in(0);
+        let mut client = MqttClient::new(StdStream::connect(addr).unwrap());
+        let mut publisher = HomeAssistantPublisher::new(test_config());
+        let indices = Indices {
+            us_aqi: Some((Field::Pm25, Index { value: 62, category: AqiCategory::Moderate })),
+            caqi: None,
+            daqi: Some((Field::Pm10, Index { value: 2, category: DaqiBand::Low })),
+        };
+
+        block_on(async {
+            publisher.start_session(&mut client).await.unwrap();
+            assert_eq!(publisher.publish_indices(&mut client, &indices).await, Ok(4));
+            assert_eq!(publisher.publish_indices(&mut client, &indices).await, Ok(4));
+            assert_eq!(publisher.publish_indices(&mut client, &Indices::default()).await, Ok(0));
+            publisher.end_session(&mut client).await.unwrap();
+        });
+
+        let session = broker.join().unwrap();
+        let topics: std::vec::Vec<&str> = session.publishes.iter().map(|p| p.topic.as_str()).collect();
+        assert_eq!(
+            topics[1..7],
+            [
+                "homeassistant/sensor/altruist-4e5f60/us_aqi/config",
+                "altruist/altruist-4e5f60/us_aqi",
+                "homeassistant/sensor/altruist-4e5f60/us_aqi_category/config",
+                "altruist/altruist-4e5f60/us_aqi_category",
+                "homeassistant/sensor/altruist-4e5f60/daqi/config",
+                "altruist/altruist-4e5f60/daqi",
+            ]
+        );
+        // Announced once per session
+        assert_eq!(topics.len(), 1 + 8 + 4 + 1);
+        assert_eq!(session.last_payload("altruist/altruist-4e5f60/us_aqi"), Some(&b"62"[..]));
+        assert_eq!(session.last_payload("altruist/altruist-4e5f60/us_aqi_category"), Some(&b"moderate"[..]));
+        assert_eq!(session.last_payload("altruist/altruist-4e5f60/daqi_category"), Some(&b"low"[..]));
+
+        let value = std::str::from_utf8(&session.publishes[1].payload).unwrap();
+        assert!(value.starts_with("{\"name\":\"US AQI\",\"unique_id\":\"altruist-4e5f60_us_aqi\""), "{}", value);
+        assert!(value.contains("\"device_class\":\"aqi\",\"state_class\":\"measurement\""), "{}", value);
+        let category = std::str::from_utf8(&session.publishes[3].payload).unwrap();
+        assert!(category.starts_with("{\"name\":\"US AQI category\""), "{}", category);
+        assert!(!category.contains("device_class") && !category.contains("state_class"), "{}", category);
+    }
+
+    #[test]
+    fn test_refused_session() {
+        let (addr, broker) = mqtt_stand_in(5);
+        let mut client = MqttClient::new(StdStream::connect(addr).unwrap());
+        let mut publisher = HomeAssistantPublisher::new(test_config());
+
+        assert_eq!(block_on(publisher.start_session(&mut client)), Err(MqttError::ConnectionRefused(5)));
+        assert!(broker.join().unwrap().publishes.is_empty());
+    }
+
+    #[test]
+    fn test_skip_readings_while_offline() {
+        let bus = ReadingBus::new();
+        let mut subscriber = bus.subscribe("home_assistant").unwrap();
+        for _ in 0..BUS_CAPACITY + 4 {
+            bus.publish(env_reading());
+        }
+
+        // The bus is emptied while waiting, and only the overflow counts as missed
+        assert_eq!(block_on(skip_readings(&mut subscriber, async { 7 })), 7);
+        block_on(skip_readings(&mut subscriber, Timer::after(Duration::from_millis(5))));
+        assert!(subscriber.try_next().is_none());
+        assert!(bus.publish(env_reading()));
+        assert_eq!(bus.subscriber_stats().as_slice(), [SubscriberStats { name: "home_assistant", missed: 4 }]);
+    }
+}
@@ -0,0 +1,321 @@
+//! Minimal HTTP/1.1 client
+//!
+//! Just enough HTTP for the upload backends: one request per connection
+//! (`Connection: close`), a fixed set of headers and a byte body. Only the
+//! response status is interpreted; the rest of the response is discarded.
+//! The protocol code works over any `embedded_io_async` stream, so it can
+//! be exercised on the host with mock or std sockets.
+
+use core::fmt::Write as _;
+use embassy_net::driver::Driver;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::Stack;
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_io_async::{Read, Write};
+
+/// A request header as name and value
+pub type Header<'a> = (&'a str, &'a str);
+
+/// Longest status line we accept
+const MAX_STATUS_LINE: usize = 128;
+
+/// Time allowed for connecting, sending and reading the status
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// HTTP errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpError {
+    /// Host name could not be resolved
+    Dns,
+    /// TCP connection could not be established
+    Connect,
+    /// Socket read/write failed or the peer closed early
+    Network,
+    /// No response in time
+    Timeout,
+    /// Response doesn't start with a valid status line
+    MalformedResponse,
+    /// Server answered with a non-2xx status
+    Status(u16),
+}
+
+impl core::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            HttpError::Dns => write!(f, "DNS lookup failed"),
+            HttpError::Connect => write!(f, "Connection failed"),
+            HttpError::Network => write!(f, "Network error"),
+            HttpError::Timeout => write!(f, "Request timed out"),
+            HttpError::MalformedResponse => write!(f, "Malformed response"),
+            HttpError::Status(status) => write!(f, "HTTP status {}", status),
+        }
+    }
+}
+
+/// Whether a status code means success
+pub fn is_success(status: u16) -> bool {
+    (200..300).contains(&status)
+}
+
+/// Whether a failed request is worth retrying
+/// Client errors other than rate limiting will fail the same way again
+pub fn is_retryable(error: &HttpError) -> bool {
+    match error {
+        HttpError::Status(status) => *status == 429 || *status >= 500,
+        _ => true,
+    }
+}
+
+/// Parse a status line such as `HTTP/1.1 201 Created`
+pub fn parse_status_line(line: &[u8]) -> Result<u16, HttpError> {
+    let line = core::str::from_utf8(line).map_err(|_| HttpError::MalformedResponse)?;
+    let mut parts = line.trim_end().splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    if !version.starts_with("HTTP/1.") {
+        return Err(HttpError::MalformedResponse);
+    }
+    let code = parts.next().unwrap_or("");
+    if code.len() != 3 {
+        return Err(HttpError::MalformedResponse);
+    }
+    code.parse().map_err(|_| HttpError::MalformedResponse)
+}
+
+/// Write a request with a body
+/// `Content-Length` and `Connection: close` are added automatically
+pub async fn write_request<W: Write>(
+    conn: &mut W,
+    method: &str,
+    host: &str,
+    path: &str,
+    headers: &[Header<'_>],
+    body: &[u8],
+) -> Result<(), HttpError> {
+    let mut length: heapless::String<20> = heapless::String::new();
+    // A usize always fits in 20 digits
+    let _ = write!(length, "{}", body.len());
+
+    let head: [&str; 7] = [method, " ", path, " HTTP/1.1\r\nHost: ", host, "\r\nConnection: close\r\nContent-Length: ", &length];
+    for part in head {
+        write_all(conn, part.as_bytes()).await?;
+    }
+    write_all(conn, b"\r\n").await?;
+    for (name, value) in headers {
+        for part in [*name, ": ", *value, "\r\n"] {
+            write_all(conn, part.as_bytes()).await?;
+        }
+    }
+    write_all(conn, b"\r\n").await?;
+    write_all(conn, body).await?;
+    conn.flush().await.map_err(|_| HttpError::Network)
+}
+
+/// Read the response status line and return the status code
+pub async fn read_status<R: Read>(conn: &mut R) -> Result<u16, HttpError> {
+    let mut line = [0u8; MAX_STATUS_LINE];
+    let mut len = 0;
+    loop {
+        if len == line.len() {
+            return Err(HttpError::MalformedResponse);
+        }
+        let n = conn.read(&mut line[len..]).await.map_err(|_| HttpError::Network)?;
+        if n == 0 {
+            return Err(if len == 0 { HttpError::Network } else { HttpError::MalformedResponse });
+        }
+        len += n;
+        if let Some(end) = line[..len].iter().position(|&b| b == b'\n') {
+            return parse_status_line(&line[..end]);
+        }
+    }
+}
+
+/// Send one request over an open connection and return the status code
+pub async fn request<C: Read + Write>(
+    conn: &mut C,
+    method: &str,
+    host: &str,
+    path: &str,
+    headers: &[Header<'_>],
+    body: &[u8],
+) -> Result<u16, HttpError> {
+    write_request(conn, method, host, path, headers, body).await?;
+    read_status(conn).await
+}
+
+async fn write_all<W: Write>(conn: &mut W, bytes: &[u8]) -> Result<(), HttpError> {
+    conn.write_all(bytes).await.map_err(|_| HttpError::Network)
+}
+
+/// Something that can send an HTTP POST and report the status
+#[allow(async_fn_in_trait)]
+pub trait HttpClient {
+    async fn post(
+        &mut self,
+        host: &str,
+        port: u16,
+        path: &str,
+        headers: &[Header<'_>],
+        body: &[u8],
+    ) -> Result<u16, HttpError>;
+}
+
+/// How often and how far apart failed requests are retried
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first
+    pub attempts: u32,
+    /// Delay between attempts
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            delay: Duration::from_secs(5),
+        }
+    }
+}
+
+/// POST with retries; non-2xx answers become `HttpError::Status`
+pub async fn post_with_retry<C: HttpClient>(
+    client: &mut C,
+    policy: RetryPolicy,
+    host: &str,
+    port: u16,
+    path: &str,
+    headers: &[Header<'_>],
+    body: &[u8],
+) -> Result<u16, HttpError> {
+    let mut attempt = 1;
+    loop {
+        let result = match client.post(host, port, path, headers, body).await {
+            Ok(status) if is_success(status) => return Ok(status),
+            Ok(status) => Err(HttpError::Status(status)),
+            Err(e) => Err(e),
+        };
+        match result {
+            Err(e) if attempt < policy.attempts && is_retryable(&e) => {
+                log::warn!("[HTTP] POST {}{} failed: {}, retrying in {}s", host, path, e, policy.delay.as_secs());
+                attempt += 1;
+                Timer::after(policy.delay).await;
+            }
+            other => return other,
+        }
+    }
+}
+
+/// HTTP client over embassy-net TCP sockets
+pub struct TcpClient<'a, D: Driver> {
+    stack: &'a Stack<D>,
+    rx_buffer: [u8; 512],
+    tx_buffer: [u8; 1024],
+}
+
+impl<'a, D: Driver> TcpClient<'a, D> {
+    pub fn new(stack: &'a Stack<D>) -> Self {
+        Self {
+            stack,
+            rx_buffer: [0; 512],
+            tx_buffer: [0; 1024],
+        }
+    }
+}
+
+impl<D: Driver> HttpClient for TcpClient<'_, D> {
+    async fn post(
+        &mut self,
+        host: &str,
+        port: u16,
+        path: &str,
+        headers: &[Header<'_>],
+        body: &[u8],
+    ) -> Result<u16, HttpError> {
+        let addrs = self
+            .stack
+            .dns_query(host, embassy_net::dns::DnsQueryType::A)
+            .await
+            .map_err(|_| HttpError::Dns)?;
+        let addr = *addrs.first().ok_or(HttpError::Dns)?;
+
+        let mut socket = TcpSocket::new(self.stack, &mut self.rx_buffer, &mut self.tx_buffer);
+        socket.set_timeout(Some(REQUEST_TIMEOUT));
+
+        let result = with_timeout(REQUEST_TIMEOUT, async {
+            socket.connect((addr, port)).await.map_err(|_| HttpError::Connect)?;
+            request(&mut socket, "POST", host, path, headers, body).await
+        })
+        .await
+        .unwrap_or(Err(HttpError::Timeout));
+
+        socket.close();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{block_on, MockUart};
+
+    #[test]
+    fn test_parse_status_line() {
+        assert_eq!(parse_status_line(b"HTTP/1.1 201 Created\r"), Ok(201));
+        assert_eq!(parse_status_line(b"HTTP/1.0 500 Internal Server Error"), Ok(500));
+        assert_eq!(parse_status_line(b"HTTP/1.1 204"), Ok(204));
+        assert_eq!(parse_status_line(b"HTTP/2 200 OK"), Err(HttpError::MalformedResponse));
+        assert_eq!(parse_status_line(b"HTTP/1.1 20x OK"), Err(HttpError::MalformedResponse));
+        assert_eq!(parse_status_line(b"HTTP/1.1 2000 OK"), Err(HttpError::MalformedResponse));
+        assert_eq!(parse_status_line(b"garbage"), Err(HttpError::MalformedResponse));
+    }
+
+    #[test]
+    fn test_request_encoding() {
+        let mut conn = MockUart::new().data(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        let status = block_on(request(
+            &mut conn,
+            "POST",
+            "example.org",
+            "/push",
+            &[("Content-Type", "application/json"), ("X-Pin", "1")],
+            b"{}",
+        ));
+
+        assert_eq!(status, Ok(200));
+        assert_eq!(
+            conn.written(),
+            b"POST /push HTTP/1.1\r\n\
+              Host: example.org\r\n\
+              Connection: close\r\n\
+              Content-Length: 2\r\n\
+              Content-Type: application/json\r\n\
+              X-Pin: 1\r\n\
+              \r\n\
+              {}"
+        );
+    }
+
+    #[test]
+    fn test_status_line_split_across_reads() {
+        let mut conn = MockUart::new().fragmented(b"HTTP/1.1 503 Service Unavailable\r\n\r\n", 4);
+        assert_eq!(block_on(read_status(&mut conn)), Ok(503));
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let mut conn = MockUart::new().error();
+        assert_eq!(block_on(read_status(&mut conn)), Err(HttpError::Network));
+
+        let mut conn = MockUart::new().data(&[b'x'; MAX_STATUS_LINE + 1]);
+        assert_eq!(block_on(read_status(&mut conn)), Err(HttpError::MalformedResponse));
+    }
+
+    #[test]
+    fn test_retry_classification() {
+        assert!(is_retryable(&HttpError::Timeout));
+        assert!(is_retryable(&HttpError::Status(503)));
+        assert!(is_retryable(&HttpError::Status(429)));
+        assert!(!is_retryable(&HttpError::Status(400)));
+        assert!(!is_retryable(&HttpError::Status(403)));
+    }
+}
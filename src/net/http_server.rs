@@ -0,0 +1,330 @@
+//! Minimal HTTP/1.1 server
+//!
+//! One request per connection: the head and body are read into a
+//! caller-provided fixed-size buffer, the handler writes a single response
+//! with `Connection: close`, and the socket is shut down. That is plenty for
+//! a configuration page and a few JSON endpoints, and keeps memory use
+//! bounded.
+
+use core::fmt::Write as _;
+
+use embassy_net::driver::Driver;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::Stack;
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::{Read, Write};
+use heapless::String;
+
+use super::http::Header;
+
+/// Default port
+pub const HTTP_PORT: u16 = 80;
+
+/// Request methods the handlers distinguish
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Other,
+}
+
+/// Parsed request, borrowing from the receive buffer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Request<'a> {
+    pub method: Method,
+    /// Path without the query string, e.g. `/api/readings`
+    pub path: &'a str,
+    /// Query string without the `?`
+    pub query: Option<&'a str>,
+    pub body: &'a [u8],
+}
+
+/// Server-side errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ServerError {
+    /// Socket read or write failed, or the client went quiet
+    Network,
+    /// Connection closed before a complete request arrived
+    Closed,
+    /// Request line or headers don't parse
+    Malformed,
+    /// Request doesn't fit the buffer
+    TooLarge,
+}
+
+impl core::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ServerError::Network => write!(f, "Network error"),
+            ServerError::Closed => write!(f, "Connection closed"),
+            ServerError::Malformed => write!(f, "Malformed request"),
+            ServerError::TooLarge => write!(f, "Request too large"),
+        }
+    }
+}
+
+/// Reason phrase for the status codes we send
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        302 => "Found",
+        303 => "See Other",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Parse a request from the start of `buf`
+///
+/// Returns `Ok(None)` while the head or body is still incomplete.
+pub fn parse_request(buf: &[u8]) -> Result<Option<Request<'_>>, ServerError> {
+    let Some(head_end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Ok(None);
+    };
+    let head = core::str::from_utf8(&buf[..head_end]).map_err(|_| ServerError::Malformed)?;
+    let mut lines = head.split("\r\n");
+
+    let mut parts = lines.next().ok_or(ServerError::Malformed)?.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(ServerError::Malformed);
+    };
+    if !version.starts_with("HTTP/1.") || !target.starts_with('/') {
+        return Err(ServerError::Malformed);
+    }
+    let method = match method {
+        "GET" => Method::Get,
+        "HEAD" => Method::Head,
+        "POST" => Method::Post,
+        _ => Method::Other,
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+
+    let mut content_length = 0;
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(ServerError::Malformed)?;
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            content_length = value.trim().parse().map_err(|_| ServerError::Malformed)?;
+        }
+    }
+
+    let body_start = head_end + 4;
+    let body_end = body_start.checked_add(content_length).ok_or(ServerError::TooLarge)?;
+    match buf.get(body_start..body_end) {
+        Some(body) => Ok(Some(Request { method, path, query, body })),
+        None => Ok(None),
+    }
+}
+
+/// Read one request into `buf`
+pub async fn read_request<'b, C: Read>(conn: &mut C, buf: &'b mut [u8]) -> Result<Request<'b>, ServerError> {
+    let mut filled = 0;
+    loop {
+        match parse_request(&buf[..filled]) {
+            Ok(Some(_)) => break,
+            Ok(None) if filled == buf.len() => return Err(ServerError::TooLarge),
+            Ok(None) => {}
+            Err(e) => return Err(e),
+        }
+        match conn.read(&mut buf[filled..]).await {
+            Ok(0) => return Err(ServerError::Closed),
+            Ok(n) => filled += n,
+            Err(_) => return Err(ServerError::Network),
+        }
+    }
+    // Parse again so the request borrows the finished buffer
+    parse_request(&buf[..filled]).and_then(|r| r.ok_or(ServerError::Malformed))
+}
+
+/// Write a complete response with `Content-Length` and `Connection: close`
+pub async fn write_response<C: Write>(
+    conn: &mut C,
+    status: u16,
+    headers: &[Header<'_>],
+    body: &[u8],
+) -> Result<(), ServerError> {
+    write_head(conn, status, headers, body.len()).await?;
+    conn.write_all(body).await.map_err(|_| ServerError::Network)?;
+    conn.flush().await.map_err(|_| ServerError::Network)
+}
+
+/// Write the status line and headers of a response whose body of
+/// `content_length` bytes the caller writes next
+pub async fn write_head<C: Write>(
+    conn: &mut C,
+    status: u16,
+    headers: &[Header<'_>],
+    content_length: usize,
+) -> Result<(), ServerError> {
+    let mut head: String<512> = String::new();
+    write!(head, "HTTP/1.1 {} {}\r\n", status, reason(status)).map_err(|_| ServerError::TooLarge)?;
+    for (name, value) in headers {
+        write!(head, "{}: {}\r\n", name, value).map_err(|_| ServerError::TooLarge)?;
+    }
+    write!(head, "Content-Length: {}\r\nConnection: close\r\n\r\n", content_length).map_err(|_| ServerError::TooLarge)?;
+
+    conn.write_all(head.as_bytes()).await.map_err(|_| ServerError::Network)
+}
+
+/// Application side of the server
+#[allow(async_fn_in_trait)]
+pub trait Handler {
+    /// Write the response to `request`, typically with `write_response`
+    async fn handle<C: Write>(&mut self, request: &Request<'_>, conn: &mut C) -> Result<(), ServerError>;
+}
+
+/// Read one request from `conn` and let `handler` answer it
+/// Requests that don't parse or fit get a 400 or 413 response
+pub async fn serve_connection<C: Read + Write, H: Handler>(
+    conn: &mut C,
+    handler: &mut H,
+    buf: &mut [u8],
+) -> Result<(), ServerError> {
+    match read_request(conn, buf).await {
+        Ok(request) => handler.handle(&request, conn).await,
+        Err(e @ (ServerError::Malformed | ServerError::TooLarge)) => {
+            let status = if e == ServerError::TooLarge { 413 } else { 400 };
+            write_response(conn, status, &[("Content-Type", "text/plain")], reason(status).as_bytes()).await?;
+            Err(e)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// How long a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for the client to close after the response
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Accept connections on `port` one at a time, forever
+/// `buf` holds each request and bounds its size
+pub async fn serve<D: Driver, H: Handler>(stack: &Stack<D>, port: u16, handler: &mut H, buf: &mut [u8]) -> ! {
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 2048];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(REQUEST_TIMEOUT));
+        if let Err(e) = socket.accept(port).await {
+            log::warn!("[HTTPD] Accept failed: {:?}", e);
+            continue;
+        }
+
+        if let Err(e) = serve_connection(&mut socket, handler, buf).await {
+            log::warn!("[HTTPD] {:?}: {}", socket.remote_endpoint(), e);
+        }
+
+        // Send our FIN and give the client a moment to answer with its own
+        socket.close();
+        let _ = with_timeout(CLOSE_TIMEOUT, async {
+            let mut discard = [0u8; 64];
+            while let Ok(1..) = socket.read(&mut discard).await {}
+        })
+        .await;
+        socket.abort();
+        let _ = with_timeout(CLOSE_TIMEOUT, socket.flush()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{block_on, MockUart};
+
+    #[test]
+    fn test_parse_request() {
+        let raw = b"POST /save?x=1 HTTP/1.1\r\nHost: 192.168.4.1\r\ncontent-length: 7\r\n\r\nssid=ab";
+        let request = parse_request(raw).unwrap().unwrap();
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.path, "/save");
+        assert_eq!(request.query, Some("x=1"));
+        assert_eq!(request.body, b"ssid=ab");
+
+        // Incomplete head or body
+        assert_eq!(parse_request(&raw[..30]), Ok(None));
+        assert_eq!(parse_request(&raw[..raw.len() - 1]), Ok(None));
+
+        let get = parse_request(b"GET / HTTP/1.0\r\n\r\n").unwrap().unwrap();
+        assert_eq!((get.method, get.path, get.query, get.body), (Method::Get, "/", None, &b""[..]));
+        assert_eq!(parse_request(b"DELETE /x HTTP/1.1\r\n\r\n").unwrap().unwrap().method, Method::Other);
+
+        for bad in [
+            &b"GET /\r\n\r\n"[..],
+            b"GET / HTTP/1.1 extra\r\n\r\n",
+            b"GET http://x/ HTTP/1.1\r\n\r\n",
+            b"GET / SPDY/3\r\n\r\n",
+            b"GET / HTTP/1.1\r\nNoColon\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: lots\r\n\r\n",
+            b"GET /\xff HTTP/1.1\r\n\r\n",
+        ] {
+            assert_eq!(parse_request(bad), Err(ServerError::Malformed), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_write_response() {
+        block_on(async {
+            let mut conn = MockUart::default();
+            write_response(&mut conn, 200, &[("Content-Type", "application/json")], b"{}").await.unwrap();
+            assert_eq!(
+                conn.written(),
+                b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}"
+            );
+        });
+    }
+
+    struct Echo;
+
+    impl Handler for Echo {
+        async fn handle<C: Write>(&mut self, request: &Request<'_>, conn: &mut C) -> Result<(), ServerError> {
+            write_response(conn, 200, &[], request.body).await
+        }
+    }
+
+    #[test]
+    fn test_serve_connection() {
+        block_on(async {
+            // Request split across reads
+            let mut conn = MockUart::new()
+                .data(b"POST / HTTP/1.1\r\nContent-Le")
+                .data(b"ngth: 5\r\n\r\nhel")
+                .data(b"lo");
+            let mut buf = [0u8; 256];
+            serve_connection(&mut conn, &mut Echo, &mut buf).await.unwrap();
+            assert!(conn.written().ends_with(b"\r\n\r\nhello"));
+
+            // Doesn't fit
+            let mut conn = MockUart::new()
+                .data(b"POST / HTTP/1.1\r\nContent-Length: 100\r\n\r\n")
+                .data(&[b'x'; 100]);
+            let mut buf = [0u8; 64];
+            assert_eq!(serve_connection(&mut conn, &mut Echo, &mut buf).await, Err(ServerError::TooLarge));
+            assert!(conn.written().starts_with(b"HTTP/1.1 413 "));
+
+            // Length that doesn't even fit the address space
+            let mut conn = MockUart::new().data(b"POST / HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n");
+            assert_eq!(serve_connection(&mut conn, &mut Echo, &mut buf).await, Err(ServerError::TooLarge));
+            assert!(conn.written().starts_with(b"HTTP/1.1 413 "));
+
+            // Garbage
+            let mut conn = MockUart::new().data(b"hello\r\n\r\n");
+            assert_eq!(serve_connection(&mut conn, &mut Echo, &mut buf).await, Err(ServerError::Malformed));
+            assert!(conn.written().starts_with(b"HTTP/1.1 400 "));
+
+            // Connection drops mid-request
+            let mut conn = MockUart::new().data(b"GET / HTTP/1.1\r\n").error();
+            assert_eq!(serve_connection(&mut conn, &mut Echo, &mut buf).await, Err(ServerError::Network));
+            assert!(conn.written().is_empty());
+        });
+    }
+}
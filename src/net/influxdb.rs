@@ -0,0 +1,424 @@
+//! InfluxDB v2 uploader
+//!
+//! Readings are encoded in line protocol, one line per reading:
+//!
+//! ```text
+//! air_quality,device=altruist-a1b2c3,quality=good,sensor_type=SDS011 pm25=5.2,pm10=10.5 1704067200000000000
+//! ```
+//!
+//! The measurement is the data variant, tags identify the sensor, device
+//! and quality, fields are the values the reading carries and the
+//! timestamp is UTC in nanoseconds. Readings are buffered until the clock
+//! is synced and posted in batches to `/api/v2/write`.
+
+use crate::net::http::{self, HttpClient, RetryPolicy};
+use crate::net::{wifi, UploadReport};
+use crate::sensors::bus::ReadingQueue;
+use crate::sensors::{SensorData, SensorReading};
+use crate::time;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{Deque, String};
+
+/// InfluxDB's default HTTP port
+pub const DEFAULT_PORT: u16 = 8086;
+
+/// Interval between uploads
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Readings kept while the network is down; the oldest are dropped first
+pub const MAX_BUFFERED: usize = 32;
+
+/// Readings per request
+pub const MAX_BATCH: usize = 16;
+
+/// Room for one request body
+pub const BODY_CAPACITY: usize = 2048;
+
+/// Readings `store_forward` hands to the uploader
+pub static UPLOAD_QUEUE: ReadingQueue = ReadingQueue::new("influxdb");
+
+/// Readings buffered by the uploader, for `store_forward` to pace itself
+pub static BACKLOG: AtomicUsize = AtomicUsize::new(0);
+
+/// Uploader settings
+#[derive(Debug, Clone)]
+pub struct InfluxDbConfig {
+    pub host: String<64>,
+    pub port: u16,
+    pub org: String<64>,
+    pub bucket: String<64>,
+    /// API token with write access to the bucket
+    pub token: String<128>,
+    /// Value of the `device` tag
+    pub device_id: String<32>,
+    pub interval: Duration,
+    pub retry: RetryPolicy,
+}
+
+impl InfluxDbConfig {
+    /// Default port and interval; `None` if a value doesn't fit
+    pub fn new(host: &str, org: &str, bucket: &str, token: &str, device_id: &str) -> Option<Self> {
+        Some(Self {
+            host: String::try_from(host).ok()?,
+            port: DEFAULT_PORT,
+            org: String::try_from(org).ok()?,
+            bucket: String::try_from(bucket).ok()?,
+            token: String::try_from(token).ok()?,
+            device_id: String::try_from(device_id).ok()?,
+            interval: DEFAULT_INTERVAL,
+            retry: RetryPolicy::default(),
+        })
+    }
+}
+
+/// Measurement name of a data variant
+pub fn measurement(data: &SensorData) -> &'static str {
+    match data {
+        SensorData::Environmental { .. } => "environmental",
+        SensorData::AirQuality { .. } => "air_quality",
+        SensorData::Gas { .. } => "gas",
+        SensorData::Radiation { .. } => "radiation",
+        SensorData::Noise { .. } => "noise",
+        SensorData::Location { .. } => "location",
+        SensorData::Analog { .. } => "analog",
+    }
+}
+
+/// Tag value with commas, equals signs and spaces escaped
+fn write_tag(out: &mut impl Write, value: &str) -> fmt::Result {
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ') {
+            out.write_char('\\')?;
+        }
+        out.write_char(c)?;
+    }
+    Ok(())
+}
+
+/// Append one reading as a line, timestamped `utc_ms`
+///
+/// Writes nothing and returns `Ok(false)` if the reading has no finite
+/// values, since a line needs at least one field.
+pub fn write_line(out: &mut impl Write, reading: &SensorReading, device_id: &str, utc_ms: u64) -> Result<bool, fmt::Error> {
+    let values = reading.data.values();
+    let mut fields = values.iter().filter(|(_, value)| value.is_finite()).peekable();
+    if fields.peek().is_none() {
+        return Ok(false);
+    }
+
+    // Tags in key order, as InfluxDB prefers
+    write!(out, "{},device=", measurement(&reading.data))?;
+    write_tag(out, device_id)?;
+    write!(out, ",quality={},sensor_type=", reading.quality.name())?;
+    write_tag(out, reading.sensor_type.name())?;
+    for (i, (field, value)) in fields.enumerate() {
+        let separator = if i == 0 { ' ' } else { ',' };
+        write!(out, "{}{}={}", separator, field.name(), value)?;
+    }
+    writeln!(out, " {}", utc_ms * 1_000_000)?;
+    Ok(true)
+}
+
+/// Percent-encode a query parameter value
+fn write_query_value(out: &mut impl Write, value: &str) -> fmt::Result {
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.write_char(byte as char)?,
+            _ => write!(out, "%{:02X}", byte)?,
+        }
+    }
+    Ok(())
+}
+
+/// Write endpoint path for an organization and bucket
+pub fn write_path(org: &str, bucket: &str) -> Result<String<256>, fmt::Error> {
+    let mut path = String::new();
+    path.push_str("/api/v2/write?org=").map_err(|_| fmt::Error)?;
+    write_query_value(&mut path, org)?;
+    path.push_str("&bucket=").map_err(|_| fmt::Error)?;
+    write_query_value(&mut path, bucket)?;
+    path.push_str("&precision=ns").map_err(|_| fmt::Error)?;
+    Ok(path)
+}
+
+/// Buffers readings and uploads them in batches
+pub struct InfluxDbUploader {
+    config: InfluxDbConfig,
+    buffer: Deque<SensorReading, MAX_BUFFERED>,
+    dropped: u32,
+}
+
+impl InfluxDbUploader {
+    pub fn new(config: InfluxDbConfig) -> Self {
+        Self {
+            config,
+            buffer: Deque::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn config(&self) -> &InfluxDbConfig {
+        &self.config
+    }
+
+    /// Buffer a reading
+    /// Invalid ones and ones without values to write are ignored
+    pub fn record(&mut self, reading: &SensorReading) {
+        if !reading.is_valid() || !reading.data.values().iter().any(|(_, value)| value.is_finite()) {
+            return;
+        }
+        if self.buffer.is_full() {
+            self.buffer.pop_front();
+            self.dropped += 1;
+        }
+        let _ = self.buffer.push_back(reading.clone());
+    }
+
+    /// Readings waiting for upload
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Readings discarded because the buffer was full
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Body for the oldest readings and how many it covers
+    /// Returns `None` if nothing can be sent yet (empty, or UTC unknown)
+    pub fn next_batch(&self) -> Option<(usize, String<BODY_CAPACITY>)> {
+        let mut body = String::new();
+        let mut count = 0;
+        for reading in self.buffer.iter().take(MAX_BATCH) {
+            let Some(utc_ms) = reading.utc_timestamp.or_else(|| time::utc_from_monotonic(reading.timestamp)) else {
+                break;
+            };
+            let before = body.len();
+            if write_line(&mut body, reading, &self.config.device_id, utc_ms).is_err() {
+                body.truncate(before);
+                break;
+            }
+            count += 1;
+        }
+        if count == 0 {
+            return None;
+        }
+        Some((count, body))
+    }
+
+    /// Upload buffered readings batch by batch
+    /// Stops at the first failed batch; it stays buffered for the next round
+    pub async fn upload<C: HttpClient>(&mut self, client: &mut C) -> UploadReport {
+        let mut report = UploadReport::default();
+        let Ok(path) = write_path(&self.config.org, &self.config.bucket) else {
+            log::error!("[INFLUXDB] Organization or bucket name too long");
+            report.failed = self.pending();
+            return report;
+        };
+        let mut authorization: String<136> = String::new();
+        let _ = write!(authorization, "Token {}", self.config.token);
+
+        while let Some((count, body)) = self.next_batch() {
+            let headers = [
+                ("Content-Type", "text/plain; charset=utf-8"),
+                ("Authorization", authorization.as_str()),
+            ];
+            let result = http::post_with_retry(
+                client,
+                self.config.retry,
+                &self.config.host,
+                self.config.port,
+                &path,
+                &headers,
+                body.as_bytes(),
+            )
+            .await;
+            match result {
+                Ok(_) => {
+                    for _ in 0..count {
+                        self.buffer.pop_front();
+                    }
+                    report.sent += count;
+                }
+                Err(e) => {
+                    log::warn!("[INFLUXDB] Upload of {} readings failed: {}", count, e);
+                    report.failed += count;
+                    break;
+                }
+            }
+        }
+        report
+    }
+}
+
+/// Upload loop fed by `UPLOAD_QUEUE`
+/// Buffers readings and uploads them every interval while the link is up
+pub async fn influxdb_task_impl<C: HttpClient>(client: &mut C, config: InfluxDbConfig) -> ! {
+    let interval = config.interval;
+    let mut uploader = InfluxDbUploader::new(config);
+    let mut next_upload = Instant::now() + interval;
+
+    log::info!(
+        "[INFLUXDB] Uploading to {}:{} bucket {} every {}s",
+        uploader.config().host, uploader.config().port, uploader.config().bucket, interval.as_secs()
+    );
+
+    loop {
+        match select(UPLOAD_QUEUE.receive(), Timer::at(next_upload)).await {
+            Either::First(reading) => {
+                uploader.record(&reading);
+                BACKLOG.store(uploader.pending(), Ordering::Relaxed);
+            }
+            Either::Second(()) => {
+                next_upload += interval;
+                if uploader.pending() == 0 || !wifi::link_state().is_up() {
+                    continue;
+                }
+                if !time::utc_valid() {
+                    log::warn!("[INFLUXDB] Waiting for time sync, {} readings buffered", uploader.pending());
+                    continue;
+                }
+                let report = uploader.upload(client).await;
+                log::info!(
+                    "[INFLUXDB] Uploaded {} readings, {} failed, {} buffered",
+                    report.sent, report.failed, uploader.pending()
+                );
+                BACKLOG.store(uploader.pending(), Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{block_on, http_stand_in, StdHttpClient};
+    use crate::sensors::{Flags, Quality, SensorType};
+
+    const TOKEN: &str = "s3cr3t-t0ken==";
+
+    /// 2024-01-01T00:00:00Z
+    const UTC_MS: u64 = 1_704_067_200_000;
+
+    fn test_config() -> InfluxDbConfig {
+        let mut config = InfluxDbConfig::new("influx.lan", "my org", "sensors/home", TOKEN, "altruist-a1b2c3").unwrap();
+        config.retry = RetryPolicy { attempts: 2, delay: Duration::from_secs(1) };
+        config
+    }
+
+    fn pm_reading(utc_ms: Option<u64>) -> SensorReading {
+        SensorReading {
+            sensor_type: SensorType::SDS011,
+            data: SensorData::AirQuality { pm25: Some(5.2), pm10: Some(10.5), pm25_corrected: None, pm10_corrected: None },
+            timestamp: 10_000,
+            utc_timestamp: utc_ms,
+            quality: Quality::Good,
+            flags: Flags::NONE,
+        }
+    }
+
+    #[test]
+    fn test_line_protocol() {
+        let mut out: String<256> = String::new();
+        assert_eq!(write_line(&mut out, &pm_reading(None), "altruist-a1b2c3", UTC_MS), Ok(true));
+        assert_eq!(
+            out.as_str(),
+            "air_quality,device=altruist-a1b2c3,quality=good,sensor_type=SDS011 pm25=5.2,pm10=10.5 1704067200000000000\n"
+        );
+
+        // Missing and non-finite values are left out, tags are escaped
+        let reading = SensorReading {
+            sensor_type: SensorType::ME2CO,
+            data: SensorData::Gas { co_ppm: Some(f32::NAN), co2_ppm: Some(800), voc_index: None },
+            timestamp: 0,
+            utc_timestamp: None,
+            quality: Quality::Degraded,
+            flags: Flags::NONE,
+        };
+        out.clear();
+        assert_eq!(write_line(&mut out, &reading, "my node,1=a", UTC_MS + 1), Ok(true));
+        assert_eq!(
+            out.as_str(),
+            "gas,device=my\\ node\\,1\\=a,quality=degraded,sensor_type=ME2-CO co2_ppm=800 1704067200001000000\n"
+        );
+
+        // A line needs at least one field
+        let empty = SensorReading { data: SensorData::AirQuality { pm25: None, pm10: None, pm25_corrected: None, pm10_corrected: None }, ..pm_reading(None) };
+        out.clear();
+        assert_eq!(write_line(&mut out, &empty, "x", UTC_MS), Ok(false));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_write_path() {
+        assert_eq!(
+            write_path("my org", "sensors/home").unwrap().as_str(),
+            "/api/v2/write?org=my%20org&bucket=sensors%2Fhome&precision=ns"
+        );
+    }
+
+    #[test]
+    fn test_batches() {
+        let mut uploader = InfluxDbUploader::new(test_config());
+        uploader.record(&pm_reading(Some(UTC_MS)));
+        uploader.record(&SensorReading { quality: Quality::Bad, ..pm_reading(Some(UTC_MS)) });
+        uploader.record(&SensorReading { data: SensorData::AirQuality { pm25: None, pm10: None, pm25_corrected: None, pm10_corrected: None }, ..pm_reading(Some(UTC_MS)) });
+        uploader.record(&pm_reading(Some(UTC_MS + 1_000)));
+        assert_eq!(uploader.pending(), 2);
+
+        let (count, body) = uploader.next_batch().unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(body.lines().count(), 2);
+
+        let mut uploader = InfluxDbUploader::new(test_config());
+        for i in 0..MAX_BUFFERED as u64 + 2 {
+            uploader.record(&pm_reading(Some(UTC_MS + i * 1_000)));
+        }
+        assert_eq!(uploader.pending(), MAX_BUFFERED);
+        assert_eq!(uploader.dropped(), 2);
+        let (count, body) = uploader.next_batch().unwrap();
+        assert_eq!(count, MAX_BATCH);
+        assert!(body.lines().next().unwrap().ends_with(" 1704067202000000000"));
+    }
+
+    #[test]
+    fn test_upload_to_local_server() {
+        let (addr, server) = http_stand_in(std::vec![204, 204]);
+        let mut client = StdHttpClient::new(addr);
+        let mut uploader = InfluxDbUploader::new(test_config());
+        for i in 0..MAX_BATCH as u64 + 1 {
+            uploader.record(&pm_reading(Some(UTC_MS + i * 1_000)));
+        }
+
+        let report = block_on(uploader.upload(&mut client));
+        assert_eq!(report, UploadReport { sent: MAX_BATCH + 1, failed: 0 });
+        assert_eq!(uploader.pending(), 0);
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        let request = &requests[0];
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/v2/write?org=my%20org&bucket=sensors%2Fhome&precision=ns");
+        assert_eq!(request.header("Host"), Some("influx.lan"));
+        assert_eq!(request.header("Authorization"), Some("Token s3cr3t-t0ken=="));
+        assert_eq!(request.header("Content-Type"), Some("text/plain; charset=utf-8"));
+        assert_eq!(request.body_str().lines().count(), MAX_BATCH);
+        assert_eq!(requests[1].body_str().lines().count(), 1);
+    }
+
+    #[test]
+    fn test_failed_batch_stays_buffered() {
+        let (addr, server) = http_stand_in(std::vec![503, 401]);
+        let mut client = StdHttpClient::new(addr);
+        let mut uploader = InfluxDbUploader::new(test_config());
+        uploader.record(&pm_reading(Some(UTC_MS)));
+
+        let report = block_on(uploader.upload(&mut client));
+        assert_eq!(report, UploadReport { sent: 0, failed: 1 });
+        assert_eq!(uploader.pending(), 1);
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,31 @@
+//! Network services
+//!
+//! Protocol logic is kept free of hardware so it can be tested on the host;
+//! the embassy-net glue is generic over the network driver and spawned by
+//! the firmware once Wi-Fi is up.
+
+pub mod api;
+pub mod dhcp_server;
+pub mod dns;
+pub mod form;
+pub mod home_assistant;
+pub mod http;
+pub mod http_server;
+pub mod influxdb;
+pub mod mqtt;
+pub mod opensensemap;
+pub mod portal;
+pub mod prometheus;
+pub mod sensor_community;
+pub mod sntp;
+pub mod store_forward;
+pub mod wifi;
+
+/// Outcome of one upload round
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct UploadReport {
+    /// Items delivered
+    pub sent: usize,
+    /// Items that failed and are kept for the next round
+    pub failed: usize,
+}
@@ -0,0 +1,528 @@
+//! MQTT 3.1.1 client
+//!
+//! A small publish-only client: CONNECT (with optional credentials and last
+//! will), QoS 0 PUBLISH, keep-alive pings and DISCONNECT. Packet encoding is
+//! plain functions over byte buffers; [`MqttClient`] drives them over any
+//! `embedded_io_async` stream.
+
+use embedded_io_async::{Read, Write};
+
+/// Default broker port without TLS
+pub const DEFAULT_PORT: u16 = 1883;
+
+/// Largest value the remaining length field can hold
+pub const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+/// Size of the client's transmit buffer, i.e. the largest packet it can send
+pub const TX_BUFFER_SIZE: usize = 1024;
+
+/// Incoming packets are only inspected up to this size
+const RX_BUFFER_SIZE: usize = 64;
+
+/// Protocol level for MQTT 3.1.1
+const PROTOCOL_LEVEL: u8 = 4;
+
+/// Control packet types (upper nibble of the first byte)
+pub const CONNECT: u8 = 1;
+pub const CONNACK: u8 = 2;
+pub const PUBLISH: u8 = 3;
+pub const PINGREQ: u8 = 12;
+pub const PINGRESP: u8 = 13;
+pub const DISCONNECT: u8 = 14;
+
+/// MQTT errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MqttError {
+    /// Packet doesn't fit the buffer
+    BufferTooSmall,
+    /// Socket read/write failed or the broker closed the connection
+    Network,
+    /// Broker didn't answer in time
+    Timeout,
+    /// Received bytes aren't a valid packet
+    MalformedPacket,
+    /// Received a packet we didn't expect at this point
+    UnexpectedPacket(u8),
+    /// Broker rejected the connection with this return code
+    ConnectionRefused(u8),
+}
+
+impl core::fmt::Display for MqttError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MqttError::BufferTooSmall => write!(f, "Packet too large"),
+            MqttError::Network => write!(f, "Network error"),
+            MqttError::Timeout => write!(f, "Broker timed out"),
+            MqttError::MalformedPacket => write!(f, "Malformed packet"),
+            MqttError::UnexpectedPacket(kind) => write!(f, "Unexpected packet type {}", kind),
+            MqttError::ConnectionRefused(code) => {
+                let reason = match code {
+                    1 => "unacceptable protocol version",
+                    2 => "client identifier rejected",
+                    3 => "server unavailable",
+                    4 => "bad user name or password",
+                    5 => "not authorized",
+                    _ => "unknown reason",
+                };
+                write!(f, "Connection refused: {}", reason)
+            }
+        }
+    }
+}
+
+/// Message the broker publishes if we disappear without DISCONNECT
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LastWill<'a> {
+    pub topic: &'a str,
+    pub message: &'a [u8],
+    pub retain: bool,
+}
+
+/// CONNECT parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectOptions<'a> {
+    pub client_id: &'a str,
+    pub keep_alive_secs: u16,
+    pub clean_session: bool,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+    pub will: Option<LastWill<'a>>,
+}
+
+impl<'a> ConnectOptions<'a> {
+    /// Clean session with a 60s keep-alive, no credentials, no will
+    pub fn new(client_id: &'a str) -> Self {
+        Self {
+            client_id,
+            keep_alive_secs: 60,
+            clean_session: true,
+            username: None,
+            password: None,
+            will: None,
+        }
+    }
+}
+
+/// Sequential writer over a byte buffer
+struct PacketWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> PacketWriter<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), MqttError> {
+        let end = self.pos + bytes.len();
+        self.buf.get_mut(self.pos..end).ok_or(MqttError::BufferTooSmall)?.copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), MqttError> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), MqttError> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    /// Length-prefixed binary data or UTF-8 string
+    fn prefixed(&mut self, bytes: &[u8]) -> Result<(), MqttError> {
+        let len = u16::try_from(bytes.len()).map_err(|_| MqttError::BufferTooSmall)?;
+        self.u16(len)?;
+        self.bytes(bytes)
+    }
+
+    fn fixed_header(&mut self, first_byte: u8, remaining: usize) -> Result<(), MqttError> {
+        let mut len = [0u8; 4];
+        let n = encode_remaining_length(remaining, &mut len)?;
+        self.u8(first_byte)?;
+        self.bytes(&len[..n])
+    }
+}
+
+/// Length of a length-prefixed field
+fn prefixed_len(bytes: &[u8]) -> usize {
+    2 + bytes.len()
+}
+
+/// Encode the variable-length "remaining length" field; returns its size
+pub fn encode_remaining_length(mut len: usize, out: &mut [u8; 4]) -> Result<usize, MqttError> {
+    if len > MAX_REMAINING_LENGTH {
+        return Err(MqttError::BufferTooSmall);
+    }
+    let mut i = 0;
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out[i] = byte;
+        i += 1;
+        if len == 0 {
+            return Ok(i);
+        }
+    }
+}
+
+/// Decode a remaining length field
+/// Returns the value and the number of bytes used, or `None` if more bytes are needed
+pub fn decode_remaining_length(bytes: &[u8]) -> Result<Option<(usize, usize)>, MqttError> {
+    let mut value = 0usize;
+    for (i, &byte) in bytes.iter().enumerate() {
+        if i == 4 {
+            return Err(MqttError::MalformedPacket);
+        }
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, i + 1)));
+        }
+    }
+    if bytes.len() >= 4 {
+        return Err(MqttError::MalformedPacket);
+    }
+    Ok(None)
+}
+
+/// Encode a CONNECT packet; returns its length
+pub fn encode_connect(buf: &mut [u8], options: &ConnectOptions<'_>) -> Result<usize, MqttError> {
+    let mut flags = 0u8;
+    let mut remaining = 10 + prefixed_len(options.client_id.as_bytes());
+    if options.clean_session {
+        flags |= 0x02;
+    }
+    if let Some(will) = &options.will {
+        flags |= 0x04;
+        if will.retain {
+            flags |= 0x20;
+        }
+        remaining += prefixed_len(will.topic.as_bytes()) + prefixed_len(will.message);
+    }
+    if let Some(username) = options.username {
+        flags |= 0x80;
+        remaining += prefixed_len(username.as_bytes());
+    }
+    if let Some(password) = options.password {
+        flags |= 0x40;
+        remaining += prefixed_len(password.as_bytes());
+    }
+
+    let mut w = PacketWriter::new(buf);
+    w.fixed_header(CONNECT << 4, remaining)?;
+    w.prefixed(b"MQTT")?;
+    w.u8(PROTOCOL_LEVEL)?;
+    w.u8(flags)?;
+    w.u16(options.keep_alive_secs)?;
+    w.prefixed(options.client_id.as_bytes())?;
+    if let Some(will) = &options.will {
+        w.prefixed(will.topic.as_bytes())?;
+        w.prefixed(will.message)?;
+    }
+    if let Some(username) = options.username {
+        w.prefixed(username.as_bytes())?;
+    }
+    if let Some(password) = options.password {
+        w.prefixed(password.as_bytes())?;
+    }
+    Ok(w.pos)
+}
+
+/// Encode a QoS 0 PUBLISH packet; returns its length
+pub fn encode_publish(buf: &mut [u8], topic: &str, payload: &[u8], retain: bool) -> Result<usize, MqttError> {
+    if topic.is_empty() || topic.contains(['+', '#']) {
+        return Err(MqttError::MalformedPacket);
+    }
+    let remaining = prefixed_len(topic.as_bytes()) + payload.len();
+    let mut w = PacketWriter::new(buf);
+    w.fixed_header((PUBLISH << 4) | u8::from(retain), remaining)?;
+    w.prefixed(topic.as_bytes())?;
+    w.bytes(payload)?;
+    Ok(w.pos)
+}
+
+/// Interpret a CONNACK body; returns the session-present flag
+pub fn decode_connack(body: &[u8]) -> Result<bool, MqttError> {
+    match body {
+        [flags, 0] => Ok(flags & 0x01 != 0),
+        [_, code] => Err(MqttError::ConnectionRefused(*code)),
+        _ => Err(MqttError::MalformedPacket),
+    }
+}
+
+/// Publish-only MQTT client over a byte stream
+pub struct MqttClient<C> {
+    conn: C,
+    tx: [u8; TX_BUFFER_SIZE],
+}
+
+impl<C: Read + Write> MqttClient<C> {
+    pub fn new(conn: C) -> Self {
+        Self {
+            conn,
+            tx: [0; TX_BUFFER_SIZE],
+        }
+    }
+
+    /// Give the connection back, e.g. to close it
+    pub fn into_inner(self) -> C {
+        self.conn
+    }
+
+    /// Send CONNECT and wait for CONNACK; returns the session-present flag
+    pub async fn connect(&mut self, options: &ConnectOptions<'_>) -> Result<bool, MqttError> {
+        let len = encode_connect(&mut self.tx, options)?;
+        self.send(len).await?;
+
+        let mut body = [0u8; RX_BUFFER_SIZE];
+        match self.read_packet(&mut body).await? {
+            (kind, 2) if kind >> 4 == CONNACK => decode_connack(&body[..2]),
+            (kind, _) => Err(MqttError::UnexpectedPacket(kind >> 4)),
+        }
+    }
+
+    /// Publish a message with QoS 0
+    pub async fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<(), MqttError> {
+        let len = encode_publish(&mut self.tx, topic, payload, retain)?;
+        self.send(len).await
+    }
+
+    /// Send PINGREQ and wait for PINGRESP
+    pub async fn ping(&mut self) -> Result<(), MqttError> {
+        self.tx[..2].copy_from_slice(&[PINGREQ << 4, 0]);
+        self.send(2).await?;
+
+        let mut body = [0u8; RX_BUFFER_SIZE];
+        loop {
+            let (kind, _) = self.read_packet(&mut body).await?;
+            match kind >> 4 {
+                PINGRESP => return Ok(()),
+                // We don't subscribe, but tolerate a broker echoing something
+                PUBLISH => continue,
+                other => return Err(MqttError::UnexpectedPacket(other)),
+            }
+        }
+    }
+
+    /// Send DISCONNECT so the broker discards the last will
+    pub async fn disconnect(&mut self) -> Result<(), MqttError> {
+        self.tx[..2].copy_from_slice(&[DISCONNECT << 4, 0]);
+        self.send(2).await
+    }
+
+    async fn send(&mut self, len: usize) -> Result<(), MqttError> {
+        self.conn.write_all(&self.tx[..len]).await.map_err(|_| MqttError::Network)?;
+        self.conn.flush().await.map_err(|_| MqttError::Network)
+    }
+
+    async fn read_byte(&mut self) -> Result<u8, MqttError> {
+        let mut byte = [0u8];
+        match self.conn.read(&mut byte).await {
+            Ok(1) => Ok(byte[0]),
+            _ => Err(MqttError::Network),
+        }
+    }
+
+    /// Read one packet; returns its first byte and body length
+    /// Bodies longer than `body` are read and discarded past its end
+    async fn read_packet(&mut self, body: &mut [u8]) -> Result<(u8, usize), MqttError> {
+        let first = self.read_byte().await?;
+        let mut len_bytes = [0u8; 4];
+        let mut n = 0;
+        let len = loop {
+            len_bytes[n] = self.read_byte().await?;
+            n += 1;
+            if let Some((len, _)) = decode_remaining_length(&len_bytes[..n])? {
+                break len;
+            }
+        };
+
+        let mut read = 0;
+        let mut scratch = [0u8; 16];
+        let kept = len.min(body.len());
+        while read < len {
+            let chunk = if read < kept {
+                &mut body[read..kept]
+            } else {
+                let end = (len - read).min(scratch.len());
+                &mut scratch[..end]
+            };
+            match self.conn.read(chunk).await {
+                Ok(0) | Err(_) => return Err(MqttError::Network),
+                Ok(n) => read += n,
+            }
+        }
+        Ok((first, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{block_on, mqtt_stand_in, MockUart, StdStream};
+
+    #[test]
+    fn test_remaining_length() {
+        let cases: [(usize, &[u8]); 6] = [
+            (0, &[0x00]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (16_383, &[0xff, 0x7f]),
+            (16_384, &[0x80, 0x80, 0x01]),
+            (MAX_REMAINING_LENGTH, &[0xff, 0xff, 0xff, 0x7f]),
+        ];
+        for (value, bytes) in cases {
+            let mut out = [0u8; 4];
+            let n = encode_remaining_length(value, &mut out).unwrap();
+            assert_eq!(&out[..n], bytes, "encoding {}", value);
+            assert_eq!(decode_remaining_length(bytes), Ok(Some((value, bytes.len()))));
+        }
+
+        let mut out = [0u8; 4];
+        assert_eq!(encode_remaining_length(MAX_REMAINING_LENGTH + 1, &mut out), Err(MqttError::BufferTooSmall));
+        assert_eq!(decode_remaining_length(&[0x80, 0x80]), Ok(None));
+        assert_eq!(decode_remaining_length(&[0x80, 0x80, 0x80, 0x80, 0x01]), Err(MqttError::MalformedPacket));
+    }
+
+    #[test]
+    fn test_connect_minimal() {
+        let mut buf = [0u8; 64];
+        let mut options = ConnectOptions::new("abc");
+        options.keep_alive_secs = 30;
+        let n = encode_connect(&mut buf, &options).unwrap();
+        assert_eq!(
+            &buf[..n],
+            &[
+                0x10, 15, // CONNECT, remaining length
+                0, 4, b'M', b'Q', b'T', b'T', 4, // protocol name and level
+                0x02, // clean session
+                0, 30, // keep alive
+                0, 3, b'a', b'b', b'c',
+            ]
+        );
+    }
+
+    #[test]
+    fn test_connect_with_will_and_credentials() {
+        let mut buf = [0u8; 128];
+        let mut options = ConnectOptions::new("c");
+        options.username = Some("u");
+        options.password = Some("pw");
+        options.will = Some(LastWill { topic: "t/s", message: b"offline", retain: true });
+        let n = encode_connect(&mut buf, &options).unwrap();
+        assert_eq!(
+            &buf[..n],
+            &[
+                0x10, 34,
+                0, 4, b'M', b'Q', b'T', b'T', 4,
+                0xe6, // user name, password, will retain, will, clean session
+                0, 60,
+                0, 1, b'c',
+                0, 3, b't', b'/', b's',
+                0, 7, b'o', b'f', b'f', b'l', b'i', b'n', b'e',
+                0, 1, b'u',
+                0, 2, b'p', b'w',
+            ]
+        );
+    }
+
+    #[test]
+    fn test_publish_encoding() {
+        let mut buf = [0u8; 32];
+        let n = encode_publish(&mut buf, "a/b", b"21.5", false).unwrap();
+        assert_eq!(&buf[..n], &[0x30, 9, 0, 3, b'a', b'/', b'b', b'2', b'1', b'.', b'5']);
+
+        let n = encode_publish(&mut buf, "a", b"", true).unwrap();
+        assert_eq!(&buf[..n], &[0x31, 3, 0, 1, b'a']);
+
+        assert_eq!(encode_publish(&mut buf, "a/#", b"x", false), Err(MqttError::MalformedPacket));
+        assert_eq!(encode_publish(&mut buf, "a", &[0; 40], false), Err(MqttError::BufferTooSmall));
+    }
+
+    #[test]
+    fn test_connack() {
+        assert_eq!(decode_connack(&[0, 0]), Ok(false));
+        assert_eq!(decode_connack(&[1, 0]), Ok(true));
+        assert_eq!(decode_connack(&[0, 5]), Err(MqttError::ConnectionRefused(5)));
+        assert_eq!(decode_connack(&[0]), Err(MqttError::MalformedPacket));
+    }
+
+    #[test]
+    fn test_client_over_mock_stream() {
+        let conn = MockUart::new().data(&[0x20, 2, 0, 0]).fragmented(&[0xd0, 0], 1);
+        let mut client = MqttClient::new(conn);
+        block_on(async {
+            assert_eq!(client.connect(&ConnectOptions::new("x")).await, Ok(false));
+            client.publish("x/y", b"1", true).await.unwrap();
+            client.ping().await.unwrap();
+            client.disconnect().await.unwrap();
+        });
+
+        let conn = client.into_inner();
+        let written = conn.written();
+        assert!(written.ends_with(&[0x31, 6, 0, 3, b'x', b'/', b'y', b'1', 0xc0, 0, 0xe0, 0]));
+        assert!(conn.rx_drained());
+    }
+
+    #[test]
+    fn test_client_rejects_refused_connection() {
+        let conn = MockUart::new().data(&[0x20, 2, 0, 4]);
+        let mut client = MqttClient::new(conn);
+        assert_eq!(block_on(client.connect(&ConnectOptions::new("x"))), Err(MqttError::ConnectionRefused(4)));
+
+        let conn = MockUart::new().data(&[0xd0, 0]);
+        let mut client = MqttClient::new(conn);
+        assert_eq!(block_on(client.connect(&ConnectOptions::new("x"))), Err(MqttError::UnexpectedPacket(PINGRESP)));
+    }
+
+    #[test]
+    fn test_client_skips_oversized_packets() {
+        let mut packet = std::vec![0x30, 0x90, 0x01]; // PUBLISH with 144 byte body
+        packet.extend_from_slice(&[b'z'; 144]);
+        packet.extend_from_slice(&[0xd0, 0]);
+        let mut client = MqttClient::new(MockUart::new().data(&packet));
+        block_on(client.ping()).unwrap();
+    }
+
+    #[test]
+    fn test_session_with_stand_in_broker() {
+        let (addr, broker) = mqtt_stand_in(0);
+        let stream = StdStream::connect(addr).unwrap();
+        let mut client = MqttClient::new(stream);
+        block_on(async {
+            let mut options = ConnectOptions::new("altruist-test");
+            options.will = Some(LastWill { topic: "altruist/test/status", message: b"offline", retain: true });
+            assert_eq!(client.connect(&options).await, Ok(false));
+            client.publish("altruist/test/status", b"online", true).await.unwrap();
+            client.ping().await.unwrap();
+            client.disconnect().await.unwrap();
+        });
+
+        let session = broker.join().unwrap();
+        assert_eq!(session.client_id, "altruist-test");
+        assert_eq!(session.will.as_ref().map(|(t, m)| (t.as_str(), m.as_slice())), Some(("altruist/test/status", &b"offline"[..])));
+        assert_eq!(session.publishes.len(), 1);
+        assert_eq!(session.publishes[0].topic, "altruist/test/status");
+        assert_eq!(session.publishes[0].payload, b"online");
+        assert!(session.publishes[0].retain);
+        assert_eq!(session.pings, 1);
+        assert!(session.clean_disconnect);
+    }
+
+    /// Round trip through a real broker, e.g. `mosquitto -p 1883`
+    #[test]
+    #[ignore = "needs an MQTT broker on localhost:1883"]
+    fn test_against_local_broker() {
+        let stream = StdStream::connect(([127, 0, 0, 1], DEFAULT_PORT).into()).unwrap();
+        let mut client = MqttClient::new(stream);
+        block_on(async {
+            client.connect(&ConnectOptions::new("altruist-host-test")).await.unwrap();
+            client.publish("altruist/host-test/state", b"ok", false).await.unwrap();
+            client.ping().await.unwrap();
+            client.disconnect().await.unwrap();
+        });
+    }
+}
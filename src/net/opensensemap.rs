@@ -0,0 +1,545 @@
+//! openSenseMap uploader
+//!
+//! Every value is a measurement of one openSenseMap sensor, so each
+//! [`Field`] is mapped to the sensor ID it was registered under. Readings
+//! are split into measurements, buffered with their timestamps and posted
+//! in batches (JSON or CSV) to the box's bulk data endpoint, authorized
+//! with the box access token.
+
+use crate::net::http::{self, HttpClient, HttpError, RetryPolicy};
+use crate::net::{wifi, UploadReport};
+use crate::sensors::bus::ReadingQueue;
+use crate::sensors::{Field, SensorReading};
+use crate::time;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{Deque, String, Vec};
+
+/// Plain-HTTP ingress for devices without TLS
+pub const DEFAULT_HOST: &str = "ingress.opensensemap.org";
+
+/// Interval between uploads
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// openSenseMap IDs are 24 hex digits
+pub type SensorId = String<24>;
+
+/// Number of fields that can be mapped to sensor IDs
+pub const MAX_SENSORS: usize = 8;
+
+/// Measurements kept while the network is down; the oldest are dropped first
+pub const MAX_BUFFERED: usize = 64;
+
+/// Measurements per request
+pub const MAX_BATCH: usize = 16;
+
+/// Room for one request body
+pub const BODY_CAPACITY: usize = 2048;
+
+/// Readings `store_forward` hands to the uploader
+pub static UPLOAD_QUEUE: ReadingQueue = ReadingQueue::new("opensensemap");
+
+/// Measurements buffered by the uploader, for `store_forward` to pace itself
+pub static BACKLOG: AtomicUsize = AtomicUsize::new(0);
+
+/// Bulk upload body format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UploadFormat {
+    /// `[{"sensor":…,"value":…,"createdAt":…}, …]`
+    Json,
+    /// One `sensorId,value,createdAt` line per measurement
+    Csv,
+}
+
+impl UploadFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            UploadFormat::Json => "application/json",
+            UploadFormat::Csv => "text/csv",
+        }
+    }
+}
+
+/// openSenseMap errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpenSenseMapError {
+    /// Box ID, token or sensor mapping is malformed or too long
+    InvalidConfig,
+    /// Upload failed
+    Http(HttpError),
+}
+
+impl core::fmt::Display for OpenSenseMapError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            OpenSenseMapError::InvalidConfig => write!(f, "Invalid configuration"),
+            OpenSenseMapError::Http(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<HttpError> for OpenSenseMapError {
+    fn from(e: HttpError) -> Self {
+        OpenSenseMapError::Http(e)
+    }
+}
+
+/// Uploader settings
+#[derive(Debug, Clone)]
+pub struct OpenSenseMapConfig {
+    pub box_id: SensorId,
+    pub access_token: String<64>,
+    pub sensor_ids: Vec<(Field, SensorId), MAX_SENSORS>,
+    pub format: UploadFormat,
+    pub host: &'static str,
+    pub port: u16,
+    pub interval: Duration,
+    pub retry: RetryPolicy,
+}
+
+impl OpenSenseMapConfig {
+    /// Default endpoint and interval for a box, with no sensors mapped yet
+    pub fn new(box_id: &str, access_token: &str) -> Result<Self, OpenSenseMapError> {
+        Ok(Self {
+            box_id: copy_str(box_id)?,
+            access_token: copy_str(access_token)?,
+            sensor_ids: Vec::new(),
+            format: UploadFormat::Json,
+            host: DEFAULT_HOST,
+            port: 80,
+            interval: DEFAULT_INTERVAL,
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    /// Map a field to an openSenseMap sensor ID, replacing any previous mapping
+    pub fn set_sensor_id(&mut self, field: Field, id: &str) -> Result<(), OpenSenseMapError> {
+        let id = copy_str(id)?;
+        if id.is_empty() {
+            return Err(OpenSenseMapError::InvalidConfig);
+        }
+        match self.sensor_ids.iter_mut().find(|(f, _)| *f == field) {
+            Some((_, slot)) => *slot = id,
+            None => self.sensor_ids.push((field, id)).map_err(|_| OpenSenseMapError::InvalidConfig)?,
+        }
+        Ok(())
+    }
+
+    /// Map fields from a `field=id,field=id` list, e.g. `pm25=5f2b…,pm10=5f2c…`
+    pub fn parse_sensor_ids(&mut self, spec: &str) -> Result<(), OpenSenseMapError> {
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, id) = entry.split_once('=').ok_or(OpenSenseMapError::InvalidConfig)?;
+            let field = Field::from_name(name.trim()).ok_or(OpenSenseMapError::InvalidConfig)?;
+            self.set_sensor_id(field, id.trim())?;
+        }
+        Ok(())
+    }
+
+    /// Sensor ID a field is uploaded as
+    pub fn sensor_id(&self, field: Field) -> Option<&str> {
+        self.sensor_ids.iter().find(|(f, _)| *f == field).map(|(_, id)| id.as_str())
+    }
+}
+
+fn copy_str<const N: usize>(s: &str) -> Result<String<N>, OpenSenseMapError> {
+    let mut out = String::new();
+    out.push_str(s).map_err(|_| OpenSenseMapError::InvalidConfig)?;
+    Ok(out)
+}
+
+/// One value waiting for upload
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    pub field: Field,
+    pub value: f32,
+    /// Monotonic time the value was measured
+    pub timestamp: u64,
+    /// UTC time, if it was known when the value was measured
+    pub utc_timestamp: Option<u64>,
+}
+
+impl Measurement {
+    /// UTC time of the measurement, if known by now
+    pub fn utc_ms(&self) -> Option<u64> {
+        self.utc_timestamp.or_else(|| time::utc_from_monotonic(self.timestamp))
+    }
+}
+
+/// Append one measurement to a request body
+pub fn write_entry<const N: usize>(
+    out: &mut String<N>,
+    format: UploadFormat,
+    sensor_id: &str,
+    value: f32,
+    utc_ms: u64,
+) -> core::fmt::Result {
+    let created_at = time::format_rfc3339(utc_ms);
+    match format {
+        UploadFormat::Json => {
+            let separator = if out.is_empty() { "[" } else { "," };
+            write!(out, "{}{{\"sensor\":\"{}\",\"value\":{:.2},\"createdAt\":\"{}\"}}", separator, sensor_id, value, created_at)
+        }
+        UploadFormat::Csv => writeln!(out, "{},{:.2},{}", sensor_id, value, created_at),
+    }
+}
+
+/// Finish a request body started with `write_entry`
+pub fn finish_body<const N: usize>(out: &mut String<N>, format: UploadFormat) -> core::fmt::Result {
+    match format {
+        UploadFormat::Json => out.push(']').map_err(|_| core::fmt::Error),
+        UploadFormat::Csv => Ok(()),
+    }
+}
+
+/// Buffers measurements and uploads them in batches
+pub struct OpenSenseMapUploader {
+    config: OpenSenseMapConfig,
+    buffer: Deque<Measurement, MAX_BUFFERED>,
+    dropped: u32,
+}
+
+impl OpenSenseMapUploader {
+    pub fn new(config: OpenSenseMapConfig) -> Self {
+        Self {
+            config,
+            buffer: Deque::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn config(&self) -> &OpenSenseMapConfig {
+        &self.config
+    }
+
+    /// Buffer every mapped value of a reading
+    /// Returns the number of measurements added
+    pub fn record(&mut self, reading: &SensorReading) -> usize {
+        if !reading.is_valid() {
+            return 0;
+        }
+        let mut added = 0;
+        for (field, value) in reading.data.values() {
+            if self.config.sensor_id(field).is_none() || !value.is_finite() {
+                continue;
+            }
+            let measurement = Measurement {
+                field,
+                value,
+                timestamp: reading.timestamp,
+                utc_timestamp: reading.utc_timestamp,
+            };
+            if self.buffer.is_full() {
+                self.buffer.pop_front();
+                self.dropped += 1;
+            }
+            let _ = self.buffer.push_back(measurement);
+            added += 1;
+        }
+        added
+    }
+
+    /// Measurements waiting for upload
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Measurements discarded because the buffer was full
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Body for the oldest measurements and how many it covers
+    /// Returns `None` if nothing can be sent yet (empty, or UTC unknown)
+    pub fn next_batch(&self) -> Option<(usize, String<BODY_CAPACITY>)> {
+        let format = self.config.format;
+        let mut body = String::new();
+        let mut count = 0;
+        for measurement in self.buffer.iter().take(MAX_BATCH) {
+            let Some(utc_ms) = measurement.utc_ms() else { break };
+            // Mappings can't disappear while buffered, but don't panic if they do
+            let sensor_id = self.config.sensor_id(measurement.field).unwrap_or("");
+            let before = body.len();
+            if write_entry(&mut body, format, sensor_id, measurement.value, utc_ms).is_err() {
+                body.truncate(before);
+                break;
+            }
+            count += 1;
+        }
+        if count == 0 || finish_body(&mut body, format).is_err() {
+            return None;
+        }
+        Some((count, body))
+    }
+
+    /// Upload buffered measurements batch by batch
+    /// Stops at the first failed batch; it stays buffered for the next round
+    pub async fn upload<C: HttpClient>(&mut self, client: &mut C) -> UploadReport {
+        let mut report = UploadReport::default();
+        let mut path: String<64> = String::new();
+        let _ = write!(path, "/boxes/{}/data", self.config.box_id);
+
+        while let Some((count, body)) = self.next_batch() {
+            let headers = [
+                ("Content-Type", self.config.format.content_type()),
+                ("Authorization", self.config.access_token.as_str()),
+            ];
+            let result = http::post_with_retry(
+                client,
+                self.config.retry,
+                self.config.host,
+                self.config.port,
+                &path,
+                &headers,
+                body.as_bytes(),
+            )
+            .await;
+            match result {
+                Ok(_) => {
+                    for _ in 0..count {
+                        self.buffer.pop_front();
+                    }
+                    report.sent += count;
+                }
+                Err(e) => {
+                    log::warn!("[OPENSENSEMAP] Upload of {} measurements failed: {}", count, e);
+                    report.failed += count;
+                    break;
+                }
+            }
+        }
+        report
+    }
+}
+
+/// Upload loop fed by `UPLOAD_QUEUE`
+/// Buffers measurements and uploads them every interval while the link is up
+pub async fn opensensemap_task_impl<C: HttpClient>(client: &mut C, config: OpenSenseMapConfig) -> ! {
+    let interval = config.interval;
+    let mut uploader = OpenSenseMapUploader::new(config);
+    let mut next_upload = Instant::now() + interval;
+
+    log::info!(
+        "[OPENSENSEMAP] Uploading {} sensor(s) for box {} every {}s",
+        uploader.config().sensor_ids.len(), uploader.config().box_id, interval.as_secs()
+    );
+
+    loop {
+        match select(UPLOAD_QUEUE.receive(), Timer::at(next_upload)).await {
+            Either::First(reading) => {
+                uploader.record(&reading);
+                BACKLOG.store(uploader.pending(), Ordering::Relaxed);
+            }
+            Either::Second(()) => {
+                next_upload += interval;
+                if uploader.pending() == 0 || !wifi::link_state().is_up() {
+                    continue;
+                }
+                if !time::utc_valid() {
+                    log::warn!("[OPENSENSEMAP] Waiting for time sync, {} measurements buffered", uploader.pending());
+                    continue;
+                }
+                let report = uploader.upload(client).await;
+                log::info!(
+                    "[OPENSENSEMAP] Uploaded {} measurements, {} failed, {} buffered",
+                    report.sent, report.failed, uploader.pending()
+                );
+                BACKLOG.store(uploader.pending(), Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{block_on, http_stand_in, StdHttpClient};
+    use crate::sensors::{Flags, Quality, SensorData, SensorType};
+    use crate::time::ManualClock;
+
+    const BOX_ID: &str = "5a0c2cc9fa1d1b001200ab01";
+    const TOKEN: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    const PM25_ID: &str = "5a0c2cc9fa1d1b001200ab02";
+    const PM10_ID: &str = "5a0c2cc9fa1d1b001200ab03";
+    const TEMP_ID: &str = "5a0c2cc9fa1d1b001200ab04";
+
+    /// 2024-01-01T00:00:00Z
+    const UTC_MS: u64 = 1_704_067_200_000;
+
+    static CLOCK: ManualClock = ManualClock::new(10_000);
+
+    fn test_config(format: UploadFormat) -> OpenSenseMapConfig {
+        let mut config = OpenSenseMapConfig::new(BOX_ID, TOKEN).unwrap();
+        config.format = format;
+        config.retry = RetryPolicy { attempts: 2, delay: Duration::from_secs(1) };
+        config.set_sensor_id(Field::Pm25, PM25_ID).unwrap();
+        config.set_sensor_id(Field::Pm10, PM10_ID).unwrap();
+        config.set_sensor_id(Field::Temperature, TEMP_ID).unwrap();
+        config
+    }
+
+    fn pm_reading(utc_ms: Option<u64>) -> SensorReading {
+        SensorReading {
+            sensor_type: SensorType::SDS011,
+            data: SensorData::AirQuality { pm25: Some(5.2), pm10: Some(10.5), pm25_corrected: None, pm10_corrected: None },
+            timestamp: 10_000,
+            utc_timestamp: utc_ms,
+            quality: Quality::Good,
+            flags: Flags::NONE,
+        }
+    }
+
+    fn env_reading(utc_ms: u64) -> SensorReading {
+        SensorReading {
+            sensor_type: SensorType::BME280,
+            data: SensorData::Environmental {
+                temperature: Some(21.456),
+                humidity: Some(48.0),
+                pressure: Some(1013.25),
+                gas_resistance: None,
+                dew_point: None,
+                absolute_humidity: None,
+                heat_index: None,
+                sea_level_pressure: None,
+            },
+            timestamp: 11_000,
+            utc_timestamp: Some(utc_ms),
+            quality: Quality::Good,
+            flags: Flags::NONE,
+        }
+    }
+
+    #[test]
+    fn test_parse_sensor_ids() {
+        let mut config = OpenSenseMapConfig::new(BOX_ID, TOKEN).unwrap();
+        config.parse_sensor_ids("pm25=aaa, pm10 = bbb,co_ppm=ccc,").unwrap();
+        assert_eq!(config.sensor_id(Field::Pm25), Some("aaa"));
+        assert_eq!(config.sensor_id(Field::Pm10), Some("bbb"));
+        assert_eq!(config.sensor_id(Field::CoPpm), Some("ccc"));
+        assert_eq!(config.sensor_id(Field::Humidity), None);
+
+        config.parse_sensor_ids("pm25=ddd").unwrap();
+        assert_eq!(config.sensor_id(Field::Pm25), Some("ddd"));
+        assert_eq!(config.sensor_ids.len(), 3);
+
+        assert_eq!(config.parse_sensor_ids("pm1=xyz"), Err(OpenSenseMapError::InvalidConfig));
+        assert_eq!(config.parse_sensor_ids("pm25"), Err(OpenSenseMapError::InvalidConfig));
+        assert_eq!(config.parse_sensor_ids("pm25="), Err(OpenSenseMapError::InvalidConfig));
+        assert_eq!(config.parse_sensor_ids("pm25=0123456789012345678901234"), Err(OpenSenseMapError::InvalidConfig));
+    }
+
+    #[test]
+    fn test_json_batch() {
+        let mut uploader = OpenSenseMapUploader::new(test_config(UploadFormat::Json));
+        assert_eq!(uploader.record(&pm_reading(Some(UTC_MS))), 2);
+        // Humidity and pressure have no sensor ID
+        assert_eq!(uploader.record(&env_reading(UTC_MS + 1_500)), 1);
+
+        let (count, body) = uploader.next_batch().unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(
+            body.as_str(),
+            concat!(
+                "[{\"sensor\":\"5a0c2cc9fa1d1b001200ab02\",\"value\":5.20,\"createdAt\":\"2024-01-01T00:00:00.000Z\"},",
+                "{\"sensor\":\"5a0c2cc9fa1d1b001200ab03\",\"value\":10.50,\"createdAt\":\"2024-01-01T00:00:00.000Z\"},",
+                "{\"sensor\":\"5a0c2cc9fa1d1b001200ab04\",\"value\":21.46,\"createdAt\":\"2024-01-01T00:00:01.500Z\"}]"
+            )
+        );
+    }
+
+    #[test]
+    fn test_csv_batch() {
+        let mut uploader = OpenSenseMapUploader::new(test_config(UploadFormat::Csv));
+        uploader.record(&pm_reading(Some(UTC_MS)));
+
+        let (count, body) = uploader.next_batch().unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(
+            body.as_str(),
+            "5a0c2cc9fa1d1b001200ab02,5.20,2024-01-01T00:00:00.000Z\n\
+             5a0c2cc9fa1d1b001200ab03,10.50,2024-01-01T00:00:00.000Z\n"
+        );
+    }
+
+    #[test]
+    fn test_batches_are_limited() {
+        let mut uploader = OpenSenseMapUploader::new(test_config(UploadFormat::Json));
+        for i in 0..MAX_BATCH as u64 {
+            uploader.record(&pm_reading(Some(UTC_MS + i * 1_000)));
+        }
+        assert_eq!(uploader.pending(), 2 * MAX_BATCH);
+        let (count, body) = uploader.next_batch().unwrap();
+        assert_eq!(count, MAX_BATCH);
+        assert!(body.ends_with("}]"));
+    }
+
+    #[test]
+    fn test_buffer_drops_oldest() {
+        let mut uploader = OpenSenseMapUploader::new(test_config(UploadFormat::Csv));
+        for i in 0..(MAX_BUFFERED as u64 / 2 + 1) {
+            uploader.record(&pm_reading(Some(UTC_MS + i * 1_000)));
+        }
+        assert_eq!(uploader.pending(), MAX_BUFFERED);
+        assert_eq!(uploader.dropped(), 2);
+        let (_, body) = uploader.next_batch().unwrap();
+        assert!(body.starts_with("5a0c2cc9fa1d1b001200ab02,5.20,2024-01-01T00:00:01.000Z"));
+    }
+
+    #[test]
+    fn test_waits_for_utc() {
+        block_on(async {
+            time::set_clock(&CLOCK);
+            time::clear_utc();
+
+            let mut uploader = OpenSenseMapUploader::new(test_config(UploadFormat::Csv));
+            uploader.record(&pm_reading(None));
+            assert!(uploader.next_batch().is_none());
+
+            // Measured at monotonic 10s; UTC is now known to be UTC_MS at 20s
+            CLOCK.set(20_000);
+            time::set_utc_now(UTC_MS);
+            let (count, body) = uploader.next_batch().unwrap();
+            assert_eq!(count, 2);
+            assert!(body.starts_with("5a0c2cc9fa1d1b001200ab02,5.20,2023-12-31T23:59:50.000Z\n"));
+
+            time::clear_utc();
+            time::set_clock(&time::EmbassyClock);
+        });
+    }
+
+    #[test]
+    fn test_upload_to_local_server() {
+        let (addr, server) = http_stand_in(std::vec![201]);
+        let mut client = StdHttpClient::new(addr);
+        let mut uploader = OpenSenseMapUploader::new(test_config(UploadFormat::Json));
+        uploader.record(&pm_reading(Some(UTC_MS)));
+
+        let report = block_on(uploader.upload(&mut client));
+        assert_eq!(report, UploadReport { sent: 2, failed: 0 });
+        assert_eq!(uploader.pending(), 0);
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/boxes/5a0c2cc9fa1d1b001200ab01/data");
+        assert_eq!(request.header("Host"), Some(DEFAULT_HOST));
+        assert_eq!(request.header("Authorization"), Some(TOKEN));
+        assert_eq!(request.header("Content-Type"), Some("application/json"));
+        assert!(request.body_str().starts_with("[{\"sensor\":\"5a0c2cc9fa1d1b001200ab02\""));
+    }
+
+    #[test]
+    fn test_failed_batch_stays_buffered() {
+        let (addr, server) = http_stand_in(std::vec![502, 502]);
+        let mut client = StdHttpClient::new(addr);
+        let mut uploader = OpenSenseMapUploader::new(test_config(UploadFormat::Csv));
+        uploader.record(&pm_reading(Some(UTC_MS)));
+
+        let report = block_on(uploader.upload(&mut client));
+        assert_eq!(report, UploadReport { sent: 0, failed: 2 });
+        assert_eq!(uploader.pending(), 2);
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,453 @@
+//! Captive setup portal
+//!
+//! When the device has no Wi-Fi credentials, or the BOOT button is held at
+//! power-on, the firmware opens an access point instead of joining a
+//! network. `dhcp_server` hands out addresses with the device as DNS
+//! server, `dns` resolves every name to it, and this module serves the
+//! configuration page: it edits the persisted `Config`, saves it and
+//! reboots into normal operation.
+
+use core::fmt::Write as _;
+
+use embassy_futures::select::select;
+use embassy_net::driver::Driver;
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+use embedded_storage::nor_flash::NorFlash;
+use heapless::String;
+
+use super::form::{Form, FormError};
+use super::http_server::{self, write_response, Handler, Method, Request, ServerError, HTTP_PORT};
+use crate::config::{Config, ConfigError, ConfigStore, MqttSettings};
+use crate::sensors::SensorType;
+
+/// Address of the device on its own access point
+pub const PORTAL_IP: [u8; 4] = [192, 168, 4, 1];
+
+/// First address handed out to clients
+pub const DHCP_POOL_START: u8 = 100;
+
+/// Rendered page size limit
+pub const PAGE_CAPACITY: usize = 4096;
+
+/// Request size limit; the form is well below this
+pub const REQUEST_CAPACITY: usize = 2048;
+
+/// Time for the "saved" page to reach the browser before rebooting
+const REBOOT_DELAY: Duration = Duration::from_secs(2);
+
+/// Portal errors, shown to the user on the page
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PortalError {
+    Form(FormError),
+    Config(ConfigError),
+    /// openSenseMap sensor list doesn't parse
+    SensorList,
+}
+
+impl core::fmt::Display for PortalError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PortalError::Form(e) => write!(f, "{}", e),
+            PortalError::Config(e) => write!(f, "{}", e),
+            PortalError::SensorList => write!(f, "Invalid openSenseMap sensor list"),
+        }
+    }
+}
+
+impl From<FormError> for PortalError {
+    fn from(e: FormError) -> Self {
+        PortalError::Form(e)
+    }
+}
+
+impl From<ConfigError> for PortalError {
+    fn from(e: ConfigError) -> Self {
+        PortalError::Config(e)
+    }
+}
+
+/// Access point name, e.g. `Altruist-4e5f60`
+pub fn ap_ssid(mac: [u8; 6]) -> String<32> {
+    let mut ssid = String::new();
+    let _ = write!(ssid, "Altruist-{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5]);
+    ssid
+}
+
+/// Form field name of a sensor's enable checkbox
+fn sensor_field(sensor_type: SensorType) -> String<24> {
+    let mut name = String::new();
+    let _ = write!(name, "sensor_{}", sensor_type.name());
+    name
+}
+
+/// A field's decoded value, or an empty string if it wasn't sent
+fn field<const N: usize>(form: &Form<'_>, name: &str) -> Result<String<N>, PortalError> {
+    Ok(form.get(name)?.unwrap_or_default())
+}
+
+/// Apply a submitted form to `config`
+///
+/// Secrets are never sent back to the browser, so an empty password or
+/// token keeps the stored one as long as the network, broker or box it
+/// belongs to is unchanged. Nothing is modified if any field is invalid.
+pub fn apply_form(config: &mut Config, form: &Form<'_>, sensors: &[SensorType]) -> Result<(), PortalError> {
+    let mut updated = config.clone();
+
+    let ssid = field(form, "ssid")?;
+    let password: String<64> = field(form, "password")?;
+    if !password.is_empty() || ssid != config.wifi.ssid {
+        updated.wifi.password = password;
+    }
+    updated.wifi.ssid = ssid;
+
+    updated.sensor_community.enabled = form.checked("sc_enabled");
+    updated.sensor_community.sensor_id = field(form, "sc_id")?;
+
+    let box_id = field(form, "osm_box")?;
+    let token: String<64> = field(form, "osm_token")?;
+    if !token.is_empty() || box_id != config.opensensemap.box_id {
+        updated.opensensemap.access_token = token;
+    }
+    updated.opensensemap.box_id = box_id;
+    updated.opensensemap.sensor_ids = field(form, "osm_sensors")?;
+    if updated.opensensemap.is_configured() && updated.opensensemap.to_config().is_err() {
+        return Err(PortalError::SensorList);
+    }
+
+    let address: String<72> = field(form, "mqtt_host")?;
+    let mqtt_password: String<64> = field(form, "mqtt_password")?;
+    updated.mqtt = MqttSettings::new(address.trim(), &field::<32>(form, "mqtt_username")?, &mqtt_password)?;
+    if mqtt_password.is_empty() && updated.mqtt.host == config.mqtt.host {
+        updated.mqtt.password = config.mqtt.password.clone();
+    }
+
+    for &sensor_type in sensors {
+        updated.sensor_mut(sensor_type)?.enabled = form.checked(&sensor_field(sensor_type));
+    }
+
+    *config = updated;
+    Ok(())
+}
+
+/// HTML-escaped text for attribute values and content
+struct Escaped<'a>(&'a str);
+
+impl core::fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '&' => f.write_str("&amp;")?,
+                '<' => f.write_str("&lt;")?,
+                '>' => f.write_str("&gt;")?,
+                '"' => f.write_str("&quot;")?,
+                '\'' => f.write_str("&#39;")?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn checked(on: bool) -> &'static str {
+    if on {
+        " checked"
+    } else {
+        ""
+    }
+}
+
+/// Render the configuration page, with an optional status message
+pub fn render_page<const N: usize>(
+    config: &Config,
+    sensors: &[SensorType],
+    message: Option<&str>,
+    out: &mut String<N>,
+) -> core::fmt::Result {
+    out.clear();
+    out.write_str(concat!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">",
+        "<meta name=\"viewport\" content=\"width=device-width,initial-scale=1\">",
+        "<title>Altruist setup</title><style>",
+        "body{font-family:sans-serif;max-width:30em;margin:auto;padding:1em}",
+        "label{display:block;margin:.5em 0}input[type=text],input[type=password]{width:100%}",
+        "</style></head><body><h1>Altruist setup</h1>",
+    ))?;
+    if let Some(message) = message {
+        write!(out, "<p><b>{}</b></p>", Escaped(message))?;
+    }
+
+    let secret = |set: bool| if set { "unchanged" } else { "" };
+    let mqtt = &config.mqtt;
+    let mut mqtt_address: String<72> = String::new();
+    if mqtt.is_configured() {
+        write!(mqtt_address, "{}:{}", mqtt.host, mqtt.port)?;
+    }
+
+    write!(
+        out,
+        concat!(
+            "<form method=\"post\" action=\"/save\"><h2>Wi-Fi</h2>",
+            "<label>Network <input type=\"text\" name=\"ssid\" value=\"{}\"></label>",
+            "<label>Password <input type=\"password\" name=\"password\" placeholder=\"{}\"></label>",
+            "<h2>sensor.community</h2>",
+            "<label><input type=\"checkbox\" name=\"sc_enabled\"{}> Upload</label>",
+            "<label>Sensor ID <input type=\"text\" name=\"sc_id\" value=\"{}\" placeholder=\"from MAC address\"></label>",
+            "<h2>openSenseMap</h2>",
+            "<label>Box ID <input type=\"text\" name=\"osm_box\" value=\"{}\"></label>",
+            "<label>Access token <input type=\"password\" name=\"osm_token\" placeholder=\"{}\"></label>",
+            "<label>Sensors <input type=\"text\" name=\"osm_sensors\" value=\"{}\" placeholder=\"pm25=id,pm10=id\"></label>",
+            "<h2>MQTT / Home Assistant</h2>",
+            "<label>Broker <input type=\"text\" name=\"mqtt_host\" value=\"{}\" placeholder=\"host:port\"></label>",
+            "<label>Username <input type=\"text\" name=\"mqtt_username\" value=\"{}\"></label>",
+            "<label>Password <input type=\"password\" name=\"mqtt_password\" placeholder=\"{}\"></label>",
+            "<h2>Sensors</h2>",
+        ),
+        Escaped(&config.wifi.ssid),
+        secret(!config.wifi.password.is_empty()),
+        checked(config.sensor_community.enabled),
+        Escaped(&config.sensor_community.sensor_id),
+        Escaped(&config.opensensemap.box_id),
+        secret(!config.opensensemap.access_token.is_empty()),
+        Escaped(&config.opensensemap.sensor_ids),
+        Escaped(&mqtt_address),
+        Escaped(&mqtt.username),
+        secret(!mqtt.password.is_empty()),
+    )?;
+    for &sensor_type in sensors {
+        write!(
+            out,
+            "<label><input type=\"checkbox\" name=\"{}\"{}> {}</label>",
+            sensor_field(sensor_type),
+            checked(config.sensor(sensor_type).enabled),
+            sensor_type.name(),
+        )?;
+    }
+    out.write_str("<p><button type=\"submit\">Save and reboot</button></p></form></body></html>")
+}
+
+/// Serves the page and saves submitted settings
+pub struct PortalHandler<'a, F> {
+    store: ConfigStore<F>,
+    config: Config,
+    sensors: &'a [SensorType],
+    page: String<PAGE_CAPACITY>,
+    saved: &'a Signal<NoopRawMutex, ()>,
+}
+
+impl<'a, F: NorFlash> PortalHandler<'a, F> {
+    /// `saved` is signalled once new settings are stored
+    pub fn new(
+        store: ConfigStore<F>,
+        config: Config,
+        sensors: &'a [SensorType],
+        saved: &'a Signal<NoopRawMutex, ()>,
+    ) -> Self {
+        Self { store, config, sensors, page: String::new(), saved }
+    }
+
+    /// Settings as currently stored
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    async fn page<C: Write>(&mut self, conn: &mut C, status: u16, message: Option<&str>) -> Result<(), ServerError> {
+        if render_page(&self.config, self.sensors, message, &mut self.page).is_err() {
+            return write_response(conn, 500, &[], b"Page too large").await;
+        }
+        write_response(conn, status, &[("Content-Type", "text/html; charset=utf-8")], self.page.as_bytes()).await
+    }
+
+    /// Apply and persist a submitted form
+    fn save(&mut self, body: &[u8]) -> Result<(), PortalError> {
+        let mut config = self.config.clone();
+        apply_form(&mut config, &Form::new(body)?, self.sensors)?;
+        self.store.save(&config)?;
+        self.config = config;
+        Ok(())
+    }
+}
+
+impl<F: NorFlash> Handler for PortalHandler<'_, F> {
+    async fn handle<C: Write>(&mut self, request: &Request<'_>, conn: &mut C) -> Result<(), ServerError> {
+        match (request.method, request.path) {
+            (Method::Get | Method::Head, "/") => self.page(conn, 200, None).await,
+            (Method::Post, "/save") => match self.save(request.body) {
+                Ok(()) => {
+                    log::info!("[PORTAL] Settings saved, rebooting");
+                    self.saved.signal(());
+                    self.page(conn, 200, Some("Saved. Rebooting, reconnect to your own network.")).await
+                }
+                Err(e) => {
+                    log::warn!("[PORTAL] Rejected settings: {}", e);
+                    let mut message: String<64> = String::new();
+                    let _ = write!(message, "Not saved: {}", e);
+                    self.page(conn, 400, Some(&message)).await
+                }
+            },
+            // Connectivity checks (generate_204, hotspot-detect.html, …)
+            // get redirected, which makes phones open the portal
+            (Method::Get | Method::Head, _) => {
+                let [a, b, c, d] = PORTAL_IP;
+                let mut location: String<24> = String::new();
+                let _ = write!(location, "http://{}.{}.{}.{}/", a, b, c, d);
+                write_response(conn, 302, &[("Location", &location)], b"").await
+            }
+            _ => write_response(conn, 405, &[], b"").await,
+        }
+    }
+}
+
+/// Serve the portal until settings are saved, then call `reboot`
+pub async fn portal_task_impl<D: Driver, F: NorFlash>(
+    stack: &Stack<D>,
+    store: ConfigStore<F>,
+    config: Config,
+    sensors: &[SensorType],
+    reboot: fn(),
+) -> ! {
+    let saved = Signal::new();
+    let mut handler = PortalHandler::new(store, config, sensors, &saved);
+    let mut buf = [0u8; REQUEST_CAPACITY];
+
+    log::info!("[PORTAL] Serving setup page on port {}", HTTP_PORT);
+    select(http_server::serve(stack, HTTP_PORT, &mut handler, &mut buf), async {
+        saved.wait().await;
+        Timer::after(REBOOT_DELAY).await;
+    })
+    .await;
+
+    reboot();
+    loop {
+        core::future::pending::<()>().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WifiSettings;
+    use crate::mock::{block_on, MockUart, RamFlash};
+    use crate::net::http_server::serve_connection;
+    use crate::storage::CONFIG_PARTITION;
+
+    const SENSORS: [SensorType; 3] = [SensorType::SDS011, SensorType::BME280, SensorType::ME2CO];
+
+    fn configured() -> Config {
+        Config {
+            wifi: WifiSettings::new("home", "old secret").unwrap(),
+            mqtt: MqttSettings::new("broker", "ha", "mqtt secret").unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn form(body: &str) -> Form<'_> {
+        Form::new(body.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_apply_form() {
+        let mut config = configured();
+        let body = concat!(
+            "ssid=home&password=&sc_enabled=on&sc_id=&osm_box=5f2b&osm_token=tok&osm_sensors=pm25%3Daa",
+            "&mqtt_host=broker%3A1884&mqtt_username=ha&mqtt_password=&sensor_SDS011=on&sensor_ME2-CO=on",
+        );
+        apply_form(&mut config, &form(body), &SENSORS).unwrap();
+
+        // Empty secrets keep the stored ones
+        assert_eq!(config.wifi.password.as_str(), "old secret");
+        assert_eq!(config.mqtt.password.as_str(), "mqtt secret");
+        assert_eq!(config.mqtt.port, 1884);
+        assert_eq!(config.opensensemap.access_token.as_str(), "tok");
+        assert!(config.sensor_community.enabled);
+        assert!(config.sensor(SensorType::SDS011).enabled);
+        assert!(!config.sensor(SensorType::BME280).enabled);
+        assert!(config.sensor(SensorType::ME2CO).enabled);
+
+        // ...unless they belong to a different network or broker now
+        let body = "ssid=other&password=&mqtt_host=&osm_box=";
+        apply_form(&mut config, &form(body), &SENSORS).unwrap();
+        assert_eq!(config.wifi.ssid.as_str(), "other");
+        assert_eq!(config.wifi.password.as_str(), "");
+        assert!(!config.mqtt.is_configured());
+        assert!(!config.opensensemap.is_configured());
+        assert!(!config.sensor_community.enabled);
+    }
+
+    #[test]
+    fn test_apply_form_rejects() {
+        let mut config = configured();
+        for body in [
+            "ssid=%zz",
+            "ssid=0123456789012345678901234567890123",
+            "ssid=x&mqtt_host=broker%3Aport",
+            "ssid=x&osm_box=5f2b&osm_sensors=pm1%3Daa",
+        ] {
+            assert!(apply_form(&mut config, &form(body), &SENSORS).is_err(), "{}", body);
+        }
+        assert_eq!(config, configured());
+    }
+
+    #[test]
+    fn test_render_page() {
+        let mut config = configured();
+        config.sensor_community.sensor_id = String::try_from("<id>").unwrap();
+        config.sensor_mut(SensorType::BME280).unwrap().enabled = false;
+        let mut page: String<PAGE_CAPACITY> = String::new();
+        render_page(&config, &SENSORS, Some("Hi & bye"), &mut page).unwrap();
+
+        assert!(page.contains("<b>Hi &amp; bye</b>"));
+        assert!(page.contains("name=\"ssid\" value=\"home\""));
+        assert!(page.contains("value=\"&lt;id&gt;\""));
+        assert!(page.contains("name=\"mqtt_host\" value=\"broker:1883\""));
+        assert!(page.contains("name=\"sensor_SDS011\" checked>"));
+        assert!(page.contains("name=\"sensor_BME280\">"));
+        // Secrets are never sent out
+        assert!(!page.contains("old secret"));
+        assert!(!page.contains("mqtt secret"));
+        assert!(page.contains("name=\"password\" placeholder=\"unchanged\""));
+    }
+
+    #[test]
+    fn test_handler() {
+        block_on(async {
+            let store = ConfigStore::new(RamFlash::new(CONFIG_PARTITION.end() as usize), CONFIG_PARTITION);
+            let saved = Signal::new();
+            let mut handler = PortalHandler::new(store, Config::default(), &SENSORS, &saved);
+            let mut buf = [0u8; REQUEST_CAPACITY];
+
+            let mut conn = MockUart::new().data(b"GET / HTTP/1.1\r\nHost: 192.168.4.1\r\n\r\n");
+            serve_connection(&mut conn, &mut handler, &mut buf).await.unwrap();
+            assert!(conn.written().starts_with(b"HTTP/1.1 200 OK\r\nContent-Type: text/html"));
+
+            let mut conn = MockUart::new().data(b"GET /generate_204 HTTP/1.1\r\n\r\n");
+            serve_connection(&mut conn, &mut handler, &mut buf).await.unwrap();
+            assert!(conn.written().starts_with(b"HTTP/1.1 302 Found\r\nLocation: http://192.168.4.1/\r\n"));
+
+            // Bad settings are reported and not saved
+            let mut conn = MockUart::new().data(b"POST /save HTTP/1.1\r\nContent-Length: 9\r\n\r\nssid=%zz&");
+            serve_connection(&mut conn, &mut handler, &mut buf).await.unwrap();
+            assert!(conn.written().starts_with(b"HTTP/1.1 400 "));
+            assert!(!saved.signaled());
+
+            let body = b"ssid=home&password=secret&sc_enabled=on&sensor_SDS011=on";
+            let mut request = std::vec::Vec::new();
+            request.extend_from_slice(format!("POST /save HTTP/1.1\r\nContent-Length: {}\r\n\r\n", body.len()).as_bytes());
+            request.extend_from_slice(body);
+            let mut conn = MockUart::new().fragmented(&request, 16);
+            serve_connection(&mut conn, &mut handler, &mut buf).await.unwrap();
+            assert!(conn.written().starts_with(b"HTTP/1.1 200 OK"));
+            assert!(saved.signaled());
+            assert_eq!(handler.config().wifi.password.as_str(), "secret");
+
+            // Persisted for the next boot
+            let mut store = ConfigStore::new(handler.store.into_inner(), CONFIG_PARTITION);
+            let stored = store.load().unwrap();
+            assert_eq!(stored.wifi.ssid.as_str(), "home");
+            assert!(!stored.sensor(SensorType::BME280).enabled);
+        });
+    }
+}
@@ -0,0 +1,170 @@
+//! Prometheus text exposition format
+//!
+//! Writes metric families (`# HELP` / `# TYPE` headers followed by their
+//! samples) into any `fmt::Write`, escaping help texts and label values as
+//! the format requires. Knows nothing about sensors, so it can be reused
+//! for any set of metrics.
+
+use core::fmt::{self, Write};
+
+/// Content type of the exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Metric family types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+}
+
+impl MetricType {
+    fn name(&self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+        }
+    }
+}
+
+/// Values a sample can carry
+pub trait SampleValue {
+    fn write_value(&self, out: &mut dyn Write) -> fmt::Result;
+}
+
+impl SampleValue for f32 {
+    fn write_value(&self, out: &mut dyn Write) -> fmt::Result {
+        if self.is_nan() {
+            out.write_str("NaN")
+        } else if self.is_infinite() {
+            out.write_str(if *self > 0.0 { "+Inf" } else { "-Inf" })
+        } else {
+            write!(out, "{}", self)
+        }
+    }
+}
+
+impl SampleValue for u32 {
+    fn write_value(&self, out: &mut dyn Write) -> fmt::Result {
+        write!(out, "{}", self)
+    }
+}
+
+impl SampleValue for u64 {
+    fn write_value(&self, out: &mut dyn Write) -> fmt::Result {
+        write!(out, "{}", self)
+    }
+}
+
+/// Writes metric families one after another
+///
+/// All samples of a family must follow its `family` call.
+pub struct Exposition<'a, W: Write> {
+    out: &'a mut W,
+}
+
+impl<'a, W: Write> Exposition<'a, W> {
+    pub fn new(out: &'a mut W) -> Self {
+        Self { out }
+    }
+
+    /// Start a family with its help text and type
+    pub fn family(&mut self, name: &str, kind: MetricType, help: &str) -> fmt::Result {
+        write!(self.out, "# HELP {} ", name)?;
+        for c in help.chars() {
+            match c {
+                '\\' => self.out.write_str("\\\\")?,
+                '\n' => self.out.write_str("\\n")?,
+                c => self.out.write_char(c)?,
+            }
+        }
+        write!(self.out, "\n# TYPE {} {}\n", name, kind.name())
+    }
+
+    /// One sample of the current family
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl SampleValue) -> fmt::Result {
+        self.out.write_str(name)?;
+        if !labels.is_empty() {
+            self.out.write_char('{')?;
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.write_char(',')?;
+                }
+                write!(self.out, "{}=\"", label)?;
+                write_label_value(self.out, value)?;
+                self.out.write_char('"')?;
+            }
+            self.out.write_char('}')?;
+        }
+        self.out.write_char(' ')?;
+        value.write_value(self.out)?;
+        self.out.write_char('\n')
+    }
+}
+
+fn write_label_value(out: &mut impl Write, value: &str) -> fmt::Result {
+    for c in value.chars() {
+        match c {
+            '\\' => out.write_str("\\\\")?,
+            '"' => out.write_str("\\\"")?,
+            '\n' => out.write_str("\\n")?,
+            c => out.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::String;
+
+    #[test]
+    fn test_exposition() {
+        let mut out: String<512> = String::new();
+        let mut metrics = Exposition::new(&mut out);
+        metrics.family("temperature_celsius", MetricType::Gauge, "Air temperature in °C").unwrap();
+        metrics.sample("temperature_celsius", &[("sensor", "BME280"), ("quality", "good")], 21.5f32).unwrap();
+        metrics.sample("temperature_celsius", &[("sensor", "SHT30"), ("quality", "bad")], f32::NAN).unwrap();
+        metrics.family("errors_total", MetricType::Counter, "Read errors").unwrap();
+        metrics.sample("errors_total", &[], 7u32).unwrap();
+        metrics.family("uptime_seconds", MetricType::Gauge, "Seconds since boot").unwrap();
+        metrics.sample("uptime_seconds", &[], 86_400u64).unwrap();
+
+        assert_eq!(
+            out.as_str(),
+            "# HELP temperature_celsius Air temperature in °C\n\
+             # TYPE temperature_celsius gauge\n\
+             temperature_celsius{sensor=\"BME280\",quality=\"good\"} 21.5\n\
+             temperature_celsius{sensor=\"SHT30\",quality=\"bad\"} NaN\n\
+             # HELP errors_total Read errors\n\
+             # TYPE errors_total counter\n\
+             errors_total 7\n\
+             # HELP uptime_seconds Seconds since boot\n\
+             # TYPE uptime_seconds gauge\n\
+             uptime_seconds 86400\n"
+        );
+    }
+
+    #[test]
+    fn test_escaping_and_special_values() {
+        let mut out: String<256> = String::new();
+        let mut metrics = Exposition::new(&mut out);
+        metrics.family("x", MetricType::Gauge, "Back\\slash\nnew \"line\"").unwrap();
+        metrics.sample("x", &[("path", "C:\\tmp \"a\"\nb")], f32::INFINITY).unwrap();
+        metrics.sample("x", &[], f32::NEG_INFINITY).unwrap();
+        metrics.sample("x", &[], -0.125f32).unwrap();
+
+        assert_eq!(
+            out.as_str(),
+            "# HELP x Back\\\\slash\\nnew \"line\"\n\
+             # TYPE x gauge\n\
+             x{path=\"C:\\\\tmp \\\"a\\\"\\nb\"} +Inf\n\
+             x -Inf\n\
+             x -0.125\n"
+        );
+
+        // Output that doesn't fit is an error, not a silent truncation
+        let mut small: String<16> = String::new();
+        assert!(Exposition::new(&mut small).family("x", MetricType::Gauge, "Too long for the buffer").is_err());
+    }
+}
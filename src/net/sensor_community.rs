@@ -0,0 +1,417 @@
+//! sensor.community (formerly Luftdaten) uploader
+//!
+//! The aggregator queues readings here; the uploader keeps the latest one
+//! per sensor and pushes them to the sensor.community API every interval,
+//! one request per sensor as the API expects. Each request carries the
+//! `X-Pin` of the sensor kind and the `X-Sensor` ID the station was
+//! registered with.
+
+use crate::net::http::{self, HttpClient, HttpError, RetryPolicy};
+use crate::net::{wifi, UploadReport};
+use crate::sensors::bus::ReadingQueue;
+use crate::sensors::{SensorData, SensorReading, SensorType};
+use core::fmt::Write;
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{String, Vec};
+
+/// Default API host; plain HTTP is accepted
+pub const DEFAULT_HOST: &str = "api.sensor.community";
+
+/// Push endpoint
+pub const DEFAULT_PATH: &str = "/v1/push-sensor-data/";
+
+/// Interval used by the original firmware
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(145);
+
+/// Reported in every upload
+pub const SOFTWARE_VERSION: &str = concat!("altruist-rs-", env!("CARGO_PKG_VERSION"));
+
+/// Room for the JSON body of one sensor
+pub const PAYLOAD_CAPACITY: usize = 256;
+
+/// Sensors with an upload waiting; one slot per sensor type
+const MAX_PENDING: usize = 4;
+
+/// Readings waiting for the uploader
+pub static UPLOAD_QUEUE: ReadingQueue = ReadingQueue::new("sensor_community");
+
+/// Queue a reading for upload
+/// Readings from sensors the API doesn't know and invalid readings are
+/// ignored; if the uploader falls behind, the reading is dropped and counted
+pub fn queue_reading(reading: &SensorReading) {
+    if reading.is_valid() && x_pin(reading.sensor_type).is_some() {
+        UPLOAD_QUEUE.send(reading.clone());
+    }
+}
+
+/// `X-Pin` header value for a sensor kind
+/// The pin numbers are the ones the airrohr firmware wires each sensor to
+pub fn x_pin(sensor_type: SensorType) -> Option<&'static str> {
+    match sensor_type {
+        SensorType::SDS011 | SensorType::PMS7003 => Some("1"),
+        SensorType::SHT30 => Some("7"),
+        SensorType::BME280 => Some("11"),
+        _ => None,
+    }
+}
+
+/// `X-Sensor` ID derived from the station's MAC address
+pub fn sensor_id_from_mac(mac: [u8; 6]) -> String<32> {
+    let mut id = String::new();
+    let _ = write!(id, "esp32-");
+    for byte in mac {
+        let _ = write!(id, "{:02X}", byte);
+    }
+    id
+}
+
+/// Values of a reading as (`value_type`, value) pairs
+/// PM10 is `P1` and PM2.5 is `P2`, uncorrected as the map expects;
+/// pressure is sent in Pa
+pub fn data_values(data: &SensorData) -> Vec<(&'static str, f32), 4> {
+    let mut values = Vec::new();
+    let candidates = match *data {
+        SensorData::AirQuality { pm25, pm10, .. } => [("P1", pm10), ("P2", pm25), ("", None)],
+        SensorData::Environmental { temperature, humidity, pressure, .. } => [
+            ("temperature", temperature),
+            ("humidity", humidity),
+            ("pressure", pressure.map(|hpa| hpa * 100.0)),
+        ],
+        _ => [("", None); 3],
+    };
+    for (name, value) in candidates {
+        if let Some(value) = value.filter(|v| v.is_finite()) {
+            let _ = values.push((name, value));
+        }
+    }
+    values
+}
+
+/// Build the `sensordatavalues` JSON body for one reading
+/// Returns `None` if the reading has nothing to upload
+pub fn build_payload(data: &SensorData) -> Option<String<PAYLOAD_CAPACITY>> {
+    let values = data_values(data);
+    if values.is_empty() {
+        return None;
+    }
+
+    let mut json = String::new();
+    write!(json, "{{\"software_version\":\"{}\",\"sensordatavalues\":[", SOFTWARE_VERSION).ok()?;
+    for (i, (name, value)) in values.iter().enumerate() {
+        if i > 0 {
+            json.push(',').ok()?;
+        }
+        write!(json, "{{\"value_type\":\"{}\",\"value\":\"{:.2}\"}}", name, value).ok()?;
+    }
+    json.push_str("]}").ok()?;
+    Some(json)
+}
+
+/// Uploader settings
+#[derive(Debug, Clone)]
+pub struct SensorCommunityConfig {
+    /// Station ID as registered on devices.sensor.community
+    pub sensor_id: String<32>,
+    pub host: &'static str,
+    pub port: u16,
+    pub path: &'static str,
+    pub interval: Duration,
+    pub retry: RetryPolicy,
+}
+
+impl SensorCommunityConfig {
+    /// Default endpoint and interval for a station ID
+    pub fn new(sensor_id: &str) -> Self {
+        let mut id = String::new();
+        let _ = id.push_str(sensor_id);
+        Self {
+            sensor_id: id,
+            host: DEFAULT_HOST,
+            port: 80,
+            path: DEFAULT_PATH,
+            interval: DEFAULT_INTERVAL,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+/// Keeps the latest reading per sensor and uploads them
+pub struct SensorCommunityUploader {
+    config: SensorCommunityConfig,
+    pending: Vec<SensorReading, MAX_PENDING>,
+}
+
+impl SensorCommunityUploader {
+    pub fn new(config: SensorCommunityConfig) -> Self {
+        Self {
+            config,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &SensorCommunityConfig {
+        &self.config
+    }
+
+    /// Remember a reading for the next upload, replacing older data from the same sensor
+    /// Returns `false` if the reading can't be uploaded
+    pub fn record(&mut self, reading: &SensorReading) -> bool {
+        if !reading.is_valid() || x_pin(reading.sensor_type).is_none() {
+            return false;
+        }
+        match self.pending.iter_mut().find(|r| r.sensor_type == reading.sensor_type) {
+            Some(slot) => *slot = reading.clone(),
+            None => {
+                if self.pending.push(reading.clone()).is_err() {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Number of sensors with data waiting
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Upload one reading
+    pub async fn send<C: HttpClient>(&self, client: &mut C, reading: &SensorReading) -> Result<(), HttpError> {
+        let Some(pin) = x_pin(reading.sensor_type) else {
+            return Ok(());
+        };
+        let Some(body) = build_payload(&reading.data) else {
+            return Ok(());
+        };
+        let headers = [
+            ("Content-Type", "application/json"),
+            ("X-Pin", pin),
+            ("X-Sensor", self.config.sensor_id.as_str()),
+        ];
+        http::post_with_retry(
+            client,
+            self.config.retry,
+            self.config.host,
+            self.config.port,
+            self.config.path,
+            &headers,
+            body.as_bytes(),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Upload everything pending
+    /// Sensors that failed stay pending until a newer reading replaces them
+    pub async fn upload<C: HttpClient>(&mut self, client: &mut C) -> UploadReport {
+        let mut report = UploadReport::default();
+        let mut i = 0;
+        while i < self.pending.len() {
+            let reading = &self.pending[i];
+            match self.send(client, reading).await {
+                Ok(()) => {
+                    self.pending.swap_remove(i);
+                    report.sent += 1;
+                }
+                Err(e) => {
+                    log::warn!("[SENSOR.COMMUNITY] Upload for {} failed: {}", reading.sensor_type, e);
+                    report.failed += 1;
+                    i += 1;
+                }
+            }
+        }
+        report
+    }
+}
+
+/// Upload loop fed by `UPLOAD_QUEUE`
+/// Collects readings and uploads them every interval while the link is up
+pub async fn sensor_community_task_impl<C: HttpClient>(client: &mut C, config: SensorCommunityConfig) -> ! {
+    let interval = config.interval;
+    let mut uploader = SensorCommunityUploader::new(config);
+    let mut next_upload = Instant::now() + interval;
+
+    log::info!(
+        "[SENSOR.COMMUNITY] Uploading as {} every {}s",
+        uploader.config().sensor_id, interval.as_secs()
+    );
+
+    loop {
+        match select(UPLOAD_QUEUE.receive(), Timer::at(next_upload)).await {
+            Either::First(reading) => {
+                uploader.record(&reading);
+            }
+            Either::Second(()) => {
+                next_upload += interval;
+                if uploader.pending() == 0 {
+                    continue;
+                }
+                if !wifi::link_state().is_up() {
+                    log::warn!("[SENSOR.COMMUNITY] Network down, skipping upload");
+                    continue;
+                }
+                let report = uploader.upload(client).await;
+                log::info!("[SENSOR.COMMUNITY] Uploaded {} sensor(s), {} failed", report.sent, report.failed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{block_on, http_stand_in, StdHttpClient};
+    use crate::sensors::Quality;
+
+    fn sds011_reading(pm25: f32, pm10: f32) -> SensorReading {
+        SensorReading::new(
+            SensorType::SDS011,
+            SensorData::AirQuality { pm25: Some(pm25), pm10: Some(pm10), pm25_corrected: None, pm10_corrected: None },
+            Quality::Good,
+        )
+    }
+
+    fn bme280_reading() -> SensorReading {
+        SensorReading::new(
+            SensorType::BME280,
+            SensorData::Environmental {
+                temperature: Some(21.456),
+                humidity: Some(48.0),
+                pressure: Some(1013.25),
+                gas_resistance: None,
+                dew_point: None,
+                absolute_humidity: None,
+                heat_index: None,
+                sea_level_pressure: None,
+            },
+            Quality::Good,
+        )
+    }
+
+    fn test_config() -> SensorCommunityConfig {
+        let mut config = SensorCommunityConfig::new("esp32-0A1B2C3D4E5F");
+        config.retry = RetryPolicy { attempts: 3, delay: Duration::from_secs(1) };
+        config
+    }
+
+    #[test]
+    fn test_pm_payload() {
+        let body = build_payload(&SensorData::AirQuality { pm25: Some(5.2), pm10: Some(10.5), pm25_corrected: None, pm10_corrected: None }).unwrap();
+        assert_eq!(
+            body.as_str(),
+            concat!(
+                "{\"software_version\":\"altruist-rs-", env!("CARGO_PKG_VERSION"), "\",\"sensordatavalues\":[",
+                "{\"value_type\":\"P1\",\"value\":\"10.50\"},",
+                "{\"value_type\":\"P2\",\"value\":\"5.20\"}]}"
+            )
+        );
+    }
+
+    #[test]
+    fn test_environmental_values() {
+        let values = data_values(&bme280_reading().data);
+        assert_eq!(values.as_slice(), &[("temperature", 21.456), ("humidity", 48.0), ("pressure", 101_325.0)]);
+
+        // Missing and non-finite values are left out
+        let partial = SensorData::Environmental {
+            temperature: Some(f32::NAN),
+            humidity: Some(40.0),
+            pressure: None,
+            gas_resistance: None,
+            dew_point: None,
+            absolute_humidity: None,
+            heat_index: None,
+            sea_level_pressure: None,
+        };
+        assert_eq!(data_values(&partial).as_slice(), &[("humidity", 40.0)]);
+
+        let empty = SensorData::AirQuality { pm25: None, pm10: None, pm25_corrected: None, pm10_corrected: None };
+        assert!(build_payload(&empty).is_none());
+    }
+
+    #[test]
+    fn test_pins_and_sensor_id() {
+        assert_eq!(x_pin(SensorType::SDS011), Some("1"));
+        assert_eq!(x_pin(SensorType::BME280), Some("11"));
+        assert_eq!(x_pin(SensorType::ME2CO), None);
+        assert_eq!(sensor_id_from_mac([0x0a, 0x1b, 0x2c, 0x3d, 0x4e, 0x5f]).as_str(), "esp32-0A1B2C3D4E5F");
+    }
+
+    #[test]
+    fn test_record_keeps_latest_per_sensor() {
+        let mut uploader = SensorCommunityUploader::new(test_config());
+        assert!(uploader.record(&sds011_reading(1.0, 2.0)));
+        assert!(uploader.record(&sds011_reading(3.0, 4.0)));
+        assert!(uploader.record(&bme280_reading()));
+        assert_eq!(uploader.pending(), 2);
+
+        let co = SensorReading::new(
+            SensorType::ME2CO,
+            SensorData::Gas { co_ppm: Some(1.0), co2_ppm: None, voc_index: None },
+            Quality::Good,
+        );
+        assert!(!uploader.record(&co));
+        let mut bad = sds011_reading(1.0, 2.0);
+        bad.quality = Quality::Bad;
+        assert!(!uploader.record(&bad));
+        assert_eq!(uploader.pending(), 2);
+    }
+
+    #[test]
+    fn test_upload_to_local_server() {
+        let (addr, server) = http_stand_in(std::vec![201, 201]);
+        let mut client = StdHttpClient::new(addr);
+        let mut uploader = SensorCommunityUploader::new(test_config());
+        uploader.record(&sds011_reading(5.2, 10.5));
+        uploader.record(&bme280_reading());
+
+        let report = block_on(uploader.upload(&mut client));
+        assert_eq!(report, UploadReport { sent: 2, failed: 0 });
+        assert_eq!(uploader.pending(), 0);
+
+        let requests = server.join().unwrap();
+        let pm = &requests[0];
+        assert_eq!(pm.method, "POST");
+        assert_eq!(pm.path, DEFAULT_PATH);
+        assert_eq!(pm.header("Host"), Some(DEFAULT_HOST));
+        assert_eq!(pm.header("Content-Type"), Some("application/json"));
+        assert_eq!(pm.header("X-Pin"), Some("1"));
+        assert_eq!(pm.header("X-Sensor"), Some("esp32-0A1B2C3D4E5F"));
+        assert!(pm.body_str().contains("{\"value_type\":\"P2\",\"value\":\"5.20\"}"));
+
+        let env = &requests[1];
+        assert_eq!(env.header("X-Pin"), Some("11"));
+        assert!(env.body_str().contains("{\"value_type\":\"temperature\",\"value\":\"21.46\"}"));
+        assert!(env.body_str().contains("{\"value_type\":\"pressure\",\"value\":\"101325.00\"}"));
+    }
+
+    #[test]
+    fn test_upload_retries_server_errors() {
+        let (addr, server) = http_stand_in(std::vec![500, 503, 200]);
+        let mut client = StdHttpClient::new(addr);
+        let mut uploader = SensorCommunityUploader::new(test_config());
+        uploader.record(&sds011_reading(5.2, 10.5));
+
+        let report = block_on(uploader.upload(&mut client));
+        assert_eq!(report, UploadReport { sent: 1, failed: 0 });
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests.iter().all(|r| r.body == requests[0].body));
+    }
+
+    #[test]
+    fn test_failed_upload_stays_pending() {
+        // Client errors are not retried
+        let (addr, server) = http_stand_in(std::vec![403]);
+        let mut client = StdHttpClient::new(addr);
+        let mut uploader = SensorCommunityUploader::new(test_config());
+        uploader.record(&sds011_reading(5.2, 10.5));
+
+        let report = block_on(uploader.upload(&mut client));
+        assert_eq!(report, UploadReport { sent: 0, failed: 1 });
+        assert_eq!(uploader.pending(), 1);
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+}
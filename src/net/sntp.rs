@@ -0,0 +1,671 @@
+//! SNTP client (RFC 4330)
+//!
+//! Measures the offset between embassy's monotonic clock and UTC and feeds
+//! it to [`crate::time`], so readings get UTC timestamps once the first
+//! exchange succeeds. Re-syncs periodically to correct for crystal drift.
+
+use crate::time;
+use embassy_net::driver::Driver;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Stack};
+use embassy_time::{with_timeout, Duration, Timer};
+
+/// Well-known NTP server port
+pub const NTP_PORT: u16 = 123;
+
+/// Size of an SNTP packet without authentication fields
+pub const PACKET_LEN: usize = 48;
+
+/// Default server pool
+pub const DEFAULT_SERVER: &str = "pool.ntp.org";
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Length of one NTP era (2^32 seconds)
+const NTP_ERA_SECONDS: u64 = 1 << 32;
+
+/// SNTP protocol version we speak
+const VERSION: u8 = 4;
+
+/// Association modes used by SNTP
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+const MODE_BROADCAST: u8 = 5;
+
+/// How long to wait for a server response
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval between successful synchronizations
+const RESYNC_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Interval before retrying a failed synchronization
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// 64-bit NTP timestamp: seconds since 1900 plus a 32-bit binary fraction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NtpTimestamp {
+    pub seconds: u32,
+    pub fraction: u32,
+}
+
+impl NtpTimestamp {
+    pub const ZERO: Self = Self { seconds: 0, fraction: 0 };
+
+    /// Convert from milliseconds since the Unix epoch
+    pub fn from_unix_ms(unix_ms: u64) -> Self {
+        let seconds = (unix_ms / 1000 + NTP_UNIX_OFFSET) % NTP_ERA_SECONDS;
+        let fraction = ((unix_ms % 1000) << 32) / 1000;
+        Self {
+            seconds: seconds as u32,
+            fraction: fraction as u32,
+        }
+    }
+
+    /// Convert to milliseconds since the Unix epoch
+    ///
+    /// Per RFC 4330 section 3, timestamps with the most significant bit
+    /// clear are taken to be in era 1 (after 2036-02-07). Returns None for
+    /// era 0 timestamps before the Unix epoch.
+    pub fn to_unix_ms(self) -> Option<u64> {
+        let mut seconds = self.seconds as u64;
+        if seconds & 0x8000_0000 == 0 {
+            seconds += NTP_ERA_SECONDS;
+        }
+        // Round so that from_unix_ms/to_unix_ms round-trip exactly
+        let ms = ((self.fraction as u64) * 1000 + (1 << 31)) >> 32;
+        Some(seconds.checked_sub(NTP_UNIX_OFFSET)? * 1000 + ms)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Self::ZERO
+    }
+
+    fn read(buf: &[u8]) -> Self {
+        Self {
+            seconds: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            fraction: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+        }
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        buf[..4].copy_from_slice(&self.seconds.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.fraction.to_be_bytes());
+    }
+}
+
+/// Leap indicator field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeapIndicator {
+    NoWarning,
+    LastMinute61,
+    LastMinute59,
+    /// Server clock not synchronized
+    Alarm,
+}
+
+impl LeapIndicator {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0x03 {
+            0 => LeapIndicator::NoWarning,
+            1 => LeapIndicator::LastMinute61,
+            2 => LeapIndicator::LastMinute59,
+            _ => LeapIndicator::Alarm,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            LeapIndicator::NoWarning => 0,
+            LeapIndicator::LastMinute61 => 1,
+            LeapIndicator::LastMinute59 => 2,
+            LeapIndicator::Alarm => 3,
+        }
+    }
+}
+
+/// SNTP packet (RFC 4330 section 4)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SntpPacket {
+    pub leap: LeapIndicator,
+    pub version: u8,
+    pub mode: u8,
+    pub stratum: u8,
+    pub poll: i8,
+    pub precision: i8,
+    pub root_delay: u32,
+    pub root_dispersion: u32,
+    pub reference_id: [u8; 4],
+    pub reference: NtpTimestamp,
+    pub originate: NtpTimestamp,
+    pub receive: NtpTimestamp,
+    pub transmit: NtpTimestamp,
+}
+
+impl SntpPacket {
+    /// Build a client request; `transmit` is echoed back as the originate timestamp
+    pub fn client_request(transmit: NtpTimestamp) -> Self {
+        Self {
+            leap: LeapIndicator::NoWarning,
+            version: VERSION,
+            mode: MODE_CLIENT,
+            stratum: 0,
+            poll: 0,
+            precision: 0,
+            root_delay: 0,
+            root_dispersion: 0,
+            reference_id: [0; 4],
+            reference: NtpTimestamp::ZERO,
+            originate: NtpTimestamp::ZERO,
+            receive: NtpTimestamp::ZERO,
+            transmit,
+        }
+    }
+
+    /// Serialize to wire format
+    pub fn encode(&self) -> [u8; PACKET_LEN] {
+        let mut buf = [0u8; PACKET_LEN];
+        buf[0] = (self.leap.bits() << 6) | ((self.version & 0x07) << 3) | (self.mode & 0x07);
+        buf[1] = self.stratum;
+        buf[2] = self.poll as u8;
+        buf[3] = self.precision as u8;
+        buf[4..8].copy_from_slice(&self.root_delay.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.root_dispersion.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.reference_id);
+        self.reference.write(&mut buf[16..24]);
+        self.originate.write(&mut buf[24..32]);
+        self.receive.write(&mut buf[32..40]);
+        self.transmit.write(&mut buf[40..48]);
+        buf
+    }
+
+    /// Parse from wire format; trailing authentication fields are ignored
+    pub fn decode(buf: &[u8]) -> Result<Self, SntpError> {
+        if buf.len() < PACKET_LEN {
+            return Err(SntpError::Truncated);
+        }
+        Ok(Self {
+            leap: LeapIndicator::from_bits(buf[0] >> 6),
+            version: (buf[0] >> 3) & 0x07,
+            mode: buf[0] & 0x07,
+            stratum: buf[1],
+            poll: buf[2] as i8,
+            precision: buf[3] as i8,
+            root_delay: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            root_dispersion: u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]),
+            reference_id: [buf[12], buf[13], buf[14], buf[15]],
+            reference: NtpTimestamp::read(&buf[16..24]),
+            originate: NtpTimestamp::read(&buf[24..32]),
+            receive: NtpTimestamp::read(&buf[32..40]),
+            transmit: NtpTimestamp::read(&buf[40..48]),
+        })
+    }
+}
+
+/// Result of one successful exchange
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SntpSample {
+    /// UTC time (ms since Unix epoch) at which the monotonic clock read zero
+    pub utc_at_zero_ms: u64,
+    /// Round-trip network delay excluding server processing time
+    pub round_trip_ms: u64,
+    /// Server stratum
+    pub stratum: u8,
+}
+
+/// SNTP errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SntpError {
+    /// Response shorter than an SNTP packet
+    Truncated,
+    /// Response is not a server or broadcast reply
+    UnexpectedMode,
+    /// Server reports its clock is not synchronized
+    ServerUnsynchronized,
+    /// Server asked us to go away (stratum 0); carries the kiss code
+    KissOfDeath([u8; 4]),
+    /// Response doesn't answer our request
+    OriginateMismatch,
+    /// Response has no transmit timestamp
+    ZeroTransmit,
+    /// Response carries a timestamp before the Unix epoch
+    InvalidTimestamp,
+    /// Server name could not be resolved
+    Dns,
+    /// Socket send/receive failed
+    Network,
+    /// No response in time
+    Timeout,
+}
+
+impl core::fmt::Display for SntpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SntpError::Truncated => write!(f, "Truncated response"),
+            SntpError::UnexpectedMode => write!(f, "Unexpected response mode"),
+            SntpError::ServerUnsynchronized => write!(f, "Server not synchronized"),
+            SntpError::KissOfDeath(code) => {
+                write!(f, "Kiss-o'-death {}", core::str::from_utf8(code).unwrap_or("????"))
+            }
+            SntpError::OriginateMismatch => write!(f, "Response doesn't match request"),
+            SntpError::ZeroTransmit => write!(f, "Missing transmit timestamp"),
+            SntpError::InvalidTimestamp => write!(f, "Invalid timestamp"),
+            SntpError::Dns => write!(f, "DNS lookup failed"),
+            SntpError::Network => write!(f, "Network error"),
+            SntpError::Timeout => write!(f, "No response"),
+        }
+    }
+}
+
+/// Validate a server response and compute the clock offset
+///
+/// `t1_ms` and `t4_ms` are the monotonic times the request was sent and the
+/// response received; `request_transmit` is the transmit timestamp we sent.
+pub fn process_response(
+    response: &SntpPacket,
+    request_transmit: NtpTimestamp,
+    t1_ms: u64,
+    t4_ms: u64,
+) -> Result<SntpSample, SntpError> {
+    if response.mode != MODE_SERVER && response.mode != MODE_BROADCAST {
+        return Err(SntpError::UnexpectedMode);
+    }
+    if response.stratum == 0 {
+        return Err(SntpError::KissOfDeath(response.reference_id));
+    }
+    if response.leap == LeapIndicator::Alarm || response.stratum > 15 {
+        return Err(SntpError::ServerUnsynchronized);
+    }
+    if response.originate != request_transmit {
+        return Err(SntpError::OriginateMismatch);
+    }
+    if response.transmit.is_zero() {
+        return Err(SntpError::ZeroTransmit);
+    }
+
+    let t1 = t1_ms as i64;
+    let t2 = response.receive.to_unix_ms().ok_or(SntpError::InvalidTimestamp)? as i64;
+    let t3 = response.transmit.to_unix_ms().ok_or(SntpError::InvalidTimestamp)? as i64;
+    let t4 = t4_ms as i64;
+
+    // Our local clock is the monotonic clock, so the offset is exactly the
+    // UTC time at which it read zero
+    let offset = ((t2 - t1) + (t3 - t4)) / 2;
+    let round_trip = ((t4 - t1) - (t3 - t2)).max(0);
+
+    Ok(SntpSample {
+        utc_at_zero_ms: offset.max(0) as u64,
+        round_trip_ms: round_trip as u64,
+        stratum: response.stratum,
+    })
+}
+
+/// Datagram transport to a single SNTP server
+#[allow(async_fn_in_trait)]
+pub trait SntpTransport {
+    async fn send(&mut self, packet: &[u8]) -> Result<(), SntpError>;
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, SntpError>;
+}
+
+/// embassy-net UDP socket bound to a server endpoint
+pub struct UdpTransport<'a, 'b> {
+    socket: &'a UdpSocket<'b>,
+    server: IpEndpoint,
+}
+
+impl<'a, 'b> UdpTransport<'a, 'b> {
+    pub fn new(socket: &'a UdpSocket<'b>, server: IpEndpoint) -> Self {
+        Self { socket, server }
+    }
+}
+
+impl SntpTransport for UdpTransport<'_, '_> {
+    async fn send(&mut self, packet: &[u8]) -> Result<(), SntpError> {
+        self.socket.send_to(packet, self.server).await.map_err(|_| SntpError::Network)
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, SntpError> {
+        loop {
+            let (n, from) = self.socket.recv_from(buf).await.map_err(|_| SntpError::Network)?;
+            // Ignore stray datagrams from anyone but our server
+            if from == self.server {
+                return Ok(n);
+            }
+        }
+    }
+}
+
+/// SNTP client state
+pub struct SntpClient {
+    last_sample: Option<SntpSample>,
+    last_sync_ms: Option<u64>,
+    consecutive_failures: u32,
+}
+
+impl Default for SntpClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SntpClient {
+    pub const fn new() -> Self {
+        Self {
+            last_sample: None,
+            last_sync_ms: None,
+            consecutive_failures: 0,
+        }
+    }
+
+    /// Perform one exchange and, on success, update the global UTC offset
+    pub async fn sync<T: SntpTransport>(&mut self, transport: &mut T) -> Result<SntpSample, SntpError> {
+        let result = self.exchange(transport).await;
+        match result {
+            Ok(sample) => {
+                time::set_utc_offset(sample.utc_at_zero_ms);
+                self.last_sample = Some(sample);
+                self.last_sync_ms = Some(time::now_ms());
+                self.consecutive_failures = 0;
+            }
+            Err(_) => self.consecutive_failures += 1,
+        }
+        result
+    }
+
+    async fn exchange<T: SntpTransport>(&mut self, transport: &mut T) -> Result<SntpSample, SntpError> {
+        // Any unique value works as transmit timestamp; use our best guess of UTC
+        let t1 = time::now_ms();
+        let transmit = NtpTimestamp::from_unix_ms(time::utc_now_ms().unwrap_or(t1));
+        transport.send(&SntpPacket::client_request(transmit).encode()).await?;
+
+        let mut buf = [0u8; 68]; // room for optional key identifier and digest
+        let n = with_timeout(RESPONSE_TIMEOUT, transport.recv(&mut buf))
+            .await
+            .map_err(|_| SntpError::Timeout)??;
+        let t4 = time::now_ms();
+
+        let response = SntpPacket::decode(&buf[..n])?;
+        process_response(&response, transmit, t1, t4)
+    }
+
+    /// Whether at least one synchronization has succeeded
+    pub fn is_synchronized(&self) -> bool {
+        self.last_sample.is_some()
+    }
+
+    /// Most recent successful exchange
+    pub fn last_sample(&self) -> Option<&SntpSample> {
+        self.last_sample.as_ref()
+    }
+
+    /// Monotonic time of the last successful synchronization
+    pub fn last_sync_ms(&self) -> Option<u64> {
+        self.last_sync_ms
+    }
+
+    /// How long to wait before the next synchronization attempt
+    pub fn next_sync_delay(&self) -> Duration {
+        if self.consecutive_failures == 0 {
+            RESYNC_INTERVAL
+        } else {
+            RETRY_INTERVAL
+        }
+    }
+}
+
+/// SNTP synchronization loop over an embassy-net stack
+/// Waits for network configuration, then syncs every hour (retrying failures)
+pub async fn sntp_task_impl<D: Driver>(stack: &Stack<D>, server: &str) -> ! {
+    let mut client = SntpClient::new();
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0u8; 128];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0u8; 128];
+
+    log::info!("[SNTP] Starting time synchronization with {}", server);
+
+    loop {
+        stack.wait_config_up().await;
+
+        let result = match stack.dns_query(server, embassy_net::dns::DnsQueryType::A).await {
+            Ok(addrs) if !addrs.is_empty() => {
+                let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+                match socket.bind(0) {
+                    Ok(()) => {
+                        let mut transport = UdpTransport::new(&socket, IpEndpoint::new(addrs[0], NTP_PORT));
+                        client.sync(&mut transport).await
+                    }
+                    Err(_) => Err(SntpError::Network),
+                }
+            }
+            _ => Err(SntpError::Dns),
+        };
+
+        match result {
+            Ok(sample) => log::info!(
+                "[SNTP] Synchronized: UTC {} ms, round trip {} ms, stratum {}",
+                time::utc_now_ms().unwrap_or(0), sample.round_trip_ms, sample.stratum
+            ),
+            Err(e) => log::warn!("[SNTP] Sync failed: {}", e),
+        }
+
+        Timer::after(client.next_sync_delay()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::block_on;
+    use std::net::UdpSocket as StdUdpSocket;
+    use std::thread;
+
+    /// 2024-01-01T00:00:00Z
+    const SERVER_UTC_MS: u64 = 1_704_067_200_000;
+
+    /// Blocking std socket standing in for the embassy-net one
+    struct StdTransport(StdUdpSocket);
+
+    impl SntpTransport for StdTransport {
+        async fn send(&mut self, packet: &[u8]) -> Result<(), SntpError> {
+            self.0.send(packet).map(|_| ()).map_err(|_| SntpError::Network)
+        }
+
+        async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, SntpError> {
+            self.0.recv(buf).map_err(|e| match e.kind() {
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => SntpError::Timeout,
+                _ => SntpError::Network,
+            })
+        }
+    }
+
+    /// Local SNTP server answering one request via `respond`
+    fn stand_in_server(respond: impl FnOnce(SntpPacket) -> Option<SntpPacket> + Send + 'static) -> StdTransport {
+        let server = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(std::time::Duration::from_millis(500))).unwrap();
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 128];
+            let (n, from) = server.recv_from(&mut buf).unwrap();
+            let request = SntpPacket::decode(&buf[..n]).unwrap();
+            if let Some(response) = respond(request) {
+                server.send_to(&response.encode(), from).unwrap();
+            }
+        });
+
+        StdTransport(client)
+    }
+
+    fn server_response(request: &SntpPacket, receive_ms: u64, transmit_ms: u64) -> SntpPacket {
+        SntpPacket {
+            leap: LeapIndicator::NoWarning,
+            version: request.version,
+            mode: MODE_SERVER,
+            stratum: 2,
+            poll: 6,
+            precision: -20,
+            root_delay: 0x0000_0800,
+            root_dispersion: 0x0000_0400,
+            reference_id: [192, 168, 1, 1],
+            reference: NtpTimestamp::from_unix_ms(receive_ms - 60_000),
+            originate: request.transmit,
+            receive: NtpTimestamp::from_unix_ms(receive_ms),
+            transmit: NtpTimestamp::from_unix_ms(transmit_ms),
+        }
+    }
+
+    #[test]
+    fn test_timestamp_unix_conversion() {
+        let ts = NtpTimestamp::from_unix_ms(SERVER_UTC_MS + 500);
+        assert_eq!(ts.seconds, 3_913_056_000);
+        assert_eq!(ts.fraction, 0x8000_0000);
+        assert_eq!(ts.to_unix_ms(), Some(SERVER_UTC_MS + 500));
+
+        // Unix epoch is NTP second 2208988800
+        assert_eq!(NtpTimestamp::from_unix_ms(0).seconds, 2_208_988_800);
+        assert_eq!(NtpTimestamp::from_unix_ms(0).to_unix_ms(), Some(0));
+
+        // Era 0 but before 1970
+        assert_eq!(NtpTimestamp { seconds: 0x8000_0000, fraction: 0 }.to_unix_ms(), None);
+        assert_eq!(NtpTimestamp { seconds: 2_208_988_799, fraction: 0 }.to_unix_ms(), None);
+    }
+
+    #[test]
+    fn test_timestamp_era_rollover() {
+        // 2040-01-01T00:00:00Z lies in NTP era 1
+        let ms = 2_208_988_800_000;
+        let ts = NtpTimestamp::from_unix_ms(ms);
+        assert!(ts.seconds < 0x8000_0000);
+        assert_eq!(ts.to_unix_ms(), Some(ms));
+    }
+
+    #[test]
+    fn test_request_encoding() {
+        let transmit = NtpTimestamp { seconds: 0x0102_0304, fraction: 0x0506_0708 };
+        let buf = SntpPacket::client_request(transmit).encode();
+
+        // LI = 0, VN = 4, Mode = 3
+        assert_eq!(buf[0], 0x23);
+        assert!(buf[1..40].iter().all(|&b| b == 0));
+        assert_eq!(&buf[40..48], &[1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let request = SntpPacket::client_request(NtpTimestamp::from_unix_ms(123_456));
+        let mut response = server_response(&request, SERVER_UTC_MS, SERVER_UTC_MS + 1);
+        response.leap = LeapIndicator::LastMinute61;
+
+        let decoded = SntpPacket::decode(&response.encode()).unwrap();
+        assert_eq!(decoded, response);
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        assert_eq!(SntpPacket::decode(&[0u8; 47]), Err(SntpError::Truncated));
+    }
+
+    #[test]
+    fn test_offset_computation() {
+        let request_transmit = NtpTimestamp::from_unix_ms(1_000);
+        let request = SntpPacket::client_request(request_transmit);
+        // Sent at monotonic 1000 ms, server received 40 ms later (in its
+        // time base) and replied after 10 ms, answer arrived at 1100 ms
+        let response = server_response(&request, SERVER_UTC_MS + 40, SERVER_UTC_MS + 50);
+
+        let sample = process_response(&response, request_transmit, 1_000, 1_100).unwrap();
+        // offset = ((T2 - T1) + (T3 - T4)) / 2, delay = (T4 - T1) - (T3 - T2)
+        assert_eq!(sample.utc_at_zero_ms, SERVER_UTC_MS - 1_005);
+        assert_eq!(sample.round_trip_ms, 90);
+        assert_eq!(sample.stratum, 2);
+    }
+
+    #[test]
+    fn test_response_validation() {
+        let transmit = NtpTimestamp::from_unix_ms(1_000);
+        let request = SntpPacket::client_request(transmit);
+        let good = server_response(&request, SERVER_UTC_MS, SERVER_UTC_MS);
+        let check = |p: &SntpPacket| process_response(p, transmit, 1_000, 1_010).map(|_| ());
+
+        assert_eq!(check(&good), Ok(()));
+
+        let mut p = good.clone();
+        p.mode = MODE_CLIENT;
+        assert_eq!(check(&p), Err(SntpError::UnexpectedMode));
+
+        let mut p = good.clone();
+        p.stratum = 0;
+        p.reference_id = *b"RATE";
+        assert_eq!(check(&p), Err(SntpError::KissOfDeath(*b"RATE")));
+
+        let mut p = good.clone();
+        p.leap = LeapIndicator::Alarm;
+        assert_eq!(check(&p), Err(SntpError::ServerUnsynchronized));
+
+        let mut p = good.clone();
+        p.originate = NtpTimestamp::from_unix_ms(2_000);
+        assert_eq!(check(&p), Err(SntpError::OriginateMismatch));
+
+        let mut p = good.clone();
+        p.transmit = NtpTimestamp::ZERO;
+        assert_eq!(check(&p), Err(SntpError::ZeroTransmit));
+
+        let mut p = good.clone();
+        p.receive = NtpTimestamp { seconds: 0x8000_0000, fraction: 0 };
+        assert_eq!(check(&p), Err(SntpError::InvalidTimestamp));
+
+        let mut p = good.clone();
+        p.transmit = NtpTimestamp { seconds: 0x83AA_7E7F, fraction: 0 };
+        assert_eq!(check(&p), Err(SntpError::InvalidTimestamp));
+    }
+
+    #[test]
+    fn test_sync_against_local_server() {
+        let mut transport = stand_in_server(|request| {
+            assert_eq!(request.mode, MODE_CLIENT);
+            assert_eq!(request.version, VERSION);
+            Some(server_response(&request, SERVER_UTC_MS, SERVER_UTC_MS))
+        });
+
+        block_on(async {
+            time::clear_utc();
+            let mut client = SntpClient::new();
+            let sample = client.sync(&mut transport).await.unwrap();
+
+            assert!(client.is_synchronized());
+            assert!(time::utc_valid());
+            assert_eq!(client.next_sync_delay(), RESYNC_INTERVAL);
+            assert_eq!(sample.round_trip_ms, 0);
+            // Virtual time doesn't move during the exchange, so UTC now is the server time
+            assert_eq!(time::utc_now_ms(), Some(SERVER_UTC_MS));
+            time::clear_utc();
+        });
+    }
+
+    #[test]
+    fn test_sync_rejects_mismatched_response() {
+        let mut transport = stand_in_server(|request| {
+            let mut response = server_response(&request, SERVER_UTC_MS, SERVER_UTC_MS);
+            response.originate.fraction ^= 1;
+            Some(response)
+        });
+
+        let mut client = SntpClient::new();
+        let result = block_on(client.sync(&mut transport));
+
+        assert_eq!(result, Err(SntpError::OriginateMismatch));
+        assert!(!client.is_synchronized());
+        assert_eq!(client.next_sync_delay(), RETRY_INTERVAL);
+    }
+
+    #[test]
+    fn test_sync_times_out_when_server_silent() {
+        let mut transport = stand_in_server(|_| None);
+
+        let mut client = SntpClient::new();
+        assert_eq!(block_on(client.sync(&mut transport)), Err(SntpError::Timeout));
+        assert_eq!(client.next_sync_delay(), RETRY_INTERVAL);
+    }
+}
@@ -0,0 +1,301 @@
+//! Store-and-forward for the cloud uploaders
+//!
+//! Readings bound for openSenseMap and InfluxDB go through here instead of
+//! straight to their upload queues. While the device is offline they are
+//! appended to the flash ring log (`crate::ring_log`), and once Wi-Fi and
+//! the clock are back they are handed to the uploaders oldest first, a
+//! batch at a time, so an outage or a reboot doesn't lose them.
+//!
+//! Readings are stored with `sensors::codec` and only logged once they
+//! carry a UTC timestamp: the time since boot means nothing after a
+//! reboot. While online and with an empty log, readings skip the flash to
+//! spare it wear.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Timer};
+use embedded_storage::nor_flash::NorFlash;
+use heapless::{Deque, Vec};
+
+use crate::net::wifi;
+use crate::ring_log::{LogError, LogStats, RingLog, MAX_RECORD};
+use crate::sensors::bus::ReadingQueue;
+use crate::sensors::{codec, SensorReading};
+use crate::time;
+
+/// Interval between attempts to forward logged readings
+pub const FORWARD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Items an uploader may hold before forwarding pauses
+pub const MAX_BACKLOG: usize = 16;
+
+/// Readings held in RAM until the clock is synced; the oldest are dropped first
+pub const MAX_UNSYNCED: usize = 16;
+
+/// Number of uploaders fed from the log
+pub const MAX_SINKS: usize = 2;
+
+/// Readings waiting to be logged or forwarded
+pub static LOG_QUEUE: ReadingQueue = ReadingQueue::new("store_forward");
+
+/// Queue a reading for the uploaders behind the log
+pub fn queue_reading(reading: &SensorReading) {
+    if reading.is_valid() {
+        LOG_QUEUE.send(reading.clone());
+    }
+}
+
+static LOG_STATS: Mutex<CriticalSectionRawMutex, Cell<Option<LogStats>>> = Mutex::new(Cell::new(None));
+
+/// Fill level of the log, once it is mounted
+pub fn log_stats() -> Option<LogStats> {
+    LOG_STATS.lock(Cell::get)
+}
+
+/// An uploader fed from the log
+#[derive(Clone, Copy)]
+pub struct Sink {
+    pub name: &'static str,
+    pub queue: &'static ReadingQueue,
+    /// Items the uploader has buffered, kept up to date by its task
+    pub backlog: &'static AtomicUsize,
+}
+
+impl Sink {
+    /// Whether the uploader can take another reading
+    fn ready(&self) -> bool {
+        !self.queue.is_full() && self.backlog.load(Ordering::Relaxed) + self.queue.len() < MAX_BACKLOG
+    }
+
+    fn deliver(&self, reading: &SensorReading) {
+        if !self.queue.send(reading.clone()) {
+            log::warn!("[STORE] {} queue full, reading dropped", self.name);
+        }
+    }
+}
+
+/// Hand logged readings to every sink, oldest first, while all of them
+/// have room; at most `max` per call
+///
+/// A record is consumed before it is delivered, so a failing flash can't
+/// make the uploaders see it twice. Records that don't decode are skipped.
+/// Returns the number of readings forwarded.
+pub fn forward<F: NorFlash>(log: &mut RingLog<F>, sinks: &[Sink], max: usize) -> Result<usize, LogError> {
+    let mut buf = [0; MAX_RECORD];
+    let mut forwarded = 0;
+    while forwarded < max && sinks.iter().all(Sink::ready) {
+        let Some(entry) = log.peek(&mut buf)? else {
+            break;
+        };
+        log.consume(entry)?;
+        match codec::decode(&buf[..entry.payload_len()]) {
+            Ok(reading) => {
+                sinks.iter().for_each(|sink| sink.deliver(&reading));
+                forwarded += 1;
+            }
+            Err(e) => log::warn!("[STORE] Skipping a log record: {}", e),
+        }
+    }
+    Ok(forwarded)
+}
+
+/// Routes readings through the log
+pub struct StoreForward<F> {
+    log: RingLog<F>,
+    sinks: Vec<Sink, MAX_SINKS>,
+    unsynced: Deque<SensorReading, MAX_UNSYNCED>,
+}
+
+impl<F: NorFlash> StoreForward<F> {
+    pub fn new(log: RingLog<F>, sinks: Vec<Sink, MAX_SINKS>) -> Self {
+        Self { log, sinks, unsynced: Deque::new() }
+    }
+
+    pub fn stats(&self) -> LogStats {
+        self.log.stats()
+    }
+
+    /// Take a new reading; `online` when the uploaders can reach their servers
+    pub fn store(&mut self, mut reading: SensorReading, online: bool) {
+        if reading.resolve_utc().is_none() {
+            if self.unsynced.is_full() {
+                self.unsynced.pop_front();
+                log::warn!("[STORE] Clock not synced, dropped the oldest reading");
+            }
+            let _ = self.unsynced.push_back(reading);
+            return;
+        }
+        self.log_unsynced();
+
+        let empty = self.unsynced.is_empty() && self.log.stats().pending == 0;
+        if online && empty && self.sinks.iter().all(Sink::ready) {
+            self.sinks.iter().for_each(|sink| sink.deliver(&reading));
+        } else {
+            self.append(&reading);
+        }
+    }
+
+    fn append(&mut self, reading: &SensorReading) {
+        let mut record = [0; codec::MAX_ENCODED_LEN];
+        let len = match codec::encode(reading, &mut record) {
+            Ok(len) => len,
+            Err(e) => {
+                log::warn!("[STORE] Can't encode a {} reading: {}", reading.sensor_type.name(), e);
+                return;
+            }
+        };
+        if let Err(e) = self.log.append(&record[..len]) {
+            // Better late in RAM than not at all
+            log::warn!("[STORE] Log append failed: {}, delivering directly", e);
+            self.sinks.iter().for_each(|sink| sink.deliver(reading));
+        }
+    }
+
+    /// Log readings that waited for the clock, if it is synced now
+    fn log_unsynced(&mut self) {
+        while let Some(reading) = self.unsynced.front_mut() {
+            if reading.resolve_utc().is_none() {
+                return;
+            }
+            if let Some(reading) = self.unsynced.pop_front() {
+                self.append(&reading);
+            }
+        }
+    }
+
+    /// Periodic work: log readings once the clock is synced and forward
+    /// logged ones while online. Returns the number forwarded.
+    pub fn poll(&mut self, online: bool) -> Result<usize, LogError> {
+        self.log_unsynced();
+        if !online {
+            return Ok(0);
+        }
+        forward(&mut self.log, &self.sinks, MAX_BACKLOG)
+    }
+}
+
+/// Log and forward readings forever
+pub async fn store_forward_task_impl<F: NorFlash>(log: RingLog<F>, sinks: Vec<Sink, MAX_SINKS>) -> ! {
+    let mut store = StoreForward::new(log, sinks);
+
+    let stats = store.stats();
+    log::info!("[STORE] {} readings in the flash log, {}% full", stats.pending, stats.fill_percent());
+
+    loop {
+        let event = select(LOG_QUEUE.receive(), Timer::after(FORWARD_INTERVAL)).await;
+        let online = wifi::link_state().is_up() && time::utc_valid();
+        match event {
+            Either::First(reading) => store.store(reading, online),
+            Either::Second(()) => match store.poll(online) {
+                Ok(0) => {}
+                Ok(forwarded) => log::info!("[STORE] Forwarded {} logged readings, {} left", forwarded, store.stats().pending),
+                Err(e) => log::warn!("[STORE] Forwarding failed: {}", e),
+            },
+        }
+        let stats = store.stats();
+        LOG_STATS.lock(|cell| cell.set(Some(stats)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+    use crate::sensors::{Flags, Quality, SensorData, SensorType};
+    use crate::storage::RING_LOG_PARTITION;
+
+    static QUEUE_A: ReadingQueue = ReadingQueue::new("a");
+    static QUEUE_B: ReadingQueue = ReadingQueue::new("b");
+    static BACKLOG_A: AtomicUsize = AtomicUsize::new(0);
+    static BACKLOG_B: AtomicUsize = AtomicUsize::new(0);
+
+    /// 2024-01-01T00:00:00Z
+    const UTC_MS: u64 = 1_704_067_200_000;
+
+    fn sinks() -> Vec<Sink, MAX_SINKS> {
+        Vec::from_slice(&[
+            Sink { name: "a", queue: &QUEUE_A, backlog: &BACKLOG_A },
+            Sink { name: "b", queue: &QUEUE_B, backlog: &BACKLOG_B },
+        ])
+        .unwrap()
+    }
+
+    fn pm_reading(n: u64) -> SensorReading {
+        SensorReading {
+            sensor_type: SensorType::SDS011,
+            data: SensorData::AirQuality { pm25: Some(n as f32), pm10: Some(10.5), pm25_corrected: None, pm10_corrected: None },
+            timestamp: 10_000 + n,
+            utc_timestamp: Some(UTC_MS + n * 1000),
+            quality: Quality::Good,
+            flags: Flags::NONE,
+        }
+    }
+
+    fn drain(queue: &ReadingQueue) -> std::vec::Vec<u64> {
+        std::iter::from_fn(|| queue.try_receive()).map(|r| r.timestamp - 10_000).collect()
+    }
+
+    #[test]
+    fn test_store_and_forward() {
+        let flash = RamFlash::new(RING_LOG_PARTITION.end() as usize);
+        let log = RingLog::mount(flash, RING_LOG_PARTITION).unwrap();
+        let mut store = StoreForward::new(log, sinks());
+
+        // Online with an empty log: straight through
+        store.store(pm_reading(0), true);
+        assert_eq!(store.stats().pending, 0);
+        assert_eq!(drain(&QUEUE_A), [0]);
+        assert_eq!(drain(&QUEUE_B), [0]);
+
+        // Offline: logged, and kept across a reboot
+        for n in 1..=20 {
+            store.store(pm_reading(n), false);
+        }
+        assert_eq!(store.poll(false), Ok(0));
+        assert_eq!(store.stats().pending, 20);
+        let log = RingLog::mount(store.log.into_inner(), RING_LOG_PARTITION).unwrap();
+        let mut store = StoreForward::new(log, sinks());
+
+        // Back online: new readings queue up behind the logged ones, which
+        // are forwarded oldest first while the uploaders have room
+        store.store(pm_reading(21), true);
+        assert!(QUEUE_A.is_empty());
+        assert_eq!(store.poll(true), Ok(8));
+        assert_eq!(drain(&QUEUE_A), (1..=8).collect::<std::vec::Vec<_>>());
+        assert_eq!(drain(&QUEUE_B).len(), 8);
+
+        BACKLOG_B.store(MAX_BACKLOG - 2, Ordering::Relaxed);
+        assert_eq!(store.poll(true), Ok(2));
+        BACKLOG_B.store(0, Ordering::Relaxed);
+        assert_eq!(drain(&QUEUE_A), [9, 10]);
+        assert_eq!(drain(&QUEUE_B), [9, 10]);
+
+        while store.stats().pending > 0 {
+            store.poll(true).unwrap();
+            drain(&QUEUE_A);
+            drain(&QUEUE_B);
+        }
+        assert_eq!(store.stats().used_bytes, 0);
+    }
+
+    #[test]
+    fn test_forward_skips_bad_records() {
+        let flash = RamFlash::new(RING_LOG_PARTITION.end() as usize);
+        let mut log = RingLog::mount(flash, RING_LOG_PARTITION).unwrap();
+        let mut record = [0; codec::MAX_ENCODED_LEN];
+        let len = codec::encode(&pm_reading(1), &mut record).unwrap();
+        log.append(&[0xEE; 8]).unwrap();
+        log.append(&record[..len]).unwrap();
+
+        static QUEUE: ReadingQueue = ReadingQueue::new("c");
+        static BACKLOG: AtomicUsize = AtomicUsize::new(0);
+        let sinks = [Sink { name: "c", queue: &QUEUE, backlog: &BACKLOG }];
+        assert_eq!(forward(&mut log, &sinks, MAX_BACKLOG), Ok(1));
+        assert_eq!(drain(&QUEUE), [1]);
+        assert_eq!(log.stats().pending, 0);
+    }
+}
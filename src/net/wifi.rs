@@ -0,0 +1,275 @@
+//! Wi-Fi station supervision
+//!
+//! The radio itself is driven by the firmware (esp-wifi); this module holds
+//! the hardware-independent part: the reconnect state machine with
+//! exponential backoff, and the shared link status other tasks watch.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::watch::{Receiver, Watch};
+use embassy_time::Duration;
+
+/// First retry delay after a failed connection attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound for the retry delay
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Maximum number of tasks watching the link state
+pub const MAX_STATUS_RECEIVERS: usize = 8;
+
+/// Link state reported to other tasks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkState {
+    /// Not connected and not trying (e.g. no credentials)
+    Down,
+    /// Association with the access point in progress
+    Connecting { attempt: u32 },
+    /// Associated, waiting for an IP configuration
+    Associated,
+    /// Associated and configured, network is usable
+    Up,
+    /// Waiting before the next connection attempt
+    Backoff { attempt: u32, delay_ms: u64 },
+}
+
+impl LinkState {
+    /// Whether the network can be used
+    pub fn is_up(&self) -> bool {
+        *self == LinkState::Up
+    }
+
+    /// Short name for logs and status pages
+    pub fn name(&self) -> &'static str {
+        match self {
+            LinkState::Down => "down",
+            LinkState::Connecting { .. } => "connecting",
+            LinkState::Associated => "associated",
+            LinkState::Up => "up",
+            LinkState::Backoff { .. } => "backoff",
+        }
+    }
+}
+
+/// Global Wi-Fi link status
+/// The supervisor publishes every state change here
+pub static WIFI_STATUS: Watch<CriticalSectionRawMutex, LinkState, MAX_STATUS_RECEIVERS> = Watch::new();
+
+/// Publish a new link state
+pub fn publish_link_state(state: LinkState) {
+    WIFI_STATUS.sender().send(state);
+}
+
+/// Current link state
+pub fn link_state() -> LinkState {
+    WIFI_STATUS.try_get().unwrap_or(LinkState::Down)
+}
+
+/// Get a receiver for link state changes
+/// Returns `None` if all receiver slots are taken
+pub fn get_wifi_status_receiver() -> Option<Receiver<'static, CriticalSectionRawMutex, LinkState, MAX_STATUS_RECEIVERS>> {
+    WIFI_STATUS.receiver()
+}
+
+/// Exponential backoff: each delay doubles up to a maximum
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+    attempt: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(INITIAL_BACKOFF, MAX_BACKOFF)
+    }
+}
+
+impl Backoff {
+    pub const fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            next: initial,
+            attempt: 0,
+        }
+    }
+
+    /// Delay to wait before the next attempt; advances the backoff
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.attempt = self.attempt.saturating_add(1);
+        self.next = Duration::from_ticks(self.next.as_ticks().saturating_mul(2)).min(self.max);
+        delay
+    }
+
+    /// Number of delays handed out since the last reset
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Start over from the initial delay
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+        self.attempt = 0;
+    }
+}
+
+/// Reconnect state machine
+///
+/// The firmware reports what the radio did; the supervisor tracks the link
+/// state and tells it how long to wait before retrying.
+pub struct WifiSupervisor {
+    state: LinkState,
+    backoff: Backoff,
+    attempt: u32,
+}
+
+impl Default for WifiSupervisor {
+    fn default() -> Self {
+        Self::new(Backoff::default())
+    }
+}
+
+impl WifiSupervisor {
+    pub const fn new(backoff: Backoff) -> Self {
+        Self {
+            state: LinkState::Down,
+            backoff,
+            attempt: 0,
+        }
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    /// A connection attempt is starting
+    pub fn on_connect_start(&mut self) -> LinkState {
+        self.attempt += 1;
+        self.state = LinkState::Connecting { attempt: self.attempt };
+        self.state
+    }
+
+    /// Association succeeded
+    pub fn on_connected(&mut self) -> LinkState {
+        self.attempt = 0;
+        self.backoff.reset();
+        self.state = LinkState::Associated;
+        self.state
+    }
+
+    /// An IP configuration was obtained
+    pub fn on_config_up(&mut self) -> LinkState {
+        if self.state == LinkState::Associated {
+            self.state = LinkState::Up;
+        }
+        self.state
+    }
+
+    /// Association failed; returns how long to wait before retrying
+    pub fn on_connect_failed(&mut self) -> Duration {
+        let delay = self.backoff.next_delay();
+        self.state = LinkState::Backoff {
+            attempt: self.attempt,
+            delay_ms: delay.as_millis(),
+        };
+        delay
+    }
+
+    /// An established link dropped
+    /// Reconnects immediately; backoff only kicks in if that fails
+    pub fn on_disconnected(&mut self) -> LinkState {
+        self.state = LinkState::Down;
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        let delays: std::vec::Vec<u64> = (0..6).map(|_| backoff.next_delay().as_secs()).collect();
+
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+        assert_eq!(backoff.attempt(), 6);
+
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_default_backoff() {
+        let mut backoff = Backoff::default();
+        assert_eq!(backoff.next_delay(), INITIAL_BACKOFF);
+        for _ in 0..20 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.next_delay(), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_supervisor_happy_path() {
+        let mut supervisor = WifiSupervisor::default();
+        assert_eq!(supervisor.state(), LinkState::Down);
+
+        assert_eq!(supervisor.on_connect_start(), LinkState::Connecting { attempt: 1 });
+        assert_eq!(supervisor.on_connected(), LinkState::Associated);
+        assert!(!supervisor.state().is_up());
+        assert_eq!(supervisor.on_config_up(), LinkState::Up);
+        assert!(supervisor.state().is_up());
+    }
+
+    #[test]
+    fn test_supervisor_backs_off_on_failures() {
+        let mut supervisor = WifiSupervisor::new(Backoff::new(Duration::from_secs(2), Duration::from_secs(60)));
+
+        supervisor.on_connect_start();
+        assert_eq!(supervisor.on_connect_failed(), Duration::from_secs(2));
+        assert_eq!(supervisor.state(), LinkState::Backoff { attempt: 1, delay_ms: 2_000 });
+
+        assert_eq!(supervisor.on_connect_start(), LinkState::Connecting { attempt: 2 });
+        assert_eq!(supervisor.on_connect_failed(), Duration::from_secs(4));
+
+        supervisor.on_connect_start();
+        assert_eq!(supervisor.on_connect_failed(), Duration::from_secs(8));
+        assert_eq!(supervisor.state().name(), "backoff");
+    }
+
+    #[test]
+    fn test_supervisor_resets_after_success() {
+        let mut supervisor = WifiSupervisor::new(Backoff::new(Duration::from_secs(1), Duration::from_secs(60)));
+        for _ in 0..3 {
+            supervisor.on_connect_start();
+            supervisor.on_connect_failed();
+        }
+        supervisor.on_connect_start();
+        supervisor.on_connected();
+        supervisor.on_config_up();
+
+        // Link drops: reconnect starts over from attempt 1 and the initial delay
+        assert_eq!(supervisor.on_disconnected(), LinkState::Down);
+        assert_eq!(supervisor.on_connect_start(), LinkState::Connecting { attempt: 1 });
+        assert_eq!(supervisor.on_connect_failed(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_config_up_requires_association() {
+        let mut supervisor = WifiSupervisor::default();
+        supervisor.on_connect_start();
+        assert_eq!(supervisor.on_config_up(), LinkState::Connecting { attempt: 1 });
+    }
+
+    #[test]
+    fn test_status_watch() {
+        let mut receiver = get_wifi_status_receiver().unwrap();
+        publish_link_state(LinkState::Associated);
+        assert_eq!(receiver.try_changed(), Some(LinkState::Associated));
+        assert_eq!(link_state(), LinkState::Associated);
+        assert_eq!(receiver.try_changed(), None);
+    }
+}
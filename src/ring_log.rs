@@ -0,0 +1,589 @@
+//! Flash ring log
+//!
+//! An append-only log of small records spread over the erase sectors of a
+//! partition, used to keep data across network outages and reboots.
+//! Records are read back oldest first and marked as consumed once handled.
+//!
+//! Every sector in use starts with an 8-byte header, followed by records
+//! aligned to 4 bytes:
+//!
+//! | offset | size | sector header                                      |
+//! |--------|------|----------------------------------------------------|
+//! | 0      | 4    | magic `ALTL`                                       |
+//! | 4      | 4    | sequence number, one higher for every new sector   |
+//!
+//! | offset | size | record                                             |
+//! |--------|------|----------------------------------------------------|
+//! | 0      | 2    | payload length, little-endian                      |
+//! | 2      | 2    | payload length, inverted                           |
+//! | 4      | 4    | CRC-32 of the payload followed by bytes 0..4       |
+//! | 8      | 4    | `FF FF FF FF` while pending, cleared when consumed |
+//! | 12     | n    | payload, padded to 4 bytes with 0xFF               |
+//!
+//! A record goes to flash in one write and is consumed by clearing its
+//! marker in place, which NOR flash allows without an erase. When the
+//! newest sector is full, the next one in the ring is erased and takes
+//! over, dropping whatever it still held unconsumed.
+//!
+//! A record that fails its checks was cut short by a power loss. Nothing
+//! after it in the sector is trusted and appends continue in a fresh
+//! sector, so a torn write costs at most that one record.
+
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::storage::{crc32, crc32_update, Partition};
+
+/// Largest payload of a record
+pub const MAX_RECORD: usize = 256;
+
+const MAGIC: [u8; 4] = *b"ALTL";
+const SECTOR_HEADER_LEN: u32 = 8;
+const RECORD_HEADER_LEN: usize = 12;
+const MARKER_OFFSET: u32 = 8;
+const ALIGN: usize = 4;
+
+/// Ring log errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogError {
+    /// Reading, writing or erasing the flash failed
+    Flash,
+    /// Record exceeds `MAX_RECORD` or the caller's buffer
+    TooLarge,
+}
+
+impl core::fmt::Display for LogError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LogError::Flash => write!(f, "Flash access failed"),
+            LogError::TooLarge => write!(f, "Record too large"),
+        }
+    }
+}
+
+/// Fill level of the log
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LogStats {
+    /// Records not consumed yet
+    pub pending: u32,
+    /// Records overwritten before they were consumed, since mounting
+    pub dropped: u32,
+    /// Bytes from the oldest pending record to the end of the log
+    pub used_bytes: u32,
+    pub capacity_bytes: u32,
+}
+
+impl LogStats {
+    /// Used space in percent of the partition
+    pub fn fill_percent(&self) -> u8 {
+        match self.capacity_bytes {
+            0 => 0,
+            capacity => (u64::from(self.used_bytes) * 100 / u64::from(capacity)) as u8,
+        }
+    }
+}
+
+/// A pending record, as returned by `RingLog::peek`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Entry {
+    sector: u32,
+    offset: u32,
+    len: u16,
+}
+
+impl Entry {
+    /// Payload length in bytes
+    pub fn payload_len(&self) -> usize {
+        usize::from(self.len)
+    }
+
+    fn end(&self) -> u32 {
+        self.offset + record_len(self.payload_len())
+    }
+}
+
+/// What a record slot holds
+enum Slot {
+    /// Erased; the sector continues from here
+    Free,
+    /// Damaged or torn; nothing after it can be trusted
+    Invalid,
+    Record { len: u16, pending: bool },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    sector: u32,
+    offset: u32,
+}
+
+/// Sector taking appends
+#[derive(Debug, Clone, Copy)]
+struct Head {
+    sector: u32,
+    sequence: u32,
+    /// Where the next record goes; the sector size once it is full or sealed
+    free: u32,
+}
+
+const fn padded(len: usize) -> usize {
+    len.div_ceil(ALIGN) * ALIGN
+}
+
+const fn record_len(payload_len: usize) -> u32 {
+    (RECORD_HEADER_LEN + padded(payload_len)) as u32
+}
+
+/// Ring log on a NOR flash partition of at least two erase sectors
+pub struct RingLog<F> {
+    flash: F,
+    partition: Partition,
+    sectors: u32,
+    /// `None` until the first record is appended to an empty log
+    head: Option<Head>,
+    /// At or before the oldest pending record
+    cursor: Position,
+    pending: u32,
+    dropped: u32,
+}
+
+impl<F: NorFlash> RingLog<F> {
+    /// Scan the partition and pick up where the log left off
+    pub fn mount(flash: F, partition: Partition) -> Result<Self, LogError> {
+        debug_assert!(ALIGN % F::WRITE_SIZE == 0 && ALIGN % F::READ_SIZE == 0);
+        let sectors = partition.sectors(F::ERASE_SIZE as u32);
+        debug_assert!(sectors >= 2);
+
+        let mut log = Self {
+            flash,
+            partition,
+            sectors,
+            head: None,
+            cursor: Position { sector: 0, offset: SECTOR_HEADER_LEN },
+            pending: 0,
+            dropped: 0,
+        };
+
+        // Oldest and newest sectors in use, as (sector, sequence)
+        let mut oldest: Option<(u32, u32)> = None;
+        let mut newest: Option<(u32, u32)> = None;
+        for sector in 0..sectors {
+            if let Some(sequence) = log.sequence(sector)? {
+                if oldest.map_or(true, |(_, s)| sequence < s) {
+                    oldest = Some((sector, sequence));
+                }
+                if newest.map_or(true, |(_, s)| sequence > s) {
+                    newest = Some((sector, sequence));
+                }
+            }
+        }
+        let (Some((oldest, _)), Some((newest, sequence))) = (oldest, newest) else {
+            return Ok(log);
+        };
+
+        // Sectors are started in ring order, so walk from the oldest to the newest
+        let mut free = Self::sector_size();
+        let mut sector = oldest;
+        loop {
+            if log.sequence(sector)?.is_some() {
+                let (pending, end) = log.scan_sector(sector)?;
+                log.pending += pending;
+                free = end;
+            }
+            if sector == newest {
+                break;
+            }
+            sector = log.next(sector);
+        }
+        log.head = Some(Head { sector: newest, sequence, free });
+        log.cursor = Position { sector: oldest, offset: SECTOR_HEADER_LEN };
+        log.seek()?;
+        Ok(log)
+    }
+
+    /// Release the flash driver
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    fn sector_size() -> u32 {
+        F::ERASE_SIZE as u32
+    }
+
+    fn address(&self, sector: u32, offset: u32) -> u32 {
+        self.partition.offset + sector * Self::sector_size() + offset
+    }
+
+    fn next(&self, sector: u32) -> u32 {
+        (sector + 1) % self.sectors
+    }
+
+    /// Sequence number of a sector, `None` if it isn't in use
+    fn sequence(&mut self, sector: u32) -> Result<Option<u32>, LogError> {
+        let mut header = [0; SECTOR_HEADER_LEN as usize];
+        self.flash.read(self.address(sector, 0), &mut header).map_err(|_| LogError::Flash)?;
+        Ok((header[..4] == MAGIC).then(|| u32::from_le_bytes([header[4], header[5], header[6], header[7]])))
+    }
+
+    /// Read and check the record slot at `offset`, leaving its payload in `payload`
+    fn read_slot(&mut self, sector: u32, offset: u32, payload: &mut [u8; MAX_RECORD]) -> Result<Slot, LogError> {
+        if offset + RECORD_HEADER_LEN as u32 > Self::sector_size() {
+            return Ok(Slot::Invalid);
+        }
+        let mut header = [0; RECORD_HEADER_LEN];
+        self.flash.read(self.address(sector, offset), &mut header).map_err(|_| LogError::Flash)?;
+        if header.iter().all(|b| *b == 0xFF) {
+            return Ok(Slot::Free);
+        }
+
+        let len = u16::from_le_bytes([header[0], header[1]]);
+        let check = u16::from_le_bytes([header[2], header[3]]);
+        if len != !check || usize::from(len) > MAX_RECORD || offset + record_len(usize::from(len)) > Self::sector_size() {
+            return Ok(Slot::Invalid);
+        }
+        let padded = &mut payload[..padded(usize::from(len))];
+        self.flash
+            .read(self.address(sector, offset + RECORD_HEADER_LEN as u32), padded)
+            .map_err(|_| LogError::Flash)?;
+        let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if crc32_update(crc32(&padded[..usize::from(len)]), &header[..4]) != crc {
+            return Ok(Slot::Invalid);
+        }
+        let pending = header[8..12] == [0xFF; 4];
+        Ok(Slot::Record { len, pending })
+    }
+
+    /// Count a sector's pending records and find where it can take the next one
+    fn scan_sector(&mut self, sector: u32) -> Result<(u32, u32), LogError> {
+        let mut payload = [0; MAX_RECORD];
+        let mut pending = 0;
+        let mut offset = SECTOR_HEADER_LEN;
+        loop {
+            match self.read_slot(sector, offset, &mut payload)? {
+                Slot::Free => return Ok((pending, offset)),
+                Slot::Invalid => return Ok((pending, Self::sector_size())),
+                Slot::Record { len, pending: is_pending } => {
+                    pending += u32::from(is_pending);
+                    offset += record_len(usize::from(len));
+                }
+            }
+        }
+    }
+
+    /// Move the cursor to the oldest pending record and return it
+    fn seek(&mut self) -> Result<Option<Entry>, LogError> {
+        let Some(head) = self.head else {
+            return Ok(None);
+        };
+        let mut payload = [0; MAX_RECORD];
+        loop {
+            let Position { sector, offset } = self.cursor;
+            let slot = match sector == head.sector && offset >= head.free {
+                true => Slot::Free,
+                false => self.read_slot(sector, offset, &mut payload)?,
+            };
+            match slot {
+                Slot::Record { len, pending: true } => return Ok(Some(Entry { sector, offset, len })),
+                Slot::Record { len, pending: false } => self.cursor.offset += record_len(usize::from(len)),
+                Slot::Free | Slot::Invalid if sector == head.sector => return Ok(None),
+                Slot::Free | Slot::Invalid => self.cursor = Position { sector: self.next(sector), offset: SECTOR_HEADER_LEN },
+            }
+        }
+    }
+
+    /// Oldest pending record, with its payload copied into `buf`
+    /// It stays pending until passed to `consume`
+    pub fn peek(&mut self, buf: &mut [u8]) -> Result<Option<Entry>, LogError> {
+        let Some(entry) = self.seek()? else {
+            return Ok(None);
+        };
+        let out = buf.get_mut(..entry.payload_len()).ok_or(LogError::TooLarge)?;
+        let mut payload = [0; MAX_RECORD];
+        match self.read_slot(entry.sector, entry.offset, &mut payload)? {
+            Slot::Record { .. } => out.copy_from_slice(&payload[..entry.payload_len()]),
+            // Checked by `seek` a moment ago
+            _ => return Err(LogError::Flash),
+        }
+        Ok(Some(entry))
+    }
+
+    /// Mark a record returned by `peek` as handled
+    pub fn consume(&mut self, entry: Entry) -> Result<(), LogError> {
+        self.flash
+            .write(self.address(entry.sector, entry.offset + MARKER_OFFSET), &[0; 4])
+            .map_err(|_| LogError::Flash)?;
+        self.pending = self.pending.saturating_sub(1);
+        if self.cursor.sector == entry.sector && self.cursor.offset == entry.offset {
+            self.cursor.offset = entry.end();
+        }
+        Ok(())
+    }
+
+    /// Erase the next sector in the ring and start writing there
+    fn start_sector(&mut self) -> Result<Head, LogError> {
+        let (sector, sequence) = match self.head {
+            Some(head) => (self.next(head.sector), head.sequence.wrapping_add(1)),
+            None => (0, 1),
+        };
+
+        // Whatever is still pending there is lost
+        if self.head.is_some() && self.sequence(sector)?.is_some() {
+            let (pending, _) = self.scan_sector(sector)?;
+            self.pending -= pending;
+            self.dropped += pending;
+        }
+        if self.head.is_none() || self.cursor.sector == sector {
+            let oldest = if self.head.is_none() { sector } else { self.next(sector) };
+            self.cursor = Position { sector: oldest, offset: SECTOR_HEADER_LEN };
+        }
+
+        let start = self.address(sector, 0);
+        self.flash.erase(start, start + Self::sector_size()).map_err(|_| LogError::Flash)?;
+        let mut header = [0; SECTOR_HEADER_LEN as usize];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4..].copy_from_slice(&sequence.to_le_bytes());
+        self.flash.write(start, &header).map_err(|_| LogError::Flash)?;
+
+        let head = Head { sector, sequence, free: SECTOR_HEADER_LEN };
+        self.head = Some(head);
+        Ok(head)
+    }
+
+    /// Append a record, erasing the oldest sector if the log is full
+    pub fn append(&mut self, payload: &[u8]) -> Result<(), LogError> {
+        if payload.len() > MAX_RECORD {
+            return Err(LogError::TooLarge);
+        }
+        let len = record_len(payload.len());
+        let head = match self.head {
+            Some(head) if head.free + len <= Self::sector_size() => head,
+            _ => self.start_sector()?,
+        };
+
+        let mut record = [0xFF; RECORD_HEADER_LEN + MAX_RECORD];
+        let length = payload.len() as u16;
+        record[0..2].copy_from_slice(&length.to_le_bytes());
+        record[2..4].copy_from_slice(&(!length).to_le_bytes());
+        let crc = crc32_update(crc32(payload), &record[..4]);
+        record[4..8].copy_from_slice(&crc.to_le_bytes());
+        record[RECORD_HEADER_LEN..RECORD_HEADER_LEN + payload.len()].copy_from_slice(payload);
+
+        if self.flash.write(self.address(head.sector, head.free), &record[..len as usize]).is_err() {
+            // Whatever made it to flash can't be overwritten; seal the sector
+            self.head = Some(Head { free: Self::sector_size(), ..head });
+            return Err(LogError::Flash);
+        }
+        self.head = Some(Head { free: head.free + len, ..head });
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Current fill level
+    pub fn stats(&self) -> LogStats {
+        let capacity_bytes = self.sectors * Self::sector_size();
+        let used_bytes = match self.head {
+            Some(head) if self.pending > 0 => {
+                let sectors = (head.sector + self.sectors - self.cursor.sector) % self.sectors;
+                sectors * Self::sector_size() + head.free - self.cursor.offset
+            }
+            _ => 0,
+        };
+        LogStats { pending: self.pending, dropped: self.dropped, used_bytes, capacity_bytes }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+    use crate::storage::RING_LOG_PARTITION;
+
+    const SECTOR: u32 = 4096;
+
+    fn mount() -> RingLog<RamFlash> {
+        RingLog::mount(RamFlash::new(RING_LOG_PARTITION.end() as usize), RING_LOG_PARTITION).unwrap()
+    }
+
+    fn remount(log: RingLog<RamFlash>) -> RingLog<RamFlash> {
+        RingLog::mount(log.into_inner(), RING_LOG_PARTITION).unwrap()
+    }
+
+    /// 20-byte payload numbered `n`, 32 bytes on flash
+    fn record(n: u32) -> [u8; 20] {
+        let mut payload = [0xA5; 20];
+        payload[..4].copy_from_slice(&n.to_le_bytes());
+        payload
+    }
+
+    /// Consume the oldest record and return its number
+    fn pop(log: &mut RingLog<RamFlash>) -> Option<u32> {
+        let mut buf = [0; MAX_RECORD];
+        let entry = log.peek(&mut buf).unwrap()?;
+        assert_eq!(entry.payload_len(), 20);
+        log.consume(entry).unwrap();
+        Some(u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]))
+    }
+
+    #[test]
+    fn test_append_and_drain() {
+        let mut log = mount();
+        assert_eq!(log.stats(), LogStats { capacity_bytes: 4 * SECTOR, ..Default::default() });
+        assert_eq!(pop(&mut log), None);
+
+        for n in 0..3 {
+            log.append(&record(n)).unwrap();
+        }
+        assert_eq!(log.stats().pending, 3);
+        assert_eq!(log.stats().used_bytes, 3 * 32);
+
+        // Peeking doesn't consume
+        let mut buf = [0; MAX_RECORD];
+        assert_eq!(log.peek(&mut buf).unwrap(), log.peek(&mut buf).unwrap());
+        assert_eq!(log.peek(&mut [0; 4]), Err(LogError::TooLarge));
+
+        assert_eq!(pop(&mut log), Some(0));
+        log.append(&record(3)).unwrap();
+        assert_eq!((1..4).map(|_| pop(&mut log).unwrap()).collect::<std::vec::Vec<_>>(), [1, 2, 3]);
+        assert_eq!(pop(&mut log), None);
+        assert_eq!(log.stats().pending, 0);
+        assert_eq!(log.stats().used_bytes, 0);
+
+        assert_eq!(log.append(&[0; MAX_RECORD + 1]), Err(LogError::TooLarge));
+        log.append(&[]).unwrap();
+        assert_eq!(log.peek(&mut buf).unwrap().unwrap().payload_len(), 0);
+    }
+
+    #[test]
+    fn test_survives_reboot() {
+        let mut log = mount();
+        for n in 0..5 {
+            log.append(&record(n)).unwrap();
+        }
+        assert_eq!(pop(&mut log), Some(0));
+        assert_eq!(pop(&mut log), Some(1));
+
+        let mut log = remount(log);
+        assert_eq!(log.stats().pending, 3);
+        assert_eq!(log.stats().used_bytes, 3 * 32);
+        log.append(&record(5)).unwrap();
+        assert_eq!((2..6).map(|_| pop(&mut log).unwrap()).collect::<std::vec::Vec<_>>(), [2, 3, 4, 5]);
+
+        let mut log = remount(log);
+        assert_eq!(pop(&mut log), None);
+    }
+
+    #[test]
+    fn test_wraparound_drops_oldest() {
+        let per_sector = (SECTOR - SECTOR_HEADER_LEN) / 32;
+        let total = 5 * per_sector;
+        let mut log = mount();
+        for n in 0..total {
+            log.append(&record(n)).unwrap();
+        }
+
+        // The fifth sector reused the first
+        let stats = log.stats();
+        assert_eq!(stats.dropped, per_sector);
+        assert_eq!(stats.pending, 4 * per_sector);
+        assert_eq!(stats.fill_percent(), 99);
+        for sector in 0..4 {
+            let erases = log.flash.erase_count(RING_LOG_PARTITION.offset + sector * SECTOR);
+            assert_eq!(erases, if sector == 0 { 2 } else { 1 });
+        }
+
+        // Oldest survivors first, across a reboot and the wrap
+        assert_eq!(pop(&mut log), Some(per_sector));
+        let mut log = remount(log);
+        assert_eq!(log.stats().pending, 4 * per_sector - 1);
+        for n in per_sector + 1..total {
+            assert_eq!(pop(&mut log), Some(n));
+        }
+        assert_eq!(pop(&mut log), None);
+
+        // Overwriting a partly consumed sector only drops what was pending
+        let mut log = mount();
+        for n in 0..4 * per_sector {
+            log.append(&record(n)).unwrap();
+        }
+        for n in 0..per_sector / 2 {
+            assert_eq!(pop(&mut log), Some(n));
+        }
+        log.append(&record(4 * per_sector)).unwrap();
+        assert_eq!(log.stats().dropped, per_sector - per_sector / 2);
+        assert_eq!(pop(&mut log), Some(per_sector));
+    }
+
+    #[test]
+    fn test_torn_append() {
+        for torn_bytes in [0, 4, 12, 20] {
+            let mut log = mount();
+            log.append(&record(0)).unwrap();
+            log.append(&record(1)).unwrap();
+            log.flash.tear_next_write(torn_bytes);
+            assert_eq!(log.append(&record(2)), Err(LogError::Flash));
+
+            // Carries on in the same session, and after a reboot
+            log.append(&record(3)).unwrap();
+            let mut log = remount(log);
+            assert_eq!(log.stats().pending, 3, "torn after {} bytes", torn_bytes);
+            log.append(&record(4)).unwrap();
+            assert_eq!((0..4).map(|_| pop(&mut log).unwrap()).collect::<std::vec::Vec<_>>(), [0, 1, 3, 4]);
+            assert_eq!(pop(&mut log), None);
+        }
+    }
+
+    #[test]
+    fn test_torn_sector_start() {
+        let per_sector = (SECTOR - SECTOR_HEADER_LEN) / 32;
+        let mut log = mount();
+        for n in 0..per_sector {
+            log.append(&record(n)).unwrap();
+        }
+        // Power lost while writing the next sector's header
+        log.flash.tear_next_write(2);
+        assert_eq!(log.append(&record(per_sector)), Err(LogError::Flash));
+
+        let mut log = remount(log);
+        assert_eq!(log.stats().pending, per_sector);
+        log.append(&record(per_sector + 1)).unwrap();
+        for n in 0..per_sector {
+            assert_eq!(pop(&mut log), Some(n));
+        }
+        assert_eq!(pop(&mut log), Some(per_sector + 1));
+    }
+
+    #[test]
+    fn test_torn_consume() {
+        let mut log = mount();
+        log.append(&record(0)).unwrap();
+        log.append(&record(1)).unwrap();
+
+        let mut buf = [0; MAX_RECORD];
+        let entry = log.peek(&mut buf).unwrap().unwrap();
+        log.flash.tear_next_write(1);
+        assert_eq!(log.consume(entry), Err(LogError::Flash));
+
+        // A partly cleared marker counts as consumed
+        let mut log = remount(log);
+        assert_eq!(log.stats().pending, 1);
+        assert_eq!(pop(&mut log), Some(1));
+    }
+
+    #[test]
+    fn test_corruption_ends_sector() {
+        let mut log = mount();
+        for n in 0..3 {
+            log.append(&record(n)).unwrap();
+        }
+        // Flip a payload bit of the second record
+        let second = (RING_LOG_PARTITION.offset + SECTOR_HEADER_LEN + 32 + 12) as usize;
+        let mut flash = log.into_inner();
+        flash.data_mut()[second] ^= 0x01;
+
+        let mut log = RingLog::mount(flash, RING_LOG_PARTITION).unwrap();
+        assert_eq!(log.stats().pending, 1);
+        log.append(&record(3)).unwrap();
+        assert_eq!(pop(&mut log), Some(0));
+        assert_eq!(pop(&mut log), Some(3));
+        assert_eq!(pop(&mut log), None);
+    }
+}
@@ -0,0 +1,730 @@
+//! Air quality indices
+//!
+//! Turns concentrations into the indices people know from weather apps:
+//!
+//! - US EPA AQI, 0..500, with the PM2.5 breakpoints revised in 2024; PM
+//!   uses 24-hour means (or the NowCast of hourly ones), CO 8-hour means
+//! - European CAQI, 0..100 and above, on hourly means (background grid)
+//! - UK DAQI, 1..10, on 24-hour PM means; it has no CO band
+//!
+//! Each index is linear within the bands of its breakpoint table. The
+//! functions take concentrations already averaged over the period the scale
+//! expects; `nowcast` turns hourly PM means into the EPA's short-term
+//! estimate. For whole readings the worst pollutant sets the index, and
+//! humidity-corrected PM is used where present.
+//!
+//! The aggregator records every reading into the global `AQI` tracker,
+//! which keeps hourly means per pollutant and turns them into the current
+//! indices for the API, metrics, Home Assistant and the log. While the
+//! history fills up after boot, means cover the hours there are.
+
+use core::cell::RefCell;
+use core::iter;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use heapless::{Deque, Vec};
+
+use super::{Field, SensorData, SensorReading};
+
+/// Micrograms per cubic metre of CO in one ppm, at 20 °C and 1013 hPa
+/// (the EU reference conditions)
+pub const CO_UG_PER_M3_PER_PPM: f32 = 1165.0;
+
+/// Hours of data the NowCast looks at
+pub const NOWCAST_HOURS: usize = 12;
+
+/// Hours of means the tracker keeps, enough for 24-hour means
+pub const HISTORY_HOURS: usize = 24;
+
+const HOUR_MS: u64 = 3_600_000;
+
+/// Pollutants the tracker follows, each covering its corrected field
+const POLLUTANTS: [Field; 3] = [Field::Pm25, Field::Pm10, Field::CoPpm];
+
+/// Concentration range and the index range it maps onto
+type Band = (f32, f32, u16, u16);
+
+/// µg/m³, 24-hour mean, truncated to 0.1
+const EPA_PM25: [Band; 6] = [
+    (0.0, 9.0, 0, 50),
+    (9.1, 35.4, 51, 100),
+    (35.5, 55.4, 101, 150),
+    (55.5, 125.4, 151, 200),
+    (125.5, 225.4, 201, 300),
+    (225.5, 325.4, 301, 500),
+];
+
+/// µg/m³, 24-hour mean, truncated to an integer
+const EPA_PM10: [Band; 6] = [
+    (0.0, 54.0, 0, 50),
+    (55.0, 154.0, 51, 100),
+    (155.0, 254.0, 101, 150),
+    (255.0, 354.0, 151, 200),
+    (355.0, 424.0, 201, 300),
+    (425.0, 604.0, 301, 500),
+];
+
+/// ppm, 8-hour mean, truncated to 0.1
+const EPA_CO: [Band; 6] = [
+    (0.0, 4.4, 0, 50),
+    (4.5, 9.4, 51, 100),
+    (9.5, 12.4, 101, 150),
+    (12.5, 15.4, 151, 200),
+    (15.5, 30.4, 201, 300),
+    (30.5, 50.4, 301, 500),
+];
+
+/// µg/m³, hourly mean; the last band is extended beyond 100
+const CAQI_PM25: [Band; 4] = [(0.0, 15.0, 0, 25), (15.0, 30.0, 25, 50), (30.0, 55.0, 50, 75), (55.0, 110.0, 75, 100)];
+const CAQI_PM10: [Band; 4] = [(0.0, 25.0, 0, 25), (25.0, 50.0, 25, 50), (50.0, 90.0, 50, 75), (90.0, 180.0, 75, 100)];
+const CAQI_CO: [Band; 4] = [
+    (0.0, 5000.0, 0, 25),
+    (5000.0, 7500.0, 25, 50),
+    (7500.0, 10000.0, 50, 75),
+    (10000.0, 20000.0, 75, 100),
+];
+
+/// µg/m³, 24-hour mean: highest rounded concentration of indices 1..9;
+/// anything above is 10
+const DAQI_PM25: [u16; 9] = [11, 23, 35, 41, 47, 53, 58, 64, 70];
+const DAQI_PM10: [u16; 9] = [16, 33, 50, 58, 66, 75, 83, 91, 100];
+
+/// An index value and the category it falls in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Index<C> {
+    pub value: u16,
+    pub category: C,
+}
+
+/// US EPA AQI categories
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AqiCategory {
+    Good,
+    Moderate,
+    UnhealthyForSensitiveGroups,
+    Unhealthy,
+    VeryUnhealthy,
+    Hazardous,
+}
+
+/// European CAQI levels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CaqiLevel {
+    VeryLow,
+    Low,
+    Medium,
+    High,
+    VeryHigh,
+}
+
+/// UK DAQI bands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DaqiBand {
+    Low,
+    Moderate,
+    High,
+    VeryHigh,
+}
+
+impl AqiCategory {
+    const ALL: [AqiCategory; 6] = [
+        AqiCategory::Good,
+        AqiCategory::Moderate,
+        AqiCategory::UnhealthyForSensitiveGroups,
+        AqiCategory::Unhealthy,
+        AqiCategory::VeryUnhealthy,
+        AqiCategory::Hazardous,
+    ];
+
+    /// Machine-friendly name
+    pub fn name(&self) -> &'static str {
+        match self {
+            AqiCategory::Good => "good",
+            AqiCategory::Moderate => "moderate",
+            AqiCategory::UnhealthyForSensitiveGroups => "unhealthy_for_sensitive_groups",
+            AqiCategory::Unhealthy => "unhealthy",
+            AqiCategory::VeryUnhealthy => "very_unhealthy",
+            AqiCategory::Hazardous => "hazardous",
+        }
+    }
+
+    /// Name as the EPA spells it
+    pub fn label(&self) -> &'static str {
+        match self {
+            AqiCategory::Good => "Good",
+            AqiCategory::Moderate => "Moderate",
+            AqiCategory::UnhealthyForSensitiveGroups => "Unhealthy for Sensitive Groups",
+            AqiCategory::Unhealthy => "Unhealthy",
+            AqiCategory::VeryUnhealthy => "Very Unhealthy",
+            AqiCategory::Hazardous => "Hazardous",
+        }
+    }
+}
+
+impl CaqiLevel {
+    const ALL: [CaqiLevel; 5] = [CaqiLevel::VeryLow, CaqiLevel::Low, CaqiLevel::Medium, CaqiLevel::High, CaqiLevel::VeryHigh];
+
+    /// Machine-friendly name
+    pub fn name(&self) -> &'static str {
+        match self {
+            CaqiLevel::VeryLow => "very_low",
+            CaqiLevel::Low => "low",
+            CaqiLevel::Medium => "medium",
+            CaqiLevel::High => "high",
+            CaqiLevel::VeryHigh => "very_high",
+        }
+    }
+}
+
+impl DaqiBand {
+    /// Band of an index value, 1..10
+    fn of(value: u16) -> Self {
+        match value {
+            0..=3 => DaqiBand::Low,
+            4..=6 => DaqiBand::Moderate,
+            7..=9 => DaqiBand::High,
+            _ => DaqiBand::VeryHigh,
+        }
+    }
+
+    /// Machine-friendly name
+    pub fn name(&self) -> &'static str {
+        match self {
+            DaqiBand::Low => "low",
+            DaqiBand::Moderate => "moderate",
+            DaqiBand::High => "high",
+            DaqiBand::VeryHigh => "very_high",
+        }
+    }
+}
+
+/// Linear interpolation within a band, rounded to the nearest integer
+fn interpolate(&(lo, hi, index_lo, index_hi): &Band, concentration: f32) -> u16 {
+    let index = f32::from(index_hi - index_lo) / (hi - lo) * (concentration - lo) + f32::from(index_lo);
+    // Saturating cast
+    libm::roundf(index) as u16
+}
+
+/// Concentrations a scale can't take
+fn usable(concentration: f32) -> bool {
+    concentration.is_finite() && concentration >= 0.0
+}
+
+/// US EPA AQI of a PM2.5, PM10 (µg/m³) or CO (ppm) concentration; values
+/// beyond the table are 500
+pub fn us_aqi(field: Field, concentration: f32) -> Option<Index<AqiCategory>> {
+    let (table, truncated) = match field {
+        Field::Pm25 | Field::Pm25Corrected => (&EPA_PM25, libm::floorf(concentration * 10.0) / 10.0),
+        Field::Pm10 | Field::Pm10Corrected => (&EPA_PM10, libm::floorf(concentration)),
+        Field::CoPpm => (&EPA_CO, libm::floorf(concentration * 10.0) / 10.0),
+        _ => return None,
+    };
+    if !usable(concentration) {
+        return None;
+    }
+    let index = match table.iter().position(|band| truncated <= band.1) {
+        Some(i) => Index { value: interpolate(&table[i], truncated), category: AqiCategory::ALL[i] },
+        None => Index { value: 500, category: AqiCategory::Hazardous },
+    };
+    Some(index)
+}
+
+/// European CAQI of a PM2.5, PM10 (µg/m³) or CO (ppm) concentration
+pub fn caqi(field: Field, concentration: f32) -> Option<Index<CaqiLevel>> {
+    let (table, concentration) = match field {
+        Field::Pm25 | Field::Pm25Corrected => (&CAQI_PM25, concentration),
+        Field::Pm10 | Field::Pm10Corrected => (&CAQI_PM10, concentration),
+        Field::CoPpm => (&CAQI_CO, concentration * CO_UG_PER_M3_PER_PPM),
+        _ => return None,
+    };
+    if !usable(concentration) {
+        return None;
+    }
+    let index = match table.iter().position(|band| concentration <= band.1) {
+        Some(i) => Index { value: interpolate(&table[i], concentration), category: CaqiLevel::ALL[i] },
+        None => Index { value: interpolate(&table[table.len() - 1], concentration), category: CaqiLevel::VeryHigh },
+    };
+    Some(index)
+}
+
+/// UK DAQI of a PM2.5 or PM10 concentration (µg/m³)
+pub fn daqi(field: Field, concentration: f32) -> Option<Index<DaqiBand>> {
+    let table = match field {
+        Field::Pm25 | Field::Pm25Corrected => &DAQI_PM25,
+        Field::Pm10 | Field::Pm10Corrected => &DAQI_PM10,
+        _ => return None,
+    };
+    if !usable(concentration) {
+        return None;
+    }
+    let rounded = libm::roundf(concentration);
+    let value = 1 + table.iter().filter(|upper| rounded > f32::from(**upper)).count() as u16;
+    Some(Index { value, category: DaqiBand::of(value) })
+}
+
+/// EPA NowCast of hourly PM means, most recent hour first
+///
+/// Uses up to `NOWCAST_HOURS` hours, `None` for hours without data. Needs
+/// two of the three most recent hours; recent hours weigh more the faster
+/// the concentration changes.
+pub fn nowcast(hourly: &[Option<f32>]) -> Option<f32> {
+    let hours = &hourly[..hourly.len().min(NOWCAST_HOURS)];
+    let valid = |c: &&Option<f32>| c.is_some_and(usable);
+    if hours.iter().take(3).filter(valid).count() < 2 {
+        return None;
+    }
+    let values = hours.iter().filter(valid).flatten();
+    let (min, max) = values.clone().fold((f32::INFINITY, 0.0f32), |(min, max), c| (min.min(*c), max.max(*c)));
+    let weight = if max > 0.0 { (min / max).max(0.5) } else { 1.0 };
+
+    let (mut sum, mut weights, mut factor) = (0.0, 0.0, 1.0);
+    for hour in hours {
+        if let Some(c) = hour.filter(|c| usable(*c)) {
+            sum += factor * c;
+            weights += factor;
+        }
+        factor *= weight;
+    }
+    Some(sum / weights)
+}
+
+/// Current value of every scale, each with the pollutant that sets it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Indices {
+    pub us_aqi: Option<(Field, Index<AqiCategory>)>,
+    pub caqi: Option<(Field, Index<CaqiLevel>)>,
+    pub daqi: Option<(Field, Index<DaqiBand>)>,
+}
+
+/// One scale's value for consumers that treat the scales alike
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScaleValue {
+    /// Machine-friendly scale name: `us_aqi`, `caqi` or `daqi`
+    pub scale: &'static str,
+    /// Name for people: `US AQI`, `CAQI` or `DAQI`
+    pub label: &'static str,
+    /// Pollutant that sets the index
+    pub pollutant: Field,
+    pub value: u16,
+    /// Machine-friendly category name
+    pub category: &'static str,
+}
+
+impl Indices {
+    /// Machine-friendly names of the scales, in `scales()` order
+    pub const SCALES: [&'static str; 3] = ["us_aqi", "caqi", "daqi"];
+
+    pub fn is_empty(&self) -> bool {
+        self.us_aqi.is_none() && self.caqi.is_none() && self.daqi.is_none()
+    }
+
+    /// Every scale, `None` where it is unknown
+    pub fn scales(&self) -> [Option<ScaleValue>; 3] {
+        let [us_aqi, caqi, daqi] = Self::SCALES;
+        let value = |(scale, label), pollutant, value, category| ScaleValue { scale, label, pollutant, value, category };
+        [
+            self.us_aqi.map(|(field, i)| value((us_aqi, "US AQI"), field, i.value, i.category.name())),
+            self.caqi.map(|(field, i)| value((caqi, "CAQI"), field, i.value, i.category.name())),
+            self.daqi.map(|(field, i)| value((daqi, "DAQI"), field, i.value, i.category.name())),
+        ]
+    }
+}
+
+impl core::fmt::Display for Indices {
+    /// `us_aqi 62 moderate (pm25), daqi 2 low (pm25)`, skipping unknown ones
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (i, scale) in self.scales().into_iter().flatten().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{} {} {} ({})", scale.scale, scale.value, scale.category, scale.pollutant.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// Hourly means of one pollutant
+#[derive(Debug, Clone)]
+struct HourlyMeans {
+    /// Hour since boot the running sums belong to
+    hour: u64,
+    sum: f32,
+    count: u32,
+    /// Means of the hours before, most recent first
+    past: Deque<Option<f32>, HISTORY_HOURS>,
+}
+
+impl HourlyMeans {
+    const fn new() -> Self {
+        Self { hour: 0, sum: 0.0, count: 0, past: Deque::new() }
+    }
+
+    fn current(&self) -> Option<f32> {
+        (self.count > 0).then(|| self.sum / self.count as f32)
+    }
+
+    fn record(&mut self, value: f32, timestamp: u64) {
+        let hour = timestamp / HOUR_MS;
+        if hour > self.hour {
+            let closed = iter::once(self.current()).chain(iter::repeat(None));
+            for mean in closed.take((hour - self.hour).min(HISTORY_HOURS as u64) as usize) {
+                if self.past.is_full() {
+                    self.past.pop_back();
+                }
+                let _ = self.past.push_front(mean);
+            }
+            (self.hour, self.sum, self.count) = (hour, 0.0, 0);
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Hourly means up to `now_ms`, the running hour first
+    fn hourly(&self, now_ms: u64) -> Vec<Option<f32>, { HISTORY_HOURS + 1 }> {
+        let quiet = (now_ms / HOUR_MS).saturating_sub(self.hour) as usize;
+        iter::repeat(None)
+            .take(quiet)
+            .chain(iter::once(self.current()))
+            .chain(self.past.iter().copied())
+            .take(HISTORY_HOURS + 1)
+            .collect()
+    }
+}
+
+/// Mean of the hourly means there are among the `hours` most recent
+fn mean(hourly: &[Option<f32>], hours: usize) -> Option<f32> {
+    let values = hourly.iter().take(hours).flatten();
+    let count = values.clone().count();
+    (count > 0).then(|| values.sum::<f32>() / count as f32)
+}
+
+/// Hourly means of every pollutant, turned into indices on demand
+#[derive(Debug, Clone)]
+pub struct AqiTracker {
+    /// Indexed like `POLLUTANTS`
+    means: [HourlyMeans; POLLUTANTS.len()],
+}
+
+impl Default for AqiTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AqiTracker {
+    pub const fn new() -> Self {
+        Self { means: [const { HourlyMeans::new() }; POLLUTANTS.len()] }
+    }
+
+    /// Add the pollutants of a reading; bad readings are ignored
+    pub fn record(&mut self, reading: &SensorReading) {
+        if !reading.is_valid() {
+            return;
+        }
+        for (field, value) in pollutants(&reading.data) {
+            let pollutant = match field {
+                Field::Pm25Corrected => Field::Pm25,
+                Field::Pm10Corrected => Field::Pm10,
+                field => field,
+            };
+            if let Some(i) = POLLUTANTS.iter().position(|p| *p == pollutant).filter(|_| usable(value)) {
+                self.means[i].record(value, reading.timestamp);
+            }
+        }
+    }
+
+    /// Indices at `now_ms`: the US AQI from the NowCast of PM and 8-hour
+    /// CO means, the CAQI from the latest hourly means and the DAQI from
+    /// 24-hour PM means
+    pub fn indices(&self, now_ms: u64) -> Indices {
+        let mut indices = Indices::default();
+        for (field, means) in POLLUTANTS.into_iter().zip(&self.means) {
+            let hourly = means.hourly(now_ms);
+            let (aqi_mean, daqi_mean) = match field {
+                Field::CoPpm => (mean(&hourly, 8), None),
+                _ => (nowcast(&hourly), mean(&hourly, 24)),
+            };
+            let hour = hourly.iter().take(2).flatten().next().copied();
+            let candidates = (
+                aqi_mean.and_then(|c| us_aqi(field, c)),
+                hour.and_then(|c| caqi(field, c)),
+                daqi_mean.and_then(|c| daqi(field, c)),
+            );
+            indices.us_aqi = worse(indices.us_aqi, candidates.0.map(|i| (field, i)));
+            indices.caqi = worse(indices.caqi, candidates.1.map(|i| (field, i)));
+            indices.daqi = worse(indices.daqi, candidates.2.map(|i| (field, i)));
+        }
+        indices
+    }
+}
+
+/// The higher of two indices
+fn worse<C>(a: Option<(Field, Index<C>)>, b: Option<(Field, Index<C>)>) -> Option<(Field, Index<C>)> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if b.1.value > a.1.value { b } else { a }),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Indices of the running device
+pub static AQI: Mutex<CriticalSectionRawMutex, RefCell<AqiTracker>> = Mutex::new(RefCell::new(AqiTracker::new()));
+
+/// Record a reading in the global tracker
+pub fn record(reading: &SensorReading) {
+    AQI.lock(|aqi| aqi.borrow_mut().record(reading));
+}
+
+/// Current indices from the global tracker
+pub fn current_indices() -> Indices {
+    AQI.lock(|aqi| aqi.borrow().indices(crate::time::now_ms()))
+}
+
+/// Pollutants of a reading the indices cover, with corrected PM replacing
+/// the raw values where present
+fn pollutants(data: &SensorData) -> Vec<(Field, f32), 3> {
+    let pm25 = data.value(Field::Pm25Corrected).map(|v| (Field::Pm25Corrected, v));
+    let pm10 = data.value(Field::Pm10Corrected).map(|v| (Field::Pm10Corrected, v));
+    let candidates = [
+        pm25.or_else(|| data.value(Field::Pm25).map(|v| (Field::Pm25, v))),
+        pm10.or_else(|| data.value(Field::Pm10).map(|v| (Field::Pm10, v))),
+        data.value(Field::CoPpm).map(|v| (Field::CoPpm, v)),
+    ];
+    candidates.into_iter().flatten().collect()
+}
+
+/// Index of the worst pollutant, with the field it came from
+fn worst<C>(data: &SensorData, index: fn(Field, f32) -> Option<Index<C>>) -> Option<(Field, Index<C>)> {
+    pollutants(data)
+        .into_iter()
+        .filter_map(|(field, concentration)| Some((field, index(field, concentration)?)))
+        .max_by_key(|(_, index)| index.value)
+}
+
+/// US EPA AQI of a reading
+pub fn us_aqi_of(data: &SensorData) -> Option<(Field, Index<AqiCategory>)> {
+    worst(data, us_aqi)
+}
+
+/// European CAQI of a reading
+pub fn caqi_of(data: &SensorData) -> Option<(Field, Index<CaqiLevel>)> {
+    worst(data, caqi)
+}
+
+/// UK DAQI of a reading
+pub fn daqi_of(data: &SensorData) -> Option<(Field, Index<DaqiBand>)> {
+    worst(data, daqi)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::{Flags, Quality, SensorType};
+
+    #[test]
+    fn test_us_aqi_breakpoints() {
+        use AqiCategory::*;
+        let cases = [
+            (Field::Pm25, 0.0, 0, Good),
+            (Field::Pm25, 9.0, 50, Good),
+            (Field::Pm25, 9.09, 50, Good),
+            (Field::Pm25, 9.1, 51, Moderate),
+            (Field::Pm25, 35.4, 100, Moderate),
+            (Field::Pm25, 35.5, 101, UnhealthyForSensitiveGroups),
+            (Field::Pm25, 55.4, 150, UnhealthyForSensitiveGroups),
+            (Field::Pm25, 55.5, 151, Unhealthy),
+            (Field::Pm25, 125.4, 200, Unhealthy),
+            (Field::Pm25, 125.5, 201, VeryUnhealthy),
+            (Field::Pm25, 225.4, 300, VeryUnhealthy),
+            (Field::Pm25, 225.5, 301, Hazardous),
+            (Field::Pm25, 325.4, 500, Hazardous),
+            (Field::Pm25, 900.0, 500, Hazardous),
+            (Field::Pm25, 20.0, 71, Moderate),
+            (Field::Pm25Corrected, 35.5, 101, UnhealthyForSensitiveGroups),
+            (Field::Pm10, 54.0, 50, Good),
+            (Field::Pm10, 54.9, 50, Good),
+            (Field::Pm10, 55.0, 51, Moderate),
+            (Field::Pm10, 154.0, 100, Moderate),
+            (Field::Pm10, 155.0, 101, UnhealthyForSensitiveGroups),
+            (Field::Pm10, 254.0, 150, UnhealthyForSensitiveGroups),
+            (Field::Pm10, 255.0, 151, Unhealthy),
+            (Field::Pm10, 355.0, 201, VeryUnhealthy),
+            (Field::Pm10, 425.0, 301, Hazardous),
+            (Field::Pm10, 604.0, 500, Hazardous),
+            (Field::CoPpm, 4.4, 50, Good),
+            (Field::CoPpm, 4.5, 51, Moderate),
+            (Field::CoPpm, 9.5, 101, UnhealthyForSensitiveGroups),
+            (Field::CoPpm, 12.5, 151, Unhealthy),
+            (Field::CoPpm, 15.5, 201, VeryUnhealthy),
+            (Field::CoPpm, 30.5, 301, Hazardous),
+            (Field::CoPpm, 50.4, 500, Hazardous),
+        ];
+        for (field, concentration, value, category) in cases {
+            assert_eq!(us_aqi(field, concentration), Some(Index { value, category }), "{:?} {}", field, concentration);
+        }
+        assert_eq!(us_aqi(Field::Temperature, 20.0), None);
+        assert_eq!(us_aqi(Field::Pm25, -1.0), None);
+        assert_eq!(us_aqi(Field::Pm25, f32::NAN), None);
+    }
+
+    #[test]
+    fn test_caqi_breakpoints() {
+        use CaqiLevel::*;
+        let cases = [
+            (Field::Pm25, 0.0, 0, VeryLow),
+            (Field::Pm25, 15.0, 25, VeryLow),
+            (Field::Pm25, 22.5, 38, Low),
+            (Field::Pm25, 30.0, 50, Low),
+            (Field::Pm25, 55.0, 75, Medium),
+            (Field::Pm25, 110.0, 100, High),
+            (Field::Pm25, 165.0, 125, VeryHigh),
+            (Field::Pm10, 25.0, 25, VeryLow),
+            (Field::Pm10, 50.0, 50, Low),
+            (Field::Pm10, 70.0, 63, Medium),
+            (Field::Pm10, 90.0, 75, Medium),
+            (Field::Pm10, 180.0, 100, High),
+            (Field::Pm10, 270.0, 125, VeryHigh),
+            // 1 ppm is 1165 µg/m³
+            (Field::CoPpm, 1.0, 6, VeryLow),
+            (Field::CoPpm, 5.0, 33, Low),
+            (Field::CoPpm, 10.0, 79, High),
+            (Field::CoPpm, 20.0, 108, VeryHigh),
+        ];
+        for (field, concentration, value, category) in cases {
+            assert_eq!(caqi(field, concentration), Some(Index { value, category }), "{:?} {}", field, concentration);
+        }
+        assert_eq!(caqi(Field::Humidity, 50.0), None);
+    }
+
+    #[test]
+    fn test_daqi_breakpoints() {
+        use DaqiBand::*;
+        let cases = [
+            (Field::Pm25, 0.0, 1, Low),
+            (Field::Pm25, 11.4, 1, Low),
+            (Field::Pm25, 11.5, 2, Low),
+            (Field::Pm25, 35.0, 3, Low),
+            (Field::Pm25, 36.0, 4, Moderate),
+            (Field::Pm25, 53.0, 6, Moderate),
+            (Field::Pm25, 54.0, 7, High),
+            (Field::Pm25, 70.0, 9, High),
+            (Field::Pm25, 71.0, 10, VeryHigh),
+            (Field::Pm10, 16.0, 1, Low),
+            (Field::Pm10, 17.0, 2, Low),
+            (Field::Pm10, 51.0, 4, Moderate),
+            (Field::Pm10, 76.0, 7, High),
+            (Field::Pm10, 100.0, 9, High),
+            (Field::Pm10, 101.0, 10, VeryHigh),
+        ];
+        for (field, concentration, value, category) in cases {
+            assert_eq!(daqi(field, concentration), Some(Index { value, category }), "{:?} {}", field, concentration);
+        }
+        assert_eq!(daqi(Field::CoPpm, 5.0), None);
+    }
+
+    #[test]
+    fn test_nowcast() {
+        // Steady air: plain mean
+        assert_eq!(nowcast(&[Some(10.0); 12]), Some(10.0));
+        // EPA worked example: weight 0.5 after a rising trend
+        let hours = [Some(40.0), Some(20.0), Some(10.0)];
+        let expected = (40.0 + 0.5 * 20.0 + 0.25 * 10.0) / 1.75;
+        assert!((nowcast(&hours).unwrap() - expected).abs() < 1e-4);
+        // Gaps keep their place in the weighting; older hours are ignored
+        let mut hours = [None; 14];
+        hours[0] = Some(20.0);
+        hours[2] = Some(10.0);
+        hours[13] = Some(1000.0);
+        let expected = (20.0 + 0.25 * 10.0) / 1.25;
+        assert!((nowcast(&hours).unwrap() - expected).abs() < 1e-4);
+        // Two of the three most recent hours are needed
+        assert_eq!(nowcast(&[Some(20.0), None, None, Some(10.0)]), None);
+        assert_eq!(nowcast(&[]), None);
+        assert_eq!(nowcast(&[Some(0.0), Some(0.0)]), Some(0.0));
+    }
+
+    #[test]
+    fn test_reading_index() {
+        let pm = SensorData::AirQuality { pm25: Some(40.0), pm10: Some(60.0), pm25_corrected: None, pm10_corrected: None };
+        let (field, index) = us_aqi_of(&pm).unwrap();
+        assert_eq!((field, index.category), (Field::Pm25, AqiCategory::UnhealthyForSensitiveGroups));
+        // Scales can disagree on the worst pollutant
+        assert_eq!(daqi_of(&pm).unwrap(), (Field::Pm10, Index { value: 5, category: DaqiBand::Moderate }));
+
+        // Corrected values win
+        let corrected = SensorData::AirQuality { pm25: Some(40.0), pm10: Some(60.0), pm25_corrected: Some(20.0), pm10_corrected: Some(40.0) };
+        assert_eq!(us_aqi_of(&corrected).unwrap(), (Field::Pm25Corrected, Index { value: 71, category: AqiCategory::Moderate }));
+
+        let gas = SensorData::Gas { co_ppm: Some(10.0), co2_ppm: Some(800), voc_index: None };
+        assert_eq!(caqi_of(&gas).unwrap(), (Field::CoPpm, Index { value: 79, category: CaqiLevel::High }));
+        assert_eq!(daqi_of(&gas), None);
+
+        let env = SensorData::Environmental { temperature: Some(20.0), humidity: None, pressure: None, gas_resistance: None, dew_point: None, absolute_humidity: None, heat_index: None, sea_level_pressure: None };
+        assert_eq!(us_aqi_of(&env), None);
+    }
+
+    fn reading(data: SensorData, timestamp: u64) -> SensorReading {
+        SensorReading {
+            sensor_type: SensorType::SDS011,
+            data,
+            timestamp,
+            utc_timestamp: None,
+            quality: Quality::Good,
+            flags: Flags::NONE,
+        }
+    }
+
+    fn pm(pm25: f32, pm10: f32, timestamp: u64) -> SensorReading {
+        reading(SensorData::AirQuality { pm25: Some(pm25), pm10: Some(pm10), pm25_corrected: None, pm10_corrected: None }, timestamp)
+    }
+
+    #[test]
+    fn test_tracker() {
+        let mut tracker = AqiTracker::new();
+        assert!(tracker.indices(0).is_empty());
+
+        // One hour is enough for the CAQI and DAQI, the NowCast needs two
+        tracker.record(&pm(20.0, 30.0, 0));
+        tracker.record(&pm(30.0, 50.0, 60_000));
+        let indices = tracker.indices(120_000);
+        assert_eq!(indices.us_aqi, None);
+        assert_eq!(indices.caqi, Some((Field::Pm25, Index { value: 42, category: CaqiLevel::Low })));
+        assert_eq!(indices.daqi, Some((Field::Pm25, Index { value: 3, category: DaqiBand::Low })));
+
+        tracker.record(&pm(10.0, 20.0, HOUR_MS));
+        let indices = tracker.indices(HOUR_MS + 60_000);
+        // NowCast of 10 and 25 is 15, 62 on the US scale
+        assert_eq!(indices.us_aqi, Some((Field::Pm25, Index { value: 62, category: AqiCategory::Moderate })));
+        assert_eq!(indices.caqi, Some((Field::Pm10, Index { value: 20, category: CaqiLevel::VeryLow })));
+        // 24-hour means of 17.5 and 30
+        assert_eq!(indices.daqi.unwrap().1.value, 2);
+
+        // CO from another sensor joins in, bad readings don't
+        let co = |ppm, timestamp| reading(SensorData::Gas { co_ppm: Some(ppm), co2_ppm: None, voc_index: None }, timestamp);
+        tracker.record(&co(12.0, HOUR_MS + 1));
+        let mut bad = pm(500.0, 500.0, HOUR_MS + 2);
+        bad.quality = Quality::Bad;
+        tracker.record(&bad);
+        let indices = tracker.indices(HOUR_MS + 60_000);
+        assert_eq!(indices.us_aqi, Some((Field::CoPpm, Index { value: 143, category: AqiCategory::UnhealthyForSensitiveGroups })));
+        assert_eq!(indices.caqi.unwrap().0, Field::CoPpm);
+
+        // Old data ages out: the running hour went quiet two hours ago
+        let indices = tracker.indices(3 * HOUR_MS);
+        assert_eq!(indices.us_aqi.unwrap().0, Field::CoPpm);
+        assert_eq!(indices.caqi, None);
+        assert_eq!(
+            std::format!("{}", tracker.indices(HOUR_MS)),
+            "us_aqi 143 unhealthy_for_sensitive_groups (co_ppm), caqi 85 high (co_ppm), daqi 2 low (pm25)"
+        );
+        assert!(tracker.indices(30 * HOUR_MS).is_empty());
+    }
+
+    #[test]
+    fn test_tracker_prefers_corrected_pm() {
+        let mut tracker = AqiTracker::new();
+        let data = SensorData::AirQuality { pm25: Some(40.0), pm10: None, pm25_corrected: Some(10.0), pm10_corrected: None };
+        tracker.record(&reading(data, 0));
+        assert_eq!(tracker.indices(0).caqi, Some((Field::Pm25, Index { value: 17, category: CaqiLevel::VeryLow })));
+    }
+}
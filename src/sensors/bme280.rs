@@ -1,10 +1,6 @@
 use super::{Sensor, SensorReading, SensorError, SensorData, SensorType, SensorInfo, Quality};
 use embassy_time::{Duration, Timer, with_timeout};
-use esp_hal::i2c::I2c as EspI2c;
-use esp_hal::peripherals::I2C0;
-
-/// Type alias for the concrete I2C type we use
-pub type Bme280I2c = EspI2c<'static, I2C0, esp_hal::Async>;
+use embedded_hal_async::i2c::I2c;
 
 /// BME280 I2C addresses
 const BME280_ADDRESS_PRIMARY: u8 = 0x76;
@@ -17,10 +13,13 @@ const BME280_CHIP_ID: u8 = 0x60;
 const BME280_REG_CHIP_ID: u8 = 0xD0;
 const BME280_REG_RESET: u8 = 0xE0;
 const BME280_REG_CTRL_HUM: u8 = 0xF2;
+const BME280_REG_STATUS: u8 = 0xF3;
 const BME280_REG_CTRL_MEAS: u8 = 0xF4;
 const BME280_REG_CONFIG: u8 = 0xF5;
 const BME280_REG_PRESS_MSB: u8 = 0xF7;
+#[allow(dead_code)]
 const BME280_REG_TEMP_MSB: u8 = 0xFA;
+#[allow(dead_code)]
 const BME280_REG_HUM_MSB: u8 = 0xFD;
 
 /// Calibration register starts
@@ -28,12 +27,177 @@ const BME280_REG_DIG_T1: u8 = 0x88;
 const BME280_REG_DIG_H1: u8 = 0xA1;
 const BME280_REG_DIG_H2: u8 = 0xE1;
 
+/// Written to the reset register for a power-on reset
+const BME280_RESET_COMMAND: u8 = 0xB6;
+
+/// Status register bits: a conversion is running, calibration data is
+/// being copied from NVM
+const BME280_STATUS_MEASURING: u8 = 1 << 3;
+const BME280_STATUS_IM_UPDATE: u8 = 1 << 0;
+
+/// Raw values of a channel that didn't convert (skipped or stuck ADC)
+const BME280_ADC_NO_DATA: u32 = 0x80000;
+const BME280_ADC_HUM_NO_DATA: u32 = 0x8000;
+
+/// Start-up time after a reset, datasheet table 1
+const BME280_STARTUP_TIME: Duration = Duration::from_millis(2);
+
+/// Longest the NVM copy after a reset may take
+const BME280_NVM_COPY_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Time between status register polls
+const BME280_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Failed reads in a row after which the sensor is reset
+const BME280_MAX_FAILURES: u8 = 3;
+
+/// `ctrl_meas` mode bits
+const BME280_MODE_FORCED: u8 = 0b01;
+const BME280_MODE_NORMAL: u8 = 0b11;
+
+/// Oversampling of one measurement channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Oversampling {
+    /// Channel not measured
+    Skip,
+    X1,
+    X2,
+    X4,
+    X8,
+    X16,
+}
+
+impl Oversampling {
+    /// `osrs_x` register bits
+    pub fn bits(self) -> u8 {
+        self as u8
+    }
+
+    /// Conversions per measurement
+    pub fn samples(self) -> u32 {
+        match self {
+            Oversampling::Skip => 0,
+            other => 1 << (other as u32 - 1),
+        }
+    }
+}
+
+/// IIR filter coefficient, smooths pressure and temperature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IirFilter {
+    Off,
+    X2,
+    X4,
+    X8,
+    X16,
+}
+
+/// Inactive time between measurements in normal mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Standby {
+    Ms0_5,
+    Ms62_5,
+    Ms125,
+    Ms250,
+    Ms500,
+    Ms1000,
+    Ms10,
+    Ms20,
+}
+
+/// Measurement mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// One measurement per read, the sensor sleeps in between
+    Forced,
+    /// Continuous measurements; reads return the latest one
+    Normal(Standby),
+}
+
+/// Sensor settings, see datasheet section 3.5 for recommended modes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bme280Config {
+    /// Pressure and humidity compensation need the temperature, so it is
+    /// measured at least once even if skipped
+    pub temperature: Oversampling,
+    pub pressure: Oversampling,
+    /// `Skip` turns the sensor into a BMP280-like one
+    pub humidity: Oversampling,
+    pub filter: IirFilter,
+    pub mode: Mode,
+}
+
+impl Default for Bme280Config {
+    /// Weather monitoring: single samples, filter off, forced mode
+    fn default() -> Self {
+        Self {
+            temperature: Oversampling::X1,
+            pressure: Oversampling::X1,
+            humidity: Oversampling::X1,
+            filter: IirFilter::Off,
+            mode: Mode::Forced,
+        }
+    }
+}
+
+impl Bme280Config {
+    /// Same settings without humidity measurements
+    pub fn without_humidity(self) -> Self {
+        Self { humidity: Oversampling::Skip, ..self }
+    }
+
+    /// `ctrl_hum` register value
+    pub fn ctrl_hum(&self) -> u8 {
+        self.humidity.bits()
+    }
+
+    /// `ctrl_meas` register value, starting a measurement in forced mode
+    pub fn ctrl_meas(&self) -> u8 {
+        let mode = match self.mode {
+            Mode::Forced => BME280_MODE_FORCED,
+            Mode::Normal(_) => BME280_MODE_NORMAL,
+        };
+        (self.temperature.max(Oversampling::X1).bits() << 5) | (self.pressure.bits() << 2) | mode
+    }
+
+    /// `config` register value
+    pub fn config(&self) -> u8 {
+        let standby = match self.mode {
+            Mode::Forced => 0,
+            Mode::Normal(standby) => standby as u8,
+        };
+        (standby << 5) | ((self.filter as u8) << 2)
+    }
+
+    /// Longest a measurement takes, in microseconds (datasheet section 9.1)
+    pub fn max_measurement_time_us(&self) -> u32 {
+        let channel = |oversampling: Oversampling| match oversampling {
+            Oversampling::Skip => 0,
+            os => 2300 * os.samples() + 575,
+        };
+        1250 + 2300 * self.temperature.max(Oversampling::X1).samples() + channel(self.pressure) + channel(self.humidity)
+    }
+
+    /// Longest a measurement takes
+    pub fn max_measurement_time(&self) -> Duration {
+        Duration::from_micros(u64::from(self.max_measurement_time_us()))
+    }
+}
+
+/// Status polls that fit into `timeout`
+fn max_polls(timeout: Duration) -> u64 {
+    timeout.as_micros() / BME280_POLL_INTERVAL.as_micros() + 1
+}
+
 /// BME280 Environmental sensor (Temperature, Humidity, Pressure)
-/// Communicates via I2C
-pub struct Bme280Sensor {
-    i2c: Bme280I2c,
+/// Communicates via any async I2C bus
+pub struct Bme280Sensor<I2C> {
+    i2c: I2C,
     address: u8,
+    config: Bme280Config,
     initialized: bool,
+    /// Failed reads since the last good one or reset
+    failures: u8,
     // Calibration coefficients
     dig_t1: u16,
     dig_t2: i16,
@@ -57,13 +221,20 @@ pub struct Bme280Sensor {
     t_fine: i32,
 }
 
-impl Bme280Sensor {
-    /// Create new BME280 sensor instance
-    pub fn new(i2c: Bme280I2c) -> Self {
+impl<I2C: I2c> Bme280Sensor<I2C> {
+    /// Create new BME280 sensor instance with the default settings
+    pub fn new(i2c: I2C) -> Self {
+        Self::with_config(i2c, Bme280Config::default())
+    }
+
+    /// Create new BME280 sensor instance with the given settings
+    pub fn with_config(i2c: I2C, config: Bme280Config) -> Self {
         Self {
             i2c,
             address: BME280_ADDRESS_PRIMARY, // Will try both addresses during init
+            config,
             initialized: false,
+            failures: 0,
             // Initialize calibration coefficients to zero
             dig_t1: 0, dig_t2: 0, dig_t3: 0,
             dig_p1: 0, dig_p2: 0, dig_p3: 0, dig_p4: 0, dig_p5: 0,
@@ -123,6 +294,32 @@ impl Bme280Sensor {
         Err(SensorError::HardwareFailure)
     }
 
+    /// Poll the status register until none of `bits` are set
+    async fn wait_status_clear(&mut self, bits: u8, timeout: Duration) -> Result<(), SensorError> {
+        for _ in 0..max_polls(timeout) {
+            if self.read_register(BME280_REG_STATUS).await? & bits == 0 {
+                return Ok(());
+            }
+            Timer::after(BME280_POLL_INTERVAL).await;
+        }
+        Err(SensorError::Timeout)
+    }
+
+    /// Soft reset, leaving the sensor asleep with fresh calibration data
+    /// copied from NVM
+    async fn soft_reset(&mut self) -> Result<(), SensorError> {
+        self.write_register(BME280_REG_RESET, BME280_RESET_COMMAND).await?;
+        Timer::after(BME280_STARTUP_TIME).await;
+        self.wait_status_clear(BME280_STATUS_IM_UPDATE, BME280_NVM_COPY_TIMEOUT).await
+    }
+
+    /// Reset the sensor and bring it back to the configured state
+    async fn reset_and_configure(&mut self) -> Result<(), SensorError> {
+        self.soft_reset().await?;
+        self.read_calibration().await?;
+        self.configure_sensor().await
+    }
+
     /// Read calibration coefficients from the sensor
     async fn read_calibration(&mut self) -> Result<(), SensorError> {
         // Read temperature and pressure calibration data
@@ -182,27 +379,32 @@ impl Bme280Sensor {
         Ok(())
     }
 
-    /// Configure sensor for forced mode measurements  
+    /// Apply oversampling, filter and mode settings
     async fn configure_sensor(&mut self) -> Result<(), SensorError> {
-        // Set humidity oversampling (1x)
-        self.write_register(BME280_REG_CTRL_HUM, 0x01).await?;
+        // Humidity oversampling only takes effect with the ctrl_meas write
+        self.write_register(BME280_REG_CTRL_HUM, self.config.ctrl_hum()).await?;
         
-        // Set temperature and pressure oversampling (1x) and forced mode
-        self.write_register(BME280_REG_CTRL_MEAS, 0x25).await?;
+        // Filter and standby writes may be ignored in normal mode, so they
+        // go before the mode is set
+        self.write_register(BME280_REG_CONFIG, self.config.config()).await?;
         
-        // Set filter and standby time (filter off, standby 1000ms)
-        self.write_register(BME280_REG_CONFIG, 0xA0).await?;
+        // Oversampling and mode; forced mode starts a first measurement
+        self.write_register(BME280_REG_CTRL_MEAS, self.config.ctrl_meas()).await?;
         
         Ok(())
     }
 
     /// Read raw sensor data and compensate using calibration
-    async fn read_compensated_data(&mut self) -> Result<(f32, f32, f32), SensorError> {
-        // Trigger forced mode measurement
-        self.write_register(BME280_REG_CTRL_MEAS, 0x25).await?;
-        
-        // Wait for measurement to complete
-        Timer::after(Duration::from_millis(50)).await;
+    ///
+    /// Returns temperature, humidity and pressure; skipped channels are
+    /// `None`.
+    async fn read_compensated_data(&mut self) -> Result<(f32, Option<f32>, Option<f32>), SensorError> {
+        if self.config.mode == Mode::Forced {
+            // Trigger a measurement and wait until it is done, allowing
+            // for a slow oscillator
+            self.write_register(BME280_REG_CTRL_MEAS, self.config.ctrl_meas()).await?;
+            self.wait_status_clear(BME280_STATUS_MEASURING, self.config.max_measurement_time() * 2).await?;
+        }
         
         // Read all measurement data at once (8 bytes starting from pressure)
         let mut data = [0u8; 8];
@@ -213,18 +415,26 @@ impl Bme280Sensor {
         let temp_raw = ((data[3] as u32) << 12) | ((data[4] as u32) << 4) | ((data[5] as u32) >> 4);
         let hum_raw = ((data[6] as u32) << 8) | (data[7] as u32);
         
+        // An enabled channel reading the no-data value means the ADC is stuck
+        let stuck = temp_raw == BME280_ADC_NO_DATA
+            || (self.config.pressure != Oversampling::Skip && press_raw == BME280_ADC_NO_DATA)
+            || (self.config.humidity != Oversampling::Skip && hum_raw == BME280_ADC_HUM_NO_DATA);
+        if stuck {
+            return Err(SensorError::InvalidData);
+        }
+        
         // Compensate temperature first (needed for pressure and humidity)
         let temperature = self.compensate_temperature(temp_raw);
-        let pressure = self.compensate_pressure(press_raw);
-        let humidity = self.compensate_humidity(hum_raw);
+        let pressure = (self.config.pressure != Oversampling::Skip).then(|| self.compensate_pressure(press_raw));
+        let humidity = (self.config.humidity != Oversampling::Skip).then(|| self.compensate_humidity(hum_raw));
         
         Ok((temperature, humidity, pressure))
     }
 
     /// Temperature compensation formula from BME280 datasheet
     fn compensate_temperature(&mut self, adc_t: u32) -> f32 {
-        let var1 = (((adc_t >> 3) as i32) - ((self.dig_t1 << 1) as i32)) * (self.dig_t2 as i32) >> 11;
-        let var2 = (((((adc_t >> 4) as i32) - (self.dig_t1 as i32)) * (((adc_t >> 4) as i32) - (self.dig_t1 as i32))) >> 12) * (self.dig_t3 as i32) >> 14;
+        let var1 = ((((adc_t >> 3) as i32) - ((self.dig_t1 as i32) << 1)) * (self.dig_t2 as i32)) >> 11;
+        let var2 = ((((((adc_t >> 4) as i32) - (self.dig_t1 as i32)) * (((adc_t >> 4) as i32) - (self.dig_t1 as i32))) >> 12) * (self.dig_t3 as i32)) >> 14;
         
         self.t_fine = var1 + var2;
         ((self.t_fine * 5 + 128) >> 8) as f32 / 100.0
@@ -234,8 +444,8 @@ impl Bme280Sensor {
     fn compensate_pressure(&self, adc_p: u32) -> f32 {
         let mut var1: i64 = (self.t_fine as i64) - 128000;
         let mut var2: i64 = var1 * var1 * (self.dig_p6 as i64);
-        var2 = var2 + ((var1 * (self.dig_p5 as i64)) << 17);
-        var2 = var2 + ((self.dig_p4 as i64) << 35);
+        var2 += (var1 * (self.dig_p5 as i64)) << 17;
+        var2 += (self.dig_p4 as i64) << 35;
         var1 = ((var1 * var1 * (self.dig_p3 as i64)) >> 8) + ((var1 * (self.dig_p2 as i64)) << 12);
         var1 = ((((1i64) << 47) + var1) * (self.dig_p1 as i64)) >> 33;
 
@@ -264,28 +474,25 @@ impl Bme280Sensor {
                         2097152) * (self.dig_h2 as i32) + 8192) >> 14);
 
         // Second calculation: subtract term with dig_H1
-        v_x1_u32r = v_x1_u32r - (((((v_x1_u32r >> 15) * (v_x1_u32r >> 15)) >> 7) *
-                                  (self.dig_h1 as i32)) >> 4);
+        v_x1_u32r -= ((((v_x1_u32r >> 15) * (v_x1_u32r >> 15)) >> 7) *
+                      (self.dig_h1 as i32)) >> 4;
 
         // Clamp result
-        let v_x1_u32r = if v_x1_u32r < 0 { 0 } else { v_x1_u32r };
-        let v_x1_u32r = if v_x1_u32r > 419430400 { 419430400 } else { v_x1_u32r };
+        let v_x1_u32r = v_x1_u32r.clamp(0, 419430400);
 
         (v_x1_u32r >> 12) as f32 / 1024.0
     }
 }
 
-impl Sensor for Bme280Sensor {
+impl<I2C: I2c + Send> Sensor for Bme280Sensor<I2C> {
     async fn init(&mut self) -> Result<(), SensorError> {
         // Find sensor at correct I2C address
         self.find_sensor().await?;
         
-        // Read calibration coefficients
-        self.read_calibration().await?;
-        
-        // Configure sensor
-        self.configure_sensor().await?;
+        // Start from a clean state, read calibration and configure
+        self.reset_and_configure().await?;
         
+        self.failures = 0;
         self.initialized = true;
         Ok(())
     }
@@ -295,13 +502,21 @@ impl Sensor for Bme280Sensor {
             return Err(SensorError::NotInitialized);
         }
         
+        // A sensor that keeps failing may have glitched; reset it
+        if self.failures >= BME280_MAX_FAILURES {
+            self.reset_and_configure().await?;
+            self.failures = 0;
+        }
+        
         // Read compensated sensor data
-        match self.read_compensated_data().await {
+        let result = self.read_compensated_data().await;
+        self.failures = if result.is_ok() { 0 } else { self.failures.saturating_add(1) };
+        match result {
             Ok((temperature, humidity, pressure)) => {
-                // Validate reasonable ranges
-                let temp_valid = temperature >= -40.0 && temperature <= 85.0;
-                let hum_valid = humidity >= 0.0 && humidity <= 100.0;
-                let press_valid = pressure >= 300.0 && pressure <= 1100.0;
+                // Validate reasonable ranges; skipped channels don't count
+                let temp_valid = (-40.0..=85.0).contains(&temperature);
+                let hum_valid = humidity.is_none_or(|h| (0.0..=100.0).contains(&h));
+                let press_valid = pressure.is_none_or(|p| (300.0..=1100.0).contains(&p));
                 
                 let quality = if temp_valid && hum_valid && press_valid {
                     Quality::Good
@@ -311,9 +526,14 @@ impl Sensor for Bme280Sensor {
                 
                 let data = SensorData::Environmental {
                     temperature: if temp_valid { Some(temperature) } else { None },
-                    humidity: if hum_valid { Some(humidity) } else { None },
-                    pressure: if press_valid { Some(pressure) } else { None },
+                    humidity: humidity.filter(|_| hum_valid),
+                    pressure: pressure.filter(|_| press_valid),
                     gas_resistance: None, // BME280 doesn't have gas sensor (BME680 does)
+                    // Filled in by the derived-metrics stage
+                    dew_point: None,
+                    absolute_humidity: None,
+                    heat_index: None,
+                    sea_level_pressure: None,
                 };
                 
                 Ok(SensorReading::new(SensorType::BME280, data, quality))
@@ -338,4 +558,376 @@ impl Sensor for Bme280Sensor {
     fn reading_interval(&self) -> Duration {
         Duration::from_secs(30) // Standard interval
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{block_on, I2cTransaction, MockI2c};
+    use embedded_hal_async::i2c::{ErrorKind, NoAcknowledgeSource};
+
+    const ADDR: u8 = BME280_ADDRESS_PRIMARY;
+
+    /// Temperature/pressure calibration from the datasheet example (section 8.2)
+    fn calibration_tp() -> [u8; 24] {
+        let words: [u16; 12] = [
+            27504, 26435, -1000i16 as u16,
+            36477, -10685i16 as u16, 3024, 2855, 140, -7i16 as u16, 15500, -14600i16 as u16, 6000,
+        ];
+        let mut buf = [0u8; 24];
+        for (i, w) in words.iter().enumerate() {
+            buf[i * 2..i * 2 + 2].copy_from_slice(&w.to_le_bytes());
+        }
+        buf
+    }
+
+    /// Humidity calibration H2..H6 packed as in registers 0xE1..0xE7
+    /// (H2=362, H3=0, H4=313, H5=50, H6=30)
+    fn calibration_h() -> [u8; 7] {
+        let h4: i16 = 313;
+        let h5: i16 = 50;
+        [
+            0x6A, 0x01,
+            0,
+            (h4 >> 4) as u8,
+            ((h4 & 0x0F) as u8) | (((h5 & 0x0F) as u8) << 4),
+            (h5 >> 4) as u8,
+            30,
+        ]
+    }
+
+    fn init_script(address: u8) -> std::vec::Vec<I2cTransaction> {
+        init_script_with(address, Bme280Config::default())
+    }
+
+    fn init_script_with(address: u8, config: Bme280Config) -> std::vec::Vec<I2cTransaction> {
+        let mut script = std::vec![I2cTransaction::write_read(address, &[BME280_REG_CHIP_ID], &[BME280_CHIP_ID])];
+        script.extend(reset_script(address, config));
+        script
+    }
+
+    /// Soft reset, NVM copy already done, calibration and configuration
+    fn reset_script(address: u8, config: Bme280Config) -> std::vec::Vec<I2cTransaction> {
+        std::vec![
+            I2cTransaction::write(address, &[BME280_REG_RESET, BME280_RESET_COMMAND]),
+            status(address, 0),
+            I2cTransaction::write_read(address, &[BME280_REG_DIG_T1], &calibration_tp()),
+            I2cTransaction::write_read(address, &[BME280_REG_DIG_H1], &[75]),
+            I2cTransaction::write_read(address, &[BME280_REG_DIG_H2], &calibration_h()),
+            I2cTransaction::write(address, &[BME280_REG_CTRL_HUM, config.ctrl_hum()]),
+            I2cTransaction::write(address, &[BME280_REG_CONFIG, config.config()]),
+            I2cTransaction::write(address, &[BME280_REG_CTRL_MEAS, config.ctrl_meas()]),
+        ]
+    }
+
+    fn status(address: u8, status: u8) -> I2cTransaction {
+        I2cTransaction::write_read(address, &[BME280_REG_STATUS], &[status])
+    }
+
+    /// Burst read of 0xF7..0xFE for the given raw ADC values
+    fn measurement(adc_p: u32, adc_t: u32, adc_h: u16) -> [u8; 8] {
+        [
+            (adc_p >> 12) as u8, (adc_p >> 4) as u8, ((adc_p & 0x0F) << 4) as u8,
+            (adc_t >> 12) as u8, (adc_t >> 4) as u8, ((adc_t & 0x0F) << 4) as u8,
+            (adc_h >> 8) as u8, adc_h as u8,
+        ]
+    }
+
+    fn measurement_script(data: [u8; 8]) -> [I2cTransaction; 3] {
+        [
+            I2cTransaction::write(ADDR, &[BME280_REG_CTRL_MEAS, 0x25]),
+            status(ADDR, 0),
+            I2cTransaction::write_read(ADDR, &[BME280_REG_PRESS_MSB], &data),
+        ]
+    }
+
+    fn initialized_sensor() -> Bme280Sensor<MockI2c> {
+        let mut sensor = Bme280Sensor::new(MockI2c::new(init_script(ADDR)));
+        block_on(sensor.init()).unwrap();
+        sensor
+    }
+
+    fn environmental(reading: &SensorReading) -> (Option<f32>, Option<f32>, Option<f32>) {
+        match reading.data {
+            SensorData::Environmental { temperature, humidity, pressure, .. } => (temperature, humidity, pressure),
+            ref other => panic!("unexpected data {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_config_registers() {
+        // Weather monitoring, the previous fixed setup
+        let config = Bme280Config::default();
+        assert_eq!((config.ctrl_hum(), config.ctrl_meas(), config.config()), (0x01, 0x25, 0x00));
+
+        // Indoor navigation (datasheet section 3.5.3)
+        let config = Bme280Config {
+            temperature: Oversampling::X2,
+            pressure: Oversampling::X16,
+            humidity: Oversampling::X1,
+            filter: IirFilter::X16,
+            mode: Mode::Normal(Standby::Ms0_5),
+        };
+        assert_eq!((config.ctrl_hum(), config.ctrl_meas(), config.config()), (0x01, 0x57, 0x10));
+
+        let config = Bme280Config { mode: Mode::Normal(Standby::Ms1000), filter: IirFilter::X4, ..Default::default() };
+        assert_eq!((config.ctrl_meas(), config.config()), (0x27, 0xA8));
+        let config = Bme280Config { mode: Mode::Normal(Standby::Ms20), ..Default::default() }.without_humidity();
+        assert_eq!((config.ctrl_hum(), config.config()), (0x00, 0xE0));
+
+        // Temperature is always measured
+        let config = Bme280Config { temperature: Oversampling::Skip, ..Default::default() };
+        assert_eq!(config.ctrl_meas(), 0x25);
+        assert_eq!(Oversampling::X16.bits(), 0b101);
+        assert_eq!(Oversampling::X8.samples(), 8);
+    }
+
+    #[test]
+    fn test_max_measurement_time() {
+        // Datasheet section 9.2: weather monitoring 9.3 ms, indoor
+        // navigation 46.1 ms
+        assert_eq!(Bme280Config::default().max_measurement_time_us(), 9300);
+        assert_eq!(Bme280Config::default().without_humidity().max_measurement_time_us(), 6425);
+        let config = Bme280Config {
+            temperature: Oversampling::X2,
+            pressure: Oversampling::X16,
+            ..Default::default()
+        };
+        assert_eq!(config.max_measurement_time_us(), 46_100);
+        assert_eq!(config.max_measurement_time(), Duration::from_micros(46_100));
+    }
+
+    #[test]
+    fn test_init_reads_calibration_and_configures() {
+        let sensor = initialized_sensor();
+        assert!(sensor.initialized);
+        assert_eq!(sensor.address, ADDR);
+        assert_eq!((sensor.dig_t1, sensor.dig_t2, sensor.dig_t3), (27504, 26435, -1000));
+        assert_eq!((sensor.dig_p1, sensor.dig_p9), (36477, 6000));
+        assert_eq!((sensor.dig_h1, sensor.dig_h2, sensor.dig_h3), (75, 362, 0));
+        assert_eq!((sensor.dig_h4, sensor.dig_h5, sensor.dig_h6), (313, 50, 30));
+        sensor.i2c.done();
+    }
+
+    #[test]
+    fn test_init_falls_back_to_secondary_address() {
+        let nack = ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address);
+        let mut script = std::vec![
+            I2cTransaction::write_read(ADDR, &[BME280_REG_CHIP_ID], &[0]).with_error(nack),
+        ];
+        script.extend(init_script(BME280_ADDRESS_SECONDARY));
+        let mut sensor = Bme280Sensor::new(MockI2c::new(script));
+
+        block_on(sensor.init()).unwrap();
+        assert_eq!(sensor.address, BME280_ADDRESS_SECONDARY);
+        sensor.i2c.done();
+    }
+
+    #[test]
+    fn test_init_rejects_wrong_chip_id() {
+        // A BMP280 answers with chip ID 0x58
+        let mut sensor = Bme280Sensor::new(MockI2c::new([
+            I2cTransaction::write_read(ADDR, &[BME280_REG_CHIP_ID], &[0x58]),
+            I2cTransaction::write_read(BME280_ADDRESS_SECONDARY, &[BME280_REG_CHIP_ID], &[0x58]),
+        ]));
+
+        assert!(matches!(block_on(sensor.init()), Err(SensorError::HardwareFailure)));
+        assert!(!sensor.initialized);
+        sensor.i2c.done();
+    }
+
+    #[test]
+    fn test_init_times_out_on_stalled_bus() {
+        let mut sensor = Bme280Sensor::new(MockI2c::new([
+            I2cTransaction::write_read(ADDR, &[BME280_REG_CHIP_ID], &[BME280_CHIP_ID]),
+            I2cTransaction::write(ADDR, &[BME280_REG_RESET, BME280_RESET_COMMAND]),
+            status(ADDR, 0),
+            I2cTransaction::write_read(ADDR, &[BME280_REG_DIG_T1], &calibration_tp()).with_stall(),
+        ]));
+
+        assert!(matches!(block_on(sensor.init()), Err(SensorError::Timeout)));
+        assert!(!sensor.initialized);
+    }
+
+    #[test]
+    fn test_read_before_init() {
+        let mut sensor = Bme280Sensor::new(MockI2c::new([]));
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::NotInitialized)));
+    }
+
+    #[test]
+    fn test_read_compensates_datasheet_example() {
+        let mut sensor = initialized_sensor();
+        sensor.i2c.expect(measurement_script(measurement(415148, 519888, 30000)));
+
+        let reading = block_on(sensor.read()).unwrap();
+        let (t, h, p) = environmental(&reading);
+
+        assert_eq!(reading.sensor_type, SensorType::BME280);
+        assert_eq!(reading.quality, Quality::Good);
+        assert!((t.unwrap() - 25.08).abs() < 0.005);
+        assert!((p.unwrap() - 1006.53).abs() < 0.01);
+        assert!((h.unwrap() - 55.00).abs() < 0.01);
+        sensor.i2c.done();
+    }
+
+    #[test]
+    fn test_read_without_humidity() {
+        let config = Bme280Config::default().without_humidity();
+        let mut sensor = Bme280Sensor::with_config(MockI2c::new(init_script_with(ADDR, config)), config);
+        block_on(sensor.init()).unwrap();
+        // Skipped channels read 0x8000
+        sensor.i2c.expect([
+            I2cTransaction::write(ADDR, &[BME280_REG_CTRL_MEAS, 0x25]),
+            status(ADDR, 0),
+            I2cTransaction::write_read(ADDR, &[BME280_REG_PRESS_MSB], &measurement(415148, 519888, 0x8000)),
+        ]);
+
+        let reading = block_on(sensor.read()).unwrap();
+        let (t, h, p) = environmental(&reading);
+        assert_eq!(reading.quality, Quality::Good);
+        assert!(t.is_some() && p.is_some());
+        assert_eq!(h, None);
+        sensor.i2c.done();
+    }
+
+    #[test]
+    fn test_read_normal_mode() {
+        let config = Bme280Config { mode: Mode::Normal(Standby::Ms125), ..Default::default() };
+        let mut sensor = Bme280Sensor::with_config(MockI2c::new(init_script_with(ADDR, config)), config);
+        block_on(sensor.init()).unwrap();
+        // No trigger, the latest measurement is read right away
+        sensor.i2c.expect([
+            I2cTransaction::write_read(ADDR, &[BME280_REG_PRESS_MSB], &measurement(415148, 519888, 30000)),
+        ]);
+
+        let reading = block_on(sensor.read()).unwrap();
+        assert!((environmental(&reading).0.unwrap() - 25.08).abs() < 0.005);
+        sensor.i2c.done();
+    }
+
+    #[test]
+    fn test_read_out_of_range_is_bad_quality() {
+        let mut sensor = initialized_sensor();
+        // 142.5 °C and 1187 hPa are outside the sensor's operating range
+        sensor.i2c.expect(measurement_script(measurement(415148, 900000, 30000)));
+
+        let reading = block_on(sensor.read()).unwrap();
+        let (t, h, p) = environmental(&reading);
+
+        assert_eq!(reading.quality, Quality::Bad);
+        assert!(!reading.is_valid());
+        assert_eq!(t, None);
+        assert_eq!(p, None);
+        assert!((h.unwrap() - 56.45).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_read_bus_error() {
+        let mut sensor = initialized_sensor();
+        sensor.i2c.expect([
+            I2cTransaction::write(ADDR, &[BME280_REG_CTRL_MEAS, 0x25]).with_error(ErrorKind::Bus),
+        ]);
+
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::CommunicationError)));
+        sensor.i2c.done();
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let mut sensor = initialized_sensor();
+        sensor.i2c.expect([
+            I2cTransaction::write(ADDR, &[BME280_REG_CTRL_MEAS, 0x25]),
+            status(ADDR, 0),
+            I2cTransaction::write_read(ADDR, &[BME280_REG_PRESS_MSB], &[0; 8]).with_stall(),
+        ]);
+
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::Timeout)));
+        sensor.i2c.done();
+    }
+
+    #[test]
+    fn test_init_waits_for_nvm_copy() {
+        let mut sensor = Bme280Sensor::new(MockI2c::new([
+            I2cTransaction::write_read(ADDR, &[BME280_REG_CHIP_ID], &[BME280_CHIP_ID]),
+            I2cTransaction::write(ADDR, &[BME280_REG_RESET, BME280_RESET_COMMAND]),
+            status(ADDR, BME280_STATUS_IM_UPDATE),
+            status(ADDR, BME280_STATUS_IM_UPDATE),
+        ]));
+        sensor.i2c.expect(reset_script(ADDR, Bme280Config::default()).into_iter().skip(1));
+
+        block_on(sensor.init()).unwrap();
+        assert_eq!(sensor.dig_t1, 27504);
+        sensor.i2c.done();
+
+        // The copy never finishes
+        let mut sensor = Bme280Sensor::new(MockI2c::new([
+            I2cTransaction::write_read(ADDR, &[BME280_REG_CHIP_ID], &[BME280_CHIP_ID]),
+            I2cTransaction::write(ADDR, &[BME280_REG_RESET, BME280_RESET_COMMAND]),
+        ]));
+        sensor.i2c.expect((0..max_polls(BME280_NVM_COPY_TIMEOUT)).map(|_| status(ADDR, BME280_STATUS_IM_UPDATE)));
+        assert!(matches!(block_on(sensor.init()), Err(SensorError::Timeout)));
+        assert!(!sensor.initialized);
+        sensor.i2c.done();
+    }
+
+    #[test]
+    fn test_read_polls_measuring_bit() {
+        let mut sensor = initialized_sensor();
+        sensor.i2c.expect([
+            I2cTransaction::write(ADDR, &[BME280_REG_CTRL_MEAS, 0x25]),
+            status(ADDR, BME280_STATUS_MEASURING),
+            status(ADDR, BME280_STATUS_MEASURING),
+        ]);
+        sensor.i2c.expect(measurement_script(measurement(415148, 519888, 30000)).into_iter().skip(1));
+        assert!(block_on(sensor.read()).is_ok());
+        sensor.i2c.done();
+
+        // A measurement that never ends
+        let polls = max_polls(Bme280Config::default().max_measurement_time() * 2);
+        assert_eq!(polls, 19);
+        sensor.i2c.expect([I2cTransaction::write(ADDR, &[BME280_REG_CTRL_MEAS, 0x25])]);
+        sensor.i2c.expect((0..polls).map(|_| status(ADDR, BME280_STATUS_MEASURING)));
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::Timeout)));
+        sensor.i2c.done();
+    }
+
+    #[test]
+    fn test_read_stuck_adc() {
+        let mut sensor = initialized_sensor();
+        sensor.i2c.expect(measurement_script(measurement(415148, BME280_ADC_NO_DATA, 30000)));
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::InvalidData)));
+        sensor.i2c.expect(measurement_script(measurement(BME280_ADC_NO_DATA, 519888, 30000)));
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::InvalidData)));
+        sensor.i2c.expect(measurement_script(measurement(415148, 519888, BME280_ADC_HUM_NO_DATA as u16)));
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::InvalidData)));
+        sensor.i2c.done();
+    }
+
+    #[test]
+    fn test_reset_after_repeated_failures() {
+        let mut sensor = initialized_sensor();
+        for _ in 0..BME280_MAX_FAILURES {
+            sensor.i2c.expect(measurement_script(measurement(415148, BME280_ADC_NO_DATA, 30000)));
+            assert!(block_on(sensor.read()).is_err());
+        }
+
+        // The next read resets and reconfigures the sensor first
+        sensor.i2c.expect(reset_script(ADDR, Bme280Config::default()));
+        sensor.i2c.expect(measurement_script(measurement(415148, 519888, 30000)));
+        let reading = block_on(sensor.read()).unwrap();
+        assert_eq!(reading.quality, Quality::Good);
+        assert_eq!(sensor.failures, 0);
+        sensor.i2c.done();
+
+        // A failed reset is retried on the following read
+        sensor.failures = BME280_MAX_FAILURES;
+        sensor.i2c.expect([
+            I2cTransaction::write(ADDR, &[BME280_REG_RESET, BME280_RESET_COMMAND]).with_error(ErrorKind::Bus),
+        ]);
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::CommunicationError)));
+        sensor.i2c.expect(reset_script(ADDR, Bme280Config::default()));
+        sensor.i2c.expect(measurement_script(measurement(415148, 519888, 30000)));
+        assert!(block_on(sensor.read()).is_ok());
+        sensor.i2c.done();
+    }
+}
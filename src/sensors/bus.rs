@@ -0,0 +1,396 @@
+//! Reading bus
+//!
+//! Sensor tasks publish every reading here and each consumer (aggregator,
+//! logger, …) subscribes on its own, so they all see every reading instead
+//! of competing for them.
+//!
+//! Publishing never waits: when the bus is full the oldest reading is
+//! overwritten. A subscriber that fell that far behind is told how many
+//! readings it missed before it gets the next one, and the count is kept
+//! per subscriber for the status pages.
+//!
+//! Consumers that don't take readings straight off the bus, such as the
+//! uploaders fed one averaged reading per window, get a `ReadingQueue`
+//! instead. It doesn't wait either and counts the readings it dropped.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel as QueueChannel;
+use embassy_sync::pubsub::{self, PubSubChannel, Subscriber, WaitResult};
+use heapless::Vec;
+
+use super::SensorReading;
+
+/// Readings held for the slowest subscriber
+pub const BUS_CAPACITY: usize = 16;
+
+/// Consumers that can subscribe at the same time
+pub const MAX_SUBSCRIBERS: usize = 6;
+
+/// Readings a `ReadingQueue` holds
+pub const QUEUE_CAPACITY: usize = 8;
+
+/// Publishers only publish immediately, which takes no publisher slot
+const PUBLISHERS: usize = 0;
+
+type Channel = PubSubChannel<CriticalSectionRawMutex, SensorReading, BUS_CAPACITY, MAX_SUBSCRIBERS, PUBLISHERS>;
+
+/// The bus all sensor tasks publish to
+pub static READINGS: ReadingBus = ReadingBus::new();
+
+/// Bus errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusError {
+    /// All `MAX_SUBSCRIBERS` slots are taken
+    TooManySubscribers,
+}
+
+impl core::fmt::Display for BusError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BusError::TooManySubscribers => write!(f, "Too many subscribers"),
+        }
+    }
+}
+
+/// Readings a subscriber missed, by subscriber name
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SubscriberStats {
+    pub name: &'static str,
+    pub missed: u64,
+}
+
+/// Statistics entry and how many live subscribers share its name
+/// An entry nobody holds any more keeps its count until the spot is needed
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    stats: SubscriberStats,
+    subscribers: usize,
+}
+
+/// Readings a queue dropped, by queue name
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueueStats {
+    pub name: &'static str,
+    pub dropped: u64,
+}
+
+/// What a subscriber gets from the bus
+#[derive(Debug, Clone)]
+pub enum Event {
+    Reading(SensorReading),
+    /// This many readings were overwritten before the subscriber got to them
+    Missed(u64),
+}
+
+/// Publish-subscribe channel for sensor readings
+pub struct ReadingBus {
+    channel: Channel,
+    stats: Mutex<CriticalSectionRawMutex, RefCell<Vec<Entry, MAX_SUBSCRIBERS>>>,
+}
+
+impl Default for ReadingBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReadingBus {
+    pub const fn new() -> Self {
+        Self { channel: PubSubChannel::new(), stats: Mutex::new(RefCell::new(Vec::new())) }
+    }
+
+    /// Publish a reading to every subscriber without waiting
+    /// Returns false if it pushed out a reading not every subscriber had seen
+    pub fn publish(&self, reading: SensorReading) -> bool {
+        let overwrote = self.channel.is_full();
+        self.channel.immediate_publisher().publish_immediate(reading);
+        !overwrote
+    }
+
+    /// Subscribe to all readings published from now on
+    /// `name` identifies the subscriber in logs and statistics
+    pub fn subscribe(&self, name: &'static str) -> Result<ReadingSubscriber<'_>, BusError> {
+        let subscriber = self.channel.subscriber().map_err(|e| match e {
+            pubsub::Error::MaximumSubscribersReached | pubsub::Error::MaximumPublishersReached => {
+                BusError::TooManySubscribers
+            }
+        })?;
+        self.stats.lock(|stats| {
+            let mut stats = stats.borrow_mut();
+            let fresh = Entry { stats: SubscriberStats { name, missed: 0 }, subscribers: 1 };
+            if let Some(entry) = stats.iter_mut().find(|e| e.stats.name == name) {
+                entry.subscribers += 1;
+            } else if let Err(fresh) = stats.push(fresh) {
+                // Full: take over the entry of a name nobody subscribes under any more
+                match stats.iter_mut().find(|e| e.subscribers == 0) {
+                    Some(entry) => *entry = fresh,
+                    None => log::warn!("[BUS] No statistics kept for {}", name),
+                }
+            }
+        });
+        Ok(ReadingSubscriber { bus: self, subscriber, name })
+    }
+
+    /// Missed readings of current subscribers and of as many dropped ones as fit
+    pub fn subscriber_stats(&self) -> Vec<SubscriberStats, MAX_SUBSCRIBERS> {
+        self.stats.lock(|stats| stats.borrow().iter().map(|e| e.stats).collect())
+    }
+
+    fn record_missed(&self, name: &str, missed: u64) {
+        self.stats.lock(|stats| {
+            if let Some(entry) = stats.borrow_mut().iter_mut().find(|e| e.stats.name == name) {
+                entry.stats.missed += missed;
+            }
+        });
+    }
+
+    fn unsubscribe(&self, name: &str) {
+        self.stats.lock(|stats| {
+            if let Some(entry) = stats.borrow_mut().iter_mut().find(|e| e.stats.name == name) {
+                entry.subscribers = entry.subscribers.saturating_sub(1);
+            }
+        });
+    }
+}
+
+/// One consumer's view of the bus
+/// Dropping it frees the subscriber slot
+pub struct ReadingSubscriber<'a> {
+    bus: &'a ReadingBus,
+    subscriber: Subscriber<'a, CriticalSectionRawMutex, SensorReading, BUS_CAPACITY, MAX_SUBSCRIBERS, PUBLISHERS>,
+    name: &'static str,
+}
+
+impl ReadingSubscriber<'_> {
+    fn event(&self, result: WaitResult<SensorReading>) -> Event {
+        match result {
+            WaitResult::Message(reading) => Event::Reading(reading),
+            WaitResult::Lagged(missed) => {
+                self.bus.record_missed(self.name, missed);
+                Event::Missed(missed)
+            }
+        }
+    }
+
+    /// Wait for the next event
+    pub async fn next(&mut self) -> Event {
+        let result = self.subscriber.next_message().await;
+        self.event(result)
+    }
+
+    /// Next event, if there is one
+    pub fn try_next(&mut self) -> Option<Event> {
+        let result = self.subscriber.try_next_message()?;
+        Some(self.event(result))
+    }
+
+    /// Wait for the next reading, logging any missed ones
+    pub async fn next_reading(&mut self) -> SensorReading {
+        loop {
+            match self.next().await {
+                Event::Reading(reading) => return reading,
+                Event::Missed(missed) => log::warn!("[BUS] {} missed {} readings", self.name, missed),
+            }
+        }
+    }
+}
+
+impl Drop for ReadingSubscriber<'_> {
+    fn drop(&mut self) {
+        self.bus.unsubscribe(self.name);
+    }
+}
+
+/// Bounded queue of readings for one consumer
+/// Sending never waits: a reading that doesn't fit is dropped and counted
+pub struct ReadingQueue {
+    name: &'static str,
+    channel: QueueChannel<CriticalSectionRawMutex, SensorReading, QUEUE_CAPACITY>,
+    dropped: AtomicU32,
+}
+
+impl ReadingQueue {
+    pub const fn new(name: &'static str) -> Self {
+        Self { name, channel: QueueChannel::new(), dropped: AtomicU32::new(0) }
+    }
+
+    /// Queue a reading; returns false if the queue was full and it was dropped
+    pub fn send(&self, reading: SensorReading) -> bool {
+        let sent = self.channel.try_send(reading).is_ok();
+        if !sent {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        sent
+    }
+
+    /// Wait for the next reading
+    pub async fn receive(&self) -> SensorReading {
+        self.channel.receive().await
+    }
+
+    /// Next reading, if there is one
+    pub fn try_receive(&self) -> Option<SensorReading> {
+        self.channel.try_receive().ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channel.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.channel.is_full()
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats { name: self.name, dropped: u64::from(self.dropped.load(Ordering::Relaxed)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::block_on;
+    use crate::sensors::{Flags, Quality, SensorData, SensorType};
+
+    fn reading(timestamp: u64) -> SensorReading {
+        SensorReading {
+            sensor_type: SensorType::SDS011,
+            data: SensorData::AirQuality { pm25: Some(1.0), pm10: None, pm25_corrected: None, pm10_corrected: None },
+            timestamp,
+            utc_timestamp: None,
+            quality: Quality::Good,
+            flags: Flags::NONE,
+        }
+    }
+
+    fn timestamp(event: Option<Event>) -> Option<u64> {
+        match event? {
+            Event::Reading(reading) => Some(reading.timestamp),
+            Event::Missed(missed) => panic!("missed {}", missed),
+        }
+    }
+
+    #[test]
+    fn test_every_subscriber_sees_every_reading() {
+        let bus = ReadingBus::new();
+        // Nobody listening: nothing is kept
+        assert!(bus.publish(reading(0)));
+
+        let mut a = bus.subscribe("a").unwrap();
+        let mut b = bus.subscribe("b").unwrap();
+        for t in 1..=3 {
+            assert!(bus.publish(reading(t)));
+        }
+        for t in 1..=3 {
+            assert_eq!(timestamp(a.try_next()), Some(t));
+        }
+        assert!(a.try_next().is_none());
+        block_on(async {
+            for t in 1..=3 {
+                assert_eq!(b.next_reading().await.timestamp, t);
+            }
+        });
+        assert!(b.try_next().is_none());
+    }
+
+    #[test]
+    fn test_lagging_subscriber() {
+        let bus = ReadingBus::new();
+        let mut fast = bus.subscribe("fast").unwrap();
+        let mut slow = bus.subscribe("slow").unwrap();
+
+        // The slow subscriber holds the bus full, publishing carries on
+        let total = BUS_CAPACITY as u64 + 5;
+        for t in 0..total {
+            let kept = bus.publish(reading(t));
+            assert_eq!(kept, t < BUS_CAPACITY as u64);
+            assert_eq!(timestamp(fast.try_next()), Some(t));
+        }
+
+        match slow.try_next() {
+            Some(Event::Missed(5)) => {}
+            event => panic!("{:?}", event),
+        }
+        assert_eq!(timestamp(slow.try_next()), Some(5));
+        block_on(async {
+            assert_eq!(slow.next_reading().await.timestamp, 6);
+        });
+
+        let stats = bus.subscriber_stats();
+        assert_eq!(stats.as_slice(), [SubscriberStats { name: "fast", missed: 0 }, SubscriberStats { name: "slow", missed: 5 }]);
+    }
+
+    #[test]
+    fn test_subscriber_slots() {
+        let bus = ReadingBus::new();
+        let subscribers: std::vec::Vec<_> = (0..MAX_SUBSCRIBERS).map(|_| bus.subscribe("x").unwrap()).collect();
+        assert_eq!(bus.subscribe("y").err(), Some(BusError::TooManySubscribers));
+        drop(subscribers);
+        assert!(bus.subscribe("y").is_ok());
+        assert_eq!(bus.subscriber_stats().len(), 2);
+    }
+
+    #[test]
+    fn test_resubscribe_under_new_names() {
+        const NAMES: [&str; MAX_SUBSCRIBERS + 3] = ["a", "b", "c", "d", "e", "f", "g", "h", "i"];
+        let bus = ReadingBus::new();
+        let mut keep = bus.subscribe("keep").unwrap();
+        for name in NAMES {
+            let mut subscriber = bus.subscribe(name).unwrap();
+            for t in 0..BUS_CAPACITY as u64 + 2 {
+                bus.publish(reading(t));
+            }
+            assert!(matches!(subscriber.try_next(), Some(Event::Missed(2))));
+            while keep.try_next().is_some() {}
+            let stats = bus.subscriber_stats();
+            assert!(stats.contains(&SubscriberStats { name, missed: 2 }), "{}: {:?}", name, stats);
+            assert!(stats.iter().any(|s| s.name == "keep"));
+        }
+
+        // Names seen most recently are still reported after their subscribers are gone
+        let stats = bus.subscriber_stats();
+        assert_eq!(stats.len(), MAX_SUBSCRIBERS);
+        assert!(stats.contains(&SubscriberStats { name: "i", missed: 2 }));
+
+        // Coming back under a known name keeps counting where it left off
+        let again = bus.subscribe("i").unwrap();
+        assert!(bus.subscriber_stats().contains(&SubscriberStats { name: "i", missed: 2 }));
+        drop(again);
+    }
+
+    #[test]
+    fn test_queue_counts_drops() {
+        let queue = ReadingQueue::new("uploader");
+        for t in 0..QUEUE_CAPACITY as u64 {
+            assert!(queue.send(reading(t)));
+        }
+        assert!(queue.is_full());
+        assert!(!queue.send(reading(100)));
+        assert!(!queue.send(reading(101)));
+        assert_eq!(queue.stats(), QueueStats { name: "uploader", dropped: 2 });
+
+        // The readings already queued are kept
+        assert_eq!(queue.try_receive().map(|r| r.timestamp), Some(0));
+        assert!(queue.send(reading(102)));
+        block_on(async {
+            for t in 1..QUEUE_CAPACITY as u64 {
+                assert_eq!(queue.receive().await.timestamp, t);
+            }
+            assert_eq!(queue.receive().await.timestamp, 102);
+        });
+        assert!(queue.is_empty());
+        assert_eq!(queue.stats().dropped, 2);
+    }
+}
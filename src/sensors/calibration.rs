@@ -0,0 +1,99 @@
+//! Per-device calibration
+//!
+//! Nodes co-located with a reference station get a linear correction per
+//! field, `corrected = raw · gain + offset`, fitted against the reference.
+//! The corrections are stored per sensor type in the device configuration
+//! and applied right after `Sensor::read()`, so the filter, the derived
+//! quantities and every consumer see corrected values. Readings that were
+//! changed carry `Flags::CALIBRATED`.
+
+use super::{Field, Flags, SensorReading};
+
+/// Linear correction of one field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub field: Field,
+    pub gain: f32,
+    pub offset: f32,
+}
+
+impl Calibration {
+    pub const fn new(field: Field, gain: f32, offset: f32) -> Self {
+        Self { field, gain, offset }
+    }
+
+    /// Correction that leaves values unchanged
+    pub const fn identity(field: Field) -> Self {
+        Self::new(field, 1.0, 0.0)
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.gain == 1.0 && self.offset == 0.0
+    }
+
+    /// Corrected value of a raw one
+    pub fn correct(&self, raw: f32) -> f32 {
+        raw * self.gain + self.offset
+    }
+}
+
+/// Correct the fields of a reading that have a calibration
+///
+/// Returns whether any value was changed; if so the reading is flagged
+/// `CALIBRATED`.
+pub fn apply(calibrations: &[Calibration], reading: &mut SensorReading) -> bool {
+    let mut changed = false;
+    for calibration in calibrations.iter().filter(|c| !c.is_identity()) {
+        if let Some(raw) = reading.data.value(calibration.field) {
+            changed |= reading.data.set_value(calibration.field, calibration.correct(raw));
+        }
+    }
+    if changed {
+        reading.flags.insert(Flags::CALIBRATED);
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::{Quality, SensorData, SensorType};
+
+    #[test]
+    fn test_apply() {
+        let mut reading = SensorReading {
+            sensor_type: SensorType::BME280,
+            data: SensorData::Environmental {
+                temperature: Some(22.0),
+                humidity: Some(50.0),
+                pressure: None,
+                gas_resistance: None,
+                dew_point: None,
+                absolute_humidity: None,
+                heat_index: None,
+                sea_level_pressure: None,
+            },
+            timestamp: 0,
+            utc_timestamp: None,
+            quality: Quality::Good,
+            flags: Flags::NONE,
+        };
+        let calibrations = [
+            Calibration::new(Field::Temperature, 1.0, -1.5),
+            Calibration::new(Field::Humidity, 1.1, 2.0),
+            // Not in this reading
+            Calibration::new(Field::Pressure, 1.0, 3.0),
+        ];
+        assert!(apply(&calibrations, &mut reading));
+        assert_eq!(reading.data.value(Field::Temperature), Some(20.5));
+        assert_eq!(reading.data.value(Field::Humidity), Some(57.0));
+        assert_eq!(reading.data.value(Field::Pressure), None);
+        assert_eq!(reading.flags, Flags::CALIBRATED);
+
+        // Nothing to correct, nothing flagged
+        let mut other = SensorReading { flags: Flags::NONE, ..reading.clone() };
+        assert!(!apply(&[Calibration::identity(Field::Temperature), calibrations[2]], &mut other));
+        assert!(other.flags.is_empty());
+        assert_eq!(other.data.value(Field::Temperature), Some(20.5));
+    }
+}
@@ -0,0 +1,654 @@
+//! Binary encoding of sensor readings
+//!
+//! A compact, versioned format for keeping readings in flash or sending
+//! them over the wire. Integers are LEB128 varints, floats are
+//! little-endian IEEE 754 and the `Option` fields of each variant are
+//! collected in a presence bitmap, so absent values take no space.
+//!
+//! | field       | encoding                                          |
+//! |-------------|---------------------------------------------------|
+//! | version     | `FORMAT_VERSION`                                  |
+//! | variant     | `SensorData` variant, in declaration order        |
+//! | sensor type | index in `SensorType::ALL`                        |
+//! | header bits | bits 0..2 quality, bit 2 UTC timestamp present,   |
+//! |             | bit 3 flags present                               |
+//! | timestamp   | varint, ms since boot                             |
+//! | utc         | varint, ms since the Unix epoch, if flagged       |
+//! | flags       | varint, `Flags` bits, if flagged                  |
+//! | presence    | bit per `Option` field of the variant, in order   |
+//! | values      | the variant's fields in order, absent ones skipped |
+//!
+//! Fields added to a variant later take the next presence bits, so older
+//! encodings, which leave them clear, still decode.
+//!
+//! Integer fields (`co2_ppm`, `raw_value`) are varints, `satellites` is a
+//! byte and `units` is a length-prefixed string. Units are `&'static str`
+//! in memory, so decoding maps them back onto the ones the firmware knows
+//! and anything else becomes `UNKNOWN_UNIT`.
+//!
+//! Decoding checks every tag and length and is plain `core` code, so host
+//! tools can link the library and read logs and dumps with it.
+
+use super::{Flags, Quality, SensorData, SensorReading, SensorType};
+
+/// Current format version, the first byte of every encoded reading
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Longest `units` string that can be encoded
+pub const MAX_UNITS_LEN: usize = 16;
+
+/// Units of analog readings the decoder knows
+pub const KNOWN_UNITS: [&str; 13] = ["", "V", "mV", "A", "mA", "%", "ppm", "ppb", "°C", "hPa", "lux", "Ω", "µSv/h"];
+
+/// Stands in for units that aren't in `KNOWN_UNITS`
+pub const UNKNOWN_UNIT: &str = "?";
+
+const VARINT_U16_LEN: usize = 3;
+const VARINT_U64_LEN: usize = 10;
+const HEADER_LEN: usize = 4 + 2 * VARINT_U64_LEN + VARINT_U16_LEN;
+
+/// Longest variant bodies, presence bitmap included
+const NOISE_LEN: usize = 1 + 4 + 4 + 8 * 4;
+const ANALOG_LEN: usize = 1 + 4 + VARINT_U16_LEN + 4 + 1 + MAX_UNITS_LEN;
+
+/// Longest encoding of any reading; a `Noise` one with frequency bands
+pub const MAX_ENCODED_LEN: usize = HEADER_LEN + if NOISE_LEN > ANALOG_LEN { NOISE_LEN } else { ANALOG_LEN };
+
+const QUALITY_MASK: u8 = 0b011;
+const UTC_PRESENT: u8 = 0b100;
+const FLAGS_PRESENT: u8 = 0b1000;
+
+/// Codec errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CodecError {
+    /// Output buffer too small
+    BufferTooSmall,
+    /// Input ends inside a reading
+    Truncated,
+    /// Input continues after a reading
+    TrailingData,
+    /// Written by an incompatible firmware
+    UnsupportedVersion(u8),
+    /// Unknown variant, sensor type or quality, or reserved bits set
+    InvalidTag,
+    /// Malformed varint, overlong units or a value out of range
+    InvalidValue,
+}
+
+impl core::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CodecError::BufferTooSmall => write!(f, "Buffer too small"),
+            CodecError::Truncated => write!(f, "Truncated reading"),
+            CodecError::TrailingData => write!(f, "Trailing data after reading"),
+            CodecError::UnsupportedVersion(v) => write!(f, "Unsupported format version {}", v),
+            CodecError::InvalidTag => write!(f, "Invalid tag"),
+            CodecError::InvalidValue => write!(f, "Invalid value"),
+        }
+    }
+}
+
+struct Writer<'a> {
+    out: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), CodecError> {
+        let end = self.len + bytes.len();
+        self.out.get_mut(self.len..end).ok_or(CodecError::BufferTooSmall)?.copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), CodecError> {
+        self.bytes(&[value])
+    }
+
+    fn varint(&mut self, mut value: u64) -> Result<(), CodecError> {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                return self.u8(byte);
+            }
+            self.u8(byte | 0x80)?;
+        }
+    }
+
+    fn f32(&mut self, value: f32) -> Result<(), CodecError> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn f64(&mut self, value: f64) -> Result<(), CodecError> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn opt_f32(&mut self, value: Option<f32>) -> Result<(), CodecError> {
+        value.map_or(Ok(()), |v| self.f32(v))
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], CodecError> {
+        let (head, rest) = self.bytes.split_first_chunk::<N>().ok_or(CodecError::Truncated)?;
+        self.bytes = rest;
+        Ok(*head)
+    }
+
+    fn u8(&mut self) -> Result<u8, CodecError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64, CodecError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            let bits = u64::from(byte & 0x7F);
+            // The tenth byte only has room for the top bit
+            if shift == 63 && bits > 1 {
+                return Err(CodecError::InvalidValue);
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(CodecError::InvalidValue)
+    }
+
+    fn varint_u16(&mut self) -> Result<u16, CodecError> {
+        u16::try_from(self.varint()?).map_err(|_| CodecError::InvalidValue)
+    }
+
+    fn f32(&mut self) -> Result<f32, CodecError> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+
+    fn f64(&mut self) -> Result<f64, CodecError> {
+        Ok(f64::from_le_bytes(self.take()?))
+    }
+
+    /// Presence bitmap with `fields` defined bits
+    fn presence(&mut self, fields: u32) -> Result<Presence, CodecError> {
+        let bits = self.u8()?;
+        if u32::from(bits) >> fields != 0 {
+            return Err(CodecError::InvalidTag);
+        }
+        Ok(Presence(bits))
+    }
+
+    fn opt_f32(&mut self, present: bool) -> Result<Option<f32>, CodecError> {
+        present.then(|| self.f32()).transpose()
+    }
+
+    fn units(&mut self) -> Result<&'static str, CodecError> {
+        let len = usize::from(self.u8()?);
+        if len > MAX_UNITS_LEN || len > self.bytes.len() {
+            return Err(if len > MAX_UNITS_LEN { CodecError::InvalidValue } else { CodecError::Truncated });
+        }
+        let (units, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        let units = core::str::from_utf8(units).map_err(|_| CodecError::InvalidValue)?;
+        Ok(KNOWN_UNITS.iter().find(|known| **known == units).copied().unwrap_or(UNKNOWN_UNIT))
+    }
+}
+
+/// Presence bitmap, bit `i` for the `i`-th `Option` field of a variant
+#[derive(Clone, Copy)]
+struct Presence(u8);
+
+impl Presence {
+    fn of(fields: &[bool]) -> Self {
+        Presence(fields.iter().enumerate().fold(0, |bits, (i, present)| bits | (u8::from(*present) << i)))
+    }
+
+    fn has(&self, field: u8) -> bool {
+        self.0 & (1 << field) != 0
+    }
+}
+
+fn variant_tag(data: &SensorData) -> u8 {
+    match data {
+        SensorData::Environmental { .. } => 0,
+        SensorData::AirQuality { .. } => 1,
+        SensorData::Gas { .. } => 2,
+        SensorData::Radiation { .. } => 3,
+        SensorData::Noise { .. } => 4,
+        SensorData::Location { .. } => 5,
+        SensorData::Analog { .. } => 6,
+    }
+}
+
+fn quality_tag(quality: Quality) -> u8 {
+    match quality {
+        Quality::Good => 0,
+        Quality::Degraded => 1,
+        Quality::Bad => 2,
+    }
+}
+
+/// Encode a reading into `out`, returning the number of bytes written
+pub fn encode(reading: &SensorReading, out: &mut [u8]) -> Result<usize, CodecError> {
+    let sensor_type = SensorType::ALL.iter().position(|t| *t == reading.sensor_type).ok_or(CodecError::InvalidTag)?;
+    let mut w = Writer { out, len: 0 };
+    w.u8(FORMAT_VERSION)?;
+    w.u8(variant_tag(&reading.data))?;
+    w.u8(sensor_type as u8)?;
+    let utc = if reading.utc_timestamp.is_some() { UTC_PRESENT } else { 0 };
+    let flags = if reading.flags.is_empty() { 0 } else { FLAGS_PRESENT };
+    w.u8(quality_tag(reading.quality) | utc | flags)?;
+    w.varint(reading.timestamp)?;
+    if let Some(utc) = reading.utc_timestamp {
+        w.varint(utc)?;
+    }
+    if !reading.flags.is_empty() {
+        w.varint(u64::from(reading.flags.bits()))?;
+    }
+
+    match &reading.data {
+        SensorData::Environmental {
+            temperature,
+            humidity,
+            pressure,
+            gas_resistance,
+            dew_point,
+            absolute_humidity,
+            heat_index,
+            sea_level_pressure,
+        } => {
+            let values = [
+                *temperature,
+                *humidity,
+                *pressure,
+                *gas_resistance,
+                *dew_point,
+                *absolute_humidity,
+                *heat_index,
+                *sea_level_pressure,
+            ];
+            w.u8(Presence::of(&values.map(|v| v.is_some())).0)?;
+            values.into_iter().try_for_each(|v| w.opt_f32(v))?;
+        }
+        SensorData::AirQuality { pm25, pm10, pm25_corrected, pm10_corrected } => {
+            let values = [*pm25, *pm10, *pm25_corrected, *pm10_corrected];
+            w.u8(Presence::of(&values.map(|v| v.is_some())).0)?;
+            values.into_iter().try_for_each(|v| w.opt_f32(v))?;
+        }
+        SensorData::Gas { co_ppm, co2_ppm, voc_index } => {
+            w.u8(Presence::of(&[co_ppm.is_some(), co2_ppm.is_some(), voc_index.is_some()]).0)?;
+            w.opt_f32(*co_ppm)?;
+            if let Some(co2) = co2_ppm {
+                w.varint(u64::from(*co2))?;
+            }
+            w.opt_f32(*voc_index)?;
+        }
+        SensorData::Radiation { dose_rate, total_dose } => {
+            w.u8(Presence::of(&[total_dose.is_some()]).0)?;
+            w.f32(*dose_rate)?;
+            w.opt_f32(*total_dose)?;
+        }
+        SensorData::Noise { db_a, db_c, frequency_data } => {
+            w.u8(Presence::of(&[db_c.is_some(), frequency_data.is_some()]).0)?;
+            w.f32(*db_a)?;
+            w.opt_f32(*db_c)?;
+            if let Some(bands) = frequency_data {
+                bands.iter().try_for_each(|v| w.f32(*v))?;
+            }
+        }
+        SensorData::Location { latitude, longitude, altitude, satellites } => {
+            w.u8(Presence::of(&[altitude.is_some(), satellites.is_some()]).0)?;
+            w.f64(*latitude)?;
+            w.f64(*longitude)?;
+            w.opt_f32(*altitude)?;
+            if let Some(satellites) = satellites {
+                w.u8(*satellites)?;
+            }
+        }
+        SensorData::Analog { voltage, raw_value, converted_value, units } => {
+            if units.len() > MAX_UNITS_LEN {
+                return Err(CodecError::InvalidValue);
+            }
+            w.u8(Presence::of(&[converted_value.is_some()]).0)?;
+            w.f32(*voltage)?;
+            w.varint(u64::from(*raw_value))?;
+            w.opt_f32(*converted_value)?;
+            w.u8(units.len() as u8)?;
+            w.bytes(units.as_bytes())?;
+        }
+    }
+    Ok(w.len)
+}
+
+/// Decode a reading that takes up all of `bytes`
+pub fn decode(bytes: &[u8]) -> Result<SensorReading, CodecError> {
+    let (reading, used) = decode_prefix(bytes)?;
+    if used != bytes.len() {
+        return Err(CodecError::TrailingData);
+    }
+    Ok(reading)
+}
+
+/// Decode the reading at the start of `bytes`, for readings stored back
+/// to back; returns it with the number of bytes it took
+pub fn decode_prefix(bytes: &[u8]) -> Result<(SensorReading, usize), CodecError> {
+    let mut r = Reader { bytes };
+    let version = r.u8()?;
+    if version != FORMAT_VERSION {
+        return Err(CodecError::UnsupportedVersion(version));
+    }
+    let variant = r.u8()?;
+    let sensor_type = SensorType::from_index(r.u8()?).ok_or(CodecError::InvalidTag)?;
+    let header = r.u8()?;
+    if header & !(QUALITY_MASK | UTC_PRESENT | FLAGS_PRESENT) != 0 {
+        return Err(CodecError::InvalidTag);
+    }
+    let quality = match header & QUALITY_MASK {
+        0 => Quality::Good,
+        1 => Quality::Degraded,
+        2 => Quality::Bad,
+        _ => return Err(CodecError::InvalidTag),
+    };
+    let timestamp = r.varint()?;
+    let utc_timestamp = (header & UTC_PRESENT != 0).then(|| r.varint()).transpose()?;
+    let flags = match header & FLAGS_PRESENT {
+        0 => Flags::NONE,
+        _ => {
+            Flags::from_bits(r.varint_u16()?).ok_or(CodecError::InvalidValue)?
+        }
+    };
+
+    let data = match variant {
+        0 => {
+            let p = r.presence(8)?;
+            SensorData::Environmental {
+                temperature: r.opt_f32(p.has(0))?,
+                humidity: r.opt_f32(p.has(1))?,
+                pressure: r.opt_f32(p.has(2))?,
+                gas_resistance: r.opt_f32(p.has(3))?,
+                dew_point: r.opt_f32(p.has(4))?,
+                absolute_humidity: r.opt_f32(p.has(5))?,
+                heat_index: r.opt_f32(p.has(6))?,
+                sea_level_pressure: r.opt_f32(p.has(7))?,
+            }
+        }
+        1 => {
+            let p = r.presence(4)?;
+            SensorData::AirQuality {
+                pm25: r.opt_f32(p.has(0))?,
+                pm10: r.opt_f32(p.has(1))?,
+                pm25_corrected: r.opt_f32(p.has(2))?,
+                pm10_corrected: r.opt_f32(p.has(3))?,
+            }
+        }
+        2 => {
+            let p = r.presence(3)?;
+            SensorData::Gas {
+                co_ppm: r.opt_f32(p.has(0))?,
+                co2_ppm: p.has(1).then(|| r.varint_u16()).transpose()?,
+                voc_index: r.opt_f32(p.has(2))?,
+            }
+        }
+        3 => {
+            let p = r.presence(1)?;
+            SensorData::Radiation { dose_rate: r.f32()?, total_dose: r.opt_f32(p.has(0))? }
+        }
+        4 => {
+            let p = r.presence(2)?;
+            let db_a = r.f32()?;
+            let db_c = r.opt_f32(p.has(0))?;
+            let mut frequency_data = None;
+            if p.has(1) {
+                let mut bands = [0.0; 8];
+                for band in bands.iter_mut() {
+                    *band = r.f32()?;
+                }
+                frequency_data = Some(bands);
+            }
+            SensorData::Noise { db_a, db_c, frequency_data }
+        }
+        5 => {
+            let p = r.presence(2)?;
+            SensorData::Location {
+                latitude: r.f64()?,
+                longitude: r.f64()?,
+                altitude: r.opt_f32(p.has(0))?,
+                satellites: p.has(1).then(|| r.u8()).transpose()?,
+            }
+        }
+        6 => {
+            let p = r.presence(1)?;
+            SensorData::Analog {
+                voltage: r.f32()?,
+                raw_value: r.varint_u16()?,
+                converted_value: r.opt_f32(p.has(0))?,
+                units: r.units()?,
+            }
+        }
+        _ => return Err(CodecError::InvalidTag),
+    };
+
+    let used = bytes.len() - r.bytes.len();
+    Ok((SensorReading { sensor_type, data, timestamp, utc_timestamp, quality, flags }, used))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+
+    /// xorshift64*, deterministic so failures reproduce
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn bool(&mut self) -> bool {
+            self.next() & 1 == 1
+        }
+
+        /// Any bit pattern, including NaNs, infinities and subnormals
+        fn f32(&mut self) -> f32 {
+            f32::from_bits(self.next() as u32)
+        }
+
+        fn opt_f32(&mut self) -> Option<f32> {
+            self.bool().then(|| self.f32())
+        }
+
+        /// Small and huge values alike, to exercise every varint length
+        fn u64(&mut self) -> u64 {
+            self.next() >> self.below(64)
+        }
+
+        fn data(&mut self) -> SensorData {
+            match self.below(7) {
+                0 => SensorData::Environmental {
+                    temperature: self.opt_f32(),
+                    humidity: self.opt_f32(),
+                    pressure: self.opt_f32(),
+                    gas_resistance: self.opt_f32(),
+                    dew_point: self.opt_f32(),
+                    absolute_humidity: self.opt_f32(),
+                    heat_index: self.opt_f32(),
+                    sea_level_pressure: self.opt_f32(),
+                },
+                1 => SensorData::AirQuality {
+                    pm25: self.opt_f32(),
+                    pm10: self.opt_f32(),
+                    pm25_corrected: self.opt_f32(),
+                    pm10_corrected: self.opt_f32(),
+                },
+                2 => SensorData::Gas {
+                    co_ppm: self.opt_f32(),
+                    co2_ppm: self.bool().then(|| self.u64() as u16),
+                    voc_index: self.opt_f32(),
+                },
+                3 => SensorData::Radiation { dose_rate: self.f32(), total_dose: self.opt_f32() },
+                4 => SensorData::Noise {
+                    db_a: self.f32(),
+                    db_c: self.opt_f32(),
+                    frequency_data: self.bool().then(|| core::array::from_fn(|_| self.f32())),
+                },
+                5 => SensorData::Location {
+                    latitude: f64::from_bits(self.next()),
+                    longitude: f64::from_bits(self.next()),
+                    altitude: self.opt_f32(),
+                    satellites: self.bool().then(|| self.next() as u8),
+                },
+                _ => SensorData::Analog {
+                    voltage: self.f32(),
+                    raw_value: self.u64() as u16,
+                    converted_value: self.opt_f32(),
+                    units: KNOWN_UNITS[self.below(KNOWN_UNITS.len() as u64) as usize],
+                },
+            }
+        }
+
+        fn reading(&mut self) -> SensorReading {
+            SensorReading {
+                sensor_type: SensorType::ALL[self.below(SensorType::ALL.len() as u64) as usize],
+                data: self.data(),
+                timestamp: self.u64(),
+                utc_timestamp: self.bool().then(|| self.u64()),
+                quality: [Quality::Good, Quality::Degraded, Quality::Bad][self.below(3) as usize],
+                flags: Flags::ALL.into_iter().filter(|_| self.bool()).fold(Flags::NONE, |all, (flag, _)| all | flag),
+            }
+        }
+    }
+
+    /// Debug output compares floats bit for bit where it matters: NaN, -0.0
+    fn same(a: &SensorReading, b: &SensorReading) -> bool {
+        format!("{:?}", a) == format!("{:?}", b)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        let mut variants = [0; 7];
+        for _ in 0..20_000 {
+            let reading = rng.reading();
+            variants[usize::from(variant_tag(&reading.data))] += 1;
+            let mut buf = [0; MAX_ENCODED_LEN];
+            let len = encode(&reading, &mut buf).unwrap();
+            let decoded = decode(&buf[..len]).unwrap();
+            assert!(same(&reading, &decoded), "{:?} != {:?}", reading, decoded);
+
+            // Every shorter buffer is too small, every prefix is truncated
+            let cut = rng.below(len as u64) as usize;
+            assert_eq!(encode(&reading, &mut buf[..cut]), Err(CodecError::BufferTooSmall));
+            assert_eq!(decode(&buf[..cut]).unwrap_err(), CodecError::Truncated);
+        }
+        assert!(variants.iter().all(|n| *n > 2_000), "{:?}", variants);
+    }
+
+    #[test]
+    fn test_golden() {
+        let reading = SensorReading {
+            sensor_type: SensorType::SDS011,
+            data: SensorData::AirQuality { pm25: Some(5.5), pm10: None, pm25_corrected: None, pm10_corrected: None },
+            timestamp: 300,
+            utc_timestamp: None,
+            quality: Quality::Degraded,
+            flags: Flags::NONE,
+        };
+        let mut buf = [0; MAX_ENCODED_LEN];
+        let len = encode(&reading, &mut buf).unwrap();
+        assert_eq!(&buf[..len], [1, 1, 3, 0x01, 0xAC, 0x02, 0b01, 0x00, 0x00, 0xB0, 0x40]);
+
+        let reading = SensorReading {
+            sensor_type: SensorType::ME2CO,
+            data: SensorData::Gas { co_ppm: None, co2_ppm: Some(415), voc_index: None },
+            timestamp: 0,
+            utc_timestamp: Some(1_704_067_200_000),
+            quality: Quality::Good,
+            flags: Flags::NONE,
+        };
+        let len = encode(&reading, &mut buf).unwrap();
+        assert_eq!(&buf[..len], [1, 2, 5, 0x04, 0x00, 0x80, 0xE8, 0xC7, 0x92, 0xCC, 0x31, 0b010, 0x9F, 0x03]);
+
+        let reading = SensorReading { flags: Flags::MEDIAN_OUTLIER | Flags::RATE_OF_CHANGE, ..reading };
+        let len = encode(&reading, &mut buf).unwrap();
+        assert_eq!(&buf[..len], [1, 2, 5, 0x0C, 0x00, 0x80, 0xE8, 0xC7, 0x92, 0xCC, 0x31, 0b101, 0b010, 0x9F, 0x03]);
+    }
+
+    #[test]
+    fn test_units() {
+        let mut reading = Rng(1).reading();
+        reading.data = SensorData::Analog { voltage: 1.5, raw_value: 1862, converted_value: None, units: "furlongs" };
+        let mut buf = [0; MAX_ENCODED_LEN];
+        let len = encode(&reading, &mut buf).unwrap();
+        match decode(&buf[..len]).unwrap().data {
+            SensorData::Analog { units, raw_value, .. } => assert_eq!((units, raw_value), (UNKNOWN_UNIT, 1862)),
+            data => panic!("{:?}", data),
+        }
+
+        reading.data = SensorData::Analog { voltage: 0.0, raw_value: 0, converted_value: None, units: "much too long units" };
+        assert_eq!(encode(&reading, &mut buf), Err(CodecError::InvalidValue));
+    }
+
+    #[test]
+    fn test_rejects() {
+        let reading = SensorReading {
+            sensor_type: SensorType::BME280,
+            data: SensorData::Radiation { dose_rate: 0.1, total_dose: None },
+            timestamp: 1,
+            utc_timestamp: None,
+            quality: Quality::Good,
+            flags: Flags::NONE,
+        };
+        let mut buf = [0; MAX_ENCODED_LEN + 1];
+        let len = encode(&reading, &mut buf).unwrap();
+        let valid = buf;
+        let check = |patch: &dyn Fn(&mut [u8]), len: usize| {
+            let mut bytes = valid;
+            patch(&mut bytes);
+            decode(&bytes[..len]).unwrap_err()
+        };
+
+        assert_eq!(check(&|_| {}, len + 1), CodecError::TrailingData);
+        assert_eq!(check(&|b| b[0] = 2, len), CodecError::UnsupportedVersion(2));
+        assert_eq!(check(&|b| b[1] = 7, len), CodecError::InvalidTag);
+        assert_eq!(check(&|b| b[2] = 12, len), CodecError::InvalidTag);
+        assert_eq!(check(&|b| b[3] = 3, len), CodecError::InvalidTag);
+        assert_eq!(check(&|b| b[3] = 16, len), CodecError::InvalidTag);
+        // Flags present, with a bit no flag uses
+        let patch = |b: &mut [u8]| {
+            b[3] = FLAGS_PRESENT;
+            b[5] = 0x80;
+            b[6] = 0x01;
+        };
+        assert_eq!(check(&patch, len), CodecError::InvalidValue);
+        // Presence bit for a field the variant doesn't have
+        assert_eq!(check(&|b| b[5] = 0b10, len), CodecError::InvalidTag);
+        assert_eq!(decode(&[]).unwrap_err(), CodecError::Truncated);
+
+        // Varints: overlong, and too large for the field
+        let mut r = Reader { bytes: &[0xFF; 11] };
+        assert_eq!(r.varint(), Err(CodecError::InvalidValue));
+        let mut r = Reader { bytes: &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01] };
+        assert_eq!(r.varint(), Ok(u64::MAX));
+        let mut r = Reader { bytes: &[0x80, 0x80, 0x04] };
+        assert_eq!(r.varint_u16(), Err(CodecError::InvalidValue));
+
+        // Back to back
+        let mut stream = std::vec::Vec::from(&valid[..len]);
+        stream.extend_from_slice(&valid[..len]);
+        let (first, used) = decode_prefix(&stream).unwrap();
+        assert_eq!(used, len);
+        assert!(same(&first, &decode(&stream[used..]).unwrap()));
+    }
+}
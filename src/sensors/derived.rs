@@ -0,0 +1,188 @@
+//! Derived environmental quantities
+//!
+//! Dew point, absolute humidity, heat index and sea-level pressure follow
+//! from temperature, relative humidity and station pressure. This stage
+//! fills them into `SensorData::Environmental`, so every consumer gets them
+//! like the measured fields.
+//!
+//! - dew point and saturation vapour pressure: Magnus formula with the
+//!   Sonntag (1990) constants
+//! - heat index: the US National Weather Service algorithm (Rothfusz
+//!   regression with its low and high humidity adjustments)
+//! - sea-level pressure: hypsometric reduction with the station temperature
+//!   and the standard lapse rate; needs the station altitude
+
+use super::SensorData;
+
+/// Magnus formula constants over water
+const MAGNUS_A: f32 = 17.62;
+const MAGNUS_B: f32 = 243.12;
+
+/// Standard temperature lapse rate, K/m
+const LAPSE_RATE: f32 = 0.0065;
+
+/// Turns vapour pressure in hPa over temperature in K into g/m³:
+/// 100 Pa/hPa · 1000 g/kg over the gas constant of water vapour
+const WATER_VAPOUR: f32 = 216.7;
+
+/// Saturation vapour pressure over water in hPa
+pub fn saturation_vapour_pressure(temperature: f32) -> f32 {
+    6.112 * libm::expf(MAGNUS_A * temperature / (MAGNUS_B + temperature))
+}
+
+/// Dew point in °C; `None` for humidity at or below 0 %
+pub fn dew_point(temperature: f32, humidity: f32) -> Option<f32> {
+    if humidity <= 0.0 {
+        return None;
+    }
+    let gamma = libm::logf(humidity.min(100.0) / 100.0) + MAGNUS_A * temperature / (MAGNUS_B + temperature);
+    Some(MAGNUS_B * gamma / (MAGNUS_A - gamma))
+}
+
+/// Absolute humidity in g/m³
+pub fn absolute_humidity(temperature: f32, humidity: f32) -> f32 {
+    let vapour_pressure = humidity.clamp(0.0, 100.0) / 100.0 * saturation_vapour_pressure(temperature);
+    WATER_VAPOUR * vapour_pressure / (temperature + 273.15)
+}
+
+/// Heat index ("feels like" temperature) in °C
+pub fn heat_index(temperature: f32, humidity: f32) -> f32 {
+    let t = temperature * 9.0 / 5.0 + 32.0;
+    let rh = humidity.clamp(0.0, 100.0);
+
+    // Steadman's simple formula is good enough below about 80 °F
+    let mut hi = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    if (hi + t) / 2.0 >= 80.0 {
+        hi = -42.379 + 2.049_015_2 * t + 10.143_331 * rh
+            - 0.224_755_4 * t * rh
+            - 0.006_837_83 * t * t
+            - 0.054_817_17 * rh * rh
+            + 0.001_228_74 * t * t * rh
+            + 0.000_852_82 * t * rh * rh
+            - 0.000_001_99 * t * t * rh * rh;
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= (13.0 - rh) / 4.0 * libm::sqrtf((17.0 - libm::fabsf(t - 95.0)) / 17.0);
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += (rh - 85.0) / 10.0 * ((87.0 - t) / 5.0);
+        }
+    }
+    (hi - 32.0) * 5.0 / 9.0
+}
+
+/// Station pressure reduced to sea level, in the unit of `pressure`
+pub fn sea_level_pressure(pressure: f32, temperature: f32, altitude_m: f32) -> f32 {
+    let drop = LAPSE_RATE * altitude_m;
+    pressure * libm::powf(1.0 - drop / (temperature + drop + 273.15), -5.257)
+}
+
+/// Derived-metrics settings
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DerivedMetrics {
+    /// Station altitude above sea level in metres; sea-level pressure is
+    /// left out without it
+    pub altitude_m: Option<f32>,
+}
+
+impl DerivedMetrics {
+    pub fn new(altitude_m: Option<f32>) -> Self {
+        Self { altitude_m }
+    }
+
+    /// Fill in the derived fields of environmental data from the measured
+    /// ones; a derived field stays `None` if an input is missing
+    pub fn apply(&self, data: &mut SensorData) {
+        let SensorData::Environmental {
+            temperature,
+            humidity,
+            pressure,
+            dew_point: dew,
+            absolute_humidity: absolute,
+            heat_index: feels_like,
+            sea_level_pressure: sea_level,
+            ..
+        } = data
+        else {
+            return;
+        };
+        let finite = |v: &Option<f32>| v.filter(|v| v.is_finite());
+        let (temperature, humidity, pressure) = (finite(temperature), finite(humidity), finite(pressure));
+
+        *dew = None;
+        *absolute = None;
+        *feels_like = None;
+        if let (Some(t), Some(rh)) = (temperature, humidity) {
+            *dew = dew_point(t, rh);
+            *absolute = Some(absolute_humidity(t, rh));
+            *feels_like = Some(heat_index(t, rh));
+        }
+        *sea_level = match (pressure, temperature, self.altitude_m) {
+            (Some(p), Some(t), Some(altitude)) => Some(sea_level_pressure(p, t, altitude)),
+            _ => None,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::Field;
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!((actual - expected).abs() <= tolerance, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn test_formulas() {
+        // Reference values from psychrometric tables
+        assert_close(dew_point(20.0, 50.0).unwrap(), 9.3, 0.05);
+        assert_close(dew_point(30.0, 80.0).unwrap(), 26.2, 0.05);
+        assert_close(dew_point(-10.0, 90.0).unwrap(), -11.3, 0.1);
+        assert_close(dew_point(25.0, 100.0).unwrap(), 25.0, 1e-3);
+        assert_eq!(dew_point(20.0, 0.0), None);
+
+        assert_close(absolute_humidity(20.0, 50.0), 8.6, 0.05);
+        assert_close(absolute_humidity(30.0, 80.0), 24.2, 0.1);
+        assert_eq!(absolute_humidity(20.0, 0.0), 0.0);
+
+        // NWS heat index chart: 90 °F and 70 % is 106 °F, 80 °F and 40 %
+        // is 80 °F; mild weather feels slightly cooler
+        assert_close(heat_index(32.22, 70.0), 41.1, 0.2);
+        assert_close(heat_index(26.67, 40.0), 26.7, 0.3);
+        assert_close(heat_index(15.0, 50.0), 13.9, 0.1);
+
+        assert_close(sea_level_pressure(955.0, 15.0, 500.0), 1013.0, 0.2);
+        assert_eq!(sea_level_pressure(1000.0, 15.0, 0.0), 1000.0);
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut data = SensorData::Environmental {
+            temperature: Some(20.0),
+            humidity: Some(50.0),
+            pressure: Some(955.0),
+            gas_resistance: None,
+            dew_point: None,
+            absolute_humidity: None,
+            heat_index: None,
+            sea_level_pressure: None,
+        };
+        DerivedMetrics::new(Some(500.0)).apply(&mut data);
+        assert_close(data.value(Field::DewPoint).unwrap(), 9.3, 0.05);
+        assert_close(data.value(Field::AbsoluteHumidity).unwrap(), 8.6, 0.05);
+        assert!(data.value(Field::HeatIndex).is_some());
+        assert_close(data.value(Field::SeaLevelPressure).unwrap(), 1012.0, 0.1);
+        assert_eq!(data.values().len(), 7);
+
+        // No altitude, no sea-level pressure; missing inputs clear stale values
+        DerivedMetrics::default().apply(&mut data);
+        assert_eq!(data.value(Field::SeaLevelPressure), None);
+        data.set_value(Field::Humidity, f32::NAN);
+        DerivedMetrics::default().apply(&mut data);
+        assert_eq!(data.value(Field::DewPoint), None);
+        assert_eq!(data.value(Field::HeatIndex), None);
+
+        let mut pm = SensorData::AirQuality { pm25: Some(1.0), pm10: None, pm25_corrected: None, pm10_corrected: None };
+        DerivedMetrics::new(Some(500.0)).apply(&mut pm);
+        assert_eq!(pm.values().len(), 1);
+    }
+}
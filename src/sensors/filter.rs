@@ -0,0 +1,315 @@
+//! Outlier and spike rejection
+//!
+//! The drivers only reject values outside the sensor's range, so a single
+//! bogus frame inside it still arrives as `Quality::Good`. This stage
+//! compares every value with the recent values of the same field:
+//!
+//! - median-of-N: the value is further from the median of the previous N
+//!   than a fraction of that median
+//! - Hampel: the value is further from that median than `hampel_k` scaled
+//!   median absolute deviations
+//! - rate of change: the value moved faster than the field plausibly can
+//!   since the last value that passed this check
+//!
+//! Each check that fires sets its `Flags` bit on the reading and lowers its
+//! quality to the one configured for the check. Values are kept in the
+//! history whether they pass or not, so a real step change is accepted once
+//! it makes up half of the window. Per-field floors keep the checks quiet
+//! around zero and on very steady signals.
+
+use heapless::{Deque, Vec};
+
+use super::stats::median;
+use super::{Field, Flags, Quality, SensorReading, MAX_FIELDS};
+
+/// Most previous values a field's checks can look at
+pub const MAX_WINDOW: usize = 9;
+
+/// Scales the median absolute deviation to the standard deviation of
+/// normally distributed values
+const MAD_SCALE: f32 = 1.4826;
+
+/// Limits of one field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FieldLimits {
+    pub field: Field,
+    /// Deviation from the median that never counts as an outlier, in the
+    /// field's unit
+    pub floor: f32,
+    /// Largest plausible change per second; infinite disables the check
+    pub max_rate: f32,
+}
+
+/// Filter settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterConfig {
+    /// Previous values per field the median and Hampel checks use, at most
+    /// `MAX_WINDOW`; both need `MIN_HISTORY` before they judge anything
+    pub window: usize,
+    /// Median-of-N: allowed deviation as a fraction of the median
+    pub median_tolerance: f32,
+    /// Hampel: allowed deviation in scaled median absolute deviations
+    pub hampel_k: f32,
+    /// Quality a reading gets at most when a check fires
+    pub median_quality: Quality,
+    pub hampel_quality: Quality,
+    pub rate_quality: Quality,
+    /// Fields without limits aren't checked
+    pub limits: Vec<FieldLimits, { Field::ALL.len() }>,
+}
+
+impl FilterConfig {
+    /// Previous values needed before the median and Hampel checks apply
+    pub const MIN_HISTORY: usize = 3;
+
+    /// Limits of a field, if it is checked
+    pub fn limits(&self, field: Field) -> Option<&FieldLimits> {
+        self.limits.iter().find(|l| l.field == field)
+    }
+
+    /// Median and Hampel checks only
+    pub fn without_rate_limits(mut self) -> Self {
+        self.limits.iter_mut().for_each(|l| l.max_rate = f32::INFINITY);
+        self
+    }
+}
+
+impl Default for FilterConfig {
+    /// Five previous values, flag anything off the median by more than
+    /// half of it or three deviations; rates a 30 s interval can't explain
+    /// mark the reading bad
+    fn default() -> Self {
+        let limits = [
+            (Field::Temperature, 1.0, 0.2),
+            (Field::Humidity, 5.0, 1.0),
+            (Field::Pressure, 2.0, 0.2),
+            (Field::GasResistance, 5_000.0, f32::INFINITY),
+            (Field::Pm25, 10.0, f32::INFINITY),
+            (Field::Pm10, 10.0, f32::INFINITY),
+            (Field::CoPpm, 5.0, f32::INFINITY),
+            (Field::Co2Ppm, 100.0, 50.0),
+            (Field::VocIndex, 50.0, f32::INFINITY),
+            (Field::DoseRate, 0.1, f32::INFINITY),
+            (Field::NoiseDbA, 10.0, f32::INFINITY),
+        ];
+        Self {
+            window: 5,
+            median_tolerance: 0.5,
+            hampel_k: 3.0,
+            median_quality: Quality::Degraded,
+            hampel_quality: Quality::Degraded,
+            rate_quality: Quality::Bad,
+            limits: limits.iter().map(|&(field, floor, max_rate)| FieldLimits { field, floor, max_rate }).collect(),
+        }
+    }
+}
+
+/// What is remembered about one field
+#[derive(Debug, Clone)]
+struct FieldHistory {
+    field: Field,
+    values: Deque<f32, MAX_WINDOW>,
+    /// Last value that passed the rate check, with its timestamp
+    last_plausible: Option<(u64, f32)>,
+}
+
+/// Outlier filter for the readings of one sensor
+pub struct OutlierFilter {
+    config: FilterConfig,
+    fields: Vec<FieldHistory, MAX_FIELDS>,
+}
+
+impl OutlierFilter {
+    pub fn new(config: FilterConfig) -> Self {
+        Self { config, fields: Vec::new() }
+    }
+
+    /// Check a reading, flag and downgrade it if any value is suspicious
+    ///
+    /// Returns the flags raised. Bad readings are passed through untouched
+    /// and don't enter the history.
+    pub fn apply(&mut self, reading: &mut SensorReading) -> Flags {
+        let mut raised = Flags::NONE;
+        if !reading.is_valid() {
+            return raised;
+        }
+        for (field, value) in reading.data.values() {
+            let Some(limits) = self.config.limits(field).copied() else {
+                continue;
+            };
+            if !value.is_finite() {
+                continue;
+            }
+            let history = match self.fields.iter().position(|h| h.field == field) {
+                Some(i) => &mut self.fields[i],
+                None => {
+                    let history = FieldHistory { field, values: Deque::new(), last_plausible: None };
+                    // One entry per field of a variant, so there is always room
+                    let _ = self.fields.push(history);
+                    let last = self.fields.len() - 1;
+                    &mut self.fields[last]
+                }
+            };
+            let flags = Self::check(&self.config, &limits, history, reading.timestamp, value);
+
+            let window = self.config.window.min(MAX_WINDOW);
+            while history.values.len() >= window.max(1) {
+                history.values.pop_front();
+            }
+            let _ = history.values.push_back(value);
+            if !flags.contains(Flags::RATE_OF_CHANGE) {
+                history.last_plausible = Some((reading.timestamp, value));
+            }
+            raised.insert(flags);
+        }
+
+        for (flag, quality) in [
+            (Flags::MEDIAN_OUTLIER, self.config.median_quality),
+            (Flags::HAMPEL_OUTLIER, self.config.hampel_quality),
+            (Flags::RATE_OF_CHANGE, self.config.rate_quality),
+        ] {
+            if raised.contains(flag) {
+                reading.quality = reading.quality.max(quality);
+            }
+        }
+        reading.flags.insert(raised);
+        raised
+    }
+
+    fn check(config: &FilterConfig, limits: &FieldLimits, history: &FieldHistory, timestamp: u64, value: f32) -> Flags {
+        let mut flags = Flags::NONE;
+
+        if history.values.len() >= FilterConfig::MIN_HISTORY {
+            let mut previous: Vec<f32, MAX_WINDOW> = history.values.iter().copied().collect();
+            // Never empty here
+            let center = median(&mut previous).unwrap_or(value);
+            let deviation = libm::fabsf(value - center);
+
+            if deviation > (config.median_tolerance * libm::fabsf(center)).max(limits.floor) {
+                flags.insert(Flags::MEDIAN_OUTLIER);
+            }
+            previous.iter_mut().for_each(|v| *v = libm::fabsf(*v - center));
+            let mad = median(&mut previous).unwrap_or_default();
+            if deviation > (config.hampel_k * MAD_SCALE * mad).max(limits.floor) {
+                flags.insert(Flags::HAMPEL_OUTLIER);
+            }
+        }
+
+        if let Some((then, previous)) = history.last_plausible {
+            let seconds = timestamp.saturating_sub(then) as f32 / 1000.0;
+            if libm::fabsf(value - previous) > limits.max_rate * seconds {
+                flags.insert(Flags::RATE_OF_CHANGE);
+            }
+        }
+        flags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::{SensorData, SensorType};
+
+    fn pm(pm25: f32, timestamp: u64) -> SensorReading {
+        SensorReading {
+            sensor_type: SensorType::SDS011,
+            data: SensorData::AirQuality { pm25: Some(pm25), pm10: None, pm25_corrected: None, pm10_corrected: None },
+            timestamp,
+            utc_timestamp: None,
+            quality: Quality::Good,
+            flags: Flags::NONE,
+        }
+    }
+
+    fn temperature(celsius: f32, timestamp: u64) -> SensorReading {
+        SensorReading {
+            sensor_type: SensorType::BME280,
+            data: SensorData::Environmental { temperature: Some(celsius), humidity: None, pressure: None, gas_resistance: None, dew_point: None, absolute_humidity: None, heat_index: None, sea_level_pressure: None },
+            timestamp,
+            utc_timestamp: None,
+            quality: Quality::Good,
+            flags: Flags::NONE,
+        }
+    }
+
+    /// Run values 30 s apart through a filter, returning the flags of each
+    fn run(filter: &mut OutlierFilter, reading: fn(f32, u64) -> SensorReading, values: &[f32]) -> std::vec::Vec<Flags> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, v)| {
+                let mut r = reading(*v, i as u64 * 30_000);
+                let raised = filter.apply(&mut r);
+                assert_eq!(r.flags, raised);
+                raised
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_single_spike() {
+        let mut filter = OutlierFilter::new(FilterConfig::default());
+        let flags = run(&mut filter, pm, &[12.0, 13.0, 11.0, 12.5, 480.0, 12.0, 14.0]);
+        assert!(flags[..4].iter().all(Flags::is_empty), "{:?}", flags);
+        assert_eq!(flags[4], Flags::MEDIAN_OUTLIER | Flags::HAMPEL_OUTLIER);
+        assert!(flags[5..].iter().all(Flags::is_empty), "{:?}", flags);
+
+        // The spike reading itself
+        let mut spike = pm(600.0, 300_000);
+        filter.apply(&mut spike);
+        assert_eq!(spike.quality, Quality::Degraded);
+        assert_eq!(spike.flags.names().collect::<std::vec::Vec<_>>(), ["median_outlier", "hampel_outlier"]);
+    }
+
+    #[test]
+    fn test_floor_and_warm_up() {
+        let mut filter = OutlierFilter::new(FilterConfig::default());
+        // Too little history to judge the jump, then a steady signal where
+        // small wobbles stay below the floor despite a zero MAD
+        let flags = run(&mut filter, pm, &[1.0, 40.0, 2.0, 2.0, 2.0, 2.0, 9.0, 2.0]);
+        assert!(flags.iter().all(Flags::is_empty), "{:?}", flags);
+    }
+
+    #[test]
+    fn test_step_change_accepted() {
+        let mut filter = OutlierFilter::new(FilterConfig::default());
+        let flags = run(&mut filter, pm, &[10.0, 10.0, 10.0, 10.0, 10.0, 80.0, 82.0, 81.0, 80.0, 79.0]);
+        assert_eq!(flags[5], Flags::MEDIAN_OUTLIER | Flags::HAMPEL_OUTLIER);
+        assert!(!flags[6].is_empty());
+        // Half the window holds the new level
+        assert!(flags[8..].iter().all(Flags::is_empty), "{:?}", flags);
+    }
+
+    #[test]
+    fn test_rate_of_change() {
+        let config = FilterConfig { window: 0, ..FilterConfig::default() };
+        let mut filter = OutlierFilter::new(config);
+        // 0.2 °C/s allows 6 °C in 30 s; the jump back is measured from the
+        // last plausible value and passes
+        let flags = run(&mut filter, temperature, &[20.0, 25.5, 35.0, 21.0]);
+        assert_eq!(flags, [Flags::NONE, Flags::NONE, Flags::RATE_OF_CHANGE, Flags::NONE]);
+
+        let mut reading = temperature(40.0, 120_000);
+        filter.apply(&mut reading);
+        assert_eq!(reading.quality, Quality::Bad);
+
+        // A value that stays there is accepted once enough time has passed
+        let mut reading = temperature(40.0, 200_000);
+        assert_eq!(filter.apply(&mut reading), Flags::NONE);
+
+        let mut filter = OutlierFilter::new(FilterConfig::default().without_rate_limits());
+        assert!(run(&mut filter, temperature, &[20.0, 35.0]).iter().all(Flags::is_empty));
+    }
+
+    #[test]
+    fn test_bad_readings_ignored() {
+        let mut filter = OutlierFilter::new(FilterConfig::default());
+        run(&mut filter, pm, &[12.0, 12.0, 12.0]);
+        let mut bad = pm(999.0, 100_000);
+        bad.quality = Quality::Bad;
+        assert_eq!(filter.apply(&mut bad), Flags::NONE);
+        // Not in the history either
+        assert!(run(&mut filter, pm, &[12.0]).iter().all(Flags::is_empty));
+        assert_eq!(filter.fields[0].values.len(), 4);
+    }
+}
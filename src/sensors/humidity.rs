@@ -0,0 +1,214 @@
+//! Humidity correction for particulate matter
+//!
+//! Optical PM sensors like the SDS011 see particles that have taken up
+//! water as larger ones, so they read high in humid air. This stage takes
+//! the relative humidity an environmental sensor on the same node reported
+//! last and adds corrected PM values next to the raw ones, using one of a
+//! few published models.
+//!
+//! Above `cutoff` the models are unreliable (fog, condensation), so the
+//! reading is still corrected but flagged `HIGH_HUMIDITY` and downgraded.
+//! Without a recent humidity value the reading passes through unchanged.
+
+use super::latest::{self, FieldValue};
+use super::{Field, Flags, Quality, SensorData, SensorReading};
+
+/// Humidity the models are evaluated at most, in percent; they diverge
+/// towards saturation
+pub const MAX_HUMIDITY: f32 = 99.0;
+
+/// Published correction models
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CorrectionModel {
+    /// κ-Köhler hygroscopic growth (Crilley et al. 2018):
+    /// PM / (1 + (κ / 1.65) / (1 / aw − 1)), aw the humidity as a fraction
+    Kohler { kappa: f32 },
+    /// Empirical growth factor (Chakrabarti et al. 2004):
+    /// PM / (1 + 0.25 · aw² / (1 − aw))
+    Chakrabarti,
+    /// US-wide correction of the US EPA (Barkjohn et al. 2021), PM2.5 only:
+    /// 0.524 · PM2.5 − 0.0862 · RH + 5.75
+    Epa,
+}
+
+impl CorrectionModel {
+    /// κ of ammonium sulfate-like urban aerosol
+    pub const DEFAULT_KAPPA: f32 = 0.4;
+
+    /// Machine-friendly name
+    pub fn name(&self) -> &'static str {
+        match self {
+            CorrectionModel::Kohler { .. } => "kohler",
+            CorrectionModel::Chakrabarti => "chakrabarti",
+            CorrectionModel::Epa => "epa",
+        }
+    }
+
+    /// Corrected PM2.5 and PM10 at `humidity` percent; a value is `None`
+    /// if it is missing or the model doesn't cover it
+    pub fn correct(&self, pm25: Option<f32>, pm10: Option<f32>, humidity: f32) -> (Option<f32>, Option<f32>) {
+        let humidity = humidity.clamp(0.0, MAX_HUMIDITY);
+        let aw = humidity / 100.0;
+        let growth = match *self {
+            CorrectionModel::Kohler { kappa } => 1.0 + kappa / 1.65 * aw / (1.0 - aw),
+            CorrectionModel::Chakrabarti => 1.0 + 0.25 * aw * aw / (1.0 - aw),
+            CorrectionModel::Epa => {
+                let pm25 = pm25.map(|pm| (0.524 * pm - 0.0862 * humidity + 5.75).max(0.0));
+                return (pm25, None);
+            }
+        };
+        (pm25.map(|pm| pm / growth), pm10.map(|pm| pm / growth))
+    }
+}
+
+/// Correction settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct HumidityCorrection {
+    pub model: CorrectionModel,
+    /// Humidity in percent above which PM readings are downgraded
+    pub cutoff: f32,
+    /// Quality a reading gets at most above the cutoff
+    pub quality: Quality,
+    /// Oldest humidity value still used, in milliseconds
+    pub max_age_ms: u64,
+}
+
+impl Default for HumidityCorrection {
+    /// Köhler with the default κ, downgrade above 80 %, humidity up to
+    /// five minutes old
+    fn default() -> Self {
+        Self {
+            model: CorrectionModel::Kohler { kappa: CorrectionModel::DEFAULT_KAPPA },
+            cutoff: 80.0,
+            quality: Quality::Degraded,
+            max_age_ms: 300_000,
+        }
+    }
+}
+
+impl HumidityCorrection {
+    /// Correct a PM reading for `humidity` percent
+    ///
+    /// Returns the flags raised. Other readings, bad ones and readings
+    /// without a usable humidity are left untouched.
+    pub fn apply(&self, reading: &mut SensorReading, humidity: Option<f32>) -> Flags {
+        let Some(humidity) = humidity.filter(|h| h.is_finite()) else {
+            return Flags::NONE;
+        };
+        if !reading.is_valid() {
+            return Flags::NONE;
+        }
+        let SensorData::AirQuality { pm25, pm10, pm25_corrected, pm10_corrected } = &mut reading.data else {
+            return Flags::NONE;
+        };
+        let finite = |v: &Option<f32>| v.filter(|v| v.is_finite());
+        (*pm25_corrected, *pm10_corrected) = self.model.correct(finite(pm25), finite(pm10), humidity);
+
+        if humidity <= self.cutoff {
+            return Flags::NONE;
+        }
+        reading.quality = reading.quality.max(self.quality);
+        reading.flags.insert(Flags::HIGH_HUMIDITY);
+        Flags::HIGH_HUMIDITY
+    }
+
+    /// Humidity to correct a reading taken at `timestamp` with, if `latest`
+    /// is recent enough
+    pub fn humidity(&self, latest: Option<FieldValue>, timestamp: u64) -> Option<f32> {
+        latest.filter(|h| h.age_ms(timestamp) <= self.max_age_ms).map(|h| h.value)
+    }
+
+    /// Correct a PM reading with the latest good humidity of the device
+    pub fn apply_latest(&self, reading: &mut SensorReading) -> Flags {
+        if !matches!(reading.data, SensorData::AirQuality { .. }) {
+            return Flags::NONE;
+        }
+        let humidity = self.humidity(latest::latest_field(Field::Humidity), reading.timestamp);
+        self.apply(reading, humidity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::SensorType;
+
+    fn pm(pm25: f32, pm10: f32) -> SensorReading {
+        SensorReading {
+            sensor_type: SensorType::SDS011,
+            data: SensorData::AirQuality { pm25: Some(pm25), pm10: Some(pm10), pm25_corrected: None, pm10_corrected: None },
+            timestamp: 60_000,
+            utc_timestamp: None,
+            quality: Quality::Good,
+            flags: Flags::NONE,
+        }
+    }
+
+    fn assert_close(actual: Option<f32>, expected: f32) {
+        let actual = actual.unwrap();
+        assert!((actual - expected).abs() < 1e-3, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn test_models() {
+        let kohler = CorrectionModel::Kohler { kappa: 0.4 };
+        let (pm25, pm10) = kohler.correct(Some(30.0), Some(60.0), 60.0);
+        assert_close(pm25, 22.0);
+        assert_close(pm10, 44.0);
+
+        let (pm25, pm10) = CorrectionModel::Chakrabarti.correct(Some(30.0), None, 60.0);
+        assert_close(pm25, 24.489_8);
+        assert_eq!(pm10, None);
+
+        let (pm25, pm10) = CorrectionModel::Epa.correct(Some(30.0), Some(60.0), 60.0);
+        assert_close(pm25, 16.298);
+        assert_eq!(pm10, None);
+        assert_eq!(CorrectionModel::Epa.correct(Some(0.0), None, 90.0).0, Some(0.0));
+
+        // Dry air leaves growth models alone, saturation stays finite
+        assert_eq!(kohler.correct(Some(30.0), None, 0.0).0, Some(30.0));
+        assert!(kohler.correct(Some(30.0), None, 100.0).0.unwrap() > 0.0);
+    }
+
+    #[test]
+    fn test_apply() {
+        let correction = HumidityCorrection::default();
+        let mut reading = pm(30.0, 60.0);
+        assert_eq!(correction.apply(&mut reading, Some(60.0)), Flags::NONE);
+        assert_eq!(reading.data.value(Field::Pm25), Some(30.0));
+        assert_close(reading.data.value(Field::Pm25Corrected), 22.0);
+        assert_close(reading.data.value(Field::Pm10Corrected), 44.0);
+        assert_eq!(reading.quality, Quality::Good);
+
+        let mut humid = pm(30.0, 60.0);
+        assert_eq!(correction.apply(&mut humid, Some(92.0)), Flags::HIGH_HUMIDITY);
+        assert_eq!(humid.quality, Quality::Degraded);
+        assert!(humid.flags.contains(Flags::HIGH_HUMIDITY));
+        assert!(humid.data.value(Field::Pm25Corrected).is_some());
+
+        // Nothing to correct with, or nothing to correct
+        let mut reading = pm(30.0, 60.0);
+        assert_eq!(correction.apply(&mut reading, None), Flags::NONE);
+        assert_eq!(correction.apply(&mut reading, Some(f32::NAN)), Flags::NONE);
+        assert_eq!(reading.data.value(Field::Pm25Corrected), None);
+        reading.quality = Quality::Bad;
+        correction.apply(&mut reading, Some(95.0));
+        assert!(reading.flags.is_empty());
+        let mut env = SensorReading {
+            sensor_type: SensorType::BME280,
+            data: SensorData::Environmental { temperature: None, humidity: Some(95.0), pressure: None, gas_resistance: None, dew_point: None, absolute_humidity: None, heat_index: None, sea_level_pressure: None },
+            ..pm(0.0, 0.0)
+        };
+        assert_eq!(correction.apply(&mut env, Some(95.0)), Flags::NONE);
+        assert_eq!(env.quality, Quality::Good);
+    }
+
+    #[test]
+    fn test_humidity_age() {
+        let correction = HumidityCorrection::default();
+        let latest = FieldValue { sensor_type: SensorType::BME280, value: 55.0, timestamp: 10_000 };
+        assert_eq!(correction.humidity(Some(latest), 310_000), Some(55.0));
+        assert_eq!(correction.humidity(Some(latest), 310_001), None);
+        assert_eq!(correction.humidity(None, 0), None);
+    }
+}
@@ -0,0 +1,211 @@
+//! Latest-value cache
+//!
+//! Keeps, per sensor type, the most recent reading and the most recent one
+//! of `Quality::Good`, so the API, a display or alarm logic can ask for
+//! "the current PM2.5" without listening to the reading bus themselves.
+//! The aggregator records every reading into the global `LATEST` store.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use heapless::Vec;
+
+use super::{Field, Quality, SensorReading, SensorType};
+
+/// Number of sensor types
+pub const SENSOR_TYPES: usize = SensorType::ALL.len();
+
+/// What is known about one sensor type
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// Most recent reading, whatever its quality
+    pub last: SensorReading,
+    /// Most recent reading of good quality
+    pub last_good: Option<SensorReading>,
+}
+
+impl Snapshot {
+    /// Milliseconds since the most recent reading
+    pub fn age_ms(&self, now_ms: u64) -> u64 {
+        now_ms.saturating_sub(self.last.timestamp)
+    }
+
+    /// Milliseconds since the most recent good reading
+    pub fn good_age_ms(&self, now_ms: u64) -> Option<u64> {
+        self.last_good.as_ref().map(|r| now_ms.saturating_sub(r.timestamp))
+    }
+}
+
+/// A single good value and where it came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FieldValue {
+    pub sensor_type: SensorType,
+    pub value: f32,
+    /// Milliseconds since boot
+    pub timestamp: u64,
+}
+
+impl FieldValue {
+    pub fn age_ms(&self, now_ms: u64) -> u64 {
+        now_ms.saturating_sub(self.timestamp)
+    }
+}
+
+/// Snapshots indexed like `SensorType::ALL`
+pub struct LatestValues {
+    snapshots: [Option<Snapshot>; SENSOR_TYPES],
+}
+
+impl Default for LatestValues {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatestValues {
+    pub const fn new() -> Self {
+        Self { snapshots: [const { None }; SENSOR_TYPES] }
+    }
+
+    fn index(sensor_type: SensorType) -> usize {
+        // Every sensor type is in `ALL`
+        SensorType::ALL.iter().position(|t| *t == sensor_type).unwrap_or_default()
+    }
+
+    /// Remember a reading as the latest of its sensor type
+    pub fn record(&mut self, reading: &SensorReading) {
+        let slot = &mut self.snapshots[Self::index(reading.sensor_type)];
+        let good = (reading.quality == Quality::Good).then(|| reading.clone());
+        match slot {
+            Some(snapshot) => {
+                snapshot.last = reading.clone();
+                if good.is_some() {
+                    snapshot.last_good = good;
+                }
+            }
+            None => *slot = Some(Snapshot { last: reading.clone(), last_good: good }),
+        }
+    }
+
+    /// Snapshot of a sensor type, if it has reported
+    pub fn latest(&self, sensor_type: SensorType) -> Option<&Snapshot> {
+        self.snapshots[Self::index(sensor_type)].as_ref()
+    }
+
+    /// Most recent good value of a field, from whichever sensor type
+    /// reported it last
+    pub fn latest_field(&self, field: Field) -> Option<FieldValue> {
+        self.snapshots
+            .iter()
+            .flatten()
+            .filter_map(|snapshot| {
+                let reading = snapshot.last_good.as_ref()?;
+                let value = reading.data.value(field)?;
+                Some(FieldValue { sensor_type: reading.sensor_type, value, timestamp: reading.timestamp })
+            })
+            .max_by_key(|value| value.timestamp)
+    }
+
+    /// Most recent reading of every sensor type that has reported, in
+    /// `SensorType::ALL` order
+    pub fn readings(&self) -> impl Iterator<Item = &SensorReading> {
+        self.snapshots.iter().flatten().map(|snapshot| &snapshot.last)
+    }
+}
+
+/// Latest values of the running device
+pub static LATEST: Mutex<CriticalSectionRawMutex, RefCell<LatestValues>> = Mutex::new(RefCell::new(LatestValues::new()));
+
+/// Record a reading in the global store
+pub fn record(reading: &SensorReading) {
+    LATEST.lock(|latest| latest.borrow_mut().record(reading));
+}
+
+/// Snapshot of a sensor type from the global store
+pub fn latest(sensor_type: SensorType) -> Option<Snapshot> {
+    LATEST.lock(|latest| latest.borrow().latest(sensor_type).cloned())
+}
+
+/// Most recent good value of a field from the global store
+pub fn latest_field(field: Field) -> Option<FieldValue> {
+    LATEST.lock(|latest| latest.borrow().latest_field(field))
+}
+
+/// Most recent reading of every sensor type from the global store
+pub fn latest_readings() -> Vec<SensorReading, SENSOR_TYPES> {
+    LATEST.lock(|latest| latest.borrow().readings().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::{Flags, SensorData};
+
+    fn pm(pm25: f32, timestamp: u64, quality: Quality) -> SensorReading {
+        SensorReading {
+            sensor_type: SensorType::SDS011,
+            data: SensorData::AirQuality { pm25: Some(pm25), pm10: Some(pm25 * 2.0), pm25_corrected: None, pm10_corrected: None },
+            timestamp,
+            utc_timestamp: None,
+            quality,
+            flags: Flags::NONE,
+        }
+    }
+
+    #[test]
+    fn test_last_and_last_good() {
+        let mut latest = LatestValues::new();
+        assert!(latest.latest(SensorType::SDS011).is_none());
+        assert!(latest.latest_field(Field::Pm25).is_none());
+
+        // Nothing good yet
+        latest.record(&pm(1.0, 1_000, Quality::Degraded));
+        let snapshot = latest.latest(SensorType::SDS011).unwrap();
+        assert_eq!(snapshot.age_ms(1_500), 500);
+        assert_eq!(snapshot.good_age_ms(1_500), None);
+        assert!(latest.latest_field(Field::Pm25).is_none());
+
+        latest.record(&pm(2.0, 2_000, Quality::Good));
+        latest.record(&pm(999.0, 3_000, Quality::Bad));
+        let snapshot = latest.latest(SensorType::SDS011).unwrap();
+        assert_eq!(snapshot.last.quality, Quality::Bad);
+        assert_eq!(snapshot.age_ms(3_500), 500);
+        assert_eq!(snapshot.good_age_ms(3_500), Some(1_500));
+        assert_eq!(
+            latest.latest_field(Field::Pm25),
+            Some(FieldValue { sensor_type: SensorType::SDS011, value: 2.0, timestamp: 2_000 })
+        );
+        assert!(latest.latest(SensorType::BME280).is_none());
+        assert_eq!(latest.readings().count(), 1);
+    }
+
+    #[test]
+    fn test_latest_field_across_sensors() {
+        let mut latest = LatestValues::new();
+        latest.record(&pm(5.0, 1_000, Quality::Good));
+        let mut pms = pm(7.0, 2_000, Quality::Good);
+        pms.sensor_type = SensorType::PMS7003;
+        latest.record(&pms);
+        latest.record(&SensorReading {
+            sensor_type: SensorType::BME280,
+            data: SensorData::Environmental { temperature: Some(21.0), humidity: None, pressure: None, gas_resistance: None, dew_point: None, absolute_humidity: None, heat_index: None, sea_level_pressure: None },
+            timestamp: 3_000,
+            utc_timestamp: None,
+            quality: Quality::Good,
+            flags: Flags::NONE,
+        });
+
+        // Newest wins, whichever sensor it came from
+        let pm25 = latest.latest_field(Field::Pm25).unwrap();
+        assert_eq!((pm25.sensor_type, pm25.value, pm25.age_ms(2_500)), (SensorType::PMS7003, 7.0, 500));
+        latest.record(&pm(6.0, 4_000, Quality::Good));
+        assert_eq!(latest.latest_field(Field::Pm25).unwrap().sensor_type, SensorType::SDS011);
+
+        assert_eq!(latest.latest_field(Field::Temperature).unwrap().value, 21.0);
+        assert!(latest.latest_field(Field::Humidity).is_none());
+
+        let order: std::vec::Vec<_> = latest.readings().map(|r| r.sensor_type).collect();
+        assert_eq!(order, [SensorType::BME280, SensorType::SDS011, SensorType::PMS7003]);
+    }
+}
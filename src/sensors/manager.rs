@@ -1,37 +1,55 @@
-use super::{Sensor, SensorReading, SensorError, SensorType};
-use embassy_sync::channel::{Channel, Receiver, Sender};
+use super::bus::READINGS;
+use super::derived::DerivedMetrics;
+use super::filter::{FilterConfig, OutlierFilter};
+use super::humidity::HumidityCorrection;
+use super::stats::{self, TumblingWindows, WindowRecord};
+use super::{Sensor, SensorError, SensorType};
+use crate::config::SensorSettings;
+use core::cell::RefCell;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_time::Timer;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
 use heapless::Vec;
 
-/// Global channel for sensor readings
-/// All sensor tasks send their readings here
-/// Buffer size of 32 should handle bursts from multiple sensors
-pub static SENSOR_CHANNEL: Channel<CriticalSectionRawMutex, SensorReading, 32> = Channel::new();
+/// Global sensor registry
+/// The firmware registers sensors at startup, sensor tasks keep their
+/// statistics up to date and status pages read them
+pub static SENSOR_MANAGER: Mutex<CriticalSectionRawMutex, RefCell<SensorManager>> =
+    Mutex::new(RefCell::new(SensorManager::new()));
 
-/// Get sender for sensor readings
-pub fn get_sensor_sender() -> Sender<'static, CriticalSectionRawMutex, SensorReading, 32> {
-    SENSOR_CHANNEL.sender()
+/// Run `f` with exclusive access to the global sensor registry
+pub fn with_sensor_manager<R>(f: impl FnOnce(&mut SensorManager) -> R) -> R {
+    SENSOR_MANAGER.lock(|manager| f(&mut manager.borrow_mut()))
 }
 
-/// Get receiver for sensor readings  
-pub fn get_sensor_receiver() -> Receiver<'static, CriticalSectionRawMutex, SensorReading, 32> {
-    SENSOR_CHANNEL.receiver()
-}
+/// Sensors the manager keeps track of at most
+pub const MAX_SENSORS: usize = 16;
 
 /// Registry entry for a sensor
+#[derive(Debug, Clone)]
 pub struct SensorRegistry {
     pub sensor_type: SensorType,
     pub task_spawned: bool,
     pub last_reading_time: u64,
     pub error_count: u32,
+    /// Readings that overwrote one a bus subscriber hadn't read yet
+    pub dropped_count: u32,
+    /// Times the task backed off after repeated errors
+    pub backoff_count: u32,
 }
 
 /// Sensor manager handles sensor registration and coordination
 /// Keeps track of all active sensors without storing the sensor objects
 /// (since they're owned by their tasks)
 pub struct SensorManager {
-    registry: Vec<SensorRegistry, 16>, // Support up to 16 sensors
+    registry: Vec<SensorRegistry, MAX_SENSORS>,
+}
+
+impl Default for SensorManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SensorManager {
@@ -55,6 +73,8 @@ impl SensorManager {
             task_spawned: false,
             last_reading_time: 0,
             error_count: 0,
+            dropped_count: 0,
+            backoff_count: 0,
         };
         
         self.registry.push(entry).map_err(|_| SensorError::ConfigError)?;
@@ -78,6 +98,27 @@ impl SensorManager {
         }
     }
     
+    /// Count a failed read without touching the last reading time
+    pub fn record_error(&mut self, sensor_type: SensorType) {
+        if let Some(entry) = self.registry.iter_mut().find(|s| s.sensor_type == sensor_type) {
+            entry.error_count += 1;
+        }
+    }
+    
+    /// Count a reading dropped on a full channel
+    pub fn record_dropped(&mut self, sensor_type: SensorType) {
+        if let Some(entry) = self.registry.iter_mut().find(|s| s.sensor_type == sensor_type) {
+            entry.dropped_count += 1;
+        }
+    }
+    
+    /// Count a back-off after repeated errors
+    pub fn record_backoff(&mut self, sensor_type: SensorType) {
+        if let Some(entry) = self.registry.iter_mut().find(|s| s.sensor_type == sensor_type) {
+            entry.backoff_count += 1;
+        }
+    }
+    
     /// Get list of registered sensors
     pub fn get_registered_sensors(&self) -> &[SensorRegistry] {
         &self.registry
@@ -94,44 +135,38 @@ impl SensorManager {
     }
 }
 
-/// Create sensor tasks dynamically for different sensor types
-/// This works around Embassy's limitation of no generic tasks
-
-/// ME2-CO sensor task
-#[embassy_executor::task]
-pub async fn me2co_sensor_task(mut sensor: super::me2co::Me2CoSensorWrapper) {
-    sensor_task_impl(&mut sensor).await;
-}
-
-/// SDS011 sensor task  
-#[embassy_executor::task]
-pub async fn sds011_sensor_task(mut sensor: super::sds011::Sds011Sensor) {
-    sensor_task_impl(&mut sensor).await;
-}
-
-/// BME280 sensor task
-#[embassy_executor::task]
-pub async fn bme280_sensor_task(mut sensor: super::bme280::Bme280Sensor) {
-    sensor_task_impl(&mut sensor).await;
-}
-
 /// Generic sensor task implementation that can work with any sensor
 /// This is the core logic shared by all sensor-specific tasks
-async fn sensor_task_impl<S: Sensor>(sensor: &mut S) {
+///
+/// Embassy tasks can't be generic, so the firmware wraps this in one
+/// `#[embassy_executor::task]` per concrete sensor type
+///
+/// `settings` may override the reading interval and calibrates every
+/// reading; `filter` sets how suspicious values are
+/// flagged before readings are published, `humidity` how PM readings
+/// are corrected for the latest humidity and `derived` which quantities
+/// are added to environmental readings
+pub async fn sensor_task_impl<S: Sensor>(
+    sensor: &mut S,
+    settings: &SensorSettings,
+    filter: FilterConfig,
+    humidity: HumidityCorrection,
+    derived: DerivedMetrics,
+) {
     let sensor_info = sensor.info();
-    let sender = get_sensor_sender();
+    let mut filter = OutlierFilter::new(filter);
     
-    esp_println::println!("[{}] Starting sensor task", sensor_info.name);
+    log::info!("[{}] Starting sensor task", sensor_info.name);
     
     // Initialize sensor
     loop {
         match sensor.init().await {
             Ok(()) => {
-                esp_println::println!("[{}] Initialized successfully", sensor_info.name);
+                log::info!("[{}] Initialized successfully", sensor_info.name);
                 break;
             }
             Err(e) => {
-                esp_println::println!("[{}] Init failed: {}, retrying in 5s", sensor_info.name, e);
+                log::warn!("[{}] Init failed: {}, retrying in 5s", sensor_info.name, e);
                 Timer::after(embassy_time::Duration::from_secs(5)).await;
             }
         }
@@ -140,40 +175,50 @@ async fn sensor_task_impl<S: Sensor>(sensor: &mut S) {
     // Wait for warm-up if needed
     let warm_up = sensor.warm_up_time();
     if warm_up.as_secs() > 0 {
-        esp_println::println!("[{}] Warming up for {}s", sensor_info.name, warm_up.as_secs());
+        log::info!("[{}] Warming up for {}s", sensor_info.name, warm_up.as_secs());
         Timer::after(warm_up).await;
     }
     
     // Main reading loop
-    let interval = sensor.reading_interval();
+    let interval = settings.interval(sensor.reading_interval());
     let mut consecutive_errors = 0u32;
     
-    esp_println::println!("[{}] Starting readings every {}s", sensor_info.name, interval.as_secs());
+    log::info!("[{}] Starting readings every {}s", sensor_info.name, interval.as_secs());
     
     loop {
         match sensor.read().await {
-            Ok(reading) => {
+            Ok(mut reading) => {
                 // Reset error counter on successful read
                 consecutive_errors = 0;
+                settings.calibrate(&mut reading);
+                let raised = filter.apply(&mut reading);
+                if !raised.is_empty() {
+                    log::warn!("[{}] Suspicious reading ({}), quality {}", 
+                        sensor_info.name, raised, reading.quality.name());
+                }
+                if !humidity.apply_latest(&mut reading).is_empty() {
+                    log::warn!("[{}] Humidity above {}%, quality {}", 
+                        sensor_info.name, humidity.cutoff, reading.quality.name());
+                }
+                derived.apply(&mut reading.data);
+                with_sensor_manager(|m| m.update_sensor_stats(sensor_info.sensor_type, reading.timestamp, !reading.is_valid()));
                 
-                // Send reading to aggregator
-                match sender.try_send(reading) {
-                    Ok(()) => {
-                        // Success - reading sent
-                    }
-                    Err(_) => {
-                        esp_println::println!("[{}] Channel full, dropping reading", sensor_info.name);
-                    }
+                // Publish to every consumer, never waiting for slow ones
+                if !READINGS.publish(reading) {
+                    with_sensor_manager(|m| m.record_dropped(sensor_info.sensor_type));
+                    log::warn!("[{}] Reading bus full, oldest reading overwritten", sensor_info.name);
                 }
             }
             Err(e) => {
                 consecutive_errors += 1;
-                esp_println::println!("[{}] Read error ({}): {}", 
+                with_sensor_manager(|m| m.record_error(sensor_info.sensor_type));
+                log::warn!("[{}] Read error ({}): {}", 
                     sensor_info.name, consecutive_errors, e);
                 
                 // If too many consecutive errors, increase delay
                 if consecutive_errors > 3 {
-                    esp_println::println!("[{}] Too many errors, backing off", sensor_info.name);
+                    with_sensor_manager(|m| m.record_backoff(sensor_info.sensor_type));
+                    log::warn!("[{}] Too many errors, backing off", sensor_info.name);
                     Timer::after(embassy_time::Duration::from_secs(60)).await;
                     continue;
                 }
@@ -184,51 +229,132 @@ async fn sensor_task_impl<S: Sensor>(sensor: &mut S) {
     }
 }
 
-/// Sensor aggregator task that receives all sensor readings
-/// This is where we can add data processing, filtering, etc.
-#[embassy_executor::task]
-pub async fn sensor_aggregator_task() {
-    let receiver = get_sensor_receiver();
+/// Hand the averages of a closed window to the uploaders that want one
+/// value per upload period
+fn emit_window(record: &WindowRecord) {
+    stats::log_record(record);
+    let reading = record.mean_reading();
+    crate::net::sensor_community::queue_reading(&reading);
+    // openSenseMap and InfluxDB accept timestamped data, so they catch up after outages
+    crate::net::store_forward::queue_reading(&reading);
+}
+
+/// Sensor aggregator, feeding readings from the bus to the upload backends
+///
+/// The periodic uploaders get one averaged reading per sensor and tumbling
+/// window of `window`; a zero window passes readings through unchanged.
+/// Live consumers (latest values, Home Assistant) subscribe to the bus
+/// themselves.
+pub async fn sensor_aggregator_impl(window: Duration) {
+    let mut subscriber = match READINGS.subscribe("aggregator") {
+        Ok(subscriber) => subscriber,
+        Err(e) => {
+            log::error!("[AGGREGATOR] Can't subscribe to readings: {}", e);
+            return;
+        }
+    };
     
-    esp_println::println!("[AGGREGATOR] Starting sensor data aggregator");
+    log::info!("[AGGREGATOR] Starting sensor data aggregator, {}s windows", window.as_secs());
+    let mut windows = TumblingWindows::new(window.as_millis());
     
     loop {
-        let reading = receiver.receive().await;
+        // Wake up when a window ends, even if its sensor went quiet
+        let deadline = windows.next_deadline().map_or(Instant::MAX, |end| {
+            Instant::now() + Duration::from_millis(end.saturating_sub(crate::time::now_ms()))
+        });
+        let reading = match select(subscriber.next_reading(), Timer::at(deadline)).await {
+            Either::First(reading) => reading,
+            Either::Second(()) => {
+                windows.close_expired(crate::time::now_ms()).iter().for_each(emit_window);
+                continue;
+            }
+        };
         
-        // For now, just print the reading
-        // Later this will do aggregation, filtering, forwarding to APIs, etc.
+        if window.as_ticks() == 0 || !TumblingWindows::accepts(&reading) {
+            crate::net::sensor_community::queue_reading(&reading);
+            crate::net::store_forward::queue_reading(&reading);
+        } else if let Some(record) = windows.push(&reading) {
+            emit_window(&record);
+        }
+    }
+}
+
+/// Keeps the latest values and air quality indices for the local API and
+/// other consumers
+pub async fn latest_values_impl() {
+    let mut subscriber = match READINGS.subscribe("latest") {
+        Ok(subscriber) => subscriber,
+        Err(e) => {
+            log::error!("[LATEST] Can't subscribe to readings: {}", e);
+            return;
+        }
+    };
+    
+    loop {
+        let reading = subscriber.next_reading().await;
+        super::latest::record(&reading);
+        super::aqi::record(&reading);
+        if matches!(reading.data, super::SensorData::AirQuality { .. } | super::SensorData::Gas { .. }) {
+            // Logged here so the indices are the ones this reading went into
+            let indices = super::aqi::current_indices();
+            if !indices.is_empty() {
+                log::info!("[AQI] {}", indices);
+            }
+        }
+    }
+}
+
+/// Prints every reading from the bus to the log
+pub async fn reading_logger_impl() {
+    let mut subscriber = match READINGS.subscribe("logger") {
+        Ok(subscriber) => subscriber,
+        Err(e) => {
+            log::error!("[LOGGER] Can't subscribe to readings: {}", e);
+            return;
+        }
+    };
+    
+    loop {
+        let reading = subscriber.next_reading().await;
         match reading.data {
             super::SensorData::Environmental { temperature, humidity, pressure, .. } => {
                 if let (Some(t), Some(h), Some(p)) = (temperature, humidity, pressure) {
-                    esp_println::println!("[{}] T: {:.1}°C, H: {:.1}%, P: {:.1}hPa", 
+                    log::info!("[{}] T: {:.1}°C, H: {:.1}%, P: {:.1}hPa", 
                         reading.sensor_type.name(), t, h, p);
                 }
             }
-            super::SensorData::AirQuality { pm25, pm10 } => {
+            super::SensorData::AirQuality { pm25, pm10, pm25_corrected, pm10_corrected } => {
                 if let (Some(pm2), Some(pm1)) = (pm25, pm10) {
-                    esp_println::println!("[{}] PM2.5: {:.1} µg/m³, PM10: {:.1} µg/m³", 
+                    log::info!("[{}] PM2.5: {:.1} µg/m³, PM10: {:.1} µg/m³", 
                         reading.sensor_type.name(), pm2, pm1);
                 }
+                match (pm25_corrected, pm10_corrected) {
+                    (Some(pm2), Some(pm1)) => log::info!("[{}] Humidity corrected PM2.5: {:.1} µg/m³, PM10: {:.1} µg/m³", 
+                        reading.sensor_type.name(), pm2, pm1),
+                    (Some(pm2), None) => log::info!("[{}] Humidity corrected PM2.5: {:.1} µg/m³", 
+                        reading.sensor_type.name(), pm2),
+                    _ => {}
+                }
             }
             super::SensorData::Gas { co_ppm, .. } => {
                 if let Some(co) = co_ppm {
-                    esp_println::println!("[{}] CO: {:.1} ppm", reading.sensor_type.name(), co);
+                    log::info!("[{}] CO: {:.1} ppm", reading.sensor_type.name(), co);
                 }
             }
             super::SensorData::Radiation { dose_rate, .. } => {
-                esp_println::println!("[{}] Radiation: {:.3} µSv/h", 
+                log::info!("[{}] Radiation: {:.3} µSv/h", 
                     reading.sensor_type.name(), dose_rate);
             }
             super::SensorData::Noise { db_a, .. } => {
-                esp_println::println!("[{}] Noise: {:.1} dB(A)", 
+                log::info!("[{}] Noise: {:.1} dB(A)", 
                     reading.sensor_type.name(), db_a);
             }
             super::SensorData::Location { latitude, longitude, .. } => {
-                esp_println::println!("[{}] Location: {:.6}, {:.6}", 
+                log::info!("[{}] Location: {:.6}, {:.6}", 
                     reading.sensor_type.name(), latitude, longitude);
             }
             super::SensorData::Analog { voltage, converted_value, units, .. } => {
-                esp_println::println!("[{}] {:.3}V = {:?} {}", 
+                log::info!("[{}] {:.3}V = {:?} {}", 
                     reading.sensor_type.name(), voltage, converted_value, units);
             }
         }
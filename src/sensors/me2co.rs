@@ -1,22 +1,17 @@
 use super::{Sensor, SensorReading, SensorError, SensorData, SensorType, SensorInfo, Quality};
 use embassy_time::{Duration, Timer, with_timeout};
 use embedded_io_async::{Read, Write};
-use esp_hal::uart::Uart;
-use esp_hal::peripherals::UART1;
-
-/// Type alias for the concrete UART type we use
-pub type Me2CoUart = Uart<'static, UART1, esp_hal::Async>;
 
 /// ME2-CO Carbon Monoxide sensor
 /// Uses async UART communication with ZE07-CO protocol
 /// Communicates on UART1: RX=GPIO19, TX=GPIO18 at 9600 baud
-pub struct Me2CoSensorWrapper {
-    uart: Me2CoUart,
+pub struct Me2CoSensorWrapper<UART> {
+    uart: UART,
     initialized: bool,
 }
 
-impl Me2CoSensorWrapper {
-    pub fn new(uart: Me2CoUart) -> Self {
+impl<UART: Read + Write> Me2CoSensorWrapper<UART> {
+    pub fn new(uart: UART) -> Self {
         Self {
             uart,
             initialized: false,
@@ -24,9 +19,9 @@ impl Me2CoSensorWrapper {
     }
 }
 
-impl Sensor for Me2CoSensorWrapper {
+impl<UART: Read + Write + Send> Sensor for Me2CoSensorWrapper<UART> {
     async fn init(&mut self) -> Result<(), SensorError> {
-        esp_println::println!("[ME2-CO] Initializing async UART communication...");
+        log::info!("[ME2-CO] Initializing async UART communication...");
         
         // Send initialization command to set Q&A mode
         let init_cmd: [u8; 9] = [0xFF, 0x01, 0x78, 0x41, 0x00, 0x00, 0x00, 0x00, 0x46];
@@ -34,17 +29,17 @@ impl Sensor for Me2CoSensorWrapper {
         // Use timeout to avoid blocking forever
         match with_timeout(Duration::from_millis(1000), self.uart.write_all(&init_cmd)).await {
             Ok(Ok(())) => {
-                esp_println::println!("[ME2-CO] Initialization command sent");
+                log::info!("[ME2-CO] Initialization command sent");
                 Timer::after(Duration::from_millis(100)).await;
                 self.initialized = true;
                 Ok(())
             }
             Ok(Err(_)) => {
-                esp_println::println!("[ME2-CO] Failed to send initialization command");
+                log::warn!("[ME2-CO] Failed to send initialization command");
                 Err(SensorError::CommunicationError)
             }
             Err(_) => {
-                esp_println::println!("[ME2-CO] Initialization timeout");
+                log::warn!("[ME2-CO] Initialization timeout");
                 Err(SensorError::Timeout)
             }
         }
@@ -74,14 +69,15 @@ impl Sensor for Me2CoSensorWrapper {
         // Try reading for up to 1 second, collecting any available bytes
         let start_time = embassy_time::Instant::now();
         while bytes_read < 9 && start_time.elapsed() < Duration::from_millis(1000) {
-            match self.uart.read(&mut response[bytes_read..]).await {
-                Ok(0) => {
+            match with_timeout(Duration::from_millis(100), self.uart.read(&mut response[bytes_read..])).await {
+                Ok(Ok(0)) | Err(_) => {
+                    // Nothing received yet, keep waiting until the deadline
                     Timer::after(Duration::from_millis(10)).await;
                 }
-                Ok(n) => {
+                Ok(Ok(n)) => {
                     bytes_read += n;
                 }
-                Err(_) => break,
+                Ok(Err(_)) => return Err(SensorError::CommunicationError),
             }
         }
         
@@ -97,14 +93,14 @@ impl Sensor for Me2CoSensorWrapper {
                 let co_ppm = co_raw as f32 * 0.1; // Convert to ppm (original uses * 0.1f)
                 
                 // Validate checksum - match original firmware: (~sum) + 1
-                let sum: u8 = response[1..8].iter().map(|&b| b).sum();
+                let sum = response[1..8].iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
                 let calculated_checksum = (!sum).wrapping_add(1);
                 if calculated_checksum != response[8] {
                     return Err(SensorError::InvalidData);
                 }
                 
                 // Validate CO reading range (0-1000 ppm is reasonable)
-                if co_ppm < 0.0 || co_ppm > 1000.0 {
+                if !(0.0..=1000.0).contains(&co_ppm) {
                     return Err(SensorError::InvalidData);
                 }
                 
@@ -136,4 +132,124 @@ impl Sensor for Me2CoSensorWrapper {
     fn reading_interval(&self) -> Duration {
         Duration::from_secs(30)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{block_on, MockUart};
+
+    const CMD_INIT: [u8; 9] = [0xFF, 0x01, 0x78, 0x41, 0x00, 0x00, 0x00, 0x00, 0x46];
+    const CMD_READ: [u8; 9] = [0xFF, 0x01, 0x86, 0x00, 0x00, 0x00, 0x00, 0x00, 0x79];
+
+    /// Build a Q&A response for a raw concentration in 0.1 ppm
+    fn response(co_raw: u16) -> [u8; 9] {
+        let mut r = [0xFF, 0x86, (co_raw >> 8) as u8, co_raw as u8, 0, 0, 0, 0, 0];
+        let sum = r[1..8].iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+        r[8] = (!sum).wrapping_add(1);
+        r
+    }
+
+    fn initialized_sensor(uart: MockUart) -> Me2CoSensorWrapper<MockUart> {
+        let mut sensor = Me2CoSensorWrapper::new(uart);
+        block_on(sensor.init()).unwrap();
+        sensor
+    }
+
+    fn co_ppm(reading: &SensorReading) -> f32 {
+        match reading.data {
+            SensorData::Gas { co_ppm: Some(co), co2_ppm: None, voc_index: None } => co,
+            ref other => panic!("unexpected data {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_response_checksum_matches_datasheet() {
+        assert_eq!(response(0x002A), [0xFF, 0x86, 0x00, 0x2A, 0x00, 0x00, 0x00, 0x00, 0x50]);
+    }
+
+    #[test]
+    fn test_init_switches_to_qa_mode() {
+        let sensor = initialized_sensor(MockUart::new());
+        assert_eq!(sensor.uart.written(), &CMD_INIT);
+        assert!(sensor.initialized);
+    }
+
+    #[test]
+    fn test_init_write_timeout() {
+        let mut sensor = Me2CoSensorWrapper::new(MockUart::new().stall_writes());
+        assert!(matches!(block_on(sensor.init()), Err(SensorError::Timeout)));
+        assert!(!sensor.initialized);
+    }
+
+    #[test]
+    fn test_read_before_init() {
+        let mut sensor = Me2CoSensorWrapper::new(MockUart::new());
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::NotInitialized)));
+    }
+
+    #[test]
+    fn test_read_parses_response() {
+        let mut sensor = initialized_sensor(MockUart::new().data(&response(42)));
+
+        let reading = block_on(sensor.read()).unwrap();
+
+        assert_eq!(reading.sensor_type, SensorType::ME2CO);
+        assert_eq!(reading.quality, Quality::Good);
+        assert!((co_ppm(&reading) - 4.2).abs() < 1e-4);
+        assert_eq!(sensor.uart.written(), &[CMD_INIT, CMD_READ].concat()[..]);
+    }
+
+    #[test]
+    fn test_read_fragmented_response_with_pauses() {
+        let r = response(1234);
+        let uart = MockUart::new()
+            .fragmented(&r[..4], 1)
+            .stall()
+            .fragmented(&r[4..], 2);
+        let mut sensor = initialized_sensor(uart);
+
+        let reading = block_on(sensor.read()).unwrap();
+        assert!((co_ppm(&reading) - 123.4).abs() < 1e-3);
+        assert!(sensor.uart.rx_drained());
+    }
+
+    #[test]
+    fn test_read_rejects_bad_header() {
+        let mut r = response(42);
+        r[1] = 0x87;
+        let mut sensor = initialized_sensor(MockUart::new().data(&r));
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::InvalidData)));
+    }
+
+    #[test]
+    fn test_read_rejects_bad_checksum() {
+        let mut r = response(42);
+        r[8] = r[8].wrapping_add(1);
+        let mut sensor = initialized_sensor(MockUart::new().data(&r));
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::InvalidData)));
+    }
+
+    #[test]
+    fn test_read_rejects_out_of_range() {
+        let mut sensor = initialized_sensor(MockUart::new().data(&response(10001)));
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::InvalidData)));
+    }
+
+    #[test]
+    fn test_read_times_out_without_response() {
+        let mut sensor = initialized_sensor(MockUart::new());
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::Timeout)));
+    }
+
+    #[test]
+    fn test_read_times_out_on_truncated_response() {
+        let mut sensor = initialized_sensor(MockUart::new().data(&response(42)[..6]));
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::Timeout)));
+    }
+
+    #[test]
+    fn test_read_uart_error() {
+        let mut sensor = initialized_sensor(MockUart::new().error());
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::CommunicationError)));
+    }
+}
@@ -2,11 +2,23 @@ pub mod sds011;
 pub mod bme280;
 pub mod me2co;
 pub mod manager;
+pub mod bus;
+pub mod latest;
+pub mod stats;
+pub mod filter;
+pub mod humidity;
+pub mod aqi;
+pub mod derived;
+pub mod calibration;
+pub mod codec;
 
 use embassy_time::Duration;
 
 /// Main sensor trait that all sensors must implement
 /// This trait is designed to be async-first and extensible
+// Sensor futures are driven by a single-threaded Embassy executor,
+// so they don't need to be `Send`
+#[allow(async_fn_in_trait)]
 pub trait Sensor: Send {
     /// Initialize the sensor hardware
     /// Called once when the sensor is first started
@@ -45,7 +57,9 @@ pub struct SensorReading {
     pub sensor_type: SensorType,
     pub data: SensorData,
     pub timestamp: u64,     // milliseconds since boot
+    pub utc_timestamp: Option<u64>, // milliseconds since Unix epoch, once wall-clock time is known
     pub quality: Quality,   // data quality indicator
+    pub flags: Flags,       // why the reading was changed or downgraded after the driver
 }
 
 /// All possible sensor data types
@@ -58,12 +72,18 @@ pub enum SensorData {
         humidity: Option<f32>,     // Percentage
         pressure: Option<f32>,     // hPa
         gas_resistance: Option<f32>, // BME680 only
+        dew_point: Option<f32>,          // Celsius, derived
+        absolute_humidity: Option<f32>,  // g/m³, derived
+        heat_index: Option<f32>,         // Celsius, derived
+        sea_level_pressure: Option<f32>, // hPa, derived
     },
     
     /// Particulate matter sensors (SDS011, etc.)
     AirQuality {
         pm25: Option<f32>,  // µg/m³
         pm10: Option<f32>,  // µg/m³
+        pm25_corrected: Option<f32>, // µg/m³, corrected for humidity
+        pm10_corrected: Option<f32>, // µg/m³, corrected for humidity
     },
     
     /// Gas sensors (ME2-CO, SCD4x, etc.)
@@ -103,9 +123,37 @@ pub enum SensorData {
     },
 }
 
+/// Most fields a single `SensorData` variant carries
+pub const MAX_FIELDS: usize = 8;
+
+/// Individual measured quantities
+/// Addresses single values across sensor types, e.g. for uploads that
+/// want one series per quantity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Temperature,
+    Humidity,
+    Pressure,
+    GasResistance,
+    Pm25,
+    Pm10,
+    CoPpm,
+    Co2Ppm,
+    VocIndex,
+    DoseRate,
+    NoiseDbA,
+    Pm25Corrected,
+    Pm10Corrected,
+    DewPoint,
+    AbsoluteHumidity,
+    HeatIndex,
+    SeaLevelPressure,
+}
+
 /// Data quality indicator
 /// Helps downstream processing decide how to handle readings
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// Ordered from best to worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Quality {
     /// Sensor operating normally, data is reliable
     Good,
@@ -115,8 +163,89 @@ pub enum Quality {
     Bad,
 }
 
+/// Set of reasons a reading was changed or downgraded between the driver
+/// and the consumers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags(u16);
+
+impl Flags {
+    pub const NONE: Flags = Flags(0);
+    /// A value was too far from the median of the previous ones
+    pub const MEDIAN_OUTLIER: Flags = Flags(1 << 0);
+    /// A value failed the Hampel test against the previous ones
+    pub const HAMPEL_OUTLIER: Flags = Flags(1 << 1);
+    /// A value changed faster than the field plausibly can
+    pub const RATE_OF_CHANGE: Flags = Flags(1 << 2);
+    /// Relative humidity was above the cutoff of the PM humidity correction
+    pub const HIGH_HUMIDITY: Flags = Flags(1 << 3);
+    /// Values were corrected with the device's calibration
+    pub const CALIBRATED: Flags = Flags(1 << 4);
+    
+    /// Every flag with its name, in bit order
+    pub const ALL: [(Flags, &'static str); 5] = [
+        (Flags::MEDIAN_OUTLIER, "median_outlier"),
+        (Flags::HAMPEL_OUTLIER, "hampel_outlier"),
+        (Flags::RATE_OF_CHANGE, "rate_of_change"),
+        (Flags::HIGH_HUMIDITY, "high_humidity"),
+        (Flags::CALIBRATED, "calibrated"),
+    ];
+    
+    /// Flags from their bits; `None` if unknown bits are set
+    pub fn from_bits(bits: u16) -> Option<Flags> {
+        let known = Flags::ALL.iter().fold(0, |all, (flag, _)| all | flag.0);
+        (bits & !known == 0).then_some(Flags(bits))
+    }
+    
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+    
+    /// Whether all of `other` is set
+    pub fn contains(&self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+    
+    pub fn insert(&mut self, other: Flags) {
+        self.0 |= other.0;
+    }
+    
+    /// Names of the set flags
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Flags::ALL.into_iter().filter(move |(flag, _)| self.contains(*flag)).map(|(_, name)| name)
+    }
+}
+
+impl core::fmt::Display for Flags {
+    /// Comma-separated names, `none` if empty
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.is_empty() {
+            return f.write_str("none");
+        }
+        for (i, name) in self.names().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            f.write_str(name)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::ops::BitOr for Flags {
+    type Output = Flags;
+    
+    fn bitor(self, other: Flags) -> Flags {
+        Flags(self.0 | other.0)
+    }
+}
+
 /// All supported sensor types
 /// Add new types here when implementing new sensors
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SensorType {
     // Environmental sensors
@@ -187,20 +316,29 @@ pub enum SensorError {
 impl SensorReading {
     /// Create a new sensor reading with current timestamp
     pub fn new(sensor_type: SensorType, data: SensorData, quality: Quality) -> Self {
+        let timestamp = crate::time::now_ms();
         Self {
             sensor_type,
             data,
-            timestamp: Self::current_timestamp(),
+            timestamp,
+            utc_timestamp: crate::time::utc_from_monotonic(timestamp),
             quality,
+            flags: Flags::NONE,
         }
     }
     
-    /// Get current timestamp (milliseconds since boot)
-    /// TODO: Replace with proper time source when available
-    fn current_timestamp() -> u64 {
-        // For now, use a simple counter
-        // In future: embassy_time::Instant::now().as_millis() or similar
-        0
+    /// Fill in the UTC timestamp for readings taken before wall-clock time was known
+    /// Returns the UTC timestamp if it is (now) available
+    pub fn resolve_utc(&mut self) -> Option<u64> {
+        if self.utc_timestamp.is_none() {
+            self.utc_timestamp = crate::time::utc_from_monotonic(self.timestamp);
+        }
+        self.utc_timestamp
+    }
+    
+    /// Age of this reading in milliseconds
+    pub fn age_ms(&self) -> u64 {
+        crate::time::now_ms().saturating_sub(self.timestamp)
     }
     
     /// Check if this reading is valid for processing
@@ -209,7 +347,188 @@ impl SensorReading {
     }
 }
 
+impl Field {
+    /// Every field, in a stable order
+    /// New fields go at the end, the position is persisted in the config
+    pub const ALL: [Field; 17] = [
+        Field::Temperature,
+        Field::Humidity,
+        Field::Pressure,
+        Field::GasResistance,
+        Field::Pm25,
+        Field::Pm10,
+        Field::CoPpm,
+        Field::Co2Ppm,
+        Field::VocIndex,
+        Field::DoseRate,
+        Field::NoiseDbA,
+        Field::Pm25Corrected,
+        Field::Pm10Corrected,
+        Field::DewPoint,
+        Field::AbsoluteHumidity,
+        Field::HeatIndex,
+        Field::SeaLevelPressure,
+    ];
+    
+    /// Machine-friendly name, matching the `SensorData` field names
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::Temperature => "temperature",
+            Field::Humidity => "humidity",
+            Field::Pressure => "pressure",
+            Field::GasResistance => "gas_resistance",
+            Field::Pm25 => "pm25",
+            Field::Pm10 => "pm10",
+            Field::CoPpm => "co_ppm",
+            Field::Co2Ppm => "co2_ppm",
+            Field::VocIndex => "voc_index",
+            Field::DoseRate => "dose_rate",
+            Field::NoiseDbA => "db_a",
+            Field::Pm25Corrected => "pm25_corrected",
+            Field::Pm10Corrected => "pm10_corrected",
+            Field::DewPoint => "dew_point",
+            Field::AbsoluteHumidity => "absolute_humidity",
+            Field::HeatIndex => "heat_index",
+            Field::SeaLevelPressure => "sea_level_pressure",
+        }
+    }
+    
+    /// Look up a field by its index in `ALL`
+    pub fn from_index(index: u8) -> Option<Field> {
+        Field::ALL.get(usize::from(index)).copied()
+    }
+    
+    /// Look up a field by its name
+    pub fn from_name(name: &str) -> Option<Field> {
+        Field::ALL.iter().copied().find(|f| f.name() == name)
+    }
+    
+    /// Unit of the value; empty for dimensionless indices
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Field::Temperature | Field::DewPoint | Field::HeatIndex => "°C",
+            Field::Humidity => "%",
+            Field::AbsoluteHumidity => "g/m³",
+            Field::Pressure | Field::SeaLevelPressure => "hPa",
+            Field::GasResistance => "Ω",
+            Field::Pm25 | Field::Pm10 | Field::Pm25Corrected | Field::Pm10Corrected => "µg/m³",
+            Field::CoPpm | Field::Co2Ppm => "ppm",
+            Field::VocIndex => "",
+            Field::DoseRate => "µSv/h",
+            Field::NoiseDbA => "dB(A)",
+        }
+    }
+}
+
+impl SensorData {
+    /// Value of a single field, if this data carries it
+    pub fn value(&self, field: Field) -> Option<f32> {
+        match (self, field) {
+            (SensorData::Environmental { temperature, .. }, Field::Temperature) => *temperature,
+            (SensorData::Environmental { humidity, .. }, Field::Humidity) => *humidity,
+            (SensorData::Environmental { pressure, .. }, Field::Pressure) => *pressure,
+            (SensorData::Environmental { gas_resistance, .. }, Field::GasResistance) => *gas_resistance,
+            (SensorData::Environmental { dew_point, .. }, Field::DewPoint) => *dew_point,
+            (SensorData::Environmental { absolute_humidity, .. }, Field::AbsoluteHumidity) => *absolute_humidity,
+            (SensorData::Environmental { heat_index, .. }, Field::HeatIndex) => *heat_index,
+            (SensorData::Environmental { sea_level_pressure, .. }, Field::SeaLevelPressure) => *sea_level_pressure,
+            (SensorData::AirQuality { pm25, .. }, Field::Pm25) => *pm25,
+            (SensorData::AirQuality { pm10, .. }, Field::Pm10) => *pm10,
+            (SensorData::AirQuality { pm25_corrected, .. }, Field::Pm25Corrected) => *pm25_corrected,
+            (SensorData::AirQuality { pm10_corrected, .. }, Field::Pm10Corrected) => *pm10_corrected,
+            (SensorData::Gas { co_ppm, .. }, Field::CoPpm) => *co_ppm,
+            (SensorData::Gas { co2_ppm, .. }, Field::Co2Ppm) => co2_ppm.map(f32::from),
+            (SensorData::Gas { voc_index, .. }, Field::VocIndex) => *voc_index,
+            (SensorData::Radiation { dose_rate, .. }, Field::DoseRate) => Some(*dose_rate),
+            (SensorData::Noise { db_a, .. }, Field::NoiseDbA) => Some(*db_a),
+            _ => None,
+        }
+    }
+    
+    /// Overwrite a single field, if this data carries it
+    /// Returns false when the field doesn't belong to this variant
+    pub fn set_value(&mut self, field: Field, value: f32) -> bool {
+        let slot = match (self, field) {
+            (SensorData::Environmental { temperature, .. }, Field::Temperature) => temperature,
+            (SensorData::Environmental { humidity, .. }, Field::Humidity) => humidity,
+            (SensorData::Environmental { pressure, .. }, Field::Pressure) => pressure,
+            (SensorData::Environmental { gas_resistance, .. }, Field::GasResistance) => gas_resistance,
+            (SensorData::Environmental { dew_point, .. }, Field::DewPoint) => dew_point,
+            (SensorData::Environmental { absolute_humidity, .. }, Field::AbsoluteHumidity) => absolute_humidity,
+            (SensorData::Environmental { heat_index, .. }, Field::HeatIndex) => heat_index,
+            (SensorData::Environmental { sea_level_pressure, .. }, Field::SeaLevelPressure) => sea_level_pressure,
+            (SensorData::AirQuality { pm25, .. }, Field::Pm25) => pm25,
+            (SensorData::AirQuality { pm10, .. }, Field::Pm10) => pm10,
+            (SensorData::AirQuality { pm25_corrected, .. }, Field::Pm25Corrected) => pm25_corrected,
+            (SensorData::AirQuality { pm10_corrected, .. }, Field::Pm10Corrected) => pm10_corrected,
+            (SensorData::Gas { co_ppm, .. }, Field::CoPpm) => co_ppm,
+            (SensorData::Gas { co2_ppm, .. }, Field::Co2Ppm) => {
+                // Saturating cast, negative values become 0
+                *co2_ppm = Some(libm::roundf(value) as u16);
+                return true;
+            }
+            (SensorData::Gas { voc_index, .. }, Field::VocIndex) => voc_index,
+            (SensorData::Radiation { dose_rate, .. }, Field::DoseRate) => {
+                *dose_rate = value;
+                return true;
+            }
+            (SensorData::Noise { db_a, .. }, Field::NoiseDbA) => {
+                *db_a = value;
+                return true;
+            }
+            _ => return false,
+        };
+        *slot = Some(value);
+        true
+    }
+    
+    /// All fields present in this data, with their values
+    pub fn values(&self) -> heapless::Vec<(Field, f32), MAX_FIELDS> {
+        let mut values = heapless::Vec::new();
+        for field in Field::ALL {
+            if let Some(value) = self.value(field) {
+                // No variant carries more than `MAX_FIELDS` fields
+                let _ = values.push((field, value));
+            }
+        }
+        values
+    }
+}
+
+impl Quality {
+    /// Lower-case name for APIs and labels
+    pub fn name(&self) -> &'static str {
+        match self {
+            Quality::Good => "good",
+            Quality::Degraded => "degraded",
+            Quality::Bad => "bad",
+        }
+    }
+}
+
 impl SensorType {
+    /// Every sensor type, in declaration order
+    /// The position is the stable index used in persisted data
+    pub const ALL: [SensorType; 12] = [
+        SensorType::BME280,
+        SensorType::BME680,
+        SensorType::SHT30,
+        SensorType::SDS011,
+        SensorType::PMS7003,
+        SensorType::ME2CO,
+        SensorType::SCD4X,
+        SensorType::SGP30,
+        SensorType::RadSens,
+        SensorType::ICS43434,
+        SensorType::GPS,
+        SensorType::AnalogSensor,
+    ];
+    
+    /// Look up a sensor type by its index in `ALL`
+    pub fn from_index(index: u8) -> Option<SensorType> {
+        SensorType::ALL.get(usize::from(index)).copied()
+    }
+    
     /// Get human-readable name for this sensor type
     pub fn name(&self) -> &'static str {
         match self {
@@ -276,6 +595,10 @@ mod tests {
                 humidity: Some(60.0),
                 pressure: Some(1013.25),
                 gas_resistance: None,
+                dew_point: None,
+                absolute_humidity: None,
+                heat_index: None,
+                sea_level_pressure: None,
             },
             Quality::Good
         );
@@ -291,10 +614,86 @@ mod tests {
             SensorData::AirQuality {
                 pm25: None,
                 pm10: None,
+                pm25_corrected: None,
+                pm10_corrected: None,
             },
             Quality::Bad
         );
         
         assert!(!reading.is_valid());
     }
+    
+    #[test]
+    fn test_field_values() {
+        let data = SensorData::Environmental {
+            temperature: Some(21.5),
+            humidity: None,
+            pressure: Some(1013.0),
+            gas_resistance: None,
+            dew_point: None,
+            absolute_humidity: None,
+            heat_index: None,
+            sea_level_pressure: None,
+        };
+        assert_eq!(data.value(Field::Temperature), Some(21.5));
+        assert_eq!(data.value(Field::Humidity), None);
+        assert_eq!(data.value(Field::Pm25), None);
+        assert_eq!(data.values().as_slice(), &[(Field::Temperature, 21.5), (Field::Pressure, 1013.0)]);
+        
+        let gas = SensorData::Gas { co_ppm: Some(3.5), co2_ppm: Some(800), voc_index: None };
+        assert_eq!(gas.values().as_slice(), &[(Field::CoPpm, 3.5), (Field::Co2Ppm, 800.0)]);
+        
+        for field in Field::ALL {
+            assert_eq!(Field::from_name(field.name()), Some(field));
+        }
+        assert_eq!(Field::from_name("pm1"), None);
+        
+        let mut gas = gas;
+        assert!(gas.set_value(Field::Co2Ppm, 812.6));
+        assert!(gas.set_value(Field::VocIndex, 120.0));
+        assert!(!gas.set_value(Field::Pm25, 5.0));
+        assert_eq!(gas.values().as_slice(), &[(Field::CoPpm, 3.5), (Field::Co2Ppm, 813.0), (Field::VocIndex, 120.0)]);
+        
+        for (index, sensor_type) in SensorType::ALL.iter().enumerate() {
+            assert_eq!(SensorType::from_index(index as u8), Some(*sensor_type));
+        }
+        assert_eq!(SensorType::from_index(12), None);
+        assert_eq!(Field::from_index(4), Some(Field::Pm25));
+    }
+    
+    #[test]
+    fn test_reading_timestamps() {
+        use crate::time::{self, ManualClock};
+        static CLOCK: ManualClock = ManualClock::new(42_000);
+        
+        crate::mock::block_on(async {
+            time::set_clock(&CLOCK);
+            time::clear_utc();
+            
+            let mut early = SensorReading::new(
+                SensorType::ME2CO,
+                SensorData::Gas { co_ppm: Some(1.0), co2_ppm: None, voc_index: None },
+                Quality::Good
+            );
+            assert_eq!(early.timestamp, 42_000);
+            assert_eq!(early.utc_timestamp, None);
+            assert_eq!(early.resolve_utc(), None);
+            
+            CLOCK.advance(1_000);
+            time::set_utc_now(1_700_000_043_000);
+            
+            let late = SensorReading::new(
+                SensorType::ME2CO,
+                SensorData::Gas { co_ppm: Some(2.0), co2_ppm: None, voc_index: None },
+                Quality::Good
+            );
+            assert_eq!(late.timestamp, 43_000);
+            assert_eq!(late.utc_timestamp, Some(1_700_000_043_000));
+            assert_eq!(early.resolve_utc(), Some(1_700_000_042_000));
+            assert_eq!(early.age_ms(), 1_000);
+            
+            time::clear_utc();
+            time::set_clock(&time::EmbassyClock);
+        });
+    }
 }
\ No newline at end of file
@@ -1,26 +1,21 @@
 use super::{Sensor, SensorReading, SensorError, SensorData, SensorType, SensorInfo, Quality};
 use embassy_time::{Duration, Timer, with_timeout};
 use embedded_io_async::{Read, Write};
-use esp_hal::uart::Uart;
-use esp_hal::peripherals::UART0;
-
-/// Type alias for the concrete UART type we use
-pub type Sds011Uart = Uart<'static, UART0, esp_hal::Async>;
 
 /// SDS011 Particulate Matter sensor
 /// Uses async UART communication
-/// Communicates on UART2: RX=GPIO5, TX=GPIO4 at 9600 baud
-pub struct Sds011Sensor {
-    uart: Sds011Uart,
+/// Communicates on UART0: RX=GPIO5, TX=GPIO4 at 9600 baud
+pub struct Sds011Sensor<UART> {
+    uart: UART,
     initialized: bool,
     is_running: bool,
     error_count: u32,
     last_error_time: Option<embassy_time::Instant>,
 }
 
-impl Sds011Sensor {
+impl<UART: Read + Write> Sds011Sensor<UART> {
     /// Create new SDS011 sensor instance
-    pub fn new(uart: Sds011Uart) -> Self {
+    pub fn new(uart: UART) -> Self {
         Self {
             uart,
             initialized: false,
@@ -29,9 +24,7 @@ impl Sds011Sensor {
             last_error_time: None,
         }
     }
-}
 
-impl Sds011Sensor {
     /// Send raw command to SDS011
     async fn send_command(&mut self, cmd: &[u8]) -> Result<(), SensorError> {
         match with_timeout(Duration::from_millis(500), self.uart.write_all(cmd)).await {
@@ -67,10 +60,7 @@ impl Sds011Sensor {
 
     /// Validate SDS011 checksum
     fn checksum_valid(&self, data: &[u8; 8]) -> bool {
-        let mut checksum: u8 = 0;
-        for i in 0..6 {
-            checksum = checksum.wrapping_add(data[i]);
-        }
+        let checksum = data[..6].iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
         data[7] == 0xAB && checksum == data[6]
     }
 
@@ -114,16 +104,14 @@ impl Sds011Sensor {
                             }
                         }
                         
-                        if data_pos == 8 {
-                            if self.checksum_valid(&data) {
-                                let pm25_raw = (data[0] as u16) | ((data[1] as u16) << 8);
-                                let pm10_raw = (data[2] as u16) | ((data[3] as u16) << 8);
-                                
-                                let pm25 = pm25_raw as f32 / 10.0;
-                                let pm10 = pm10_raw as f32 / 10.0;
-                                
-                                return Ok((pm25, pm10));
-                            }
+                        if data_pos == 8 && self.checksum_valid(&data) {
+                            let pm25_raw = (data[0] as u16) | ((data[1] as u16) << 8);
+                            let pm10_raw = (data[2] as u16) | ((data[3] as u16) << 8);
+                            
+                            let pm25 = pm25_raw as f32 / 10.0;
+                            let pm10 = pm10_raw as f32 / 10.0;
+                            
+                            return Ok((pm25, pm10));
                         }
                     }
                     
@@ -144,9 +132,9 @@ impl Sds011Sensor {
     }
 }
 
-impl Sensor for Sds011Sensor {
+impl<UART: Read + Write + Send> Sensor for Sds011Sensor<UART> {
     async fn init(&mut self) -> Result<(), SensorError> {
-        esp_println::println!("[SDS011] Initializing UART communication...");
+        log::info!("[SDS011] Initializing UART communication...");
         
         // Set continuous mode
         self.cmd_continuous_mode().await?;
@@ -157,7 +145,7 @@ impl Sensor for Sds011Sensor {
         self.is_running = false;
         
         self.initialized = true;
-        esp_println::println!("[SDS011] Initialized successfully");
+        log::info!("[SDS011] Initialized successfully");
         Ok(())
     }
     
@@ -200,13 +188,16 @@ impl Sensor for Sds011Sensor {
         match self.read_measurement().await {
             Ok((pm25, pm10)) => {
                 // Validate reasonable range
-                if pm25 >= 0.0 && pm25 < 1000.0 && pm10 >= 0.0 && pm10 < 1000.0 {
+                if (0.0..1000.0).contains(&pm25) && (0.0..1000.0).contains(&pm10) {
                     self.error_count = 0; // Reset error count on success
                     let data = SensorData::AirQuality { 
                         pm25: Some(pm25), 
-                        pm10: Some(pm10) 
+                        pm10: Some(pm10),
+                        // Filled in by the humidity correction, if enabled
+                        pm25_corrected: None,
+                        pm10_corrected: None,
                     };
-                    return Ok(SensorReading::new(SensorType::SDS011, data, Quality::Good));
+                    Ok(SensorReading::new(SensorType::SDS011, data, Quality::Good))
                 } else {
                     self.error_count += 1;
                     self.last_error_time = Some(embassy_time::Instant::now());
@@ -237,4 +228,180 @@ impl Sensor for Sds011Sensor {
     fn reading_interval(&self) -> Duration {
         Duration::from_secs(30) // Standard 30-second interval
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{block_on, MockUart};
+
+    const CMD_CONTINUOUS_WORK: [u8; 19] = [0xAA, 0xB4, 0x08, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0x07, 0xAB];
+    const CMD_CONTINUOUS_REPORT: [u8; 19] = [0xAA, 0xB4, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0x01, 0xAB];
+    const CMD_STOP: [u8; 19] = [0xAA, 0xB4, 0x06, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0x05, 0xAB];
+    const CMD_START: [u8; 19] = [0xAA, 0xB4, 0x06, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0x06, 0xAB];
+
+    /// Build a measurement frame for raw values in 0.1 µg/m³
+    fn frame(pm25: u16, pm10: u16) -> [u8; 10] {
+        let mut f = [0xAA, 0xC0, pm25 as u8, (pm25 >> 8) as u8, pm10 as u8, (pm10 >> 8) as u8, 0x12, 0x34, 0, 0xAB];
+        f[8] = f[2..8].iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+        f
+    }
+
+    /// Sensor that has been initialized but not started yet
+    fn initialized_sensor(uart: MockUart) -> Sds011Sensor<MockUart> {
+        let mut sensor = Sds011Sensor::new(uart);
+        block_on(sensor.init()).unwrap();
+        sensor
+    }
+
+    fn air_quality(reading: &SensorReading) -> (f32, f32) {
+        match reading.data {
+            SensorData::AirQuality { pm25: Some(pm25), pm10: Some(pm10), pm25_corrected: None, pm10_corrected: None } => (pm25, pm10),
+            ref other => panic!("unexpected data {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_init_sets_continuous_mode_and_stops() {
+        let sensor = initialized_sensor(MockUart::new());
+
+        let expected = [CMD_CONTINUOUS_WORK, CMD_CONTINUOUS_REPORT, CMD_STOP].concat();
+        assert_eq!(sensor.uart.written(), &expected[..]);
+        assert!(sensor.initialized);
+        assert!(!sensor.is_running);
+    }
+
+    #[test]
+    fn test_init_write_timeout() {
+        let mut sensor = Sds011Sensor::new(MockUart::new().stall_writes());
+        assert!(matches!(block_on(sensor.init()), Err(SensorError::Timeout)));
+        assert!(!sensor.initialized);
+    }
+
+    #[test]
+    fn test_read_before_init() {
+        let mut sensor = Sds011Sensor::new(MockUart::new());
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::NotInitialized)));
+    }
+
+    #[test]
+    fn test_read_starts_sensor_and_parses_frame() {
+        let mut sensor = initialized_sensor(MockUart::new().stall().data(&frame(123, 456)));
+
+        let reading = block_on(sensor.read()).unwrap();
+
+        assert_eq!(reading.sensor_type, SensorType::SDS011);
+        assert_eq!(reading.quality, Quality::Good);
+        assert_eq!(air_quality(&reading), (12.3, 45.6));
+        assert!(sensor.is_running);
+        assert!(sensor.uart.written().ends_with(&CMD_START));
+    }
+
+    #[test]
+    fn test_read_discards_stale_data() {
+        // Whatever is buffered before the read starts is drained and ignored
+        let uart = MockUart::new()
+            .data(&frame(999, 999))
+            .stall()
+            .data(&frame(50, 80));
+        let mut sensor = initialized_sensor(uart);
+
+        let reading = block_on(sensor.read()).unwrap();
+        assert_eq!(air_quality(&reading), (5.0, 8.0));
+    }
+
+    #[test]
+    fn test_read_skips_garbage_prefix() {
+        let uart = MockUart::new()
+            .stall()
+            .data(&[0x00, 0xC0, 0xAA, 0x13, 0xAB, 0xAA])
+            .data(&frame(250, 400));
+        let mut sensor = initialized_sensor(uart);
+
+        let reading = block_on(sensor.read()).unwrap();
+        assert_eq!(air_quality(&reading), (25.0, 40.0));
+        assert!(sensor.uart.rx_drained());
+    }
+
+    #[test]
+    fn test_read_fragmented_frame() {
+        let uart = MockUart::new()
+            .stall()
+            .fragmented(&frame(1, 65535 / 100), 3);
+        let mut sensor = initialized_sensor(uart);
+
+        let reading = block_on(sensor.read()).unwrap();
+        assert_eq!(air_quality(&reading), (0.1, 65.5));
+    }
+
+    #[test]
+    fn test_read_skips_bad_checksum() {
+        let mut corrupted = frame(100, 200);
+        corrupted[8] ^= 0xFF;
+        let uart = MockUart::new()
+            .stall()
+            .data(&corrupted)
+            .data(&frame(110, 210));
+        let mut sensor = initialized_sensor(uart);
+
+        let reading = block_on(sensor.read()).unwrap();
+        assert_eq!(air_quality(&reading), (11.0, 21.0));
+    }
+
+    #[test]
+    fn test_read_bad_tail_rejected() {
+        let mut corrupted = frame(100, 200);
+        corrupted[9] = 0x00;
+        let mut sensor = initialized_sensor(MockUart::new().stall().data(&corrupted));
+
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::Timeout)));
+        assert_eq!(sensor.error_count, 1);
+    }
+
+    #[test]
+    fn test_read_times_out_on_silent_line() {
+        let mut sensor = initialized_sensor(MockUart::new());
+
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::Timeout)));
+        assert_eq!(sensor.error_count, 1);
+        assert!(sensor.last_error_time.is_some());
+    }
+
+    #[test]
+    fn test_read_stall_mid_frame_loses_frame() {
+        let f = frame(100, 200);
+        let uart = MockUart::new()
+            .stall()
+            .data(&f[..5])
+            .stall()
+            .data(&f[5..]);
+        let mut sensor = initialized_sensor(uart);
+
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::Timeout)));
+    }
+
+    #[test]
+    fn test_read_out_of_range_is_invalid() {
+        let mut sensor = initialized_sensor(MockUart::new().stall().data(&frame(10000, 200)));
+
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::InvalidData)));
+        assert_eq!(sensor.error_count, 1);
+    }
+
+    #[test]
+    fn test_read_backs_off_after_repeated_errors() {
+        let mut sensor = initialized_sensor(MockUart::new().stall().data(&frame(70, 90)));
+        sensor.error_count = 5;
+        sensor.last_error_time = Some(block_on(async { embassy_time::Instant::now() }));
+        let written = sensor.uart.written().len();
+
+        // Within the backoff window the UART isn't touched
+        assert!(matches!(block_on(sensor.read()), Err(SensorError::Timeout)));
+        assert_eq!(sensor.uart.written().len(), written);
+
+        block_on(Timer::after(Duration::from_secs(60)));
+
+        let reading = block_on(sensor.read()).unwrap();
+        assert_eq!(air_quality(&reading), (7.0, 9.0));
+        assert_eq!(sensor.error_count, 0);
+    }
+}
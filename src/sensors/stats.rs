@@ -0,0 +1,408 @@
+//! Windowed statistics
+//!
+//! Readings are grouped per sensor type into tumbling windows, fixed
+//! slices of time aligned to multiples of the window length. When a window
+//! closes, each field it saw is summarized (count, mean, min, max, median,
+//! standard deviation) and the window yields a reading carrying the means,
+//! which is what sensor.community and openSenseMap expect per upload.
+//!
+//! Pure code driven by the reading timestamps, so it runs the same on the
+//! host; the aggregator task feeds it readings and the clock.
+
+use heapless::{Deque, Vec};
+
+use super::{Field, Flags, Quality, SensorReading, SensorType};
+
+/// Default window length
+pub const DEFAULT_WINDOW_MS: u64 = 60_000;
+
+/// Values kept per field for the median; later ones replace the oldest
+pub const MAX_SAMPLES: usize = 32;
+
+/// Fields per window; no `SensorData` variant carries more
+pub const MAX_FIELDS: usize = super::MAX_FIELDS;
+
+/// Windows open at the same time, one per sensor type
+pub const MAX_WINDOWS: usize = SensorType::ALL.len();
+
+/// Statistics of one field over a window
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub count: u32,
+    pub mean: f32,
+    pub min: f32,
+    pub max: f32,
+    pub median: f32,
+    /// Population standard deviation
+    pub stddev: f32,
+}
+
+/// Median of `values`, which get sorted; `None` if empty
+pub fn median(values: &mut [f32]) -> Option<f32> {
+    values.sort_unstable_by(f32::total_cmp);
+    let mid = values.len() / 2;
+    match values.len() {
+        0 => None,
+        n if n % 2 == 1 => Some(values[mid]),
+        _ => Some((values[mid - 1] + values[mid]) / 2.0),
+    }
+}
+
+/// Summary of a slice of values; `None` if empty
+pub fn summarize(values: &[f32]) -> Option<Summary> {
+    let mut accumulator = Accumulator::new();
+    values.iter().for_each(|v| accumulator.push(*v));
+    accumulator.summary()
+}
+
+/// Running statistics of one field
+///
+/// Mean and variance are updated with Welford's method, so they stay exact
+/// however many values arrive; the median covers the last `MAX_SAMPLES`.
+#[derive(Debug, Clone)]
+pub struct Accumulator {
+    count: u32,
+    mean: f64,
+    /// Sum of squared differences from the mean
+    m2: f64,
+    min: f32,
+    max: f32,
+    samples: Deque<f32, MAX_SAMPLES>,
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Accumulator {
+    pub const fn new() -> Self {
+        Self { count: 0, mean: 0.0, m2: 0.0, min: f32::INFINITY, max: f32::NEG_INFINITY, samples: Deque::new() }
+    }
+
+    /// Add a value; NaN and infinities are ignored
+    pub fn push(&mut self, value: f32) {
+        if !value.is_finite() {
+            return;
+        }
+        self.count += 1;
+        let delta = f64::from(value) - self.mean;
+        self.mean += delta / f64::from(self.count);
+        self.m2 += delta * (f64::from(value) - self.mean);
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        if self.samples.is_full() {
+            self.samples.pop_front();
+        }
+        let _ = self.samples.push_back(value);
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Statistics so far; `None` before the first value
+    pub fn summary(&self) -> Option<Summary> {
+        let mut samples: Vec<f32, MAX_SAMPLES> = self.samples.iter().copied().collect();
+        Some(Summary {
+            count: self.count,
+            mean: self.mean as f32,
+            min: self.min,
+            max: self.max,
+            median: median(&mut samples)?,
+            stddev: libm::sqrt(self.m2 / f64::from(self.count)) as f32,
+        })
+    }
+}
+
+/// A closed window of one sensor type
+#[derive(Debug, Clone)]
+pub struct WindowRecord {
+    pub sensor_type: SensorType,
+    /// Window bounds in milliseconds since boot, end exclusive
+    pub start_ms: u64,
+    pub end_ms: u64,
+    /// Worst quality of the readings in the window
+    pub quality: Quality,
+    /// Flags of all readings in the window
+    pub flags: Flags,
+    pub fields: Vec<(Field, Summary), MAX_FIELDS>,
+    /// Latest reading of the window, the template for `mean_reading`
+    last: SensorReading,
+}
+
+impl WindowRecord {
+    /// A reading with the mean of every field, stamped with the time of
+    /// the last reading in the window
+    pub fn mean_reading(&self) -> SensorReading {
+        let mut reading = self.last.clone();
+        for (field, summary) in &self.fields {
+            reading.data.set_value(*field, summary.mean);
+        }
+        reading.quality = self.quality;
+        reading.flags = self.flags;
+        reading
+    }
+
+    /// Summary of one field
+    pub fn field(&self, field: Field) -> Option<&Summary> {
+        self.fields.iter().find(|(f, _)| *f == field).map(|(_, summary)| summary)
+    }
+}
+
+/// An open window
+#[derive(Debug, Clone)]
+struct Window {
+    start_ms: u64,
+    quality: Quality,
+    flags: Flags,
+    fields: Vec<(Field, Accumulator), MAX_FIELDS>,
+    last: SensorReading,
+}
+
+impl Window {
+    fn new(start_ms: u64, reading: &SensorReading) -> Self {
+        Self { start_ms, quality: Quality::Good, flags: Flags::NONE, fields: Vec::new(), last: reading.clone() }
+    }
+
+    fn push(&mut self, reading: &SensorReading) {
+        self.quality = self.quality.max(reading.quality);
+        self.flags.insert(reading.flags);
+        for (field, value) in reading.data.values() {
+            match self.fields.iter_mut().find(|(f, _)| *f == field) {
+                Some((_, accumulator)) => accumulator.push(value),
+                None => {
+                    let mut accumulator = Accumulator::new();
+                    accumulator.push(value);
+                    let _ = self.fields.push((field, accumulator));
+                }
+            }
+        }
+        self.last = reading.clone();
+    }
+
+    fn close(self, window_ms: u64) -> Option<WindowRecord> {
+        let fields: Vec<(Field, Summary), MAX_FIELDS> =
+            self.fields.iter().filter_map(|(field, accumulator)| Some((*field, accumulator.summary()?))).collect();
+        if fields.is_empty() {
+            return None;
+        }
+        Some(WindowRecord {
+            sensor_type: self.last.sensor_type,
+            start_ms: self.start_ms,
+            end_ms: self.start_ms + window_ms,
+            quality: self.quality,
+            flags: self.flags,
+            fields,
+            last: self.last,
+        })
+    }
+}
+
+/// Tumbling windows for every sensor type
+pub struct TumblingWindows {
+    window_ms: u64,
+    windows: Vec<Window, MAX_WINDOWS>,
+}
+
+impl TumblingWindows {
+    /// Windows of `window_ms` milliseconds, at least 1
+    pub fn new(window_ms: u64) -> Self {
+        Self { window_ms: window_ms.max(1), windows: Vec::new() }
+    }
+
+    pub fn window_ms(&self) -> u64 {
+        self.window_ms
+    }
+
+    /// Whether a reading is summarized: valid and carrying field values
+    pub fn accepts(reading: &SensorReading) -> bool {
+        reading.is_valid() && !reading.data.values().is_empty()
+    }
+
+    /// Add a reading to its sensor type's window
+    ///
+    /// Returns the previous window if the reading starts a new one. Readings
+    /// that aren't `accepts`ed are ignored.
+    pub fn push(&mut self, reading: &SensorReading) -> Option<WindowRecord> {
+        if !Self::accepts(reading) {
+            return None;
+        }
+        let start_ms = reading.timestamp - reading.timestamp % self.window_ms;
+        let index = self.windows.iter().position(|w| w.last.sensor_type == reading.sensor_type);
+
+        let mut closed = None;
+        if let Some(i) = index.filter(|i| self.windows[*i].start_ms != start_ms) {
+            closed = self.windows.swap_remove(i).close(self.window_ms);
+        }
+        match self.windows.iter_mut().find(|w| w.last.sensor_type == reading.sensor_type) {
+            Some(window) => window.push(reading),
+            None => {
+                let mut window = Window::new(start_ms, reading);
+                window.push(reading);
+                // One window per sensor type, so there is always room
+                let _ = self.windows.push(window);
+            }
+        }
+        closed
+    }
+
+    /// Close every window that ended by `now_ms`
+    pub fn close_expired(&mut self, now_ms: u64) -> Vec<WindowRecord, MAX_WINDOWS> {
+        let mut closed = Vec::new();
+        let mut i = 0;
+        while i < self.windows.len() {
+            if self.windows[i].start_ms + self.window_ms <= now_ms {
+                if let Some(record) = self.windows.swap_remove(i).close(self.window_ms) {
+                    let _ = closed.push(record);
+                }
+            } else {
+                i += 1;
+            }
+        }
+        closed
+    }
+
+    /// When the next window ends, if any is open
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.windows.iter().map(|w| w.start_ms + self.window_ms).min()
+    }
+}
+
+/// Log a closed window, one line per field
+pub fn log_record(record: &WindowRecord) {
+    for (field, s) in &record.fields {
+        log::info!(
+            "[AGGREGATOR] {} {} over {}s: n={} mean={:.2} min={:.2} max={:.2} median={:.2} sd={:.2}",
+            record.sensor_type.name(),
+            field.name(),
+            (record.end_ms - record.start_ms) / 1000,
+            s.count,
+            s.mean,
+            s.min,
+            s.max,
+            s.median,
+            s.stddev
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::SensorData;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-4, "{} != {}", actual, expected);
+    }
+
+    fn pm(pm25: f32, timestamp: u64) -> SensorReading {
+        SensorReading {
+            sensor_type: SensorType::SDS011,
+            data: SensorData::AirQuality { pm25: Some(pm25), pm10: Some(pm25 * 2.0), pm25_corrected: None, pm10_corrected: None },
+            timestamp,
+            utc_timestamp: Some(1_704_067_200_000 + timestamp),
+            quality: Quality::Good,
+            flags: Flags::NONE,
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        assert_eq!(summarize(&[]), None);
+        assert_eq!(
+            summarize(&[4.0]),
+            Some(Summary { count: 1, mean: 4.0, min: 4.0, max: 4.0, median: 4.0, stddev: 0.0 })
+        );
+
+        let s = summarize(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]).unwrap();
+        assert_eq!((s.count, s.min, s.max), (8, 2.0, 9.0));
+        assert_close(s.mean, 5.0);
+        assert_close(s.median, 4.5);
+        assert_close(s.stddev, 2.0);
+
+        // Odd count, unsorted input, non-finite values skipped
+        let s = summarize(&[9.0, f32::NAN, 1.0, 5.0, f32::INFINITY]).unwrap();
+        assert_eq!((s.count, s.median), (3, 5.0));
+
+        // Large offset, where a naive sum of squares loses precision
+        let s = summarize(&[100_000.5, 100_001.5, 100_002.5]).unwrap();
+        assert_close(s.stddev, 0.816_496_6);
+    }
+
+    #[test]
+    fn test_median_window() {
+        let mut accumulator = Accumulator::new();
+        for v in 0..MAX_SAMPLES as u32 + 10 {
+            accumulator.push(v as f32);
+        }
+        let s = accumulator.summary().unwrap();
+        // Exact over everything, the median over the latest samples
+        assert_eq!((s.count, s.min, s.max), (42, 0.0, 41.0));
+        assert_close(s.mean, 20.5);
+        assert_close(s.median, 25.5);
+    }
+
+    #[test]
+    fn test_tumbling_windows() {
+        let mut windows = TumblingWindows::new(60_000);
+        assert_eq!(windows.next_deadline(), None);
+
+        for (i, value) in [10.0, 12.0, 20.0].into_iter().enumerate() {
+            assert!(windows.push(&pm(value, 61_000 + i as u64 * 20_000)).is_none());
+        }
+        let mut degraded = pm(14.0, 119_999);
+        degraded.quality = Quality::Degraded;
+        degraded.flags = Flags::HAMPEL_OUTLIER;
+        assert!(windows.push(&degraded).is_none());
+        let mut bad = pm(500.0, 119_999);
+        bad.quality = Quality::Bad;
+        assert!(windows.push(&bad).is_none());
+        assert_eq!(windows.next_deadline(), Some(120_000));
+
+        // The first reading of the next window closes this one
+        let record = windows.push(&pm(30.0, 120_000)).unwrap();
+        assert_eq!((record.sensor_type, record.start_ms, record.end_ms), (SensorType::SDS011, 60_000, 120_000));
+        assert_eq!(record.quality, Quality::Degraded);
+        let pm25 = record.field(Field::Pm25).unwrap();
+        assert_eq!((pm25.count, pm25.min, pm25.max, pm25.median), (4, 10.0, 20.0, 13.0));
+        assert_close(pm25.mean, 14.0);
+        assert_close(record.field(Field::Pm10).unwrap().mean, 28.0);
+
+        let mean = record.mean_reading();
+        assert_eq!(mean.data.value(Field::Pm25), Some(14.0));
+        assert_eq!(mean.timestamp, 119_999);
+        assert_eq!(mean.utc_timestamp, Some(1_704_067_200_000 + 119_999));
+        assert_eq!(mean.quality, Quality::Degraded);
+        assert_eq!(mean.flags, Flags::HAMPEL_OUTLIER);
+    }
+
+    #[test]
+    fn test_close_expired() {
+        let mut windows = TumblingWindows::new(10_000);
+        windows.push(&pm(1.0, 5_000));
+        let mut bme = pm(0.0, 12_000);
+        bme.sensor_type = SensorType::BME280;
+        bme.data = SensorData::Environmental { temperature: Some(20.0), humidity: None, pressure: None, gas_resistance: None, dew_point: None, absolute_humidity: None, heat_index: None, sea_level_pressure: None };
+        windows.push(&bme);
+
+        // Readings without values aren't windowed
+        let mut gps = pm(0.0, 5_000);
+        gps.sensor_type = SensorType::GPS;
+        gps.data = SensorData::Location { latitude: 1.0, longitude: 2.0, altitude: None, satellites: None };
+        assert!(!TumblingWindows::accepts(&gps));
+        windows.push(&gps);
+
+        assert_eq!(windows.next_deadline(), Some(10_000));
+        assert!(windows.close_expired(9_999).is_empty());
+        let closed = windows.close_expired(10_000);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].sensor_type, SensorType::SDS011);
+        assert_eq!(windows.next_deadline(), Some(20_000));
+
+        let closed = windows.close_expired(60_000);
+        assert_eq!(closed[0].field(Field::Temperature).unwrap().count, 1);
+        assert_eq!(windows.next_deadline(), None);
+    }
+}
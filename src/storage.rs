@@ -0,0 +1,83 @@
+//! Flash layout and integrity helpers
+//!
+//! Persistent data lives in fixed regions of the SPI flash, accessed through
+//! the `embedded-storage` NOR flash traits so the same code runs against
+//! `esp-storage` on the device and a RAM image in host tests.
+//!
+//! The regions sit inside the `nvs` partition of the default ESP-IDF
+//! partition table (0x9000..0xF000), which this firmware doesn't otherwise
+//! use. Flashing tools leave it alone, so data survives firmware updates.
+
+/// A region of flash, in bytes from the start of the chip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// Device configuration, two 4 KiB sectors
+pub const CONFIG_PARTITION: Partition = Partition {
+    offset: 0x9000,
+    size: 0x2000,
+};
+
+/// Store-and-forward ring log, the remaining four sectors of `nvs`
+pub const RING_LOG_PARTITION: Partition = Partition {
+    offset: 0xB000,
+    size: 0x4000,
+};
+
+impl Partition {
+    /// First byte after the partition
+    pub const fn end(&self) -> u32 {
+        self.offset + self.size
+    }
+
+    /// Number of whole erase sectors of `sector_size` bytes
+    pub const fn sectors(&self, sector_size: u32) -> u32 {
+        self.size / sector_size
+    }
+}
+
+/// CRC-32 (IEEE 802.3, as used by zlib and Ethernet)
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Continue a CRC-32 over more data
+/// `crc32_update(crc32(a), b)` equals the CRC of `a` followed by `b`
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        // Standard check value
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32_update(crc32(b"1234"), b"56789"), crc32(b"123456789"));
+    }
+
+    #[test]
+    fn test_partition_layout() {
+        assert_eq!(CONFIG_PARTITION.sectors(4096), 2);
+        assert_eq!(CONFIG_PARTITION.end(), 0xB000);
+        assert_eq!(CONFIG_PARTITION.offset % 4096, 0);
+        assert_eq!(RING_LOG_PARTITION.offset, CONFIG_PARTITION.end());
+        assert_eq!(RING_LOG_PARTITION.sectors(4096), 4);
+        // Within the nvs partition
+        assert_eq!(RING_LOG_PARTITION.end(), 0xF000);
+    }
+}
@@ -0,0 +1,197 @@
+//! Time sources for sensor readings
+//!
+//! Readings carry a monotonic timestamp (milliseconds since boot) taken from
+//! a pluggable [`Clock`], and a UTC timestamp once wall-clock time is known.
+//! On the device the clock is embassy's `Instant`; tests and host tools can
+//! install a [`ManualClock`] instead.
+
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+/// Source of monotonic time in milliseconds
+pub trait Clock: Sync {
+    /// Milliseconds since an arbitrary fixed point (normally boot)
+    fn now_ms(&self) -> u64;
+}
+
+/// Clock backed by the embassy time driver
+pub struct EmbassyClock;
+
+impl Clock for EmbassyClock {
+    fn now_ms(&self) -> u64 {
+        embassy_time::Instant::now().as_millis()
+    }
+}
+
+/// Clock that only moves when told to
+/// Useful for tests and for replaying recorded data
+pub struct ManualClock {
+    ms: Mutex<CriticalSectionRawMutex, Cell<u64>>,
+}
+
+impl ManualClock {
+    pub const fn new(start_ms: u64) -> Self {
+        Self {
+            ms: Mutex::new(Cell::new(start_ms)),
+        }
+    }
+
+    /// Jump to an absolute time
+    pub fn set(&self, ms: u64) {
+        self.ms.lock(|c| c.set(ms));
+    }
+
+    /// Move time forward
+    pub fn advance(&self, ms: u64) {
+        self.ms.lock(|c| c.set(c.get() + ms));
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.ms.lock(|c| c.get())
+    }
+}
+
+/// Clock used for all reading timestamps
+static CLOCK: Mutex<CriticalSectionRawMutex, Cell<&'static dyn Clock>> =
+    Mutex::new(Cell::new(&EmbassyClock));
+
+/// UTC time (ms since Unix epoch) corresponding to monotonic time zero
+/// `None` until wall-clock time has been set
+static UTC_AT_ZERO: Mutex<CriticalSectionRawMutex, Cell<Option<u64>>> = Mutex::new(Cell::new(None));
+
+/// Replace the clock used for timestamps
+pub fn set_clock(clock: &'static dyn Clock) {
+    CLOCK.lock(|c| c.set(clock));
+}
+
+/// Current monotonic time in milliseconds
+pub fn now_ms() -> u64 {
+    CLOCK.lock(|c| c.get()).now_ms()
+}
+
+/// Record the current wall-clock time (milliseconds since Unix epoch)
+/// All later UTC conversions are relative to this point
+pub fn set_utc_now(utc_ms: u64) {
+    set_utc_offset(utc_ms.saturating_sub(now_ms()));
+}
+
+/// Set the UTC time (ms since Unix epoch) at which the monotonic clock read zero
+/// This is the offset an NTP exchange measures directly
+pub fn set_utc_offset(utc_at_zero_ms: u64) {
+    UTC_AT_ZERO.lock(|c| c.set(Some(utc_at_zero_ms)));
+}
+
+/// Whether wall-clock time is known, i.e. readings get UTC timestamps
+pub fn utc_valid() -> bool {
+    UTC_AT_ZERO.lock(|c| c.get()).is_some()
+}
+
+/// Forget the wall-clock time, e.g. when it can no longer be trusted
+pub fn clear_utc() {
+    UTC_AT_ZERO.lock(|c| c.set(None));
+}
+
+/// Current UTC time in milliseconds since Unix epoch, if known
+pub fn utc_now_ms() -> Option<u64> {
+    utc_from_monotonic(now_ms())
+}
+
+/// Convert a monotonic timestamp to UTC milliseconds, if wall-clock time is known
+pub fn utc_from_monotonic(monotonic_ms: u64) -> Option<u64> {
+    UTC_AT_ZERO.lock(|c| c.get()).map(|zero| zero + monotonic_ms)
+}
+
+/// Civil date (year, month, day) for a count of days since 1970-01-01
+/// Proleptic Gregorian calendar, after Howard Hinnant's `civil_from_days`
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Format UTC milliseconds as RFC 3339, e.g. `2024-01-01T12:30:00.000Z`
+pub fn format_rfc3339(utc_ms: u64) -> heapless::String<24> {
+    use core::fmt::Write;
+
+    let secs = utc_ms / 1000;
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+    let mut out = heapless::String::new();
+    // Four-digit years always fit; later ones are truncated rather than panicking
+    let _ = write!(
+        out,
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, time / 3600, time / 60 % 60, time % 60, utc_ms % 1000
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::block_on;
+
+    static CLOCK_A: ManualClock = ManualClock::new(1_000);
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new(5);
+        assert_eq!(clock.now_ms(), 5);
+        clock.advance(10);
+        assert_eq!(clock.now_ms(), 15);
+        clock.set(100);
+        assert_eq!(clock.now_ms(), 100);
+    }
+
+    #[test]
+    fn test_utc_follows_installed_clock() {
+        // Global clock state is shared, so run under the mock time lock
+        block_on(async {
+            set_clock(&CLOCK_A);
+            clear_utc();
+            assert_eq!(now_ms(), 1_000);
+            assert_eq!(utc_now_ms(), None);
+            assert!(!utc_valid());
+
+            set_utc_now(1_700_000_000_000);
+            assert!(utc_valid());
+            assert_eq!(utc_now_ms(), Some(1_700_000_000_000));
+            assert_eq!(utc_from_monotonic(0), Some(1_699_999_999_000));
+
+            CLOCK_A.advance(2_500);
+            assert_eq!(utc_now_ms(), Some(1_700_000_002_500));
+
+            clear_utc();
+            assert_eq!(utc_now_ms(), None);
+            set_clock(&EmbassyClock);
+        });
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(format_rfc3339(0).as_str(), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_rfc3339(1_704_067_200_000).as_str(), "2024-01-01T00:00:00.000Z");
+        assert_eq!(format_rfc3339(1_709_210_096_789).as_str(), "2024-02-29T12:34:56.789Z");
+        assert_eq!(format_rfc3339(4_107_542_399_999).as_str(), "2100-02-28T23:59:59.999Z");
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn test_embassy_clock_is_default() {
+        block_on(async {
+            let before = now_ms();
+            embassy_time::Timer::after_millis(20).await;
+            assert!(now_ms() >= before + 20);
+        });
+    }
+}